@@ -58,6 +58,26 @@ rec {
       # File a bug if you depend on any for non-debug work!
       debug = internal.debugCrate { inherit packageId; };
     };
+    "plenty-core" = rec {
+      packageId = "plenty-core";
+      build = internal.buildRustCrateWithFeatures {
+        packageId = "plenty-core";
+      };
+
+      # Debug support which might change between releases.
+      # File a bug if you depend on any for non-debug work!
+      debug = internal.debugCrate { inherit packageId; };
+    };
+    "plenty-ffi" = rec {
+      packageId = "plenty-ffi";
+      build = internal.buildRustCrateWithFeatures {
+        packageId = "plenty-ffi";
+      };
+
+      # Debug support which might change between releases.
+      # File a bug if you depend on any for non-debug work!
+      debug = internal.debugCrate { inherit packageId; };
+    };
     "plentys" = rec {
       packageId = "plentys";
       build = internal.buildRustCrateWithFeatures {
@@ -181,6 +201,19 @@ rec {
             name = "find-msvc-tools";
             packageId = "find-msvc-tools";
           }
+          {
+            name = "jobserver";
+            packageId = "jobserver";
+            optional = true;
+            usesDefaultFeatures = false;
+          }
+          {
+            name = "libc";
+            packageId = "libc";
+            optional = true;
+            usesDefaultFeatures = false;
+            target = { target, features }: (target."unix" or false);
+          }
           {
             name = "shlex";
             packageId = "shlex";
@@ -189,6 +222,7 @@ rec {
         features = {
           "parallel" = [ "dep:libc" "dep:jobserver" ];
         };
+        resolvedDefaultFeatures = [ "parallel" ];
       };
       "cfg-if" = rec {
         crateName = "cfg-if";
@@ -302,6 +336,23 @@ rec {
           "serde_impl" = [ "serde" ];
         };
       };
+      "jobserver" = rec {
+        crateName = "jobserver";
+        version = "0.1.35";
+        edition = "2021";
+        sha256 = "1crwgbb0wjph42ni4hqryjxlv4vlr0hyk81g76id9fpa56ysq00w";
+        authors = [
+          "Alex Crichton <alex@alexcrichton.com>"
+        ];
+        dependencies = [
+          {
+            name = "libc";
+            packageId = "libc";
+            target = { target, features }: (target."unix" or false);
+          }
+        ];
+
+      };
       "libc" = rec {
         crateName = "libc";
         version = "0.2.177";
@@ -412,7 +463,7 @@ rec {
           "user" = [ "feature" ];
           "zerocopy" = [ "fs" "uio" ];
         };
-        resolvedDefaultFeatures = [ "default" "fs" ];
+        resolvedDefaultFeatures = [ "default" "fs" "hostname" ];
       };
       "once_cell" = rec {
         crateName = "once_cell";
@@ -464,18 +515,14 @@ rec {
             name = "anyhow";
             packageId = "anyhow";
           }
-          {
-            name = "nix";
-            packageId = "nix";
-            features = [ "fs" ];
-          }
           {
             name = "plenty-common";
             packageId = "plenty-common";
+            features = [ "sqlite" ];
           }
           {
-            name = "thiserror";
-            packageId = "thiserror";
+            name = "plenty-core";
+            packageId = "plenty-core";
           }
         ];
 
@@ -494,11 +541,93 @@ rec {
             name = "anyhow";
             packageId = "anyhow";
           }
+          {
+            name = "rusqlite";
+            packageId = "rusqlite";
+            optional = true;
+            features = [ "bundled" "functions" ];
+          }
           {
             name = "thiserror";
             packageId = "thiserror";
           }
         ];
+        features = {
+          "sqlite" = [ "dep:rusqlite" ];
+        };
+        resolvedDefaultFeatures = [ "sqlite" ];
+      };
+      "plenty-core" = rec {
+        crateName = "plenty-core";
+        version = "0.1.0";
+        edition = "2021";
+        src = lib.cleanSourceWith { filter = sourceFilter;  src = ./core; };
+        libName = "plenty_core";
+        authors = [
+          "plenty contributors"
+        ];
+        dependencies = [
+          {
+            name = "anyhow";
+            packageId = "anyhow";
+          }
+          {
+            name = "nix";
+            packageId = "nix";
+            features = [ "fs" "hostname" ];
+          }
+          {
+            name = "plenty-common";
+            packageId = "plenty-common";
+            features = [ "sqlite" ];
+          }
+          {
+            name = "regex-lite";
+            packageId = "regex-lite";
+          }
+          {
+            name = "rusqlite";
+            packageId = "rusqlite";
+            features = [ "bundled" ];
+          }
+          {
+            name = "thiserror";
+            packageId = "thiserror";
+          }
+          {
+            name = "zstd";
+            packageId = "zstd";
+            usesDefaultFeatures = false;
+          }
+        ];
+        devDependencies = [
+          {
+            name = "plentys";
+            packageId = "plentys";
+          }
+        ];
+
+      };
+      "plenty-ffi" = rec {
+        crateName = "plenty-ffi";
+        version = "0.1.0";
+        edition = "2021";
+        src = lib.cleanSourceWith { filter = sourceFilter;  src = ./ffi; };
+        libName = "plenty_ffi";
+        type = [ "cdylib" "staticlib" ];
+        authors = [
+          "plenty contributors"
+        ];
+        dependencies = [
+          {
+            name = "anyhow";
+            packageId = "anyhow";
+          }
+          {
+            name = "plenty-common";
+            packageId = "plenty-common";
+          }
+        ];
 
       };
       "plentys" = rec {
@@ -524,18 +653,30 @@ rec {
           {
             name = "plenty-common";
             packageId = "plenty-common";
+            features = [ "sqlite" ];
+          }
+          {
+            name = "regex-lite";
+            packageId = "regex-lite";
           }
           {
             name = "rusqlite";
             packageId = "rusqlite";
-            features = [ "bundled" ];
+            features = [ "bundled" "backup" ];
           }
           {
             name = "thiserror";
             packageId = "thiserror";
           }
+          {
+            name = "zstd";
+            packageId = "zstd";
+            usesDefaultFeatures = false;
+          }
         ];
-
+        features = {
+          "sqlcipher" = [ "rusqlite/bundled-sqlcipher" ];
+        };
       };
       "proc-macro2" = rec {
         crateName = "proc-macro2";
@@ -579,6 +720,21 @@ rec {
         };
         resolvedDefaultFeatures = [ "default" "proc-macro" ];
       };
+      "regex-lite" = rec {
+        crateName = "regex-lite";
+        version = "0.1.9";
+        edition = "2021";
+        sha256 = "0wzr31ysmiy9sw48i36raqbm1iyk2xnq0lp4zbs6fzi47p3k9f6a";
+        libName = "regex_lite";
+        authors = [
+          "The Rust Project Developers"
+          "Andrew Gallant <jamslam@gmail.com>"
+        ];
+        features = {
+          "default" = [ "std" "string" ];
+        };
+        resolvedDefaultFeatures = [ "default" "std" "string" ];
+      };
       "rusqlite" = rec {
         crateName = "rusqlite";
         version = "0.31.0";
@@ -642,7 +798,7 @@ rec {
           "window" = [ "functions" ];
           "with-asan" = [ "libsqlite3-sys/with-asan" ];
         };
-        resolvedDefaultFeatures = [ "bundled" "modern_sqlite" ];
+        resolvedDefaultFeatures = [ "backup" "bundled" "functions" "modern_sqlite" ];
       };
       "shlex" = rec {
         crateName = "shlex";
@@ -851,6 +1007,100 @@ rec {
         ];
 
       };
+      "zstd" = rec {
+        crateName = "zstd";
+        version = "0.13.3";
+        edition = "2018";
+        sha256 = "12n0h4w9l526li7jl972rxpyf012jw3nwmji2qbjghv9ll8y67p9";
+        authors = [
+          "Alexandre Bury <alexandre.bury@gmail.com>"
+        ];
+        dependencies = [
+          {
+            name = "zstd-safe";
+            packageId = "zstd-safe";
+            usesDefaultFeatures = false;
+            features = [ "std" ];
+          }
+        ];
+        features = {
+          "arrays" = [ "zstd-safe/arrays" ];
+          "bindgen" = [ "zstd-safe/bindgen" ];
+          "debug" = [ "zstd-safe/debug" ];
+          "default" = [ "legacy" "arrays" "zdict_builder" ];
+          "experimental" = [ "zstd-safe/experimental" ];
+          "fat-lto" = [ "zstd-safe/fat-lto" ];
+          "legacy" = [ "zstd-safe/legacy" ];
+          "no_asm" = [ "zstd-safe/no_asm" ];
+          "pkg-config" = [ "zstd-safe/pkg-config" ];
+          "thin" = [ "zstd-safe/thin" ];
+          "thin-lto" = [ "zstd-safe/thin-lto" ];
+          "zdict_builder" = [ "zstd-safe/zdict_builder" ];
+          "zstdmt" = [ "zstd-safe/zstdmt" ];
+        };
+      };
+      "zstd-safe" = rec {
+        crateName = "zstd-safe";
+        version = "7.3.0";
+        edition = "2018";
+        sha256 = "10kq3hik4yhm9n6ar9d02i3xm3llrnz402n8z7vdkfbdmd4hdn34";
+        libName = "zstd_safe";
+        authors = [
+          "Alexandre Bury <alexandre.bury@gmail.com>"
+        ];
+        dependencies = [
+          {
+            name = "zstd-sys";
+            packageId = "zstd-sys";
+            usesDefaultFeatures = false;
+          }
+        ];
+        features = {
+          "bindgen" = [ "zstd-sys/bindgen" ];
+          "debug" = [ "zstd-sys/debug" ];
+          "default" = [ "legacy" "arrays" "zdict_builder" ];
+          "experimental" = [ "zstd-sys/experimental" ];
+          "fat-lto" = [ "zstd-sys/fat-lto" ];
+          "legacy" = [ "zstd-sys/legacy" ];
+          "no_asm" = [ "zstd-sys/no_asm" ];
+          "pkg-config" = [ "zstd-sys/pkg-config" ];
+          "seekable" = [ "zstd-sys/seekable" ];
+          "std" = [ "zstd-sys/std" ];
+          "thin" = [ "zstd-sys/thin" ];
+          "thin-lto" = [ "zstd-sys/thin-lto" ];
+          "zdict_builder" = [ "zstd-sys/zdict_builder" ];
+          "zstdmt" = [ "zstd-sys/zstdmt" ];
+        };
+        resolvedDefaultFeatures = [ "std" ];
+      };
+      "zstd-sys" = rec {
+        crateName = "zstd-sys";
+        version = "2.1.1+zstd.1.5.7";
+        edition = "2018";
+        links = "zstd";
+        sha256 = "0y50xj2hmnbyzls0g8b6ja7bncxargzx0fz2069d1fzz5nprxv5f";
+        libName = "zstd_sys";
+        authors = [
+          "Alexandre Bury <alexandre.bury@gmail.com>"
+        ];
+        buildDependencies = [
+          {
+            name = "cc";
+            packageId = "cc";
+            features = [ "parallel" ];
+          }
+          {
+            name = "pkg-config";
+            packageId = "pkg-config";
+          }
+        ];
+        features = {
+          "bindgen" = [ "dep:bindgen" ];
+          "cmake" = [ "dep:cmake" ];
+          "default" = [ "legacy" "zdict_builder" ];
+        };
+        resolvedDefaultFeatures = [ "std" ];
+      };
     };
 
     #
@@ -0,0 +1,115 @@
+/// The codec on a sync's worth of entries: `cargo bench -p plenty-common`
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use plenty_common::{FlushPolicy, Flushing, HistoryEntry, Message, MessageType, ENTRY_CHUNK_SIZE};
+use std::io::{self, BufWriter, Write};
+
+const ENTRIES: u64 = 10_000;
+
+/// Entries like those of a shell history, with the metadata recorded
+fn entries() -> Vec<HistoryEntry> {
+    (0..ENTRIES as i64)
+        .map(|i| HistoryEntry {
+            cwd: format!("/home/user/src/project{}", i % 20),
+            exit_code: Some((i % 3) as i32),
+            duration_ms: Some(i as u64 * 7),
+            session: "4f1c2a".to_string(),
+            ..HistoryEntry::new(
+                format!("cargo test -p crate{} -- --nocapture", i),
+                i,
+                String::new(),
+            )
+            .with_host("laptop".to_string())
+        })
+        .collect()
+}
+
+fn encode(c: &mut Criterion) {
+    let entries = entries();
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Elements(ENTRIES));
+    group.bench_function("encode", |b| {
+        b.iter(|| {
+            for entry in &entries {
+                black_box(entry.encode());
+            }
+        })
+    });
+    group.bench_function("encode_into", |b| {
+        let mut buf = Vec::new();
+        b.iter(|| {
+            for entry in &entries {
+                buf.clear();
+                entry.encode_into(&mut buf);
+                black_box(&buf);
+            }
+        })
+    });
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let encoded: Vec<_> = entries().iter().map(HistoryEntry::encode).collect();
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(ENTRIES));
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            for data in &encoded {
+                black_box(HistoryEntry::decode(data).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Sending entries down a buffered stream, as syncs and exports do
+fn write(c: &mut Criterion) {
+    let entries = entries();
+    let mut group = c.benchmark_group("write");
+    group.throughput(Throughput::Elements(ENTRIES));
+    group.bench_function("message per entry, flushed", |b| {
+        b.iter_batched_ref(
+            || BufWriter::new(io::sink()),
+            |writer| {
+                for entry in &entries {
+                    Message::new(MessageType::HistoryEntry, entry.encode())
+                        .write_to(writer)
+                        .unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("write_messages_with", |b| {
+        let mut buf = Vec::new();
+        b.iter_batched_ref(
+            || BufWriter::new(io::sink()),
+            |writer| {
+                for entry in &entries {
+                    entry.write_messages_with(writer, true, &mut buf).unwrap();
+                }
+                writer.flush().unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("write_messages_with, flushed every 64 KiB", |b| {
+        let mut buf = Vec::new();
+        b.iter_batched_ref(
+            || {
+                let writer = BufWriter::with_capacity(4 * ENTRY_CHUNK_SIZE, io::sink());
+                Flushing::new(writer, FlushPolicy::Bytes(ENTRY_CHUNK_SIZE))
+            },
+            |writer| {
+                for entry in &entries {
+                    entry.write_messages_with(writer, true, &mut buf).unwrap();
+                }
+                writer.flush().unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode, write);
+criterion_main!(benches);
@@ -0,0 +1,321 @@
+/// Minimal TOML-subset parser shared by the plenty configuration files
+///
+/// Supports `[section]` headers, `key = value` pairs, basic and literal
+/// strings, integers, booleans, and (possibly multi-line) arrays of those.
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("{key}: expected {expected}")]
+    Type { key: String, expected: &'static str },
+    #[error("failed to read {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+}
+
+/// A configuration value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// A parsed configuration document, keyed by section then key.
+/// Top-level keys live in the section named `""`.
+#[derive(Debug, Clone, Default)]
+pub struct Document {
+    sections: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+impl Document {
+    /// Parse a document from its textual form
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut doc = Document::default();
+        let mut section = String::new();
+        let mut lines = text.lines().enumerate();
+
+        while let Some((idx, raw)) = lines.next() {
+            let line_no = idx + 1;
+            let mut line = strip_comment(raw).trim().to_string();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(syntax(line_no, "unterminated section header"));
+                }
+                section = line[1..line.len() - 1]
+                    .split('.')
+                    .map(|part| part.trim().trim_matches('"'))
+                    .collect::<Vec<_>>()
+                    .join(".");
+                if section.is_empty() {
+                    return Err(syntax(line_no, "empty section name"));
+                }
+                doc.sections.entry(section.clone()).or_default();
+                continue;
+            }
+
+            // Multi-line arrays: keep reading until brackets balance
+            while bracket_depth(&line) > 0 {
+                match lines.next() {
+                    Some((_, more)) => {
+                        line.push(' ');
+                        line.push_str(strip_comment(more).trim());
+                    }
+                    None => return Err(syntax(line_no, "unterminated array")),
+                }
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| syntax(line_no, "expected `key = value`"))?;
+            let key = key.trim().trim_matches('"');
+            if key.is_empty() {
+                return Err(syntax(line_no, "empty key"));
+            }
+            let (value, rest) = parse_value(value.trim()).map_err(|m| syntax(line_no, &m))?;
+            if !rest.trim().is_empty() {
+                return Err(syntax(line_no, "trailing characters after value"));
+            }
+
+            let table = doc.sections.entry(section.clone()).or_default();
+            if table.insert(key.to_string(), value).is_some() {
+                return Err(syntax(line_no, &format!("duplicate key `{}`", key)));
+            }
+        }
+
+        Ok(doc)
+    }
+
+    /// Load a document from disk, returning an empty document if the file does not exist
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(ConfigError::Io {
+                path: path.display().to_string(),
+                source: e,
+            }),
+        }
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<&Value> {
+        self.sections.get(section).and_then(|t| t.get(key))
+    }
+
+    /// Keys defined in `section`, in sorted order
+    pub fn keys(&self, section: &str) -> impl Iterator<Item = &str> {
+        self.sections
+            .get(section)
+            .into_iter()
+            .flat_map(|t| t.keys().map(String::as_str))
+    }
+
+    /// Names of all sections, including the top-level `""` section if it has keys
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.sections.keys().map(String::as_str)
+    }
+
+    pub fn get_str(&self, section: &str, key: &str) -> Result<Option<&str>, ConfigError> {
+        match self.get(section, key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err(type_error(section, key, "a string")),
+        }
+    }
+
+    pub fn get_int(&self, section: &str, key: &str) -> Result<Option<i64>, ConfigError> {
+        match self.get(section, key) {
+            None => Ok(None),
+            Some(Value::Integer(i)) => Ok(Some(*i)),
+            Some(_) => Err(type_error(section, key, "an integer")),
+        }
+    }
+
+    pub fn get_bool(&self, section: &str, key: &str) -> Result<Option<bool>, ConfigError> {
+        match self.get(section, key) {
+            None => Ok(None),
+            Some(Value::Boolean(b)) => Ok(Some(*b)),
+            Some(_) => Err(type_error(section, key, "a boolean")),
+        }
+    }
+
+    pub fn get_str_array(
+        &self,
+        section: &str,
+        key: &str,
+    ) -> Result<Option<Vec<String>>, ConfigError> {
+        match self.get(section, key) {
+            None => Ok(None),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|v| match v {
+                    Value::String(s) => Ok(s.clone()),
+                    _ => Err(type_error(section, key, "an array of strings")),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(Some),
+            Some(_) => Err(type_error(section, key, "an array of strings")),
+        }
+    }
+}
+
+fn syntax(line: usize, message: &str) -> ConfigError {
+    ConfigError::Syntax {
+        line,
+        message: message.to_string(),
+    }
+}
+
+fn type_error(section: &str, key: &str, expected: &'static str) -> ConfigError {
+    let key = if section.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", section, key)
+    };
+    ConfigError::Type { key, expected }
+}
+
+/// Remove a trailing `#` comment, ignoring `#` inside strings
+fn strip_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+/// Count unclosed `[` outside strings, for multi-line arrays
+fn bracket_depth(line: &str) -> i32 {
+    let Some((_, value)) = line.split_once('=') else {
+        return 0;
+    };
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for c in value.chars() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '[' => depth += 1,
+            None if c == ']' => depth -= 1,
+            None => {}
+        }
+    }
+    depth
+}
+
+/// Parse one value from the start of `input`, returning it and the unparsed remainder
+fn parse_value(input: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = input.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(out), &rest[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => out.push('\n'),
+                    Some((_, 't')) => out.push('\t'),
+                    Some((_, '"')) => out.push('"'),
+                    Some((_, '\\')) => out.push('\\'),
+                    Some((_, other)) => return Err(format!("unsupported escape `\\{}`", other)),
+                    None => break,
+                },
+                _ => out.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+
+    if let Some(rest) = input.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("unterminated string")?;
+        return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+    }
+
+    if let Some(mut rest) = input.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected `,` or `]` in array".to_string());
+            }
+        }
+    }
+
+    let end = input
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(input.len());
+    let (token, rest) = input.split_at(end);
+    match token {
+        "true" => Ok((Value::Boolean(true), rest)),
+        "false" => Ok((Value::Boolean(false), rest)),
+        _ => token
+            .replace('_', "")
+            .parse::<i64>()
+            .map(|i| (Value::Integer(i), rest))
+            .map_err(|_| format!("invalid value `{}`", token)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections_and_values() {
+        let doc = Document::parse(
+            "hosts = [\"a\", 'b'] # comment\n\n[local]\nmax_entries = 100_000\nenabled = true\nname = \"x # y\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            doc.get_str_array("", "hosts").unwrap(),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(doc.get_int("local", "max_entries").unwrap(), Some(100_000));
+        assert_eq!(doc.get_bool("local", "enabled").unwrap(), Some(true));
+        assert_eq!(doc.get_str("local", "name").unwrap(), Some("x # y"));
+        assert!(doc.get_int("local", "name").is_err());
+    }
+
+    #[test]
+    fn parses_multiline_arrays_and_reports_errors() {
+        let doc = Document::parse("list = [\n  \"a\",\n  \"b\",\n]\n").unwrap();
+        assert_eq!(
+            doc.get_str_array("", "list").unwrap().unwrap(),
+            vec!["a", "b"]
+        );
+        assert!(matches!(
+            Document::parse("[x]\nkey\n"),
+            Err(ConfigError::Syntax { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,139 @@
+/// Reading and writing fish's history file format
+use crate::HistoryEntry;
+use anyhow::Result;
+use std::io::Write;
+
+/// Decode fish_history bytes into text, writing each byte that isn't valid
+/// UTF-8 as `\xNN`. fish escapes backslashes in the file, so a lone `\x`
+/// cannot otherwise occur and the bytes can be restored on the way out.
+fn from_fish_bytes(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    text
+}
+
+/// Inverse of `from_fish_bytes`
+fn to_fish_bytes(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match &bytes[i..] {
+            [b'\\', b'\\', ..] => {
+                out.extend_from_slice(b"\\\\");
+                i += 2;
+            }
+            [b'\\', b'x', hi, lo, ..] if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                let hex = std::str::from_utf8(&bytes[i + 2..i + 4]).unwrap_or_default();
+                out.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Parse the entries of a fish_history file, keeping the lines after
+/// `when` (such as `paths`) as each entry's extra field
+pub fn parse_history(content: &[u8]) -> Result<Vec<HistoryEntry>> {
+    let content = from_fish_bytes(content);
+    let mut entries = Vec::new();
+    let mut current_cmd: Option<String> = None;
+    let mut current_when: Option<i64> = None;
+    let mut current_extra_lines: Vec<String> = Vec::new();
+
+    for line in content.lines() {
+        if let Some(cmd) = line.strip_prefix("- cmd: ") {
+            if let (Some(cmd), Some(when)) = (current_cmd.take(), current_when.take()) {
+                let extra = current_extra_lines.join("\n");
+                entries.push(HistoryEntry::new(cmd, when, extra));
+                current_extra_lines.clear();
+            }
+            current_cmd = Some(cmd.to_string());
+        } else if let Some(when) = line.strip_prefix("  when: ") {
+            current_when = when.parse().ok();
+        } else if line.starts_with("  ") && current_cmd.is_some() {
+            current_extra_lines.push(line.to_string());
+        }
+    }
+
+    if let (Some(cmd), Some(when)) = (current_cmd, current_when) {
+        let extra = current_extra_lines.join("\n");
+        entries.push(HistoryEntry::new(cmd, when, extra));
+    }
+
+    Ok(entries)
+}
+
+/// A command as fish records it in fish_history, on one line: backslashes
+/// doubled and newlines as `\n`
+pub fn escape_cmd(cmd: &str) -> String {
+    cmd.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+/// Write an entry the way fish does, the inverse of `parse_history`
+pub fn write_entry<W: Write>(out: &mut W, entry: &HistoryEntry) -> std::io::Result<()> {
+    out.write_all(b"- cmd: ")?;
+    out.write_all(&to_fish_bytes(&entry.cmd))?;
+    writeln!(out, "\n  when: {}", entry.when)?;
+    if !entry.extra.is_empty() {
+        out.write_all(&to_fish_bytes(&entry.extra))?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_preserves_multiline_paths() {
+        let sample = "- cmd: ls\n  when: 42\n  paths:\n    - /tmp\n    - /etc\n";
+        let entries = parse_history(sample.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].extra, "  paths:\n    - /tmp\n    - /etc");
+    }
+
+    #[test]
+    fn format_round_trip_preserves_paths() {
+        let entry = HistoryEntry::new(
+            "ls".to_string(),
+            42,
+            "  paths:\n    - /tmp\n    - /etc".to_string(),
+        );
+        let mut formatted = Vec::new();
+        write_entry(&mut formatted, &entry).unwrap();
+        assert_eq!(
+            String::from_utf8(formatted).unwrap(),
+            "- cmd: ls\n  when: 42\n  paths:\n    - /tmp\n    - /etc\n"
+        );
+    }
+
+    #[test]
+    fn commands_are_escaped_onto_one_line() {
+        assert_eq!(
+            escape_cmd("for f in *\n  echo \\$f\nend"),
+            "for f in *\\n  echo \\\\$f\\nend"
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_round_trips() {
+        let sample = b"- cmd: echo caf\xe9 \\\\x41\n  when: 1\n";
+        let entries = parse_history(sample).unwrap();
+        assert_eq!(entries[0].cmd, "echo caf\\xe9 \\\\x41");
+        let mut formatted = Vec::new();
+        write_entry(&mut formatted, &entries[0]).unwrap();
+        assert_eq!(formatted, sample);
+    }
+}
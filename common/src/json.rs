@@ -0,0 +1,307 @@
+/// Just enough JSON for log lines and the jsonl exports: strings, integers,
+/// booleans and null, in objects that don't nest
+use crate::HistoryEntry;
+use anyhow::{bail, Context, Result};
+
+/// A value in a flat JSON object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+    Null,
+}
+
+/// Quote and escape `text` as a JSON string
+pub fn string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `entry` as the JSON object the jsonl format has on each line
+pub fn entry(entry: &HistoryEntry) -> String {
+    let mut metadata = String::new();
+    if !entry.cwd.is_empty() {
+        metadata += &format!(",\"cwd\":{}", string(&entry.cwd));
+    }
+    if let Some(exit_code) = entry.exit_code {
+        metadata += &format!(",\"exit_code\":{}", exit_code);
+    }
+    if let Some(duration_ms) = entry.duration_ms {
+        metadata += &format!(",\"duration_ms\":{}", duration_ms);
+    }
+    if !entry.session.is_empty() {
+        metadata += &format!(",\"session\":{}", string(&entry.session));
+    }
+    if !entry.origin_device.is_empty() {
+        metadata += &format!(",\"origin_device\":{}", string(&entry.origin_device));
+    }
+    if let Some(received_at) = entry.received_at {
+        metadata += &format!(",\"received_at\":{}", received_at);
+    }
+    if !entry.namespace.is_empty() {
+        metadata += &format!(",\"namespace\":{}", string(&entry.namespace));
+    }
+    if !entry.signature.is_empty() {
+        metadata += &format!(",\"signature\":\"{}\"", hex(&entry.signature));
+    }
+    format!(
+        "{{\"cmd\":{},\"when\":{},\"extra\":{},\"host\":{},\"pinned\":{}{}}}",
+        string(&entry.cmd),
+        entry.when,
+        string(&entry.extra),
+        string(&entry.host),
+        entry.pinned,
+        metadata
+    )
+}
+
+/// `bytes` in lowercase hex, as binary fields are written
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The bytes of hex text
+pub fn unhex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        bail!("Invalid hex {:?}", hex);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).with_context(|| format!("Invalid hex {:?}", hex))
+        })
+        .collect()
+}
+
+/// Parse a JSON object whose values are all strings, integers, booleans or
+/// null, returning its members in order
+pub fn parse_object(text: &str) -> Result<Vec<(String, Value)>> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+    };
+    let mut members = Vec::new();
+    parser.expect('{')?;
+    if parser.peek() == Some('}') {
+        parser.chars.next();
+    } else {
+        loop {
+            let key = parser.string()?;
+            parser.expect(':')?;
+            members.push((key, parser.value()?));
+            match parser.next()? {
+                ',' => continue,
+                '}' => break,
+                c => bail!("expected ',' or '}}', found {:?}", c),
+            }
+        }
+    }
+    if let Some(c) = parser.peek() {
+        bail!("unexpected {:?} after object", c);
+    }
+    Ok(members)
+}
+
+/// Parse a line written by the jsonl export; only cmd and when are required
+pub fn parse_entry(line: &str) -> Result<HistoryEntry> {
+    let mut entry = HistoryEntry::new(String::new(), 0, String::new());
+    let (mut has_cmd, mut has_when) = (false, false);
+    for (key, value) in parse_object(line)? {
+        match (key.as_str(), value) {
+            ("cmd", Value::String(cmd)) => {
+                entry.cmd = cmd;
+                has_cmd = true;
+            }
+            ("when", Value::Integer(when)) => {
+                entry.when = when;
+                has_when = true;
+            }
+            ("extra", Value::String(extra)) => entry.extra = extra,
+            ("host", Value::String(host)) => entry.host = host,
+            ("pinned", Value::Bool(pinned)) => entry.pinned = pinned,
+            ("cwd", Value::String(cwd)) => entry.cwd = cwd,
+            ("exit_code", Value::Integer(code)) if i32::try_from(code).is_ok() => {
+                entry.exit_code = Some(code as i32)
+            }
+            ("duration_ms", Value::Integer(duration)) if duration >= 0 => {
+                entry.duration_ms = Some(duration as u64)
+            }
+            ("session", Value::String(session)) => entry.session = session,
+            ("origin_device", Value::String(device)) => entry.origin_device = device,
+            ("received_at", Value::Integer(received_at)) => entry.received_at = Some(received_at),
+            ("namespace", Value::String(namespace)) => entry.namespace = namespace,
+            ("signature", Value::String(signature)) => entry.signature = unhex(&signature)?,
+            (
+                "extra" | "host" | "pinned" | "cwd" | "exit_code" | "duration_ms" | "session"
+                | "origin_device" | "received_at" | "namespace" | "signature",
+                Value::Null,
+            ) => {}
+            (
+                "cmd" | "when" | "extra" | "host" | "pinned" | "cwd" | "exit_code" | "duration_ms"
+                | "session" | "origin_device" | "received_at" | "namespace" | "signature",
+                value,
+            ) => bail!("Invalid {}: {:?}", key, value),
+            _ => {}
+        }
+    }
+    if !has_cmd || !has_when {
+        bail!("Entries need a cmd and a when");
+    }
+    Ok(entry)
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    /// The next character that isn't whitespace, without consuming it
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        self.peek();
+        self.chars.next().context("unexpected end of line")
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => bail!("expected {:?}, found {:?}", expected, c),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = self.chars.next_if(|c| *c == '-' || c.is_ascii_digit()) {
+                    number.push(c);
+                }
+                Ok(Value::Integer(number.parse().with_context(|| {
+                    format!("invalid integer {:?}", number)
+                })?))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(c) = self.chars.next_if(char::is_ascii_alphabetic) {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "null" => Ok(Value::Null),
+                    _ => bail!("unexpected {:?}", word),
+                }
+            }
+            Some(c) => bail!("unsupported value starting with {:?}", c),
+            None => bail!("unexpected end of line"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut text = String::new();
+        loop {
+            match self.chars.next().context("unterminated string")? {
+                '"' => return Ok(text),
+                '\\' => match self.chars.next().context("unterminated string")? {
+                    'n' => text.push('\n'),
+                    'r' => text.push('\r'),
+                    't' => text.push('\t'),
+                    'b' => text.push('\u{8}'),
+                    'f' => text.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.hex4()?;
+                        if (0xd800..0xdc00).contains(&code) {
+                            if self.chars.next() != Some('\\') || self.chars.next() != Some('u') {
+                                bail!("unpaired surrogate in string");
+                            }
+                            let low = self.hex4()?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                bail!("unpaired surrogate in string");
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        text.push(char::from_u32(code).context("invalid \\u escape")?);
+                    }
+                    c @ ('"' | '\\' | '/') => text.push(c),
+                    c => bail!("invalid escape \\{}", c),
+                },
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits: String = (0..4).filter_map(|_| self.chars.next()).collect();
+        u32::from_str_radix(&digits, 16).with_context(|| format!("invalid \\u{}", digits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(string("plain"), "\"plain\"");
+        assert_eq!(
+            string("say \"hi\"\\\n\u{1}"),
+            "\"say \\\"hi\\\"\\\\\\n\\u0001\""
+        );
+    }
+
+    #[test]
+    fn objects_round_trip() {
+        let text = "say \"hi\"\\\n\u{1} café";
+        let line = format!(
+            "{{\"cmd\": {}, \"when\":-42 ,\"pinned\":true,\"host\":null}}",
+            string(text)
+        );
+        assert_eq!(
+            parse_object(&line).unwrap(),
+            vec![
+                ("cmd".to_string(), Value::String(text.to_string())),
+                ("when".to_string(), Value::Integer(-42)),
+                ("pinned".to_string(), Value::Bool(true)),
+                ("host".to_string(), Value::Null),
+            ]
+        );
+        assert_eq!(
+            parse_object(r#"{"s":"\ud83d\ude00\u00e9/\/"}"#).unwrap(),
+            vec![("s".to_string(), Value::String("😀é//".to_string()))]
+        );
+        assert!(parse_object("{}").unwrap().is_empty());
+        assert!(parse_object(r#"{"a":[1]}"#).is_err());
+        assert!(parse_object(r#"{"a":1} x"#).is_err());
+        assert!(parse_object(r#"{"a":"x"#).is_err());
+    }
+
+    #[test]
+    fn signatures_are_written_in_hex() {
+        let mut signed = HistoryEntry::new("ls".to_string(), 1, String::new());
+        signed.signature = vec![0, 0xab, 0x7f];
+        let line = entry(&signed);
+        assert!(line.ends_with(",\"signature\":\"00ab7f\"}"));
+        assert_eq!(parse_entry(&line).unwrap(), signed);
+        assert!(parse_entry(r#"{"cmd":"ls","when":1,"signature":"0g"}"#).is_err());
+        assert!(parse_entry(r#"{"cmd":"ls","when":1,"signature":"abc"}"#).is_err());
+    }
+}
@@ -1,5 +1,28 @@
 /// TLV (Type-Length-Value) protocol implementation for plenty
-use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, IoSlice, Read, Result as IoResult, Write};
+
+pub mod config;
+pub mod fish;
+pub mod json;
+#[cfg(feature = "sqlite")]
+pub mod store;
+pub mod time;
+pub mod vectors;
+
+/// Version of the wire protocol, exchanged in Hello messages
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Largest EntryChunk message, and HistoryEntry message when chunking
+pub const ENTRY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest history entry read from chunks, so that a peer sending chunks
+/// without end fails rather than exhausting memory
+pub const MAX_CHUNKED_ENTRY: usize = 16 * 1024 * 1024;
+
+/// What the content entry signatures sign starts with, so that they can't
+/// be taken for signatures of anything else
+pub const SIGNED_PREFIX: &[u8] = b"plenty entry\0";
 
 /// Message types in the TLV protocol
 #[repr(u8)]
@@ -9,10 +32,64 @@ pub enum MessageType {
     HistoryEntry = 1,
     /// Request full history from server
     GetHistory = 2,
-    /// End of transmission
+    /// End of transmission; after history sent for GetHistory, the server's
+    /// SessionSummary of the uploads so far
     End = 3,
     /// Error message
     Error = 4,
+    /// Request server statistics
+    GetStats = 5,
+    /// Server statistics, in response to GetStats
+    Stats = 6,
+    /// Handshake: protocol and software version, sent by both sides
+    Hello = 7,
+    /// Delete every entry with a command hash, and keep it deleted (8-byte hash)
+    DeleteEntry = 8,
+    /// Number of entries removed, in response to DeleteEntry (8-byte count)
+    Deleted = 9,
+    /// Pin (1) or unpin (0) a command: 1-byte flag, then the command
+    PinEntry = 10,
+    /// Number of entries for the command, in response to PinEntry (8-byte count)
+    Pinned = 11,
+    /// Search, answered with matching HistoryEntry messages then End, which
+    /// holds a cursor to the next page if the limit was reached
+    Query = 12,
+    /// The client went over one of the server's limits, which ends the session
+    QuotaExceeded = 13,
+    /// Entries the server failed to store, which ends the session
+    NotStored = 14,
+    /// Ask the server what it runs and holds
+    GetServerInfo = 15,
+    /// Server version, schema, entry count and capabilities, in response to
+    /// GetServerInfo
+    ServerInfo = 16,
+    /// The HistoryEntry messages answering a GetHistory that asked for a
+    /// snapshot, compressed together with zstd; End follows as usual
+    Snapshot = 17,
+    /// Delete entries and keep them deleted: a count-prefixed list of their
+    /// 8-byte content hashes, answered by Deleted
+    DeleteEntries = 18,
+    /// Content hashes of entries deleted since the GetHistory request's
+    /// `tombstones_since`, as in DeleteEntries, sent before its entries
+    Tombstones = 19,
+    /// Ask for the summaries of the server's entries per bucket of time
+    GetBuckets = 20,
+    /// Bucket summaries, in response to GetBuckets: a count, then each
+    /// bucket's 8-byte start, entry count and XOR of entry hashes
+    Buckets = 21,
+    /// Ask for the commands best worth suggesting, as a SuggestQuery
+    Suggest = 22,
+    /// Suggested commands, in response to Suggest: a count, then each
+    /// command with its use count and last use, best first
+    Suggestions = 23,
+    /// Part of the encoding of a history entry too large for one message,
+    /// between peers supporting "entry-chunks": the HistoryEntry message
+    /// following the entry's chunks holds the rest of it
+    EntryChunk = 24,
+    /// Acknowledges uploads to clients listing "acks": the 8-byte count of
+    /// the session's entries, in the order sent, that the server has stored,
+    /// skipped as already stored or rejected, sent after each batch commits
+    Stored = 25,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -24,6 +101,27 @@ impl TryFrom<u8> for MessageType {
             2 => Ok(MessageType::GetHistory),
             3 => Ok(MessageType::End),
             4 => Ok(MessageType::Error),
+            5 => Ok(MessageType::GetStats),
+            6 => Ok(MessageType::Stats),
+            7 => Ok(MessageType::Hello),
+            8 => Ok(MessageType::DeleteEntry),
+            9 => Ok(MessageType::Deleted),
+            10 => Ok(MessageType::PinEntry),
+            11 => Ok(MessageType::Pinned),
+            12 => Ok(MessageType::Query),
+            13 => Ok(MessageType::QuotaExceeded),
+            14 => Ok(MessageType::NotStored),
+            15 => Ok(MessageType::GetServerInfo),
+            16 => Ok(MessageType::ServerInfo),
+            17 => Ok(MessageType::Snapshot),
+            18 => Ok(MessageType::DeleteEntries),
+            19 => Ok(MessageType::Tombstones),
+            20 => Ok(MessageType::GetBuckets),
+            21 => Ok(MessageType::Buckets),
+            22 => Ok(MessageType::Suggest),
+            23 => Ok(MessageType::Suggestions),
+            24 => Ok(MessageType::EntryChunk),
+            25 => Ok(MessageType::Stored),
             _ => Err(anyhow::anyhow!("Invalid message type: {}", value)),
         }
     }
@@ -41,19 +139,32 @@ impl Message {
         Self { msg_type, data }
     }
 
-    /// Write a TLV message to a writer
+    /// Write a TLV message to a writer and flush it
     pub fn write_to<W: Write>(&self, writer: &mut W) -> IoResult<()> {
-        // Type (1 byte)
-        writer.write_all(&[self.msg_type as u8])?;
+        self.write_unflushed(writer)?;
+        writer.flush()
+    }
+
+    /// Write a TLV message to a writer, leaving it in the writer's buffer
+    pub fn write_unflushed<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        Self::write_frame(writer, self.msg_type, &self.data)
+    }
 
-        // Length (4 bytes, big-endian)
-        let len = self.data.len() as u32;
-        writer.write_all(&len.to_be_bytes())?;
+    /// Write the frame of a message of `msg_type` holding `data`, leaving it
+    /// in the writer's buffer, without building a Message to own the data.
+    /// The header and value go in one vectored write: a single syscall on
+    /// unbuffered writers such as pipes, and a single copy into buffers
+    pub fn write_frame<W: Write>(
+        writer: &mut W,
+        msg_type: MessageType,
+        data: &[u8],
+    ) -> IoResult<()> {
+        // Type (1 byte), then length (4 bytes, big-endian)
+        let mut header = [msg_type as u8, 0, 0, 0, 0];
+        header[1..].copy_from_slice(&(data.len() as u32).to_be_bytes());
 
         // Value
-        writer.write_all(&self.data)?;
-
-        writer.flush()
+        write_all_vectored(writer, &mut [IoSlice::new(&header), IoSlice::new(data)])
     }
 
     /// Read a TLV message from a reader
@@ -75,25 +186,477 @@ impl Message {
 
         Ok(Message { msg_type, data })
     }
+
+    /// Read a message, the EntryChunk messages of an entry being joined with
+    /// the HistoryEntry message ending them into one HistoryEntry message
+    pub fn read_joined<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let mut chunks = EntryChunks::default();
+        loop {
+            let msg = Message::read_from(reader)?;
+            if let Some(msg) = chunks
+                .push(msg)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+            {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// The message's frame, as `write_to` writes it
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(5 + self.data.len());
+        frame.push(self.msg_type as u8);
+        frame.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&self.data);
+        frame
+    }
+
+    /// The message framed at the start of `bytes`, and the length of its
+    /// frame, or None if `bytes` end before it does; for callers without
+    /// readers, such as browsers
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Option<(Self, usize)>> {
+        let Some(header) = bytes.get(..5) else {
+            return Ok(None);
+        };
+        let msg_type = MessageType::try_from(header[0])?;
+        let len = u32::from_be_bytes(header[1..].try_into()?) as usize;
+        Ok(bytes
+            .get(5..5 + len)
+            .map(|data| (Message::new(msg_type, data.to_vec()), 5 + len)))
+    }
+}
+
+/// Write all of `bufs`, in as few writes as the writer takes them in
+fn write_all_vectored<W: Write>(writer: &mut W, mut bufs: &mut [IoSlice]) -> IoResult<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "failed to write frame")),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// When a `Flushing` writer flushes what it is given, besides when its
+/// caller flushes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Only when the caller flushes, at the end of each batch of frames
+    #[default]
+    Batch,
+    /// Also whenever this many bytes were written since the last flush, so
+    /// the peer gets to work on a long batch before it ends
+    Bytes(usize),
+}
+
+/// A writer flushing its inner writer as its policy says, so that frames
+/// written with `Message::write_unflushed` or
+/// `HistoryEntry::write_messages_with` reach the peer per batch, or per so
+/// many bytes, instead of per frame
+pub struct Flushing<W: Write> {
+    inner: W,
+    policy: FlushPolicy,
+    /// Bytes written since the last flush
+    unflushed: usize,
+}
+
+impl<W: Write> Flushing<W> {
+    pub fn new(inner: W, policy: FlushPolicy) -> Self {
+        Flushing {
+            inner,
+            policy,
+            unflushed: 0,
+        }
+    }
+
+    /// Flush as `policy` says from now on
+    pub fn set_policy(&mut self, policy: FlushPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn wrote(&mut self, written: usize) -> IoResult<usize> {
+        self.unflushed += written;
+        if let FlushPolicy::Bytes(limit) = self.policy {
+            if self.unflushed >= limit {
+                self.flush()?;
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl<W: Write> Write for Flushing<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let written = self.inner.write(buf)?;
+        self.wrote(written)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> IoResult<usize> {
+        let written = self.inner.write_vectored(bufs)?;
+        self.wrote(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.unflushed = 0;
+        self.inner.flush()
+    }
+}
+
+/// Joins the EntryChunk messages of an entry with the HistoryEntry message
+/// ending them, as they are received
+#[derive(Debug, Default)]
+pub struct EntryChunks {
+    data: Vec<u8>,
+}
+
+impl EntryChunks {
+    /// Take the next message received: None for a chunk, otherwise the
+    /// message, with the data of the chunks before it if any
+    pub fn push(&mut self, mut msg: Message) -> anyhow::Result<Option<Message>> {
+        match msg.msg_type {
+            MessageType::EntryChunk => {
+                if self.data.len() + msg.data.len() > MAX_CHUNKED_ENTRY {
+                    anyhow::bail!("History entry over {} bytes", MAX_CHUNKED_ENTRY);
+                }
+                self.data.extend_from_slice(&msg.data);
+                Ok(None)
+            }
+            _ if self.data.is_empty() => Ok(Some(msg)),
+            MessageType::HistoryEntry => {
+                let mut data = std::mem::take(&mut self.data);
+                data.extend_from_slice(&msg.data);
+                msg.data = data;
+                Ok(Some(msg))
+            }
+            other => anyhow::bail!(
+                "Entry chunks followed by {:?} rather than HistoryEntry",
+                other
+            ),
+        }
+    }
+}
+
+/// Stable 64-bit FNV-1a hash of a command, identifying it in deletions
+pub fn cmd_hash(cmd: &str) -> u64 {
+    cmd.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Stable 64-bit FNV-1a hash of an entry's content, identifying duplicates
+pub fn entry_hash(cmd: &str, when: i64, extra: &str) -> u64 {
+    (cmd.len() as u64)
+        .to_be_bytes()
+        .iter()
+        .chain(cmd.as_bytes())
+        .chain(&when.to_be_bytes())
+        .chain(extra.as_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// Decode a message payload consisting of a single big-endian u64
+pub fn decode_u64(data: &[u8]) -> anyhow::Result<u64> {
+    Cursor::new(data).u64("value")
+}
+
+/// Encode a count-prefixed list of 8-byte hashes, as in DeleteEntries
+pub fn encode_hashes(hashes: &[u64]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + hashes.len() * 8);
+    data.extend_from_slice(&(hashes.len() as u32).to_be_bytes());
+    for hash in hashes {
+        data.extend_from_slice(&hash.to_be_bytes());
+    }
+    data
+}
+
+/// Decode a count-prefixed list of 8-byte hashes
+pub fn decode_hashes(data: &[u8]) -> anyhow::Result<Vec<u64>> {
+    let mut cursor = Cursor::new(data);
+    let count = cursor.u32("hash count")?;
+    (0..count).map(|_| cursor.u64("hash")).collect()
+}
+
+/// Seconds of history each reconciliation bucket covers
+pub const BUCKET_SECONDS: i64 = 86400;
+
+/// The entries of one bucket of time, summarized so that two sides can tell
+/// whether they hold the same ones without exchanging them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Bucket {
+    /// Start of the bucket, a multiple of `BUCKET_SECONDS`
+    pub start: i64,
+    pub entries: u64,
+    /// XOR of the entries' content hashes
+    pub hash: u64,
+}
+
+impl Bucket {
+    /// Start of the bucket holding entries run at `when`
+    pub fn start_of(when: i64) -> i64 {
+        when - when.rem_euclid(BUCKET_SECONDS)
+    }
+}
+
+/// Summaries of the buckets holding `entries`, oldest first; entries with
+/// the same content count once, as they are stored once
+pub fn buckets<'a>(entries: impl IntoIterator<Item = &'a HistoryEntry>) -> Vec<Bucket> {
+    let mut seen = std::collections::HashSet::new();
+    let mut buckets = std::collections::BTreeMap::new();
+    for entry in entries {
+        let hash = entry_hash(&entry.cmd, entry.when, &entry.extra);
+        if seen.insert(hash) {
+            let start = Bucket::start_of(entry.when);
+            let bucket = buckets.entry(start).or_insert(Bucket {
+                start,
+                ..Default::default()
+            });
+            bucket.entries += 1;
+            bucket.hash ^= hash;
+        }
+    }
+    buckets.into_values().collect()
+}
+
+/// Encode bucket summaries as in Buckets
+pub fn encode_buckets(buckets: &[Bucket]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + buckets.len() * 24);
+    data.extend_from_slice(&(buckets.len() as u32).to_be_bytes());
+    for bucket in buckets {
+        data.extend_from_slice(&bucket.start.to_be_bytes());
+        data.extend_from_slice(&bucket.entries.to_be_bytes());
+        data.extend_from_slice(&bucket.hash.to_be_bytes());
+    }
+    data
+}
+
+/// Decode bucket summaries from a Buckets message
+pub fn decode_buckets(data: &[u8]) -> anyhow::Result<Vec<Bucket>> {
+    let mut cursor = Cursor::new(data);
+    let count = cursor.u32("bucket count")?;
+    (0..count)
+        .map(|_| {
+            Ok(Bucket {
+                start: cursor.i64("bucket start")?,
+                entries: cursor.u64("bucket entries")?,
+                hash: cursor.u64("bucket hash")?,
+            })
+        })
+        .collect()
 }
 
 /// History entry structure
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HistoryEntry {
     pub cmd: String,
     pub when: i64,
     pub extra: String,
+    /// Host the entry was first uploaded from; empty if unknown
+    pub host: String,
+    /// Whether the command is pinned on the server
+    pub pinned: bool,
+    /// Directory the command ran in; empty if unknown
+    pub cwd: String,
+    /// Exit status of the command, if known
+    pub exit_code: Option<i32>,
+    /// How long the command ran, in milliseconds, if known
+    pub duration_ms: Option<u64>,
+    /// Shell session the command ran in; empty if unknown
+    pub session: String,
+    /// Device, or peer address, of the session that uploaded the entry to
+    /// the server; empty if unknown
+    pub origin_device: String,
+    /// When the server received the entry, if known
+    pub received_at: Option<i64>,
+    /// Namespace the entry is shared in, such as `team-infra`; empty for the
+    /// default, personal one
+    pub namespace: String,
+    /// The ed25519 public key of the device that uploaded the entry, then its
+    /// signature of `signed_content`; empty if unsigned
+    pub signature: Vec<u8>,
 }
 
 impl HistoryEntry {
     pub fn new(cmd: String, when: i64, extra: String) -> Self {
-        Self { cmd, when, extra }
+        Self {
+            cmd,
+            when,
+            extra,
+            host: String::new(),
+            pinned: false,
+            cwd: String::new(),
+            exit_code: None,
+            duration_ms: None,
+            session: String::new(),
+            origin_device: String::new(),
+            received_at: None,
+            namespace: String::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    pub fn with_host(mut self, host: String) -> Self {
+        self.host = host;
+        self
+    }
+
+    pub fn with_pinned(mut self, pinned: bool) -> Self {
+        self.pinned = pinned;
+        self
+    }
+
+    /// How much of what shells can record about a command is known
+    pub fn richness(&self) -> usize {
+        [
+            !self.extra.is_empty(),
+            !self.host.is_empty(),
+            !self.cwd.is_empty(),
+            self.exit_code.is_some(),
+            self.duration_ms.is_some(),
+            !self.session.is_empty(),
+        ]
+        .into_iter()
+        .filter(|&known| known)
+        .count()
+    }
+
+    /// Whether `other` records the same command run at the same second, in
+    /// the same namespace and on the same host as far as both know
+    pub fn same_run(&self, other: &HistoryEntry) -> bool {
+        self.cmd == other.cmd
+            && self.when == other.when
+            && self.namespace == other.namespace
+            && (self.host.is_empty() || other.host.is_empty() || self.host == other.host)
+    }
+
+    /// This entry and `other`, recording the same run, as one: the richer of
+    /// them, with what it doesn't know filled in from the other
+    pub fn merged_with(&self, other: &HistoryEntry) -> HistoryEntry {
+        let (mut richer, poorer) = if other.richness() > self.richness() {
+            (other.clone(), self)
+        } else {
+            (self.clone(), other)
+        };
+        richer.fill_from(poorer);
+        richer
+    }
+
+    /// Fill in what this entry doesn't know from `other`
+    fn fill_from(&mut self, other: &HistoryEntry) {
+        fn fill(field: &mut String, other: &str) {
+            if field.is_empty() {
+                *field = other.to_string();
+            }
+        }
+        fill(&mut self.extra, &other.extra);
+        fill(&mut self.host, &other.host);
+        fill(&mut self.cwd, &other.cwd);
+        fill(&mut self.session, &other.session);
+        fill(&mut self.origin_device, &other.origin_device);
+        self.exit_code = self.exit_code.or(other.exit_code);
+        self.duration_ms = self.duration_ms.or(other.duration_ms);
+        self.received_at = self.received_at.or(other.received_at);
+        self.pinned |= other.pinned;
+    }
+
+    const PINNED: u8 = 1;
+    const HAS_CWD: u8 = 2;
+    const HAS_EXIT_CODE: u8 = 4;
+    const HAS_DURATION: u8 = 8;
+    const HAS_SESSION: u8 = 16;
+    const HAS_ORIGIN_DEVICE: u8 = 32;
+    const HAS_RECEIVED_AT: u8 = 64;
+    const HAS_NAMESPACE: u8 = 128;
+    // Extended flags, in a second byte after the fields of the first
+    const HAS_SIGNATURE: u8 = 1;
+
+    /// What the signature of an entry signs: a fixed prefix, then the
+    /// encoding of the entry with only what its device recorded, leaving out
+    /// what servers add or change (pins, provenance and namespaces)
+    pub fn signed_content(&self) -> Vec<u8> {
+        let mut signed = HistoryEntry::new(self.cmd.clone(), self.when, self.extra.clone())
+            .with_host(self.host.clone());
+        signed.cwd = self.cwd.clone();
+        signed.exit_code = self.exit_code;
+        signed.duration_ms = self.duration_ms;
+        signed.session = self.session.clone();
+        let mut data = SIGNED_PREFIX.to_vec();
+        signed.encode_into(&mut data);
+        data
     }
 
     /// Encode history entry as TLV message data
     pub fn encode(&self) -> Vec<u8> {
-        let mut data = Vec::new();
+        let mut data = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut data);
+        data
+    }
+
+    /// Length of the entry's encoding, to size buffers before encoding it
+    pub fn encoded_len(&self) -> usize {
+        let string = |s: &str| 4 + s.len();
+        let optional = |s: &str| if s.is_empty() { 0 } else { string(s) };
+        let flags = self.flags();
+        let extended = self.extended_flags();
+        let mut len = string(&self.cmd) + 8 + string(&self.extra);
+        if !self.host.is_empty() || flags != 0 || extended != 0 {
+            len += string(&self.host);
+        }
+        if flags != 0 || extended != 0 {
+            len += 1;
+        }
+        if extended != 0 {
+            len += 1 + 4 + self.signature.len();
+        }
+        len + optional(&self.cwd)
+            + self.exit_code.map_or(0, |_| 4)
+            + self.duration_ms.map_or(0, |_| 8)
+            + optional(&self.session)
+            + optional(&self.origin_device)
+            + self.received_at.map_or(0, |_| 8)
+            + optional(&self.namespace)
+    }
 
+    /// The flags announcing the metadata the entry has
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        for (set, flag) in [
+            (self.pinned, Self::PINNED),
+            (!self.cwd.is_empty(), Self::HAS_CWD),
+            (self.exit_code.is_some(), Self::HAS_EXIT_CODE),
+            (self.duration_ms.is_some(), Self::HAS_DURATION),
+            (!self.session.is_empty(), Self::HAS_SESSION),
+            (!self.origin_device.is_empty(), Self::HAS_ORIGIN_DEVICE),
+            (self.received_at.is_some(), Self::HAS_RECEIVED_AT),
+            (!self.namespace.is_empty(), Self::HAS_NAMESPACE),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+        flags
+    }
+
+    /// The flags of the second byte, after the fields of the first
+    fn extended_flags(&self) -> u8 {
+        if self.signature.is_empty() {
+            0
+        } else {
+            Self::HAS_SIGNATURE
+        }
+    }
+
+    /// Append the entry's encoding to `data`, a buffer callers encoding many
+    /// entries clear and reuse rather than allocating one for each
+    pub fn encode_into(&self, data: &mut Vec<u8>) {
         // cmd length (4 bytes) + cmd
         let cmd_bytes = self.cmd.as_bytes();
         data.extend_from_slice(&(cmd_bytes.len() as u32).to_be_bytes());
@@ -107,7 +670,90 @@ impl HistoryEntry {
         data.extend_from_slice(&(extra_bytes.len() as u32).to_be_bytes());
         data.extend_from_slice(extra_bytes);
 
-        data
+        // flags, then the metadata they announce, in this order
+        let flags = self.flags();
+        let extended = self.extended_flags();
+
+        // host, optional: only sent when known or followed by flags
+        if !self.host.is_empty() || flags != 0 || extended != 0 {
+            put_str(data, &self.host);
+        }
+
+        // flags, optional: only sent when set or followed by extended ones
+        if flags != 0 || extended != 0 {
+            data.push(flags);
+        }
+        if !self.cwd.is_empty() {
+            put_str(data, &self.cwd);
+        }
+        if let Some(exit_code) = self.exit_code {
+            data.extend_from_slice(&(exit_code as u32).to_be_bytes());
+        }
+        if let Some(duration_ms) = self.duration_ms {
+            data.extend_from_slice(&duration_ms.to_be_bytes());
+        }
+        if !self.session.is_empty() {
+            put_str(data, &self.session);
+        }
+        if !self.origin_device.is_empty() {
+            put_str(data, &self.origin_device);
+        }
+        if let Some(received_at) = self.received_at {
+            data.extend_from_slice(&received_at.to_be_bytes());
+        }
+        if !self.namespace.is_empty() {
+            put_str(data, &self.namespace);
+        }
+
+        // extended flags, optional: only sent when set
+        if extended != 0 {
+            data.push(extended);
+            put_bytes(data, &self.signature);
+        }
+    }
+
+    /// The messages sending this entry: a HistoryEntry message, preceded if
+    /// `chunked` and its encoding is over ENTRY_CHUNK_SIZE bytes by the
+    /// EntryChunk messages holding all but the rest of it
+    pub fn messages(&self, chunked: bool) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut data = self.encode();
+        if chunked {
+            while data.len() > ENTRY_CHUNK_SIZE {
+                let rest = data.split_off(ENTRY_CHUNK_SIZE);
+                messages.push(Message::new(MessageType::EntryChunk, data));
+                data = rest;
+            }
+        }
+        messages.push(Message::new(MessageType::HistoryEntry, data));
+        messages
+    }
+
+    /// Write the messages sending this entry, leaving them in the writer's
+    /// buffer
+    pub fn write_messages<W: Write>(&self, writer: &mut W, chunked: bool) -> IoResult<()> {
+        self.write_messages_with(writer, chunked, &mut Vec::new())
+    }
+
+    /// Write the messages sending this entry like `write_messages`, encoding
+    /// it into `buf`, which callers sending many entries reuse across them
+    pub fn write_messages_with<W: Write>(
+        &self,
+        writer: &mut W,
+        chunked: bool,
+        buf: &mut Vec<u8>,
+    ) -> IoResult<()> {
+        buf.clear();
+        self.encode_into(buf);
+        let mut data = &buf[..];
+        if chunked {
+            while data.len() > ENTRY_CHUNK_SIZE {
+                let (chunk, rest) = data.split_at(ENTRY_CHUNK_SIZE);
+                Message::write_frame(writer, MessageType::EntryChunk, chunk)?;
+                data = rest;
+            }
+        }
+        Message::write_frame(writer, MessageType::HistoryEntry, data)
     }
 
     /// Decode history entry from TLV message data
@@ -156,38 +802,1806 @@ impl HistoryEntry {
             return Err(anyhow::anyhow!("Invalid data: too short for extra"));
         }
         let extra = String::from_utf8(data[pos..pos + extra_len].to_vec())?;
+        pos += extra_len;
 
-        Ok(HistoryEntry { cmd, when, extra })
+        // Read host and flags, optional trailing fields absent from older peers
+        let mut cursor = Cursor { data, pos };
+        let host = if cursor.is_empty() {
+            String::new()
+        } else {
+            cursor.string("host")?
+        };
+        let flags = if cursor.is_empty() {
+            0
+        } else {
+            cursor.u8("flags")?
+        };
+
+        let mut entry = HistoryEntry::new(cmd, when, extra)
+            .with_host(host)
+            .with_pinned(flags & Self::PINNED != 0);
+        if flags & Self::HAS_CWD != 0 {
+            entry.cwd = cursor.string("cwd")?;
+        }
+        if flags & Self::HAS_EXIT_CODE != 0 {
+            entry.exit_code = Some(cursor.u32("exit code")? as i32);
+        }
+        if flags & Self::HAS_DURATION != 0 {
+            entry.duration_ms = Some(cursor.u64("duration")?);
+        }
+        if flags & Self::HAS_SESSION != 0 {
+            entry.session = cursor.string("session")?;
+        }
+        if flags & Self::HAS_ORIGIN_DEVICE != 0 {
+            entry.origin_device = cursor.string("origin device")?;
+        }
+        if flags & Self::HAS_RECEIVED_AT != 0 {
+            entry.received_at = Some(cursor.i64("received at")?);
+        }
+        if flags & Self::HAS_NAMESPACE != 0 {
+            entry.namespace = cursor.string("namespace")?;
+        }
+        let extended = if cursor.is_empty() {
+            0
+        } else {
+            cursor.u8("extended flags")?
+        };
+        if extended & Self::HAS_SIGNATURE != 0 {
+            entry.signature = cursor.bytes("signature")?.to_vec();
+        }
+        Ok(entry)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Merge the entries recording the same run, such as fish's and another
+/// tool's history of the same commands, into one where the first of them was
+pub fn merge_same_runs(entries: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+    let mut merged: Vec<HistoryEntry> = Vec::with_capacity(entries.len());
+    // Indices in `merged` of the runs of each command at each second
+    let mut runs: HashMap<(String, i64), Vec<usize>> = HashMap::new();
+    for entry in entries {
+        let at_second = runs.entry((entry.cmd.clone(), entry.when)).or_default();
+        match at_second
+            .iter()
+            .copied()
+            .find(|&run| merged[run].same_run(&entry))
+        {
+            Some(run) => merged[run] = merged[run].merged_with(&entry),
+            None => {
+                at_second.push(merged.len());
+                merged.push(entry);
+            }
+        }
+    }
+    merged
+}
 
-    #[test]
-    fn test_history_entry_encode_decode() {
-        let entry = HistoryEntry::new("ls -la".to_string(), 1234567890, "paths: /home".to_string());
-        let encoded = entry.encode();
-        let decoded = HistoryEntry::decode(&encoded).unwrap();
+/// How entries sharing the same `when` are ordered
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TieBreak {
+    /// In the order the server first stored them
+    #[default]
+    Insertion = 0,
+    /// By the host they were uploaded from, untagged entries first
+    Host = 1,
+    /// By command, independently of any server's insertion order
+    Command = 2,
+}
 
-        assert_eq!(entry.cmd, decoded.cmd);
-        assert_eq!(entry.when, decoded.when);
-        assert_eq!(entry.extra, decoded.extra);
+impl TryFrom<u8> for TieBreak {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, <Self as TryFrom<u8>>::Error> {
+        match value {
+            0 => Ok(TieBreak::Insertion),
+            1 => Ok(TieBreak::Host),
+            2 => Ok(TieBreak::Command),
+            _ => Err(anyhow::anyhow!("Invalid tie break: {}", value)),
+        }
     }
+}
 
-    #[test]
-    fn test_message_write_read() {
-        let entry = HistoryEntry::new("echo test".to_string(), 9876543210, "".to_string());
-        let msg = Message::new(MessageType::HistoryEntry, entry.encode());
+/// Parameters of a GetHistory request; empty message data asks for everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryRequest {
+    /// Only entries whose `when` is at or after this time
+    pub since: Option<i64>,
+    /// Only the most recent N entries
+    pub limit: Option<u64>,
+    /// Only entries from these hosts (plus untagged ones), if not empty
+    pub only_hosts: Vec<String>,
+    /// No entries from these hosts
+    pub exclude_hosts: Vec<String>,
+    /// Only entries whose `when` is before this time
+    pub until: Option<i64>,
+    /// Only entries whose command contains this substring
+    pub pattern: Option<String>,
+    /// Exempt pinned commands from `since` and `limit`
+    pub keep_pinned: bool,
+    /// Order of entries sharing the same `when`
+    pub tie_break: TieBreak,
+    /// Only entries stored after this high-water mark, from a previous End
+    pub after_seq: Option<u64>,
+    /// Send the entries in one Snapshot message rather than one message each;
+    /// servers that don't know this flag ignore it
+    pub snapshot: bool,
+    /// Send a Tombstones message first, with the entries deleted since then
+    pub tombstones_since: Option<i64>,
+    /// Only entries in these namespaces, `""` being the default one, if not
+    /// empty
+    pub namespaces: Vec<String>,
+}
 
-        let mut buffer = Vec::new();
-        msg.write_to(&mut buffer).unwrap();
+impl HistoryRequest {
+    const HAS_SINCE: u8 = 1;
+    const HAS_LIMIT: u8 = 2;
+    const HAS_ONLY_HOSTS: u8 = 4;
+    const HAS_EXCLUDE_HOSTS: u8 = 8;
+    const HAS_UNTIL: u8 = 16;
+    const HAS_PATTERN: u8 = 32;
+    const KEEP_PINNED: u8 = 64;
+    const HAS_TIE_BREAK: u8 = 128;
 
-        let mut reader = &buffer[..];
-        let read_msg = Message::read_from(&mut reader).unwrap();
+    // Extended flags, in a second byte after the tie break
+    const HAS_AFTER_SEQ: u8 = 1;
+    const SNAPSHOT: u8 = 2;
+    const HAS_TOMBSTONES_SINCE: u8 = 4;
+    const HAS_NAMESPACES: u8 = 8;
 
-        assert_eq!(msg.msg_type, read_msg.msg_type);
-        assert_eq!(msg.data, read_msg.data);
+    /// Encode request as TLV message data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        if *self == Self::default() {
+            return data;
+        }
+
+        let mut flags = 0;
+        if self.since.is_some() {
+            flags |= Self::HAS_SINCE;
+        }
+        if self.limit.is_some() {
+            flags |= Self::HAS_LIMIT;
+        }
+        if !self.only_hosts.is_empty() {
+            flags |= Self::HAS_ONLY_HOSTS;
+        }
+        if !self.exclude_hosts.is_empty() {
+            flags |= Self::HAS_EXCLUDE_HOSTS;
+        }
+        if self.until.is_some() {
+            flags |= Self::HAS_UNTIL;
+        }
+        if self.pattern.is_some() {
+            flags |= Self::HAS_PATTERN;
+        }
+        if self.keep_pinned {
+            flags |= Self::KEEP_PINNED;
+        }
+        let mut extended = 0;
+        if self.after_seq.is_some() {
+            extended |= Self::HAS_AFTER_SEQ;
+        }
+        if self.snapshot {
+            extended |= Self::SNAPSHOT;
+        }
+        if self.tombstones_since.is_some() {
+            extended |= Self::HAS_TOMBSTONES_SINCE;
+        }
+        if !self.namespaces.is_empty() {
+            extended |= Self::HAS_NAMESPACES;
+        }
+        // Extended flags follow the tie break, which is then always sent
+        if self.tie_break != TieBreak::default() || extended != 0 {
+            flags |= Self::HAS_TIE_BREAK;
+        }
+        data.push(flags);
+        if let Some(since) = self.since {
+            data.extend_from_slice(&since.to_be_bytes());
+        }
+        if let Some(limit) = self.limit {
+            data.extend_from_slice(&limit.to_be_bytes());
+        }
+        if !self.only_hosts.is_empty() {
+            put_str_list(&mut data, &self.only_hosts);
+        }
+        if !self.exclude_hosts.is_empty() {
+            put_str_list(&mut data, &self.exclude_hosts);
+        }
+        if let Some(until) = self.until {
+            data.extend_from_slice(&until.to_be_bytes());
+        }
+        if let Some(pattern) = &self.pattern {
+            put_str(&mut data, pattern);
+        }
+        if flags & Self::HAS_TIE_BREAK != 0 {
+            data.push(self.tie_break as u8);
+        }
+        if extended != 0 {
+            data.push(extended);
+        }
+        if let Some(after_seq) = self.after_seq {
+            data.extend_from_slice(&after_seq.to_be_bytes());
+        }
+        if let Some(since) = self.tombstones_since {
+            data.extend_from_slice(&since.to_be_bytes());
+        }
+        if !self.namespaces.is_empty() {
+            put_str_list(&mut data, &self.namespaces);
+        }
+        data
+    }
+
+    /// Decode request from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut request = HistoryRequest::default();
+        if data.is_empty() {
+            return Ok(request);
+        }
+
+        let mut cursor = Cursor::new(data);
+        let flags = cursor.u8("flags")?;
+        if flags & Self::HAS_SINCE != 0 {
+            request.since = Some(cursor.i64("since")?);
+        }
+        if flags & Self::HAS_LIMIT != 0 {
+            request.limit = Some(cursor.u64("limit")?);
+        }
+        if flags & Self::HAS_ONLY_HOSTS != 0 {
+            request.only_hosts = cursor.string_list("only hosts")?;
+        }
+        if flags & Self::HAS_EXCLUDE_HOSTS != 0 {
+            request.exclude_hosts = cursor.string_list("excluded hosts")?;
+        }
+        if flags & Self::HAS_UNTIL != 0 {
+            request.until = Some(cursor.i64("until")?);
+        }
+        if flags & Self::HAS_PATTERN != 0 {
+            request.pattern = Some(cursor.string("pattern")?);
+        }
+        request.keep_pinned = flags & Self::KEEP_PINNED != 0;
+        if flags & Self::HAS_TIE_BREAK != 0 {
+            request.tie_break = TieBreak::try_from(cursor.u8("tie break")?)?;
+        }
+        if cursor.is_empty() {
+            return Ok(request);
+        }
+        let extended = cursor.u8("extended flags")?;
+        if extended & Self::HAS_AFTER_SEQ != 0 {
+            request.after_seq = Some(cursor.u64("after seq")?);
+        }
+        request.snapshot = extended & Self::SNAPSHOT != 0;
+        if extended & Self::HAS_TOMBSTONES_SINCE != 0 {
+            request.tombstones_since = Some(cursor.i64("tombstones since")?);
+        }
+        if extended & Self::HAS_NAMESPACES != 0 {
+            request.namespaces = cursor.string_list("namespaces")?;
+        }
+        Ok(request)
+    }
+}
+
+/// Payload of a PinEntry message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinRequest {
+    pub cmd: String,
+    /// Pin the command, or unpin it
+    pub pinned: bool,
+}
+
+impl PinRequest {
+    /// Encode pin request as TLV message data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![self.pinned as u8];
+        data.extend_from_slice(self.cmd.as_bytes());
+        data
+    }
+
+    /// Decode pin request from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let pinned = cursor.u8("pin flag")? != 0;
+        let cmd = String::from_utf8(data[cursor.pos..].to_vec())?;
+        Ok(PinRequest { cmd, pinned })
+    }
+}
+
+/// How the text of a Query is matched against commands
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchMode {
+    /// Every word appears, each possibly as a prefix, through the search index
+    #[default]
+    Words = 0,
+    /// The command contains the text
+    Substring = 1,
+    /// The command matches the text as a regular expression
+    Regex = 2,
+    /// The whole command matches the text as a shell glob, as `glob_regex`
+    /// translates it
+    Glob = 3,
+}
+
+impl TryFrom<u8> for MatchMode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, <Self as TryFrom<u8>>::Error> {
+        match value {
+            0 => Ok(MatchMode::Words),
+            1 => Ok(MatchMode::Substring),
+            2 => Ok(MatchMode::Regex),
+            3 => Ok(MatchMode::Glob),
+            _ => Err(anyhow::anyhow!("Invalid match mode: {}", value)),
+        }
+    }
+}
+
+/// The regular expression matching the commands a shell glob does: `*` is
+/// any text, `?` any character, `[...]` one of the characters and `[!...]`
+/// or `[^...]` any other; everything else, backslashes included, is literal
+pub fn glob_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let chars: Vec<char> = glob.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => match glob_class(&chars[i + 1..]) {
+                Some((class, len)) => {
+                    regex.push_str(&class);
+                    i += len;
+                }
+                None => regex.push_str("\\["),
+            },
+            c => push_literal(&mut regex, c),
+        }
+        i += 1;
+    }
+    regex.push('$');
+    regex
+}
+
+/// The regex class of the glob class whose `[` `chars` follow, and how many
+/// of them it spans, closing `]` included; `None` if it isn't closed
+fn glob_class(chars: &[char]) -> Option<(String, usize)> {
+    let mut class = String::from("[");
+    let mut i = 0;
+    if matches!(chars.first(), Some('!' | '^')) {
+        class.push('^');
+        i += 1;
+    }
+    let start = i;
+    loop {
+        match *chars.get(i)? {
+            // Right after the opening, `]` is one of the characters
+            ']' if i > start => break,
+            c @ ('\\' | '[' | ']' | '^' | '&' | '~' | '-') if c != '-' || i == start => {
+                class.push('\\');
+                class.push(c);
+            }
+            c => class.push(c),
+        }
+        i += 1;
+    }
+    class.push(']');
+    Some((class, i + 1))
+}
+
+fn push_literal(regex: &mut String, c: char) {
+    if "\\.+*?()|[]{}^$#&-~".contains(c) {
+        regex.push('\\');
+    }
+    regex.push(c);
+}
+
+/// Texts every command matching `text` in `mode` contains, with whether
+/// commands start with them, so searches can prefilter with them; none for
+/// words, nor for regexes with alternations, flags or escapes other than
+/// punctuation and `\d`-like classes
+fn required_texts(text: &str, mode: MatchMode) -> Vec<(String, bool)> {
+    match mode {
+        MatchMode::Words => return Vec::new(),
+        MatchMode::Substring if text.is_empty() => return Vec::new(),
+        MatchMode::Substring => return vec![(text.to_string(), false)],
+        MatchMode::Regex if text.contains('|') => return Vec::new(),
+        MatchMode::Glob | MatchMode::Regex => {}
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let mut texts = Vec::new();
+    let mut current = String::new();
+    let mut leading = mode == MatchMode::Glob;
+    let mut end = |current: &mut String, leading: &mut bool| {
+        if !current.is_empty() {
+            texts.push((std::mem::take(current), *leading));
+        }
+        *leading = false;
+    };
+    let mut i = 0;
+    if mode == MatchMode::Glob {
+        while i < chars.len() {
+            match chars[i] {
+                '*' | '?' => end(&mut current, &mut leading),
+                '[' => match glob_class(&chars[i + 1..]) {
+                    Some((_, len)) => {
+                        end(&mut current, &mut leading);
+                        i += len;
+                    }
+                    None => current.push('['),
+                },
+                c => current.push(c),
+            }
+            i += 1;
+        }
+    } else {
+        if chars.first() == Some(&'^') {
+            leading = true;
+            i += 1;
+        }
+        while i < chars.len() {
+            match chars[i] {
+                '\\' => match chars.get(i + 1) {
+                    Some(c) if c.is_ascii_punctuation() => {
+                        current.push(*c);
+                        i += 1;
+                    }
+                    Some('d' | 'D' | 'w' | 'W' | 's' | 'S' | 'b' | 'B') => {
+                        end(&mut current, &mut leading);
+                        i += 1;
+                    }
+                    _ => return Vec::new(),
+                },
+                // The previous character may not be there
+                '*' | '?' | '{' => {
+                    current.pop();
+                    end(&mut current, &mut leading);
+                    if chars[i] == '{' {
+                        while i + 1 < chars.len() && chars[i] != '}' {
+                            i += 1;
+                        }
+                    }
+                }
+                '+' | '.' | '$' | '^' => end(&mut current, &mut leading),
+                '(' if chars.get(i + 1) == Some(&'?') => return Vec::new(),
+                // Groups may be optional, and classes match one character
+                '(' | '[' => {
+                    end(&mut current, &mut leading);
+                    i = regex_closing(&chars, i);
+                }
+                c => current.push(c),
+            }
+            i += 1;
+        }
+    }
+    end(&mut current, &mut leading);
+    texts
+}
+
+/// Where the regex group or class opening at `start` closes
+fn regex_closing(chars: &[char], start: usize) -> usize {
+    let class = chars[start] == '[';
+    let mut i = start + 1;
+    if class {
+        if chars.get(i) == Some(&'^') {
+            i += 1;
+        }
+        // Right after the opening, `]` is one of the characters
+        if chars.get(i) == Some(&']') {
+            i += 1;
+        }
+    }
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '[' => i = regex_closing(chars, i),
+            '(' if !class => i = regex_closing(chars, i),
+            ']' if class => return i,
+            ')' if !class => return i,
+            _ => {}
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+/// The longest text every command matching `text` in `mode` contains, if
+/// any, for a substring search to narrow candidates down
+pub fn required_substring(text: &str, mode: MatchMode) -> Option<String> {
+    required_texts(text, mode)
+        .into_iter()
+        .map(|(text, _)| text)
+        .max_by_key(|text| text.chars().count())
+}
+
+/// Words every command matching `text` in `mode` has, each as a word prefix,
+/// for a search index to narrow candidates down: runs of ASCII letters and
+/// digits after punctuation or whitespace, or starting commands
+pub fn required_words(text: &str, mode: MatchMode) -> Vec<String> {
+    let mut words = Vec::new();
+    for (text, leading) in required_texts(text, mode) {
+        let mut word = String::new();
+        // Whether the text so far ended with a word boundary
+        let mut boundary = leading;
+        for c in text.chars().chain([' ']) {
+            if c.is_ascii_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+            if !word.is_empty() && boundary {
+                words.push(std::mem::take(&mut word));
+            }
+            word.clear();
+            boundary = c.is_ascii();
+        }
+    }
+    words
+}
+
+/// Which matches a Query returns, and in which order
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SearchOrder {
+    /// The most recent matches, oldest first, as a shell history lists them
+    #[default]
+    Recent = 0,
+    /// The most recent matches, newest first
+    Newest = 1,
+    /// The oldest matches, oldest first
+    Oldest = 2,
+}
+
+impl TryFrom<u8> for SearchOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, <Self as TryFrom<u8>>::Error> {
+        match value {
+            0 => Ok(SearchOrder::Recent),
+            1 => Ok(SearchOrder::Newest),
+            2 => Ok(SearchOrder::Oldest),
+            _ => Err(anyhow::anyhow!("Invalid search order: {}", value)),
+        }
+    }
+}
+
+/// Where a page of search results ended: the sort key of its last entry in
+/// paging order, sent in the End message of a Query that hit its limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchCursor {
+    pub when: i64,
+    /// The server's identifier of the entry, breaking ties between equal `when`s
+    pub id: i64,
+}
+
+impl SearchCursor {
+    /// Encode cursor as TLV message data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = self.when.to_be_bytes().to_vec();
+        data.extend_from_slice(&self.id.to_be_bytes());
+        data
+    }
+
+    /// Decode cursor from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        Ok(SearchCursor {
+            when: cursor.i64("cursor time")?,
+            id: cursor.i64("cursor id")?,
+        })
+    }
+}
+
+/// Search over stored commands, sent in a Query message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    /// What commands must match; in modes other than `MatchMode::Words`,
+    /// empty matches every command
+    pub text: String,
+    pub mode: MatchMode,
+    /// Return only this many matches, the first in `order`
+    pub limit: Option<u64>,
+    /// Only entries whose `when` is at or after this time
+    pub since: Option<i64>,
+    /// Only entries whose `when` is before this time
+    pub until: Option<i64>,
+    /// Only entries uploaded from these hosts, if not empty
+    pub hosts: Vec<String>,
+    /// Only entries run in this directory or below it
+    pub cwd: Option<String>,
+    pub order: SearchOrder,
+    /// Continue after the page that ended here
+    pub after: Option<SearchCursor>,
+}
+
+impl SearchQuery {
+    const HAS_LIMIT: u8 = 1;
+    const HAS_SINCE: u8 = 2;
+    const HAS_UNTIL: u8 = 4;
+    const HAS_HOSTS: u8 = 8;
+    const HAS_CWD: u8 = 16;
+    const HAS_CURSOR: u8 = 32;
+    const HAS_MODE: u8 = 64;
+    const HAS_ORDER: u8 = 128;
+
+    /// Encode search query as TLV message data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut flags = 0;
+        for (set, flag) in [
+            (self.limit.is_some(), Self::HAS_LIMIT),
+            (self.since.is_some(), Self::HAS_SINCE),
+            (self.until.is_some(), Self::HAS_UNTIL),
+            (!self.hosts.is_empty(), Self::HAS_HOSTS),
+            (self.cwd.is_some(), Self::HAS_CWD),
+            (self.after.is_some(), Self::HAS_CURSOR),
+            (self.mode != MatchMode::default(), Self::HAS_MODE),
+            (self.order != SearchOrder::default(), Self::HAS_ORDER),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+        data.push(flags);
+        if let Some(limit) = self.limit {
+            data.extend_from_slice(&limit.to_be_bytes());
+        }
+        if let Some(since) = self.since {
+            data.extend_from_slice(&since.to_be_bytes());
+        }
+        if let Some(until) = self.until {
+            data.extend_from_slice(&until.to_be_bytes());
+        }
+        if !self.hosts.is_empty() {
+            put_str_list(&mut data, &self.hosts);
+        }
+        if let Some(cwd) = &self.cwd {
+            put_str(&mut data, cwd);
+        }
+        if let Some(after) = &self.after {
+            data.extend_from_slice(&after.encode());
+        }
+        if flags & Self::HAS_MODE != 0 {
+            data.push(self.mode as u8);
+        }
+        if flags & Self::HAS_ORDER != 0 {
+            data.push(self.order as u8);
+        }
+        data.extend_from_slice(self.text.as_bytes());
+        data
+    }
+
+    /// Decode search query from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let flags = cursor.u8("flags")?;
+        let mut query = SearchQuery::default();
+        if flags & Self::HAS_LIMIT != 0 {
+            query.limit = Some(cursor.u64("limit")?);
+        }
+        if flags & Self::HAS_SINCE != 0 {
+            query.since = Some(cursor.i64("since")?);
+        }
+        if flags & Self::HAS_UNTIL != 0 {
+            query.until = Some(cursor.i64("until")?);
+        }
+        if flags & Self::HAS_HOSTS != 0 {
+            query.hosts = cursor.string_list("hosts")?;
+        }
+        if flags & Self::HAS_CWD != 0 {
+            query.cwd = Some(cursor.string("cwd")?);
+        }
+        if flags & Self::HAS_CURSOR != 0 {
+            query.after = Some(SearchCursor {
+                when: cursor.i64("cursor time")?,
+                id: cursor.i64("cursor id")?,
+            });
+        }
+        if flags & Self::HAS_MODE != 0 {
+            query.mode = MatchMode::try_from(cursor.u8("match mode")?)?;
+        }
+        if flags & Self::HAS_ORDER != 0 {
+            query.order = SearchOrder::try_from(cursor.u8("search order")?)?;
+        }
+        query.text = String::from_utf8(data[cursor.pos..].to_vec())?;
+        Ok(query)
+    }
+}
+
+/// Commands to suggest, the best ranked by how often and how recently they
+/// ran, favoring those run in a directory or on a host, sent in a Suggest
+/// message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SuggestQuery {
+    /// What suggested commands start with
+    pub prefix: String,
+    /// Where the commands will run, if known
+    pub cwd: Option<String>,
+    /// Host the commands will run on, if known
+    pub host: Option<String>,
+    pub limit: u64,
+}
+
+impl SuggestQuery {
+    const HAS_CWD: u8 = 1;
+    const HAS_HOST: u8 = 2;
+
+    /// Encode as TLV message data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.cwd.is_some() {
+            flags |= Self::HAS_CWD;
+        }
+        if self.host.is_some() {
+            flags |= Self::HAS_HOST;
+        }
+        let mut data = vec![flags];
+        data.extend_from_slice(&self.limit.to_be_bytes());
+        if let Some(cwd) = &self.cwd {
+            put_str(&mut data, cwd);
+        }
+        if let Some(host) = &self.host {
+            put_str(&mut data, host);
+        }
+        data.extend_from_slice(self.prefix.as_bytes());
+        data
+    }
+
+    /// Decode from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let flags = cursor.u8("flags")?;
+        let mut query = SuggestQuery {
+            limit: cursor.u64("limit")?,
+            ..Default::default()
+        };
+        if flags & Self::HAS_CWD != 0 {
+            query.cwd = Some(cursor.string("cwd")?);
+        }
+        if flags & Self::HAS_HOST != 0 {
+            query.host = Some(cursor.string("host")?);
+        }
+        query.prefix = String::from_utf8(data[cursor.pos..].to_vec())?;
+        Ok(query)
+    }
+}
+
+/// A command worth suggesting, and what its rank comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    pub cmd: String,
+    /// Entries of the command
+    pub uses: u64,
+    /// Time of its latest entry
+    pub last_used: i64,
+}
+
+/// Encode suggestions as in Suggestions
+pub fn encode_suggestions(suggestions: &[Suggestion]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(suggestions.len() as u32).to_be_bytes());
+    for suggestion in suggestions {
+        put_str(&mut data, &suggestion.cmd);
+        data.extend_from_slice(&suggestion.uses.to_be_bytes());
+        data.extend_from_slice(&suggestion.last_used.to_be_bytes());
+    }
+    data
+}
+
+/// Decode suggestions from a Suggestions message
+pub fn decode_suggestions(data: &[u8]) -> anyhow::Result<Vec<Suggestion>> {
+    let mut cursor = Cursor::new(data);
+    let count = cursor.u32("suggestion count")?;
+    (0..count)
+        .map(|_| {
+            Ok(Suggestion {
+                cmd: cursor.string("suggested command")?,
+                uses: cursor.u64("suggestion uses")?,
+                last_used: cursor.i64("suggestion last use")?,
+            })
+        })
+        .collect()
+}
+
+/// Server statistics, sent in a Stats message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    pub entries: u64,
+}
+
+impl ServerStats {
+    /// Encode statistics as TLV message data
+    pub fn encode(&self) -> Vec<u8> {
+        self.entries.to_be_bytes().to_vec()
+    }
+
+    /// Decode statistics from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let entries = data
+            .get(..8)
+            .ok_or_else(|| anyhow::anyhow!("Invalid data: too short for entry count"))?;
+        Ok(ServerStats {
+            entries: u64::from_be_bytes(entries.try_into()?),
+        })
+    }
+}
+
+/// What became of a session's uploads, sent in the End answering GetHistory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionSummary {
+    /// The server's high-water mark, to pass as `after_seq` next time
+    pub high_water_mark: u64,
+    /// Entries the session stored
+    pub stored: u64,
+    /// Entries the server already had, or had forgotten or pruned, and
+    /// skipped; None from servers that don't tell
+    pub duplicates: Option<u64>,
+    /// Entries refused by the server's policy or hooks; None from servers
+    /// that don't tell
+    pub rejected: Option<u64>,
+}
+
+impl SessionSummary {
+    /// Encode as TLV message data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.high_water_mark.to_be_bytes());
+        data.extend_from_slice(&self.stored.to_be_bytes());
+        if let (Some(duplicates), Some(rejected)) = (self.duplicates, self.rejected) {
+            data.extend_from_slice(&duplicates.to_be_bytes());
+            data.extend_from_slice(&rejected.to_be_bytes());
+        }
+        data
+    }
+
+    /// Decode from TLV message data, where older servers end after the
+    /// stored count
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let high_water_mark = cursor.u64("high-water mark")?;
+        let stored = cursor.u64("stored count")?;
+        let (duplicates, rejected) = if cursor.is_empty() {
+            (None, None)
+        } else {
+            (
+                Some(cursor.u64("duplicate count")?),
+                Some(cursor.u64("rejected count")?),
+            )
+        };
+        Ok(SessionSummary {
+            high_water_mark,
+            stored,
+            duplicates,
+            rejected,
+        })
+    }
+}
+
+/// What a server runs and holds, sent in a ServerInfo message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub protocol: u32,
+    pub version: String,
+    /// Migrations applied to the database sessions use
+    pub schema_version: u32,
+    pub entries: u64,
+    /// Names of the optional features the server supports
+    pub capabilities: Vec<String>,
+}
+
+impl ServerInfo {
+    /// Encode as TLV message data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.protocol.to_be_bytes());
+        put_str(&mut data, &self.version);
+        data.extend_from_slice(&self.schema_version.to_be_bytes());
+        data.extend_from_slice(&self.entries.to_be_bytes());
+        put_str_list(&mut data, &self.capabilities);
+        data
+    }
+
+    /// Decode from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        Ok(ServerInfo {
+            protocol: cursor.u32("protocol version")?,
+            version: cursor.string("version")?,
+            schema_version: cursor.u32("schema version")?,
+            entries: cursor.u64("entry count")?,
+            capabilities: cursor.string_list("capabilities")?,
+        })
+    }
+}
+
+/// A limit the server enforces on each identity syncing with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    /// Entries received per minute, over every session
+    EntriesPerMinute = 1,
+    /// Bytes received in one session
+    SessionBytes = 2,
+    /// Entries stored in the database
+    StoredEntries = 3,
+}
+
+impl TryFrom<u8> for Quota {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, <Self as TryFrom<u8>>::Error> {
+        match value {
+            1 => Ok(Quota::EntriesPerMinute),
+            2 => Ok(Quota::SessionBytes),
+            3 => Ok(Quota::StoredEntries),
+            _ => Err(anyhow::anyhow!("Invalid quota: {}", value)),
+        }
+    }
+}
+
+/// Payload of QuotaExceeded, and the error clients turn it into
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub struct QuotaExceeded {
+    pub quota: Quota,
+    pub limit: u64,
+    /// Seconds until syncing again may succeed, if waiting helps
+    pub retry_after: Option<u64>,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.quota {
+            Quota::EntriesPerMinute => write!(
+                f,
+                "Server quota exceeded: {} entries per minute",
+                self.limit
+            )?,
+            Quota::SessionBytes => {
+                write!(f, "Server quota exceeded: {} bytes per session", self.limit)?
+            }
+            Quota::StoredEntries => {
+                write!(f, "Server quota exceeded: {} stored entries", self.limit)?
+            }
+        }
+        match self.retry_after {
+            Some(seconds) => write!(f, ", retry in {}s", seconds),
+            None => Ok(()),
+        }
+    }
+}
+
+impl QuotaExceeded {
+    /// Encode as TLV message data: quota, limit, then seconds to wait (0 if
+    /// waiting doesn't help)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![self.quota as u8];
+        data.extend_from_slice(&self.limit.to_be_bytes());
+        data.extend_from_slice(&self.retry_after.unwrap_or(0).to_be_bytes());
+        data
+    }
+
+    /// Decode from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let quota = Quota::try_from(cursor.u8("quota")?)?;
+        let limit = cursor.u64("limit")?;
+        let retry_after = Some(cursor.u64("retry delay")?).filter(|seconds| *seconds > 0);
+        Ok(QuotaExceeded {
+            quota,
+            limit,
+            retry_after,
+        })
+    }
+}
+
+/// Payload of NotStored, and the error clients turn it into: entries sent in
+/// the session that the server failed to store, which the client sends again
+/// on its next sync
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Server failed to store {} entries: {error}", .entries.len())]
+pub struct NotStored {
+    pub error: String,
+    /// Content hashes of the entries, as computed by `entry_hash`
+    pub entries: Vec<u64>,
+}
+
+impl NotStored {
+    /// Encode as TLV message data: the error, then a count-prefixed list of hashes
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_str(&mut data, &self.error);
+        data.extend_from_slice(&encode_hashes(&self.entries));
+        data
+    }
+
+    /// Decode from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let error = cursor.string("error")?;
+        let entries = decode_hashes(&data[cursor.pos..])?;
+        Ok(NotStored { error, entries })
+    }
+}
+
+/// Handshake payload, sent by the client first and answered by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol: u32,
+    pub version: String,
+    /// Sender's wall-clock Unix time, for clock skew detection
+    pub time: Option<i64>,
+    /// Name of the syncing machine, for the server's device registry; only
+    /// sent along with `time`
+    pub device: Option<String>,
+    /// Optional features the sender supports, as in ServerInfo; sent by
+    /// servers, after an empty device, and by clients taking entry chunks
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// Hello describing this build
+    pub fn current() -> Self {
+        Hello {
+            protocol: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            // Browsers' WebAssembly has no clock to read; leave it out there
+            time: if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+                None
+            } else {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs() as i64)
+            },
+            device: None,
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Whether the sender supports the optional feature `capability`
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// This Hello, introducing the sender as `device`
+    pub fn with_device(mut self, device: String) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// Encode handshake as TLV message data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.protocol.to_be_bytes());
+        put_str(&mut data, &self.version);
+        if let Some(time) = self.time {
+            data.extend_from_slice(&time.to_be_bytes());
+            if self.device.is_some() || !self.capabilities.is_empty() {
+                put_str(&mut data, self.device.as_deref().unwrap_or_default());
+            }
+            if !self.capabilities.is_empty() {
+                put_str_list(&mut data, &self.capabilities);
+            }
+        }
+        data
+    }
+
+    /// Decode handshake from TLV message data
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        Ok(Hello {
+            protocol: cursor.u32("protocol version")?,
+            version: cursor.string("version")?,
+            // Optional trailing field, absent from older peers
+            time: if cursor.is_empty() {
+                None
+            } else {
+                Some(cursor.i64("time")?)
+            },
+            device: if cursor.is_empty() {
+                None
+            } else {
+                Some(cursor.string("device")?).filter(|device| !device.is_empty())
+            },
+            capabilities: if cursor.is_empty() {
+                Vec::new()
+            } else {
+                cursor.string_list("capabilities")?
+            },
+        })
+    }
+}
+
+/// Append a length-prefixed (4 bytes, big-endian) string
+fn put_str(data: &mut Vec<u8>, s: &str) {
+    data.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    data.extend_from_slice(s.as_bytes());
+}
+
+/// Append length-prefixed (4 bytes, big-endian) bytes
+fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    data.extend_from_slice(bytes);
+}
+
+/// Append a count-prefixed (4 bytes, big-endian) list of strings
+fn put_str_list(data: &mut Vec<u8>, items: &[String]) {
+    data.extend_from_slice(&(items.len() as u32).to_be_bytes());
+    for item in items {
+        put_str(data, item);
+    }
+}
+
+/// Bounds-checked reader over TLV message data
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize, what: &str) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| anyhow::anyhow!("Invalid data: too short for {}", what))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn u8(&mut self, what: &str) -> anyhow::Result<u8> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u32(&mut self, what: &str) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4, what)?.try_into()?))
+    }
+
+    fn u64(&mut self, what: &str) -> anyhow::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8, what)?.try_into()?))
+    }
+
+    fn i64(&mut self, what: &str) -> anyhow::Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8, what)?.try_into()?))
+    }
+
+    fn string(&mut self, what: &str) -> anyhow::Result<String> {
+        Ok(String::from_utf8(self.bytes(what)?.to_vec())?)
+    }
+
+    fn bytes(&mut self, what: &str) -> anyhow::Result<&'a [u8]> {
+        let len = self.u32(what)? as usize;
+        self.take(len, what)
+    }
+
+    fn string_list(&mut self, what: &str) -> anyhow::Result<Vec<String>> {
+        let count = self.u32(what)?;
+        (0..count).map(|_| self.string(what)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+
+    impl Arbitrary for HistoryEntry {
+        fn arbitrary(g: &mut Gen) -> Self {
+            HistoryEntry {
+                cmd: String::arbitrary(g),
+                when: i64::arbitrary(g),
+                extra: String::arbitrary(g),
+                host: String::arbitrary(g),
+                pinned: bool::arbitrary(g),
+                cwd: String::arbitrary(g),
+                exit_code: Option::arbitrary(g),
+                duration_ms: Option::arbitrary(g),
+                session: String::arbitrary(g),
+                origin_device: String::arbitrary(g),
+                received_at: Option::arbitrary(g),
+                namespace: String::arbitrary(g),
+                signature: Vec::arbitrary(g),
+            }
+        }
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        fn round_trips(entry: HistoryEntry) -> bool {
+            HistoryEntry::decode(&entry.encode()).ok() == Some(entry)
+        }
+        quickcheck(round_trips as fn(HistoryEntry) -> bool);
+    }
+
+    #[test]
+    fn damaged_entries_decode_to_what_they_encode() {
+        // Decoding fails or gives an entry encoded canonically, never panics
+        fn canonical(entry: HistoryEntry, at: usize, byte: u8, cut: bool) -> bool {
+            let mut data = entry.encode();
+            let at = at % data.len();
+            if cut {
+                data.truncate(at);
+            } else {
+                data[at] = byte;
+            }
+            HistoryEntry::decode(&data).map_or(true, |entry| {
+                HistoryEntry::decode(&entry.encode()).ok() == Some(entry)
+            })
+        }
+        quickcheck(canonical as fn(HistoryEntry, usize, u8, bool) -> bool);
+    }
+
+    #[test]
+    fn messages_round_trip_through_their_frames() {
+        fn round_trips(msg_type: u8, data: Vec<u8>) -> bool {
+            let msg_type = MessageType::try_from(1 + msg_type % 25).unwrap();
+            let mut frame = Vec::new();
+            Message::new(msg_type, data.clone())
+                .write_to(&mut frame)
+                .unwrap();
+            let mut reader = &frame[..];
+            let msg = Message::read_from(&mut reader).unwrap();
+            let (decoded, len) = Message::decode(&frame).unwrap().unwrap();
+            frame.len() == 5 + data.len()
+                && reader.is_empty()
+                && msg.msg_type == msg_type
+                && msg.data == data
+                && Message::new(msg_type, data).encode() == frame
+                && (decoded.msg_type, decoded.data, len) == (msg.msg_type, msg.data, frame.len())
+                && Message::decode(&frame[..frame.len() - 1])
+                    .unwrap()
+                    .is_none()
+        }
+        quickcheck(round_trips as fn(u8, Vec<u8>) -> bool);
+    }
+
+    #[test]
+    fn entries_round_trip_in_chunks() {
+        fn round_trips(mut entry: HistoryEntry, kilobytes: u8, chunked: bool) -> bool {
+            entry.extra += &"x".repeat(kilobytes as usize * 1024);
+            let mut frames = Vec::new();
+            entry.write_messages(&mut frames, chunked).unwrap();
+            let mut reader = &frames[..];
+            let mut sizes = Vec::new();
+            while !reader.is_empty() {
+                sizes.push(Message::read_from(&mut reader).unwrap().data.len());
+            }
+            let joined = Message::read_joined(&mut &frames[..]).unwrap();
+            let expected = if chunked {
+                entry.encode().len().div_ceil(ENTRY_CHUNK_SIZE).max(1)
+            } else {
+                1
+            };
+            sizes.len() == expected
+                && (!chunked || sizes.iter().all(|&size| size <= ENTRY_CHUNK_SIZE))
+                && joined.msg_type == MessageType::HistoryEntry
+                && HistoryEntry::decode(&joined.data).ok() == Some(entry)
+        }
+        quickcheck(round_trips as fn(HistoryEntry, u8, bool) -> bool);
+    }
+
+    /// Takes a few bytes per write, and counts flushes
+    #[derive(Default)]
+    struct Trickle {
+        written: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            let n = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames_survive_short_writes() {
+        let msg = Message::new(MessageType::Error, b"disk full".to_vec());
+        let mut writer = Trickle::default();
+        msg.write_unflushed(&mut writer).unwrap();
+        assert_eq!(writer.written, msg.encode());
+        assert_eq!(writer.flushes, 0);
+    }
+
+    #[test]
+    fn flushing_follows_its_policy() {
+        let msg = Message::new(MessageType::Error, b"abc".to_vec());
+        let mut writer = Flushing::new(Trickle::default(), FlushPolicy::Batch);
+        for _ in 0..4 {
+            msg.write_unflushed(&mut writer).unwrap();
+        }
+        assert_eq!(writer.get_mut().flushes, 0);
+        writer.flush().unwrap();
+        assert_eq!(writer.get_mut().flushes, 1);
+
+        // 32 bytes, written 3 at most at a time, pass 10 unflushed 3 times
+        writer.set_policy(FlushPolicy::Bytes(10));
+        for _ in 0..4 {
+            msg.write_unflushed(&mut writer).unwrap();
+        }
+        assert_eq!(writer.get_mut().flushes, 4);
+        assert_eq!(writer.get_mut().written, msg.encode().repeat(8));
+    }
+
+    #[test]
+    fn reused_buffers_encode_like_fresh_ones() {
+        fn same(entry: HistoryEntry, previous: HistoryEntry, chunked: bool) -> bool {
+            let encoded = entry.encode();
+            let mut buf = previous.encode();
+            let start = buf.len();
+            entry.encode_into(&mut buf);
+            let appended = buf[..start] == previous.encode()[..] && buf[start..] == encoded[..];
+
+            let mut frames = Vec::new();
+            entry
+                .write_messages_with(&mut frames, chunked, &mut buf)
+                .unwrap();
+            let messages = entry.messages(chunked);
+            encoded.len() == entry.encoded_len()
+                && appended
+                && frames
+                    == messages
+                        .iter()
+                        .flat_map(Message::encode)
+                        .collect::<Vec<_>>()
+        }
+        quickcheck(same as fn(HistoryEntry, HistoryEntry, bool) -> bool);
+    }
+
+    #[test]
+    fn not_stored_round_trips() {
+        let not_stored = NotStored {
+            error: "disk I/O error".to_string(),
+            entries: vec![entry_hash("ls", 1, ""), 7],
+        };
+        assert_eq!(NotStored::decode(&not_stored.encode()).unwrap(), not_stored);
+        assert_eq!(
+            not_stored.to_string(),
+            "Server failed to store 2 entries: disk I/O error"
+        );
+        assert!(NotStored::decode(&not_stored.encode()[..10]).is_err());
+    }
+
+    #[test]
+    fn large_entries_are_chunked_and_joined() {
+        // A heredoc over two chunks long
+        let cmd = format!("cat <<EOF\n{}\nEOF", "x".repeat(2 * ENTRY_CHUNK_SIZE + 10));
+        let entry = HistoryEntry::new(cmd, 1, String::new());
+        let mut frames = Vec::new();
+        entry.write_messages(&mut frames, true).unwrap();
+        entry.write_messages(&mut frames, false).unwrap();
+        Message::new(MessageType::End, Vec::new())
+            .write_to(&mut frames)
+            .unwrap();
+
+        let mut reader = &frames[..];
+        let types: Vec<_> = (0..4)
+            .map(|_| Message::read_from(&mut reader).unwrap())
+            .map(|msg| (msg.msg_type, msg.data.len() <= ENTRY_CHUNK_SIZE))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                (MessageType::EntryChunk, true),
+                (MessageType::EntryChunk, true),
+                (MessageType::HistoryEntry, true),
+                (MessageType::HistoryEntry, false),
+            ]
+        );
+        let mut reader = &frames[..];
+        for _ in 0..2 {
+            let msg = Message::read_joined(&mut reader).unwrap();
+            assert_eq!(msg.msg_type, MessageType::HistoryEntry);
+            assert_eq!(HistoryEntry::decode(&msg.data).unwrap(), entry);
+        }
+        assert_eq!(
+            Message::read_joined(&mut reader).unwrap().msg_type,
+            MessageType::End
+        );
+
+        // Chunks must end with their entry
+        let mut frames = Vec::new();
+        for msg_type in [MessageType::EntryChunk, MessageType::End] {
+            Message::new(msg_type, b"cat".to_vec())
+                .write_to(&mut frames)
+                .unwrap();
+        }
+        assert!(Message::read_joined(&mut &frames[..]).is_err());
+    }
+
+    #[test]
+    fn hashes_round_trip() {
+        let hashes = [entry_hash("ls", 1, ""), 0, u64::MAX];
+        assert_eq!(decode_hashes(&encode_hashes(&hashes)).unwrap(), hashes);
+        assert_eq!(decode_hashes(&encode_hashes(&[])).unwrap(), []);
+        assert!(decode_hashes(&encode_hashes(&hashes)[..20]).is_err());
+    }
+
+    #[test]
+    fn suggestions_round_trip() {
+        let query = SuggestQuery {
+            prefix: "git ".to_string(),
+            cwd: Some("~/src".to_string()),
+            host: None,
+            limit: 10,
+        };
+        assert_eq!(SuggestQuery::decode(&query.encode()).unwrap(), query);
+        let query = SuggestQuery {
+            host: Some("laptop".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(SuggestQuery::decode(&query.encode()).unwrap(), query);
+        assert!(SuggestQuery::decode(&query.encode()[..12]).is_err());
+
+        let suggestions = [Suggestion {
+            cmd: "git push".to_string(),
+            uses: 3,
+            last_used: -1,
+        }];
+        let data = encode_suggestions(&suggestions);
+        assert_eq!(decode_suggestions(&data).unwrap(), suggestions);
+        assert!(decode_suggestions(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn server_info_round_trips() {
+        let info = ServerInfo {
+            protocol: PROTOCOL_VERSION,
+            version: "1.2.3".to_string(),
+            schema_version: 14,
+            entries: 1234,
+            capabilities: vec!["search".to_string(), "pins".to_string()],
+        };
+        let data = info.encode();
+        assert_eq!(ServerInfo::decode(&data).unwrap(), info);
+        assert!(ServerInfo::decode(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn session_summaries_round_trip() {
+        let summary = SessionSummary {
+            high_water_mark: 42,
+            stored: 3,
+            duplicates: Some(5),
+            rejected: Some(1),
+        };
+        let data = summary.encode();
+        assert_eq!(SessionSummary::decode(&data).unwrap(), summary);
+        assert!(SessionSummary::decode(&data[..data.len() - 1]).is_err());
+        // As older servers send it
+        let older = SessionSummary::decode(&data[..16]).unwrap();
+        assert_eq!((older.high_water_mark, older.stored), (42, 3));
+        assert_eq!((older.duplicates, older.rejected), (None, None));
+        assert!(SessionSummary::decode(&data[..8]).is_err());
+    }
+
+    #[test]
+    fn quota_exceeded_round_trips() {
+        let exceeded = QuotaExceeded {
+            quota: Quota::EntriesPerMinute,
+            limit: 1000,
+            retry_after: Some(42),
+        };
+        assert_eq!(QuotaExceeded::decode(&exceeded.encode()).unwrap(), exceeded);
+        assert_eq!(
+            exceeded.to_string(),
+            "Server quota exceeded: 1000 entries per minute, retry in 42s"
+        );
+        let stored = QuotaExceeded {
+            quota: Quota::StoredEntries,
+            limit: 5,
+            retry_after: None,
+        };
+        assert_eq!(QuotaExceeded::decode(&stored.encode()).unwrap(), stored);
+        assert!(QuotaExceeded::decode(&[9]).is_err());
+    }
+
+    #[test]
+    fn test_history_entry_encode_decode() {
+        let entry = HistoryEntry::new("ls -la".to_string(), 1234567890, "paths: /home".to_string());
+        let encoded = entry.encode();
+        let decoded = HistoryEntry::decode(&encoded).unwrap();
+
+        assert_eq!(entry.cmd, decoded.cmd);
+        assert_eq!(entry.when, decoded.when);
+        assert_eq!(entry.extra, decoded.extra);
+        assert_eq!(decoded.host, "");
+
+        let tagged = entry.with_host("laptop".to_string());
+        let decoded = HistoryEntry::decode(&tagged.encode()).unwrap();
+        assert_eq!(decoded.host, "laptop");
+        assert_eq!(decoded.extra, "paths: /home");
+        assert!(!decoded.pinned);
+
+        let pinned = HistoryEntry::new("make".to_string(), 1, String::new()).with_pinned(true);
+        let decoded = HistoryEntry::decode(&pinned.encode()).unwrap();
+        assert_eq!(decoded.host, "");
+        assert!(decoded.pinned);
+    }
+
+    #[test]
+    fn entry_metadata_round_trips() {
+        let mut entry = HistoryEntry::new("false".to_string(), 1, String::new());
+        entry.cwd = "/tmp".to_string();
+        entry.exit_code = Some(-1);
+        entry.duration_ms = Some(1500);
+        entry.session = "tty1".to_string();
+        entry.origin_device = "laptop".to_string();
+        entry.received_at = Some(2);
+        entry.namespace = "team-infra".to_string();
+        entry.signature = vec![7; 96];
+        let decoded = HistoryEntry::decode(&entry.encode()).unwrap();
+        assert_eq!(decoded, entry);
+
+        entry.cwd.clear();
+        entry.exit_code = None;
+        entry.origin_device.clear();
+        let decoded = HistoryEntry::decode(&entry.encode()).unwrap();
+        assert_eq!(decoded, entry);
+        assert_eq!(decoded.host, "");
+
+        // Signatures alone still need the flags byte before them
+        let signed = HistoryEntry {
+            signature: vec![7; 96],
+            ..HistoryEntry::new("ls".to_string(), 1, String::new())
+        };
+        let encoded = signed.encode();
+        assert_eq!(encoded.len(), signed.encoded_len());
+        assert_eq!(encoded[22..24], [0, 1]);
+        assert_eq!(HistoryEntry::decode(&encoded).unwrap(), signed);
+    }
+
+    #[test]
+    fn signatures_cover_what_devices_record() {
+        let mut entry = HistoryEntry::new("make".to_string(), 1, "x".to_string())
+            .with_host("laptop".to_string());
+        entry.cwd = "/src".to_string();
+        let signed = entry.signed_content();
+        assert!(signed.starts_with(SIGNED_PREFIX));
+        assert_eq!(
+            HistoryEntry::decode(&signed[SIGNED_PREFIX.len()..]).unwrap(),
+            entry
+        );
+
+        // Servers pin, stamp, share and carry the signature itself
+        entry.pinned = true;
+        entry.origin_device = "10.0.0.2".to_string();
+        entry.received_at = Some(2);
+        entry.namespace = "team-infra".to_string();
+        entry.signature = vec![7; 96];
+        assert_eq!(entry.signed_content(), signed);
+        entry.exit_code = Some(1);
+        assert_ne!(entry.signed_content(), signed);
+    }
+
+    #[test]
+    fn test_message_write_read() {
+        let entry = HistoryEntry::new("echo test".to_string(), 9876543210, "".to_string());
+        let msg = Message::new(MessageType::HistoryEntry, entry.encode());
+
+        let mut buffer = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+
+        let mut reader = &buffer[..];
+        let read_msg = Message::read_from(&mut reader).unwrap();
+
+        assert_eq!(msg.msg_type, read_msg.msg_type);
+        assert_eq!(msg.data, read_msg.data);
+    }
+
+    #[test]
+    fn test_history_request_encode_decode() {
+        assert!(HistoryRequest::default().encode().is_empty());
+        assert_eq!(
+            HistoryRequest::decode(&[]).unwrap(),
+            HistoryRequest::default()
+        );
+
+        let request = HistoryRequest {
+            since: Some(-5),
+            limit: Some(1000),
+            only_hosts: vec!["laptop".to_string(), "desktop".to_string()],
+            exclude_hosts: vec!["ci".to_string()],
+            until: Some(2000),
+            pattern: Some("git".to_string()),
+            keep_pinned: true,
+            tie_break: TieBreak::Command,
+            after_seq: Some(77),
+            snapshot: true,
+            tombstones_since: Some(-1),
+            namespaces: vec![String::new(), "team-infra".to_string()],
+        };
+        assert_eq!(HistoryRequest::decode(&request.encode()).unwrap(), request);
+
+        let request = HistoryRequest {
+            limit: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(request.encode().len(), 9);
+        assert_eq!(HistoryRequest::decode(&request.encode()).unwrap(), request);
+
+        let request = HistoryRequest {
+            after_seq: Some(12),
+            ..Default::default()
+        };
+        assert_eq!(request.encode().len(), 11);
+        assert_eq!(HistoryRequest::decode(&request.encode()).unwrap(), request);
+
+        let request = HistoryRequest {
+            snapshot: true,
+            ..Default::default()
+        };
+        assert_eq!(request.encode(), [128, 0, 2]);
+        assert_eq!(HistoryRequest::decode(&request.encode()).unwrap(), request);
+    }
+
+    #[test]
+    fn test_search_query_encode_decode() {
+        let query = SearchQuery {
+            text: "git push".to_string(),
+            limit: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(SearchQuery::decode(&query.encode()).unwrap(), query);
+        let query = SearchQuery {
+            text: "ls".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(SearchQuery::decode(&query.encode()).unwrap(), query);
+        assert_eq!(query.encode(), b"\0ls");
+
+        let query = SearchQuery {
+            text: "^cargo (build|test)".to_string(),
+            mode: MatchMode::Regex,
+            limit: Some(50),
+            since: Some(-1),
+            until: Some(1000),
+            hosts: vec!["laptop".to_string(), String::new()],
+            cwd: Some("/src".to_string()),
+            order: SearchOrder::Newest,
+            after: Some(SearchCursor { when: 999, id: 42 }),
+        };
+        assert_eq!(SearchQuery::decode(&query.encode()).unwrap(), query);
+        let cursor = query.after.unwrap();
+        assert_eq!(SearchCursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(SearchQuery::decode(&[SearchQuery::HAS_MODE, 4]).is_err());
+    }
+
+    #[test]
+    fn test_cmd_hash_is_stable() {
+        assert_eq!(cmd_hash(""), 0xcbf29ce484222325);
+        assert_eq!(cmd_hash("a"), 0xaf63dc4c8601ec8c);
+        assert_ne!(cmd_hash("ls"), cmd_hash("ls "));
+    }
+
+    #[test]
+    fn test_hello_encode_decode() {
+        let hello = Hello::current();
+        assert_eq!(Hello::decode(&hello.encode()).unwrap(), hello);
+        assert!(Hello::decode(&hello.encode()[..6]).is_err());
+
+        let old = Hello {
+            time: None,
+            ..Hello::current()
+        };
+        assert_eq!(Hello::decode(&old.encode()).unwrap(), old);
+
+        let laptop = Hello::current().with_device("laptop".into());
+        assert_eq!(Hello::decode(&laptop.encode()).unwrap(), laptop);
+
+        let server = Hello {
+            capabilities: vec!["buckets".to_string()],
+            ..Hello::current()
+        };
+        let decoded = Hello::decode(&server.encode()).unwrap();
+        assert_eq!(decoded, server);
+        assert!(decoded.supports("buckets") && !laptop.supports("buckets"));
+    }
+
+    #[test]
+    fn buckets_summarize_entries_per_day() {
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        let entries = [
+            entry("ls", -1),
+            entry("ls", 5),
+            entry("make", 86399),
+            entry("ls", 5),
+            entry("ls", 86400),
+        ];
+        let summaries = buckets(&entries);
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|b| (b.start, b.entries))
+                .collect::<Vec<_>>(),
+            [(-86400, 1), (0, 2), (86400, 1)]
+        );
+        assert_eq!(
+            summaries[1].hash,
+            entry_hash("ls", 5, "") ^ entry_hash("make", 86399, "")
+        );
+        assert_eq!(
+            decode_buckets(&encode_buckets(&summaries)).unwrap(),
+            summaries
+        );
+    }
+
+    #[test]
+    fn same_runs_merge_into_the_richest() {
+        let fish = HistoryEntry::new("make".to_string(), 5, "- Makefile".to_string());
+        let mut atuin =
+            HistoryEntry::new("make".to_string(), 5, String::new()).with_host("laptop".to_string());
+        atuin.cwd = "/src".to_string();
+        atuin.exit_code = Some(2);
+        let mut elsewhere = atuin.clone().with_host("desktop".to_string());
+        elsewhere.exit_code = Some(0);
+        let later = HistoryEntry::new("make".to_string(), 6, String::new());
+        let merged = merge_same_runs(vec![
+            fish.clone(),
+            later.clone(),
+            atuin.clone(),
+            elsewhere.clone(),
+        ]);
+
+        let mut richest = atuin;
+        richest.extra = fish.extra;
+        assert_eq!(merged, [richest, later, elsewhere]);
+    }
+
+    #[test]
+    fn patterns_yield_the_text_matches_need() {
+        assert_eq!(glob_regex("kubectl * -n prod"), r"^kubectl .* \-n prod$");
+        assert_eq!(glob_regex(r"[!a-c]?.rs\"), r"^[^a-c].\.rs\\$");
+        assert_eq!(glob_regex("[]x]y[z"), r"^[\]x]y\[z$");
+
+        assert_eq!(
+            required_words("kubectl * -n prod", MatchMode::Glob),
+            ["kubectl", "n", "prod"]
+        );
+        // Unanchored, the first word may end a longer one
+        assert_eq!(
+            required_words("kubectl .* -n prod", MatchMode::Regex),
+            ["n", "prod"]
+        );
+        assert_eq!(
+            required_words("^git (commit|push)", MatchMode::Regex),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            required_words(r"^git\s+(commit|push) -m", MatchMode::Regex),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            required_words(r"^git (commit)? -m", MatchMode::Regex),
+            ["git", "m"]
+        );
+        assert_eq!(
+            required_words("(?i)git", MatchMode::Regex),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            required_words("git push", MatchMode::Words),
+            Vec::<String>::new()
+        );
+
+        assert_eq!(
+            required_substring("kubectl .* -n prod", MatchMode::Regex).as_deref(),
+            Some(" -n prod")
+        );
+        assert_eq!(
+            required_substring(r"[)(]xab\.c{2}d", MatchMode::Regex).as_deref(),
+            Some("xab.")
+        );
+        assert_eq!(required_substring(r"\x41", MatchMode::Regex), None);
     }
 }
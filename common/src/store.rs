@@ -0,0 +1,3046 @@
+/// SQLite storage for history entries, shared by the server and the client cache
+use crate::{
+    cmd_hash, entry_hash, glob_regex, required_words, Bucket, HistoryEntry, HistoryRequest,
+    MatchMode, SearchCursor, SearchOrder, SearchQuery, SuggestQuery, Suggestion, TieBreak,
+};
+use anyhow::{bail, Context, Result};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Insert entries in one transaction, skipping duplicates, forgotten commands,
+/// archived entries and entries pruned by the retention policy, returning how
+/// many were stored
+pub fn insert_entries(conn: &mut Connection, entries: &[HistoryEntry]) -> Result<usize> {
+    insert_entries_collapsing(conn, entries, None)
+}
+
+/// Which received entries repeat a stored one closely enough to be skipped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Collapse {
+    /// Entries of a command already stored within this many seconds of them
+    pub window: u64,
+    /// Also those of the same command with or without a leading `sudo `
+    pub ignore_sudo: bool,
+}
+
+impl Collapse {
+    /// The window, as SQL takes it
+    pub fn seconds(&self) -> i64 {
+        self.window.min(i32::MAX as u64) as i64
+    }
+
+    /// The other command entries of `cmd` repeat, if any: `cmd` without its
+    /// leading `sudo `, or with one
+    pub fn alias(&self, cmd: &str) -> Option<String> {
+        if !self.ignore_sudo {
+            return None;
+        }
+        Some(match cmd.strip_prefix("sudo ") {
+            Some(bare) => bare.trim_start().to_string(),
+            None => format!("sudo {}", cmd),
+        })
+    }
+}
+
+/// `insert_entries`, also skipping entries that `collapse` says repeat a
+/// stored one, keeping the stored one
+pub fn insert_entries_collapsing(
+    conn: &mut Connection,
+    entries: &[HistoryEntry],
+    collapse: Option<Collapse>,
+) -> Result<usize> {
+    if entries.is_empty() {
+        return Ok(0);
+    }
+
+    let tx = conn
+        .transaction()
+        .context("Failed to begin transaction for batched history insert")?;
+
+    let mut stored = 0;
+    {
+        let mut seq = high_water_mark(&tx)?;
+        let mut stmt = tx
+            .prepare_cached(
+                "INSERT OR IGNORE INTO history
+                   (cmd, \"when\", extra, host, seq, hash, cwd, exit_code, duration_ms, session,
+                    origin_device, received_at, namespace, signature)
+                 SELECT ?1, ?2, ?3, ?4, ?6, ?7, ?8, ?9, ?10, ?11, ?13, ?14, ?16, ?17
+                 WHERE NOT EXISTS (SELECT 1 FROM tombstones WHERE cmd_hash = ?5)
+                 AND NOT EXISTS (SELECT 1 FROM entry_tombstones WHERE hash = ?7)
+                 AND NOT EXISTS (SELECT 1 FROM archived WHERE hash = ?7)
+                 AND (?1 IN (SELECT cmd FROM pins) OR (
+                   NOT EXISTS (SELECT 1 FROM retention WHERE key = 'horizon' AND value > ?2)
+                   AND NOT EXISTS (
+                     SELECT 1 FROM retention WHERE key = 'dedup' AND value
+                     AND EXISTS (SELECT 1 FROM history WHERE cmd = ?1 AND \"when\" > ?2)
+                   )
+                 ))
+                 AND (?12 IS NULL OR NOT EXISTS (
+                   SELECT 1 FROM history
+                   WHERE \"when\" BETWEEN ?2 - ?12 AND ?2 + ?12 AND (cmd = ?1 OR cmd = ?15)
+                 ))",
+            )
+            .context("Failed to prepare batched history insert statement")?;
+        let collapse_window = collapse.map(|collapse| collapse.seconds());
+
+        for entry in entries {
+            let host = Some(&entry.host).filter(|h| !h.is_empty());
+            let cwd = Some(&entry.cwd).filter(|c| !c.is_empty());
+            let session = Some(&entry.session).filter(|s| !s.is_empty());
+            let origin_device = Some(&entry.origin_device).filter(|d| !d.is_empty());
+            let namespace = Some(&entry.namespace).filter(|n| !n.is_empty());
+            let signature = Some(&entry.signature).filter(|s| !s.is_empty());
+            let duration_ms = entry.duration_ms.map(|d| d.min(i64::MAX as u64) as i64);
+            let hash = cmd_hash(&entry.cmd) as i64;
+            let next = seq as i64 + 1;
+            let content = entry_hash(&entry.cmd, entry.when, &entry.extra) as i64;
+            let alias = collapse.and_then(|collapse| collapse.alias(&entry.cmd));
+            let inserted = stmt
+                .execute(params![
+                    &entry.cmd,
+                    entry.when,
+                    &entry.extra,
+                    host,
+                    hash,
+                    next,
+                    content,
+                    cwd,
+                    entry.exit_code,
+                    duration_ms,
+                    session,
+                    collapse_window,
+                    origin_device,
+                    entry.received_at,
+                    alias,
+                    namespace,
+                    signature
+                ])
+                .with_context(|| {
+                    format!(
+                        "Failed to insert history entry during batch (cmd='{}')",
+                        &entry.cmd
+                    )
+                })?;
+            seq += inserted as u64;
+            stored += inserted;
+        }
+        tx.execute("UPDATE sequence SET value = ?1", [seq as i64])
+            .context("Failed to update sequence")?;
+    }
+
+    tx.commit()
+        .context("Failed to commit batched history insert transaction")?;
+
+    Ok(stored)
+}
+
+/// Merge `entries` recording the same run as a stored entry into it, as
+/// `HistoryEntry::merged_with` does but keeping its text and hash, and return
+/// the others, for imports not to store every command twice. Merged entries
+/// lose their signature, which no longer holds.
+pub fn merge_into_stored(
+    conn: &mut Connection,
+    entries: Vec<HistoryEntry>,
+) -> Result<Vec<HistoryEntry>> {
+    let tx = conn
+        .transaction()
+        .context("Failed to begin transaction for merging entries")?;
+    let mut others = Vec::new();
+    {
+        let mut select = tx
+            .prepare_cached(&format!(
+                "SELECT rowid, extra, host, {} FROM history
+                 WHERE cmd = ?1 AND \"when\" = ?2 AND COALESCE(namespace, '') = ?3",
+                METADATA_COLUMNS
+            ))
+            .context("Failed to prepare same run query")?;
+        let mut update = tx
+            .prepare_cached(
+                "UPDATE history SET host = ?2, cwd = ?3, exit_code = ?4, duration_ms = ?5,
+                   session = ?6, signature = NULL
+                 WHERE rowid = ?1",
+            )
+            .context("Failed to prepare merged entry update")?;
+        for entry in entries {
+            let stored = select
+                .query_map(params![&entry.cmd, entry.when, &entry.namespace], |row| {
+                    let stored = HistoryEntry::new(entry.cmd.clone(), entry.when, row.get(1)?)
+                        .with_host(row.get::<_, Option<String>>(2)?.unwrap_or_default());
+                    Ok((row.get::<_, i64>(0)?, with_metadata(stored, row, 3)?))
+                })
+                .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+                .context("Failed to read entries of the same run")?;
+            let Some((rowid, stored)) = stored.into_iter().find(|(_, s)| s.same_run(&entry)) else {
+                others.push(entry);
+                continue;
+            };
+            let merged = stored.merged_with(&entry);
+            let metadata = |e: &HistoryEntry| {
+                (
+                    e.host.clone(),
+                    e.cwd.clone(),
+                    e.exit_code,
+                    e.duration_ms,
+                    e.session.clone(),
+                )
+            };
+            if metadata(&merged) == metadata(&stored) {
+                continue;
+            }
+            update
+                .execute(params![
+                    rowid,
+                    Some(&merged.host).filter(|h| !h.is_empty()),
+                    Some(&merged.cwd).filter(|c| !c.is_empty()),
+                    merged.exit_code,
+                    merged.duration_ms.map(|d| d.min(i64::MAX as u64) as i64),
+                    Some(&merged.session).filter(|s| !s.is_empty()),
+                ])
+                .context("Failed to update merged entry")?;
+        }
+    }
+    tx.commit().context("Failed to commit merged entries")?;
+    Ok(others)
+}
+
+/// SQL condition true for entries of pinned commands
+const PINNED: &str = "cmd IN (SELECT cmd FROM pins)";
+
+/// Columns of the metadata clients may send along with entries, and of their
+/// provenance, in the order `with_metadata` reads them
+pub const METADATA_COLUMNS: &str =
+    "cwd, exit_code, duration_ms, session, origin_device, received_at, namespace, signature";
+
+/// `entry` with its metadata read from the `METADATA_COLUMNS` of `row`,
+/// starting at column `first`
+pub fn with_metadata(
+    mut entry: HistoryEntry,
+    row: &rusqlite::Row,
+    first: usize,
+) -> rusqlite::Result<HistoryEntry> {
+    entry.cwd = row.get::<_, Option<String>>(first)?.unwrap_or_default();
+    entry.exit_code = row.get(first + 1)?;
+    entry.duration_ms = row.get::<_, Option<i64>>(first + 2)?.map(|d| d as u64);
+    entry.session = row.get::<_, Option<String>>(first + 3)?.unwrap_or_default();
+    entry.origin_device = row.get::<_, Option<String>>(first + 4)?.unwrap_or_default();
+    entry.received_at = row.get(first + 5)?;
+    entry.namespace = row.get::<_, Option<String>>(first + 6)?.unwrap_or_default();
+    entry.signature = row
+        .get::<_, Option<Vec<u8>>>(first + 7)?
+        .unwrap_or_default();
+    Ok(entry)
+}
+
+/// Build the SELECT answering a GetHistory request, returning entries oldest
+/// first. Besides the entry columns, it returns the `tie` and `rid` columns
+/// completing its sort key, for paging, then the `METADATA_COLUMNS`.
+pub fn history_query(request: &HistoryRequest) -> (String, Vec<Value>) {
+    let mut conditions = Vec::new();
+    let mut query_params = Vec::new();
+
+    if let Some(since) = request.since {
+        query_params.push(Value::Integer(since));
+        if request.keep_pinned {
+            conditions.push(format!(
+                "(\"when\" >= ?{} OR {})",
+                query_params.len(),
+                PINNED
+            ));
+        } else {
+            conditions.push(format!("\"when\" >= ?{}", query_params.len()));
+        }
+    }
+    if let Some(until) = request.until {
+        query_params.push(Value::Integer(until));
+        conditions.push(format!("\"when\" < ?{}", query_params.len()));
+    }
+    if let Some(after_seq) = request.after_seq {
+        query_params.push(Value::Integer(after_seq.min(i64::MAX as u64) as i64));
+        conditions.push(format!("seq > ?{}", query_params.len()));
+    }
+    if let Some(pattern) = &request.pattern {
+        query_params.push(Value::Text(pattern.clone()));
+        conditions.push(format!("instr(cmd, ?{}) > 0", query_params.len()));
+    }
+    for (hosts, negate) in [(&request.only_hosts, ""), (&request.exclude_hosts, "NOT ")] {
+        if hosts.is_empty() {
+            continue;
+        }
+        let mut placeholders = Vec::new();
+        for host in hosts {
+            query_params.push(Value::Text(host.clone()));
+            placeholders.push(format!("?{}", query_params.len()));
+        }
+        // Untagged entries predate host tagging and are never filtered out
+        conditions.push(format!(
+            "(host IS NULL OR host = '' OR host {}IN ({}))",
+            negate,
+            placeholders.join(", ")
+        ));
+    }
+    if !request.namespaces.is_empty() {
+        let mut placeholders = Vec::new();
+        for namespace in &request.namespaces {
+            query_params.push(Value::Text(namespace.clone()));
+            placeholders.push(format!("?{}", query_params.len()));
+        }
+        conditions.push(format!(
+            "COALESCE(namespace, '') IN ({})",
+            placeholders.join(", ")
+        ));
+    }
+
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+
+    // Entries sharing a timestamp are ordered by this, then by rowid
+    let tie = match request.tie_break {
+        TieBreak::Insertion => "rowid",
+        TieBreak::Host => "COALESCE(host, '')",
+        TieBreak::Command => "cmd",
+    };
+
+    let sql = match request.limit {
+        Some(limit) => {
+            query_params.push(Value::Integer(limit.min(i64::MAX as u64) as i64));
+            // Pinned commands are picked first when they are kept
+            let priority = if request.keep_pinned {
+                format!("{} DESC, ", PINNED)
+            } else {
+                String::new()
+            };
+            format!(
+                "SELECT cmd, \"when\", extra, host, pinned, tie, rid, {} FROM (
+                   SELECT rowid AS rid, cmd, \"when\", extra, host, {} AS pinned, {} AS tie, {}
+                   FROM history {}
+                   ORDER BY {}\"when\" DESC, tie DESC, rid DESC LIMIT ?{}
+                 ) ORDER BY \"when\" ASC, tie ASC, rid ASC",
+                METADATA_COLUMNS,
+                PINNED,
+                tie,
+                METADATA_COLUMNS,
+                filter,
+                priority,
+                query_params.len(),
+            )
+        }
+        None => format!(
+            "SELECT cmd, \"when\", extra, host, {} AS pinned, {} AS tie, rowid AS rid, {}
+             FROM history {} ORDER BY \"when\" ASC, tie ASC, rid ASC",
+            PINNED, tie, METADATA_COLUMNS, filter
+        ),
+    };
+
+    (sql, query_params)
+}
+
+/// Add a column to an existing table unless it is already there
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+            table
+        ))?
+        .exists([column])?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )
+        .with_context(|| format!("Failed to add {} column to {}", column, table))?;
+    }
+    Ok(())
+}
+
+/// A schema change, applied once in a transaction
+type Migration = fn(&Connection) -> Result<()>;
+
+/// Schema changes in order: a database at version N has applied the first N.
+/// The early ones are idempotent because databases created before versioning
+/// may already have some of their changes.
+const MIGRATIONS: &[Migration] = &[
+    create_history,
+    add_host_column,
+    create_tombstones,
+    create_pins,
+    create_search_index,
+    create_retention,
+    index_when,
+    add_sequence,
+    create_sync_log,
+    create_devices,
+    add_entry_hash,
+    add_rejected_count,
+    create_archived,
+    add_entry_metadata,
+    create_entry_tombstones,
+    create_buckets,
+    add_entry_provenance,
+    create_trash,
+    create_command_stats,
+    create_command_hosts,
+    add_entry_namespace,
+    create_enrollments,
+    add_entry_signature,
+];
+
+fn create_history(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS history (
+          \"when\" INTEGER,
+          cmd TEXT,
+          extra TEXT
+        )",
+        [],
+    )
+    .context("Failed to create history table")?;
+
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_history_unique
+         ON history(cmd, \"when\", extra)",
+        [],
+    )
+    .context("Failed to create unique index")?;
+    Ok(())
+}
+
+fn add_host_column(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "history", "host", "TEXT")
+}
+
+/// Forgotten commands, so that re-uploads from other machines stay deleted
+fn create_tombstones(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tombstones (
+          cmd_hash INTEGER PRIMARY KEY,
+          deleted_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create tombstones table")?;
+    Ok(())
+}
+
+/// Pinned commands, kept regardless of age and listed first in searches
+fn create_pins(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pins (
+          cmd TEXT PRIMARY KEY,
+          pinned_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create pins table")?;
+    Ok(())
+}
+
+/// Full-text index over commands and their extra fields, kept in sync with
+/// history by triggers
+fn create_search_index(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE history_fts USING fts5(
+           cmd, extra, content='history', content_rowid='rowid'
+         );
+         CREATE TRIGGER history_fts_insert AFTER INSERT ON history BEGIN
+           INSERT INTO history_fts (rowid, cmd, extra) VALUES (new.rowid, new.cmd, new.extra);
+         END;
+         CREATE TRIGGER history_fts_delete AFTER DELETE ON history BEGIN
+           INSERT INTO history_fts (history_fts, rowid, cmd, extra)
+           VALUES ('delete', old.rowid, old.cmd, old.extra);
+         END;
+         CREATE TRIGGER history_fts_update AFTER UPDATE ON history BEGIN
+           INSERT INTO history_fts (history_fts, rowid, cmd, extra)
+           VALUES ('delete', old.rowid, old.cmd, old.extra);
+           INSERT INTO history_fts (rowid, cmd, extra) VALUES (new.rowid, new.cmd, new.extra);
+         END;
+         INSERT INTO history_fts (history_fts) VALUES ('rebuild');",
+    )
+    .context("Failed to create search index")
+}
+
+/// Retention horizon and dedup setting from the last pruning
+fn create_retention(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE retention (
+          key TEXT PRIMARY KEY,
+          value INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create retention table")?;
+    Ok(())
+}
+
+/// Index for time ranges and for resuming paged reads
+fn index_when(conn: &Connection) -> Result<()> {
+    conn.execute("CREATE INDEX idx_history_when ON history(\"when\")", [])
+        .context("Failed to create index on when")?;
+    Ok(())
+}
+
+/// Number entries in the order they are stored, for incremental reads. Rowids
+/// can't serve: they are reused after deleting the newest rows, and VACUUM
+/// may renumber them.
+fn add_sequence(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "DROP TRIGGER history_fts_update;
+         CREATE TRIGGER history_fts_update AFTER UPDATE OF cmd, extra ON history BEGIN
+           INSERT INTO history_fts (history_fts, rowid, cmd, extra)
+           VALUES ('delete', old.rowid, old.cmd, old.extra);
+           INSERT INTO history_fts (rowid, cmd, extra) VALUES (new.rowid, new.cmd, new.extra);
+         END;
+         ALTER TABLE history ADD COLUMN seq INTEGER;
+         UPDATE history SET seq = rowid;
+         CREATE INDEX idx_history_seq ON history(seq);
+         CREATE TABLE sequence (value INTEGER NOT NULL);
+         INSERT INTO sequence SELECT COALESCE(MAX(seq), 0) FROM history;",
+    )
+    .context("Failed to add sequence numbers")
+}
+
+fn create_sync_log(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE sync_log (
+          id INTEGER PRIMARY KEY,
+          peer TEXT NOT NULL,
+          started INTEGER NOT NULL,
+          ended INTEGER NOT NULL,
+          received INTEGER NOT NULL,
+          sent INTEGER NOT NULL,
+          error TEXT
+        )",
+        [],
+    )
+    .context("Failed to create sync_log table")?;
+    Ok(())
+}
+
+fn create_devices(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE devices (
+          name TEXT PRIMARY KEY,
+          first_seen INTEGER NOT NULL,
+          last_seen INTEGER NOT NULL,
+          last_mark INTEGER,
+          peer TEXT NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create devices table")?;
+    Ok(())
+}
+
+/// Identify entries by a hash of their content rather than a unique index
+/// over it, which let NULL columns through and stored every command twice
+fn add_entry_hash(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE history ADD COLUMN hash INTEGER", [])
+        .context("Failed to add entry hash column")?;
+    dedupe(conn)?;
+    conn.execute_batch(
+        "DROP INDEX idx_history_unique;
+         CREATE UNIQUE INDEX idx_history_hash ON history(hash);",
+    )
+    .context("Failed to index entry hashes")
+}
+
+fn add_rejected_count(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "ALTER TABLE sync_log ADD COLUMN rejected INTEGER NOT NULL DEFAULT 0",
+        [],
+    )
+    .context("Failed to add rejected column to sync_log")?;
+    Ok(())
+}
+
+/// Hashes of entries moved to an archive database, so that clients uploading
+/// them again don't bring them back
+fn create_archived(conn: &Connection) -> Result<()> {
+    conn.execute("CREATE TABLE archived (hash INTEGER PRIMARY KEY)", [])
+        .context("Failed to create archived table")?;
+    Ok(())
+}
+
+/// Where and how commands ran, for clients that send it
+fn add_entry_metadata(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE history ADD COLUMN cwd TEXT;
+         ALTER TABLE history ADD COLUMN exit_code INTEGER;
+         ALTER TABLE history ADD COLUMN duration_ms INTEGER;
+         ALTER TABLE history ADD COLUMN session TEXT;",
+    )
+    .context("Failed to add entry metadata columns")
+}
+
+/// Deleted entries, by content hash, so that re-uploads stay deleted and
+/// clients learn to delete them too
+fn create_entry_tombstones(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE entry_tombstones (
+           hash INTEGER PRIMARY KEY,
+           deleted_at INTEGER NOT NULL
+         );
+         CREATE INDEX idx_entry_tombstones_deleted_at ON entry_tombstones(deleted_at);",
+    )
+    .context("Failed to create entry tombstones table")
+}
+
+/// Per-day summaries of the stored entries, kept up to date by triggers so
+/// that reconciliation reads one row per day instead of every entry. SQLite
+/// has no XOR operator, but `(a | b) - (a & b)` is one that can't overflow.
+fn create_buckets(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE buckets (
+           start INTEGER PRIMARY KEY,
+           entries INTEGER NOT NULL,
+           hash INTEGER NOT NULL
+         );
+         CREATE TRIGGER history_buckets_insert AFTER INSERT ON history BEGIN
+           INSERT INTO buckets (start, entries, hash)
+           VALUES (new.\"when\" - (new.\"when\" % 86400 + 86400) % 86400, 1, new.hash)
+           ON CONFLICT (start) DO UPDATE
+           SET entries = entries + 1, hash = (hash | excluded.hash) - (hash & excluded.hash);
+         END;
+         CREATE TRIGGER history_buckets_delete AFTER DELETE ON history BEGIN
+           UPDATE buckets SET entries = entries - 1, hash = (hash | old.hash) - (hash & old.hash)
+           WHERE start = old.\"when\" - (old.\"when\" % 86400 + 86400) % 86400;
+         END;
+         CREATE TRIGGER history_buckets_update AFTER UPDATE OF \"when\", hash ON history BEGIN
+           UPDATE buckets SET entries = entries - 1, hash = (hash | old.hash) - (hash & old.hash)
+           WHERE start = old.\"when\" - (old.\"when\" % 86400 + 86400) % 86400;
+           INSERT INTO buckets (start, entries, hash)
+           VALUES (new.\"when\" - (new.\"when\" % 86400 + 86400) % 86400, 1, new.hash)
+           ON CONFLICT (start) DO UPDATE
+           SET entries = entries + 1, hash = (hash | excluded.hash) - (hash & excluded.hash);
+         END;",
+    )
+    .context("Failed to create buckets table")?;
+    fill_buckets(conn, bucket_summaries(conn)?)
+}
+
+/// Entries and combined hash of each day's bucket, summarized from history
+fn bucket_summaries(conn: &Connection) -> Result<BTreeMap<i64, (i64, u64)>> {
+    let mut buckets = BTreeMap::<i64, (i64, u64)>::new();
+    let mut stmt = conn
+        .prepare("SELECT \"when\", hash FROM history WHERE hash IS NOT NULL")
+        .context("Failed to prepare bucket backfill")?;
+    let mut rows = stmt
+        .query([])
+        .context("Failed to read entries into buckets")?;
+    while let Some(row) = rows.next().context("Failed to read entry into buckets")? {
+        let bucket = buckets.entry(Bucket::start_of(row.get(0)?)).or_default();
+        bucket.0 += 1;
+        bucket.1 ^= row.get::<_, i64>(1)? as u64;
+    }
+    Ok(buckets)
+}
+
+fn fill_buckets(conn: &Connection, buckets: BTreeMap<i64, (i64, u64)>) -> Result<()> {
+    let mut insert = conn
+        .prepare("INSERT INTO buckets (start, entries, hash) VALUES (?1, ?2, ?3)")
+        .context("Failed to prepare bucket backfill")?;
+    for (start, (entries, hash)) in buckets {
+        insert
+            .execute(params![start, entries, hash as i64])
+            .context("Failed to fill buckets")?;
+    }
+    Ok(())
+}
+
+/// Which device's session uploaded each entry, and when
+fn add_entry_provenance(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE history ADD COLUMN origin_device TEXT;
+         ALTER TABLE history ADD COLUMN received_at INTEGER;",
+    )
+    .context("Failed to add entry provenance columns")
+}
+
+/// Deleted entries, kept for a while on databases with a trash so that
+/// deletions can be undone
+fn create_trash(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE trash (
+           cmd TEXT NOT NULL,
+           \"when\" INTEGER NOT NULL,
+           extra TEXT NOT NULL,
+           host TEXT,
+           hash INTEGER NOT NULL,
+           cwd TEXT,
+           exit_code INTEGER,
+           duration_ms INTEGER,
+           session TEXT,
+           origin_device TEXT,
+           received_at INTEGER,
+           pinned INTEGER NOT NULL,
+           reason TEXT NOT NULL,
+           deleted_at INTEGER NOT NULL
+         );
+         CREATE INDEX idx_trash_deleted_at ON trash(deleted_at);",
+    )
+    .context("Failed to create trash table")
+}
+
+/// How often and when each command last ran, overall and in each working
+/// directory, kept up to date by triggers for ranking suggestions
+fn create_command_stats(conn: &Connection) -> Result<()> {
+    const VALID: &str = "cmd IS NOT NULL AND \"when\" IS NOT NULL";
+    let count = |row: &str| {
+        format!(
+            "INSERT INTO command_stats (cmd, uses, last_used)
+             SELECT {row}.cmd, 1, {row}.\"when\"
+             WHERE {row}.cmd IS NOT NULL AND {row}.\"when\" IS NOT NULL
+             ON CONFLICT (cmd) DO UPDATE
+             SET uses = uses + 1, last_used = MAX(last_used, excluded.last_used);
+             INSERT INTO command_dirs (cmd, cwd, uses, last_used)
+             SELECT {row}.cmd, {row}.cwd, 1, {row}.\"when\"
+             WHERE {row}.cmd IS NOT NULL AND {row}.\"when\" IS NOT NULL AND {row}.cwd <> ''
+             ON CONFLICT (cmd, cwd) DO UPDATE
+             SET uses = uses + 1, last_used = MAX(last_used, excluded.last_used);",
+            row = row
+        )
+    };
+    // Once the row is gone, the latest of the command's remaining entries
+    let uncount = |row: &str| {
+        format!(
+            "UPDATE command_stats SET uses = uses - 1, last_used = COALESCE(
+               (SELECT MAX(\"when\") FROM history WHERE cmd = {row}.cmd), last_used)
+             WHERE cmd = {row}.cmd AND {row}.\"when\" IS NOT NULL;
+             DELETE FROM command_stats WHERE cmd = {row}.cmd AND uses <= 0;
+             UPDATE command_dirs SET uses = uses - 1, last_used = COALESCE(
+               (SELECT MAX(\"when\") FROM history WHERE cmd = {row}.cmd AND cwd = {row}.cwd),
+               last_used)
+             WHERE cmd = {row}.cmd AND cwd = {row}.cwd AND {row}.\"when\" IS NOT NULL;
+             DELETE FROM command_dirs WHERE cmd = {row}.cmd AND cwd = {row}.cwd AND uses <= 0;",
+            row = row
+        )
+    };
+    conn.execute_batch(&format!(
+        "CREATE TABLE command_stats (
+           cmd TEXT PRIMARY KEY,
+           uses INTEGER NOT NULL,
+           last_used INTEGER NOT NULL
+         );
+         CREATE TABLE command_dirs (
+           cmd TEXT NOT NULL,
+           cwd TEXT NOT NULL,
+           uses INTEGER NOT NULL,
+           last_used INTEGER NOT NULL,
+           PRIMARY KEY (cmd, cwd)
+         );
+         INSERT INTO command_stats
+           SELECT cmd, COUNT(*), MAX(\"when\") FROM history WHERE {valid} GROUP BY cmd;
+         INSERT INTO command_dirs
+           SELECT cmd, cwd, COUNT(*), MAX(\"when\") FROM history
+           WHERE {valid} AND cwd <> '' GROUP BY cmd, cwd;
+         CREATE TRIGGER history_command_stats_insert AFTER INSERT ON history BEGIN
+           {count_new}
+         END;
+         CREATE TRIGGER history_command_stats_delete AFTER DELETE ON history BEGIN
+           {uncount_old}
+         END;
+         CREATE TRIGGER history_command_stats_update AFTER UPDATE OF cmd, \"when\", cwd
+         ON history BEGIN
+           {uncount_old}
+           {count_new}
+         END;",
+        valid = VALID,
+        count_new = count("new"),
+        uncount_old = uncount("old"),
+    ))
+    .context("Failed to create command stats tables")
+}
+
+/// How often and when each command last ran on each host, kept up to date
+/// by triggers as `command_dirs` is for directories
+fn create_command_hosts(conn: &Connection) -> Result<()> {
+    const VALID: &str = "cmd IS NOT NULL AND \"when\" IS NOT NULL AND host <> ''";
+    let count = |row: &str| {
+        format!(
+            "INSERT INTO command_hosts (cmd, host, uses, last_used)
+             SELECT {row}.cmd, {row}.host, 1, {row}.\"when\"
+             WHERE {row}.cmd IS NOT NULL AND {row}.\"when\" IS NOT NULL AND {row}.host <> ''
+             ON CONFLICT (cmd, host) DO UPDATE
+             SET uses = uses + 1, last_used = MAX(last_used, excluded.last_used);",
+            row = row
+        )
+    };
+    let uncount = |row: &str| {
+        format!(
+            "UPDATE command_hosts SET uses = uses - 1, last_used = COALESCE(
+               (SELECT MAX(\"when\") FROM history WHERE cmd = {row}.cmd AND host = {row}.host),
+               last_used)
+             WHERE cmd = {row}.cmd AND host = {row}.host AND {row}.\"when\" IS NOT NULL;
+             DELETE FROM command_hosts WHERE cmd = {row}.cmd AND host = {row}.host AND uses <= 0;",
+            row = row
+        )
+    };
+    conn.execute_batch(&format!(
+        "CREATE TABLE command_hosts (
+           cmd TEXT NOT NULL,
+           host TEXT NOT NULL,
+           uses INTEGER NOT NULL,
+           last_used INTEGER NOT NULL,
+           PRIMARY KEY (cmd, host)
+         );
+         INSERT INTO command_hosts
+           SELECT cmd, host, COUNT(*), MAX(\"when\") FROM history
+           WHERE {valid} GROUP BY cmd, host;
+         CREATE TRIGGER history_command_hosts_insert AFTER INSERT ON history BEGIN
+           {count_new}
+         END;
+         CREATE TRIGGER history_command_hosts_delete AFTER DELETE ON history BEGIN
+           {uncount_old}
+         END;
+         CREATE TRIGGER history_command_hosts_update AFTER UPDATE OF cmd, \"when\", host
+         ON history BEGIN
+           {uncount_old}
+           {count_new}
+         END;",
+        valid = VALID,
+        count_new = count("new"),
+        uncount_old = uncount("old"),
+    ))
+    .context("Failed to create command hosts table")
+}
+
+/// Namespace each entry is shared in, kept along with it in the trash
+fn add_entry_namespace(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE history ADD COLUMN namespace TEXT;
+         ALTER TABLE trash ADD COLUMN namespace TEXT;",
+    )
+    .context("Failed to add entry namespace columns")
+}
+
+/// Devices asking for an API token, until they collect it
+fn create_enrollments(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE enrollments (
+          code TEXT PRIMARY KEY,
+          secret TEXT NOT NULL,
+          device TEXT NOT NULL,
+          peer TEXT NOT NULL,
+          requested_at INTEGER NOT NULL,
+          token TEXT
+        )",
+        [],
+    )
+    .context("Failed to create enrollments table")?;
+    Ok(())
+}
+
+/// Signature of each entry by the device that uploaded it, kept along with
+/// it in the trash
+fn add_entry_signature(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE history ADD COLUMN signature BLOB;
+         ALTER TABLE trash ADD COLUMN signature BLOB;",
+    )
+    .context("Failed to add entry signature columns")
+}
+
+/// Version of the schema this build migrates databases to
+pub fn latest_schema_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Version of the schema, the number of migrations applied
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    let version: Option<i64> = conn
+        .query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+            row.get(0)
+        })
+        .context("Failed to read schema version")?;
+    Ok(version.unwrap_or(0) as usize)
+}
+
+/// Bring the schema up to date, applying each pending migration in its own
+/// transaction, and register the functions queries rely on
+pub fn init_schema(conn: &mut Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+          version INTEGER PRIMARY KEY,
+          applied_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create schema_version table")?;
+
+    register_functions(conn)?;
+
+    let current = schema_version(conn)?;
+    if current > MIGRATIONS.len() {
+        bail!(
+            "Database schema version {} is newer than the latest known ({}), upgrade plenty",
+            current,
+            MIGRATIONS.len()
+        );
+    }
+    for (version, migrate) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = version + 1;
+        let tx = conn
+            .transaction()
+            .context("Failed to begin schema migration")?;
+        migrate(&tx).with_context(|| format!("Failed to migrate schema to version {}", version))?;
+        tx.execute(
+            "INSERT INTO schema_version (version, applied_at) VALUES (?1, ?2)",
+            params![version as i64, unix_now()],
+        )
+        .context("Failed to record schema version")?;
+        tx.commit()
+            .with_context(|| format!("Failed to commit schema version {}", version))?;
+    }
+
+    Ok(())
+}
+
+/// Functions used by queries and migrations
+fn register_functions(conn: &Connection) -> Result<()> {
+    conn.create_scalar_function(
+        "plenty_cmd_hash",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| Ok(cmd_hash(&ctx.get::<String>(0)?) as i64),
+    )
+    .context("Failed to register plenty_cmd_hash function")?;
+    conn.create_scalar_function(
+        "plenty_entry_hash",
+        3,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| Ok(entry_hash(&ctx.get::<String>(0)?, ctx.get(1)?, &ctx.get::<String>(2)?) as i64),
+    )
+    .context("Failed to register plenty_entry_hash function")?;
+    Ok(())
+}
+
+/// Delete entries without a command or time, store missing extra fields as
+/// empty, and keep a single entry of each content, preferring one with a
+/// host, then the earliest stored. Returns how many entries were deleted.
+pub fn dedupe(conn: &Connection) -> Result<usize> {
+    let invalid = conn
+        .execute(
+            "DELETE FROM history WHERE cmd IS NULL OR \"when\" IS NULL",
+            [],
+        )
+        .context("Failed to delete invalid entries")?;
+    let duplicates = conn
+        .execute(
+            "DELETE FROM history WHERE rowid IN (
+               SELECT rowid FROM (
+                 SELECT rowid, ROW_NUMBER() OVER (
+                   PARTITION BY plenty_entry_hash(cmd, \"when\", COALESCE(extra, ''))
+                   ORDER BY host IS NULL, rowid
+                 ) AS copy
+                 FROM history
+               ) WHERE copy > 1
+             )",
+            [],
+        )
+        .context("Failed to delete duplicate entries")?;
+    conn.execute("UPDATE history SET extra = '' WHERE extra IS NULL", [])
+        .context("Failed to fill in missing extra fields")?;
+    conn.execute(
+        "UPDATE history SET hash = plenty_entry_hash(cmd, \"when\", extra)
+         WHERE hash IS NOT plenty_entry_hash(cmd, \"when\", extra)",
+        [],
+    )
+    .context("Failed to hash entries")?;
+    Ok(invalid + duplicates)
+}
+
+/// Replace what isn't valid UTF-8 in the text columns of history, which
+/// damaged pages or other writers may leave, with U+FFFD, returning how many
+/// entries had any. Their hashes are left for `dedupe` to correct.
+pub fn repair_text(conn: &Connection) -> Result<usize> {
+    const COLUMNS: [&str; 7] = [
+        "cmd",
+        "extra",
+        "host",
+        "cwd",
+        "session",
+        "origin_device",
+        "namespace",
+    ];
+    let mut repairs = Vec::new();
+    {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT rowid, {} FROM history",
+                COLUMNS.join(", ")
+            ))
+            .context("Failed to prepare text check")?;
+        let mut rows = stmt.query([]).context("Failed to check entry text")?;
+        while let Some(row) = rows.next().context("Failed to check entry text")? {
+            for (i, column) in COLUMNS.iter().enumerate() {
+                let text = match row.get_ref(i + 1)? {
+                    ValueRef::Text(bytes) if std::str::from_utf8(bytes).is_err() => bytes,
+                    ValueRef::Blob(bytes) => bytes,
+                    _ => continue,
+                };
+                let text = String::from_utf8_lossy(text).into_owned();
+                repairs.push((row.get::<_, i64>(0)?, *column, text));
+            }
+        }
+    }
+    let mut entries = HashSet::new();
+    for (rowid, column, text) in repairs {
+        conn.execute(
+            &format!("UPDATE history SET {} = ?1 WHERE rowid = ?2", column),
+            params![text, rowid],
+        )
+        .context("Failed to repair entry text")?;
+        entries.insert(rowid);
+    }
+    Ok(entries.len())
+}
+
+/// Rebuild every index from the tables, including the search index
+pub fn rebuild_indexes(conn: &Connection) -> Result<()> {
+    conn.execute_batch("REINDEX")
+        .context("Failed to rebuild indexes")?;
+    conn.execute(
+        "INSERT INTO history_fts (history_fts) VALUES ('rebuild')",
+        [],
+    )
+    .context("Failed to rebuild search index")?;
+    Ok(())
+}
+
+/// Summarize the buckets from history again, returning how many disagreed
+/// with it
+pub fn rebuild_buckets(conn: &Connection) -> Result<usize> {
+    let summaries = bucket_summaries(conn)?;
+    let mut stmt = conn
+        .prepare("SELECT start, entries, hash FROM buckets WHERE entries != 0")
+        .context("Failed to prepare bucket check")?;
+    let stored = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                (row.get(1)?, row.get::<_, i64>(2)? as u64),
+            ))
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<BTreeMap<_, _>>>())
+        .context("Failed to read buckets")?;
+    let stale = summaries
+        .iter()
+        .filter(|(start, summary)| stored.get(start) != Some(summary))
+        .count()
+        + stored
+            .keys()
+            .filter(|start| !summaries.contains_key(start))
+            .count();
+    conn.execute("DELETE FROM buckets", [])
+        .context("Failed to clear buckets")?;
+    fill_buckets(conn, summaries)?;
+    Ok(stale)
+}
+
+/// Columns of history the trash keeps, in the order of its own
+const TRASH_COLUMNS: &str =
+    "cmd, \"when\", extra, host, hash, cwd, exit_code, duration_ms, session, origin_device, \
+     received_at, namespace, signature";
+
+/// Keep deleted entries in the trash for `days` days from now on, or
+/// delete them at once if `None`, emptying the trash of those kept longer
+pub fn set_trash_days(conn: &Connection, days: Option<u64>, now: i64) -> Result<()> {
+    let days = days.map(|days| days.min(i64::MAX as u64 / 86400) as i64);
+    let recorded: Option<i64> = conn
+        .query_row(
+            "SELECT value FROM retention WHERE key = 'trash_days'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+        .context("Failed to read trash setting")?;
+    if recorded != days {
+        match days {
+            Some(days) => conn.execute(
+                "INSERT OR REPLACE INTO retention (key, value) VALUES ('trash_days', ?1)",
+                [days],
+            ),
+            None => conn.execute("DELETE FROM retention WHERE key = 'trash_days'", []),
+        }
+        .context("Failed to record trash setting")?;
+    }
+    // Only take the write lock when something expired
+    let expiry = days.map_or(i64::MAX, |days| now.saturating_sub(days * 86400));
+    let expired = conn
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM trash WHERE deleted_at < ?1)",
+            [expiry],
+            |row| row.get::<_, bool>(0),
+        )
+        .context("Failed to check the trash")?;
+    if expired {
+        conn.execute("DELETE FROM trash WHERE deleted_at < ?1", [expiry])
+            .context("Failed to empty the trash")?;
+    }
+    Ok(())
+}
+
+/// Copy the entries of history `condition` selects to the trash, deleted at
+/// `now` for `reason` (forget, delete or prune), if the database keeps one
+fn trash(
+    conn: &Connection,
+    condition: &str,
+    params: impl rusqlite::Params,
+    reason: &'static str,
+    now: i64,
+) -> Result<()> {
+    let keeps: bool = conn
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM retention WHERE key = 'trash_days')",
+            [],
+            |row| row.get(0),
+        )
+        .context("Failed to read trash setting")?;
+    if !keeps {
+        return Ok(());
+    }
+    conn.execute(
+        &format!(
+            "INSERT INTO trash ({columns}, pinned, reason, deleted_at)
+             SELECT cmd, \"when\", COALESCE(extra, ''),
+                    host, plenty_entry_hash(cmd, \"when\", COALESCE(extra, '')),
+                    cwd, exit_code, duration_ms, session, origin_device, received_at,
+                    namespace, signature, {pinned}, '{reason}', {now}
+             FROM history
+             WHERE cmd IS NOT NULL AND \"when\" IS NOT NULL AND ({condition})",
+            columns = TRASH_COLUMNS,
+            pinned = PINNED,
+            reason = reason,
+            now = now,
+            condition = condition,
+        ),
+        params,
+    )
+    .context("Failed to move deleted entries to the trash")?;
+    Ok(())
+}
+
+/// Put the entries of the trash deleted at or after `since` back, only
+/// those of `cmd` if given, lifting the tombstones and re-pinning commands
+/// their deletion left behind, and returning how many were restored. They
+/// get new sequence numbers, so that clients download them again.
+pub fn undelete(conn: &mut Connection, since: i64, cmd: Option<&str>) -> Result<usize> {
+    const TRASHED: &str = "deleted_at >= ?1 AND (?2 IS NULL OR cmd = ?2)";
+    let tx = conn
+        .transaction()
+        .context("Failed to begin transaction for undeletion")?;
+    tx.execute(
+        &format!(
+            "DELETE FROM tombstones WHERE cmd_hash IN (
+               SELECT plenty_cmd_hash(cmd) FROM trash WHERE {}
+             )",
+            TRASHED
+        ),
+        params![since, cmd],
+    )
+    .context("Failed to lift tombstones")?;
+    tx.execute(
+        &format!(
+            "DELETE FROM entry_tombstones WHERE hash IN (SELECT hash FROM trash WHERE {})",
+            TRASHED
+        ),
+        params![since, cmd],
+    )
+    .context("Failed to lift entry tombstones")?;
+    tx.execute(
+        &format!(
+            "INSERT OR IGNORE INTO pins (cmd, pinned_at)
+             SELECT DISTINCT cmd, ?3 FROM trash WHERE pinned AND {}",
+            TRASHED
+        ),
+        params![since, cmd, unix_now()],
+    )
+    .context("Failed to pin restored commands")?;
+    let mut restored = 0;
+    {
+        let mut seq = high_water_mark(&tx)?;
+        let mut select = tx
+            .prepare(&format!(
+                "SELECT rowid FROM trash WHERE {} ORDER BY \"when\", rowid",
+                TRASHED
+            ))
+            .context("Failed to prepare trash query")?;
+        let rowids = select
+            .query_map(params![since, cmd], |row| row.get::<_, i64>(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .context("Failed to read the trash")?;
+        let mut insert = tx
+            .prepare(&format!(
+                "INSERT OR IGNORE INTO history ({columns}, seq)
+                 SELECT {columns}, ?2 FROM trash WHERE rowid = ?1",
+                columns = TRASH_COLUMNS
+            ))
+            .context("Failed to prepare undeletion")?;
+        for rowid in rowids {
+            let inserted = insert
+                .execute(params![rowid, seq as i64 + 1])
+                .context("Failed to restore entry")?;
+            seq += inserted as u64;
+            restored += inserted;
+        }
+        tx.execute("UPDATE sequence SET value = ?1", [seq as i64])
+            .context("Failed to update sequence")?;
+    }
+    tx.execute(
+        &format!("DELETE FROM trash WHERE {}", TRASHED),
+        params![since, cmd],
+    )
+    .context("Failed to empty the trash")?;
+    tx.commit().context("Failed to commit undeletion")?;
+    Ok(restored)
+}
+
+/// When the last entries moved to the trash were deleted, if any are there
+pub fn last_trashed(conn: &Connection) -> Result<Option<i64>> {
+    conn.query_row("SELECT MAX(deleted_at) FROM trash", [], |row| row.get(0))
+        .context("Failed to read the trash")
+}
+
+/// Delete every entry for a command and record a tombstone for it
+pub fn forget_command(conn: &mut Connection, hash: u64) -> Result<usize> {
+    let now = unix_now();
+
+    let tx = conn
+        .transaction()
+        .context("Failed to begin transaction for deletion")?;
+    trash(
+        &tx,
+        "plenty_cmd_hash(cmd) = ?1",
+        params![hash as i64],
+        "forget",
+        now,
+    )?;
+    tx.execute(
+        "INSERT OR REPLACE INTO tombstones (cmd_hash, deleted_at) VALUES (?1, ?2)",
+        params![hash as i64, now],
+    )
+    .context("Failed to record tombstone")?;
+    tx.execute(
+        "DELETE FROM pins WHERE plenty_cmd_hash(cmd) = ?1",
+        params![hash as i64],
+    )
+    .context("Failed to unpin deleted command")?;
+    let deleted = tx
+        .execute(
+            "DELETE FROM history WHERE plenty_cmd_hash(cmd) = ?1",
+            params![hash as i64],
+        )
+        .context("Failed to delete history entries")?;
+    tx.commit().context("Failed to commit deletion")?;
+
+    Ok(deleted)
+}
+
+/// Delete the entries with these content hashes and record tombstones for
+/// them, deleted at `now`, returning how many entries were deleted
+pub fn delete_entries(conn: &mut Connection, hashes: &[u64], now: i64) -> Result<usize> {
+    let tx = conn
+        .transaction()
+        .context("Failed to begin transaction for deletion")?;
+    let mut deleted = 0;
+    {
+        let mut tombstone = tx
+            .prepare_cached(
+                "INSERT OR REPLACE INTO entry_tombstones (hash, deleted_at) VALUES (?1, ?2)",
+            )
+            .context("Failed to prepare entry tombstone statement")?;
+        let mut delete = tx
+            .prepare_cached("DELETE FROM history WHERE hash = ?1")
+            .context("Failed to prepare entry deletion statement")?;
+        for hash in hashes {
+            tombstone
+                .execute(params![*hash as i64, now])
+                .context("Failed to record entry tombstone")?;
+            trash(&tx, "hash = ?1", [*hash as i64], "delete", now)?;
+            deleted += delete
+                .execute([*hash as i64])
+                .context("Failed to delete history entry")?;
+        }
+    }
+    tx.commit().context("Failed to commit deletion")?;
+    Ok(deleted)
+}
+
+/// Move the entries with these content hashes to `namespace`, returning how
+/// many there were
+pub fn set_namespace(conn: &mut Connection, hashes: &[u64], namespace: &str) -> Result<usize> {
+    let tx = conn
+        .transaction()
+        .context("Failed to begin transaction for sharing")?;
+    let mut moved = 0;
+    {
+        let mut update = tx
+            .prepare_cached("UPDATE history SET namespace = NULLIF(?2, '') WHERE hash = ?1")
+            .context("Failed to prepare namespace update")?;
+        for hash in hashes {
+            moved += update
+                .execute(params![*hash as i64, namespace])
+                .context("Failed to update entry namespace")?;
+        }
+    }
+    tx.commit().context("Failed to commit namespace update")?;
+    Ok(moved)
+}
+
+/// The namespace of each entry outside the default one, by content hash
+pub fn entry_namespaces(conn: &Connection) -> Result<HashMap<u64, String>> {
+    let mut stmt = conn
+        .prepare("SELECT hash, namespace FROM history WHERE namespace <> '' AND hash IS NOT NULL")
+        .context("Failed to prepare namespaces query")?;
+    let namespaces = stmt
+        .query_map([], |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?)))
+        .and_then(|rows| rows.collect())
+        .context("Failed to read entry namespaces")?;
+    Ok(namespaces)
+}
+
+/// Hashes of the entries deleted at or after `since`
+pub fn entry_tombstones_since(conn: &Connection, since: i64) -> Result<Vec<u64>> {
+    let mut stmt = conn
+        .prepare("SELECT hash FROM entry_tombstones WHERE deleted_at >= ?1 ORDER BY hash")
+        .context("Failed to prepare entry tombstones query")?;
+    let hashes = stmt
+        .query_map([since], |row| row.get::<_, i64>(0).map(|hash| hash as u64))
+        .and_then(|rows| rows.collect())
+        .context("Failed to read entry tombstones")?;
+    Ok(hashes)
+}
+
+/// Summaries of the buckets holding entries, oldest first
+pub fn buckets(conn: &Connection) -> Result<Vec<Bucket>> {
+    let mut stmt = conn
+        .prepare("SELECT start, entries, hash FROM buckets WHERE entries > 0 ORDER BY start")
+        .context("Failed to prepare bucket query")?;
+    let buckets = stmt
+        .query_map([], |row| {
+            Ok(Bucket {
+                start: row.get(0)?,
+                entries: row.get::<_, i64>(1)? as u64,
+                hash: row.get::<_, i64>(2)? as u64,
+            })
+        })
+        .and_then(|rows| rows.collect())
+        .context("Failed to read buckets")?;
+    Ok(buckets)
+}
+
+fn pin(conn: &Connection, cmd: &str, pinned: bool) -> Result<()> {
+    if pinned {
+        conn.execute(
+            "INSERT OR IGNORE INTO pins (cmd, pinned_at) VALUES (?1, ?2)",
+            params![cmd, unix_now()],
+        )
+        .context("Failed to pin command")?;
+    } else {
+        conn.execute("DELETE FROM pins WHERE cmd = ?1", [cmd])
+            .context("Failed to unpin command")?;
+    }
+    Ok(())
+}
+
+/// Pin or unpin commands in one transaction
+pub fn apply_pins(conn: &mut Connection, pins: &[(String, bool)]) -> Result<()> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+    let tx = conn
+        .transaction()
+        .context("Failed to begin transaction for pins")?;
+    for (cmd, pinned) in pins {
+        pin(&tx, cmd, *pinned)?;
+    }
+    tx.commit().context("Failed to commit pins")
+}
+
+/// Pin or unpin a command, returning how many entries it has
+pub fn set_pinned(conn: &Connection, cmd: &str, pinned: bool) -> Result<usize> {
+    pin(conn, cmd, pinned)?;
+    let entries: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM history WHERE cmd = ?1",
+            [cmd],
+            |row| row.get(0),
+        )
+        .context("Failed to count entries for command")?;
+    Ok(entries as usize)
+}
+
+/// Limits on what a database keeps; pinned commands are always kept
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Drop entries older than this many days
+    pub max_age_days: Option<u64>,
+    /// Keep only about this many of the most recent entries (ties are kept)
+    pub max_entries: Option<u64>,
+    /// Keep only the most recent entry of each command
+    pub dedup: bool,
+    /// Drop tombstones of entries deleted more than this many days ago, after
+    /// which they may be uploaded again
+    pub tombstone_days: Option<u64>,
+}
+
+impl Retention {
+    pub fn is_empty(&self) -> bool {
+        *self == Retention::default()
+    }
+}
+
+/// Entries deleted by `apply_retention`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pruned {
+    /// Older than the retention horizon
+    pub expired: usize,
+    /// Superseded by a more recent entry of the same command
+    pub duplicates: usize,
+    /// Tombstones of deleted entries past `tombstone_days`
+    pub tombstones: usize,
+}
+
+/// Delete what `retention` doesn't keep. The resulting horizon and dedup
+/// setting are recorded so that `insert_entries` refuses pruned entries
+/// when other machines upload them again.
+pub fn apply_retention(conn: &mut Connection, retention: &Retention, now: i64) -> Result<Pruned> {
+    let tx = conn
+        .transaction()
+        .context("Failed to begin transaction for pruning")?;
+
+    let mut horizon = retention
+        .max_age_days
+        .map(|days| now.saturating_sub(days.min(i64::MAX as u64 / 86400) as i64 * 86400));
+    if let Some(max_entries) = retention.max_entries {
+        let oldest_kept: Option<i64> = tx
+            .query_row(
+                &format!(
+                    "SELECT \"when\" FROM history WHERE NOT {}
+                     ORDER BY \"when\" DESC LIMIT 1 OFFSET ?1",
+                    PINNED
+                ),
+                [max_entries.saturating_sub(1).min(i64::MAX as u64) as i64],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to find the oldest entry to keep")?;
+        if max_entries == 0 {
+            horizon = Some(i64::MAX);
+        } else if let Some(oldest_kept) = oldest_kept {
+            horizon = Some(horizon.map_or(oldest_kept, |h| h.max(oldest_kept)));
+        }
+    }
+
+    let mut pruned = Pruned::default();
+    if let Some(horizon) = horizon {
+        tx.execute(
+            "INSERT INTO retention (key, value) VALUES ('horizon', ?1)
+             ON CONFLICT (key) DO UPDATE SET value = MAX(value, excluded.value)",
+            [horizon],
+        )
+        .context("Failed to record retention horizon")?;
+        let expired = format!("\"when\" < ?1 AND NOT {}", PINNED);
+        trash(&tx, &expired, [horizon], "prune", now)?;
+        pruned.expired = tx
+            .execute(&format!("DELETE FROM history WHERE {}", expired), [horizon])
+            .context("Failed to prune expired history entries")?;
+    }
+
+    tx.execute(
+        "INSERT OR REPLACE INTO retention (key, value) VALUES ('dedup', ?1)",
+        [retention.dedup],
+    )
+    .context("Failed to record dedup setting")?;
+    if retention.dedup {
+        let superseded = format!(
+            "NOT {} AND EXISTS (
+               SELECT 1 FROM history AS newer WHERE newer.cmd = history.cmd
+               AND (newer.\"when\", newer.rowid) > (history.\"when\", history.rowid)
+             )",
+            PINNED
+        );
+        trash(&tx, &superseded, params![], "prune", now)?;
+        pruned.duplicates = tx
+            .execute(&format!("DELETE FROM history WHERE {}", superseded), [])
+            .context("Failed to prune duplicate history entries")?;
+    }
+
+    if let Some(days) = retention.tombstone_days {
+        pruned.tombstones = tx
+            .execute(
+                "DELETE FROM entry_tombstones WHERE deleted_at < ?1",
+                [now.saturating_sub(days.min(i64::MAX as u64 / 86400) as i64 * 86400)],
+            )
+            .context("Failed to expire entry tombstones")?;
+    }
+
+    tx.commit().context("Failed to commit pruning")?;
+    Ok(pruned)
+}
+
+/// Rebuild the database file to reclaim space, then the search index, since
+/// VACUUM may renumber the rowids it refers to. The rebuilt file reclaims
+/// space incrementally from then on.
+pub fn vacuum(conn: &Connection) -> Result<()> {
+    conn.pragma_update(None, "auto_vacuum", "incremental")
+        .context("Failed to enable incremental vacuum")?;
+    conn.execute_batch("VACUUM")
+        .context("Failed to vacuum database")?;
+    conn.execute(
+        "INSERT INTO history_fts (history_fts) VALUES ('rebuild')",
+        [],
+    )
+    .context("Failed to rebuild search index")?;
+    Ok(())
+}
+
+/// Size of the database file in pages, and how many of them are free
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Space {
+    pub page_size: u64,
+    pub pages: u64,
+    pub free_pages: u64,
+}
+
+impl Space {
+    pub fn bytes(&self) -> u64 {
+        self.pages * self.page_size
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free_pages * self.page_size
+    }
+}
+
+pub fn space(conn: &Connection) -> Result<Space> {
+    let pragma = |name: &str| -> Result<u64> {
+        conn.pragma_query_value(None, name, |row| row.get::<_, i64>(0))
+            .map(|value| value as u64)
+            .with_context(|| format!("Failed to read {}", name))
+    };
+    Ok(Space {
+        page_size: pragma("page_size")?,
+        pages: pragma("page_count")?,
+        free_pages: pragma("freelist_count")?,
+    })
+}
+
+/// Whether free pages can be given back a few at a time, which databases
+/// created before this was the default can't until vacuumed once
+pub fn incremental_vacuum_enabled(conn: &Connection) -> Result<bool> {
+    let mode: i64 = conn
+        .pragma_query_value(None, "auto_vacuum", |row| row.get(0))
+        .context("Failed to read auto_vacuum mode")?;
+    Ok(mode == 2)
+}
+
+/// Give up to `pages` free pages back to the file system
+pub fn incremental_vacuum(conn: &Connection, pages: u64) -> Result<()> {
+    let mut stmt = conn
+        .prepare(&format!("PRAGMA incremental_vacuum({})", pages))
+        .context("Failed to prepare incremental vacuum")?;
+    // Each step frees one page
+    let mut rows = stmt.query([]).context("Failed to reclaim free pages")?;
+    while rows
+        .next()
+        .context("Failed to reclaim free pages")?
+        .is_some()
+    {}
+    Ok(())
+}
+
+/// Entries in history when the query planner's statistics were last
+/// gathered, if ever
+pub fn analyzed_entries(conn: &Connection) -> Result<Option<u64>> {
+    let analyzed = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE name = 'sqlite_stat1'")?
+        .exists([])?;
+    if !analyzed {
+        return Ok(None);
+    }
+    let stat: Option<String> = conn
+        .query_row(
+            "SELECT stat FROM sqlite_stat1 WHERE tbl = 'history' LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+        .context("Failed to read query statistics")?;
+    // The first number is the table's row count
+    Ok(stat.and_then(|stat| stat.split(' ').next()?.parse().ok()))
+}
+
+/// Problems with the database's tables and indexes, including the search
+/// index disagreeing with history; none if healthy
+pub fn check_indexes(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn
+        .prepare("PRAGMA integrity_check")
+        .context("Failed to prepare integrity check")?;
+    let mut problems = stmt
+        .query_map([], |row| row.get::<_, String>(0))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .context("Failed to check database integrity")?;
+    problems.retain(|problem| problem != "ok");
+    if let Err(e) = conn.execute(
+        "INSERT INTO history_fts (history_fts, rank) VALUES ('integrity-check', 1)",
+        [],
+    ) {
+        problems.push(format!("search index: {}", e));
+    }
+    Ok(problems)
+}
+
+/// Gather statistics for the query planner
+pub fn analyze(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ANALYZE")
+        .context("Failed to analyze database")
+}
+
+/// Turn search words into an FTS5 query matching entries containing all of
+/// them, each as a word prefix, without interpreting FTS5 syntax
+pub fn fts_query(text: &str) -> Option<String> {
+    let terms: Vec<_> = text
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// The directory a search is limited to, and the prefix of those below it
+pub fn cwd_filter(cwd: &str) -> (String, String) {
+    let dir = cwd.trim_end_matches('/');
+    let prefix = format!("{}/", dir);
+    let dir = if dir.is_empty() { cwd } else { dir };
+    (dir.to_string(), prefix)
+}
+
+/// Build the SELECT answering `query` over the history table of `schema`,
+/// returning entries in its order, with the `rid` column of their position
+/// then the `METADATA_COLUMNS`
+fn search_query(query: &SearchQuery, schema: &str) -> Result<(String, Vec<Value>)> {
+    let mut conditions = Vec::new();
+    let mut query_params = Vec::new();
+
+    match query.mode {
+        MatchMode::Words => {
+            let Some(fts_query) = fts_query(&query.text) else {
+                bail!("Empty search");
+            };
+            query_params.push(Value::Text(fts_query));
+            conditions.push(format!(
+                "rowid IN (SELECT rowid FROM {}.history_fts WHERE history_fts MATCH ?{})",
+                schema,
+                query_params.len()
+            ));
+        }
+        _ if query.text.is_empty() => {}
+        MatchMode::Substring => {
+            query_params.push(Value::Text(query.text.clone()));
+            conditions.push(format!("instr(cmd, ?{}) > 0", query_params.len()));
+        }
+        MatchMode::Regex | MatchMode::Glob => {
+            // The search index narrows down the commands to match
+            if let Some(fts_query) = fts_query(&required_words(&query.text, query.mode).join(" ")) {
+                query_params.push(Value::Text(fts_query));
+                conditions.push(format!(
+                    "rowid IN (SELECT rowid FROM {}.history_fts WHERE history_fts MATCH ?{})",
+                    schema,
+                    query_params.len()
+                ));
+            }
+            let regex = match query.mode {
+                MatchMode::Glob => glob_regex(&query.text),
+                _ => query.text.clone(),
+            };
+            query_params.push(Value::Text(regex));
+            conditions.push(format!("cmd REGEXP ?{}", query_params.len()));
+        }
+    }
+    if let Some(since) = query.since {
+        query_params.push(Value::Integer(since));
+        conditions.push(format!("\"when\" >= ?{}", query_params.len()));
+    }
+    if let Some(until) = query.until {
+        query_params.push(Value::Integer(until));
+        conditions.push(format!("\"when\" < ?{}", query_params.len()));
+    }
+    if !query.hosts.is_empty() {
+        let mut placeholders = Vec::new();
+        for host in &query.hosts {
+            query_params.push(Value::Text(host.clone()));
+            placeholders.push(format!("?{}", query_params.len()));
+        }
+        conditions.push(format!(
+            "COALESCE(host, '') IN ({})",
+            placeholders.join(", ")
+        ));
+    }
+    if let Some(cwd) = &query.cwd {
+        let (dir, prefix) = cwd_filter(cwd);
+        query_params.push(Value::Text(dir));
+        query_params.push(Value::Text(prefix));
+        conditions.push(format!(
+            "(cwd = ?{} OR substr(cwd, 1, length(?{1})) = ?{1})",
+            query_params.len() - 1,
+            query_params.len()
+        ));
+    }
+    if schema != "main" {
+        // Archives keep entries forgotten or deleted after they were archived
+        conditions.push(
+            "plenty_cmd_hash(cmd) NOT IN (SELECT cmd_hash FROM main.tombstones)
+             AND hash NOT IN (SELECT hash FROM main.entry_tombstones)"
+                .to_string(),
+        );
+    }
+    // Pages go back in time, unless the oldest matches come first
+    let newest_first = query.order != SearchOrder::Oldest;
+    if let Some(after) = query.after {
+        query_params.push(Value::Integer(after.when));
+        query_params.push(Value::Integer(after.id));
+        conditions.push(format!(
+            "(\"when\", rowid) {} (?{}, ?{})",
+            if newest_first { "<" } else { ">" },
+            query_params.len() - 1,
+            query_params.len()
+        ));
+    }
+    query_params.push(Value::Integer(
+        query
+            .limit
+            .map_or(-1, |limit| limit.min(i64::MAX as u64) as i64),
+    ));
+
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let (picked, listed) = match query.order {
+        SearchOrder::Recent => ("DESC", "ASC"),
+        SearchOrder::Newest => ("DESC", "DESC"),
+        SearchOrder::Oldest => ("ASC", "ASC"),
+    };
+    let sql = format!(
+        "SELECT cmd, \"when\", extra, host, pinned, rid, {} FROM (
+           SELECT rowid AS rid, cmd, \"when\", extra, host, {} AS pinned, {}
+           FROM {}.history {}
+           ORDER BY \"when\" {}, rowid {} LIMIT ?{}
+         ) ORDER BY \"when\" {}, rid {}",
+        METADATA_COLUMNS,
+        PINNED,
+        METADATA_COLUMNS,
+        schema,
+        filter,
+        picked,
+        picked,
+        query_params.len(),
+        listed,
+        listed
+    );
+    Ok((sql, query_params))
+}
+
+/// Call `on_entry` for each entry matching `query`, in its order, returning
+/// where the next page starts if there may be one
+pub fn search(
+    conn: &Connection,
+    query: &SearchQuery,
+    on_entry: impl FnMut(HistoryEntry) -> Result<()>,
+) -> Result<Option<SearchCursor>> {
+    search_in(conn, "main", query, on_entry)
+}
+
+/// `search` over the history table of the attached database `schema`
+pub fn search_in(
+    conn: &Connection,
+    schema: &str,
+    query: &SearchQuery,
+    mut on_entry: impl FnMut(HistoryEntry) -> Result<()>,
+) -> Result<Option<SearchCursor>> {
+    let (sql, query_params) = search_query(query, schema)?;
+    let mut stmt = conn
+        .prepare_cached(&sql)
+        .context("Failed to prepare search statement")?;
+    let mut rows = stmt
+        .query(params_from_iter(query_params))
+        .context("Failed to search history")?;
+    let (mut found, mut first, mut last) = (0, None, None);
+    while let Some(row) = rows.next().context("Failed to read search result")? {
+        let entry = HistoryEntry::new(row.get(0)?, row.get(1)?, row.get(2)?)
+            .with_host(row.get::<_, Option<String>>(3)?.unwrap_or_default())
+            .with_pinned(row.get(4)?);
+        let position = SearchCursor {
+            when: entry.when,
+            id: row.get(5)?,
+        };
+        first.get_or_insert(position);
+        last = Some(position);
+        found += 1;
+        on_entry(with_metadata(entry, row, 6)?)?;
+    }
+    if query.limit.is_none_or(|limit| found < limit) {
+        return Ok(None);
+    }
+    // The most recent matches are listed oldest first, the page's end first
+    Ok(match query.order {
+        SearchOrder::Recent => first,
+        SearchOrder::Newest | SearchOrder::Oldest => last,
+    })
+}
+
+/// How much more uses in the directory of a `SuggestQuery` count
+pub const DIRECTORY_WEIGHT: u32 = 4;
+
+/// How much more uses on the host of a `SuggestQuery` count
+pub const HOST_WEIGHT: u32 = 2;
+
+/// SQL scoring `uses` entries, the latest at `last_used`, at `now`: as in
+/// zoxide, uses count more the more recent the latest is
+pub fn frecency(uses: &str, last_used: &str, now: &str) -> String {
+    format!(
+        "{uses} * CASE
+           WHEN {now} - {last_used} < 3600 THEN 4.0
+           WHEN {now} - {last_used} < 86400 THEN 2.0
+           WHEN {now} - {last_used} < 604800 THEN 0.5
+           ELSE 0.25
+         END",
+        uses = uses,
+        last_used = last_used,
+        now = now
+    )
+}
+
+/// The commands `query` asks for, best ranked at `now` first
+pub fn suggest(conn: &Connection, query: &SuggestQuery, now: i64) -> Result<Vec<Suggestion>> {
+    let sql = format!(
+        "SELECT s.cmd, s.uses, s.last_used FROM command_stats AS s
+         LEFT JOIN command_dirs AS d ON d.cmd = s.cmd AND d.cwd = ?2
+         LEFT JOIN command_hosts AS h ON h.cmd = s.cmd AND h.host = ?5
+         WHERE substr(s.cmd, 1, length(?1)) = ?1
+         ORDER BY {} + {} * COALESCE({}, 0) + {} * COALESCE({}, 0) DESC, s.last_used DESC, s.cmd
+         LIMIT ?4",
+        frecency("s.uses", "s.last_used", "?3"),
+        DIRECTORY_WEIGHT,
+        frecency("d.uses", "d.last_used", "?3"),
+        HOST_WEIGHT,
+        frecency("h.uses", "h.last_used", "?3")
+    );
+    let mut stmt = conn
+        .prepare_cached(&sql)
+        .context("Failed to prepare suggestions query")?;
+    let suggestions = stmt
+        .query_map(
+            params![
+                query.prefix,
+                query.cwd,
+                now,
+                query.limit.min(i64::MAX as u64) as i64,
+                query.host
+            ],
+            |row| {
+                Ok(Suggestion {
+                    cmd: row.get(0)?,
+                    uses: row.get::<_, i64>(1)? as u64,
+                    last_used: row.get(2)?,
+                })
+            },
+        )
+        .and_then(|rows| rows.collect())
+        .context("Failed to read suggestions")?;
+    Ok(suggestions)
+}
+
+/// Current Unix time, in seconds
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Entries read per statement by `for_each_page`
+pub const PAGE_SIZE: usize = 1000;
+
+/// Call `on_page` with successive pages of up to `PAGE_SIZE` entries selected
+/// by `request`, oldest first. Each page is read by its own short statement,
+/// resuming after the sort key of the previous one, so nothing is held open
+/// on the database while `on_page` runs (e.g. while a slow client drains it).
+pub fn for_each_page(
+    conn: &Connection,
+    request: &HistoryRequest,
+    mut on_page: impl FnMut(Vec<HistoryEntry>) -> Result<()>,
+) -> Result<()> {
+    let (sql, query_params) = history_query(request);
+    let first = query_params.len() + 1;
+    let first_page = format!("SELECT * FROM ({}) LIMIT ?{}", sql, first);
+    let next_page = format!(
+        "SELECT * FROM ({}) WHERE (\"when\", tie, rid) > (?{}, ?{}, ?{})
+         ORDER BY \"when\" ASC, tie ASC, rid ASC LIMIT ?{}",
+        sql,
+        first,
+        first + 1,
+        first + 2,
+        first + 3
+    );
+
+    let mut after: Option<(Value, Value, Value)> = None;
+    loop {
+        let mut page_params = query_params.clone();
+        let sql = match after.take() {
+            None => &first_page,
+            Some((when, tie, rid)) => {
+                page_params.extend([when, tie, rid]);
+                &next_page
+            }
+        };
+        page_params.push(Value::Integer(PAGE_SIZE as i64));
+
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        {
+            let mut stmt = conn
+                .prepare_cached(sql)
+                .context("Failed to prepare select statement")?;
+            let mut rows = stmt
+                .query(params_from_iter(page_params))
+                .context("Failed to query history")?;
+            while let Some(row) = rows.next().context("Failed to read history entry")? {
+                let entry = HistoryEntry::new(row.get(0)?, row.get(1)?, row.get(2)?)
+                    .with_host(row.get::<_, Option<String>>(3)?.unwrap_or_default())
+                    .with_pinned(row.get(4)?);
+                let entry = with_metadata(entry, row, 7)?;
+                after = Some((Value::Integer(entry.when), row.get(5)?, row.get(6)?));
+                page.push(entry);
+            }
+        }
+
+        let last = page.len() < PAGE_SIZE;
+        if !page.is_empty() {
+            on_page(page)?;
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Call `on_entry` for each entry selected by `request`, oldest first
+pub fn for_each_entry(
+    conn: &Connection,
+    request: &HistoryRequest,
+    mut on_entry: impl FnMut(HistoryEntry) -> Result<()>,
+) -> Result<()> {
+    for_each_page(conn, request, |page| {
+        page.into_iter().try_for_each(&mut on_entry)
+    })
+}
+
+/// Sequence number of the last entry stored, which entries stored later exceed
+pub fn high_water_mark(conn: &Connection) -> Result<u64> {
+    let seq: i64 = conn
+        .query_row("SELECT value FROM sequence", [], |row| row.get(0))
+        .context("Failed to read sequence")?;
+    Ok(seq as u64)
+}
+
+/// Number of entries stored
+pub fn count_entries(conn: &Connection) -> Result<u64> {
+    let entries: i64 = conn
+        .query_row("SELECT COUNT(*) FROM history", [], |row| row.get(0))
+        .context("Failed to count history entries")?;
+    Ok(entries as u64)
+}
+
+/// One sync session, as recorded in the `sync_log` table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionRecord {
+    /// Who connected: the ssh client address or the listening socket's peer
+    pub peer: String,
+    /// Unix times the session started and ended
+    pub started: i64,
+    pub ended: i64,
+    /// Entries the client pushed, including those already stored
+    pub received: u64,
+    /// Entries sent back to the client
+    pub sent: u64,
+    /// Entries refused because they match the server's reject patterns, or
+    /// its hooks denied them
+    pub rejected: u64,
+    /// The last error reported to or by the client, if any
+    pub error: Option<String>,
+}
+
+/// Record a finished sync session
+pub fn log_session(conn: &Connection, session: &SessionRecord) -> Result<()> {
+    conn.execute(
+        "INSERT INTO sync_log (peer, started, ended, received, sent, error, rejected)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            session.peer,
+            session.started,
+            session.ended,
+            session.received as i64,
+            session.sent as i64,
+            session.error,
+            session.rejected as i64,
+        ],
+    )
+    .context("Failed to record sync session")?;
+    Ok(())
+}
+
+/// Entries received by sync sessions that ended at or after `since`
+pub fn received_since(conn: &Connection, since: i64) -> Result<u64> {
+    let received: i64 = conn
+        .query_row(
+            "SELECT COALESCE(SUM(received), 0) FROM sync_log WHERE ended >= ?1",
+            [since],
+            |row| row.get(0),
+        )
+        .context("Failed to sum recently received entries")?;
+    Ok(received as u64)
+}
+
+/// The `limit` most recent sync sessions, oldest first
+pub fn recent_sessions(conn: &Connection, limit: u64) -> Result<Vec<SessionRecord>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT peer, started, ended, received, sent, error, rejected FROM (
+               SELECT * FROM sync_log ORDER BY id DESC LIMIT ?1
+             ) ORDER BY id",
+        )
+        .context("Failed to prepare sync log query")?;
+    let sessions = stmt
+        .query_map([limit.min(i64::MAX as u64) as i64], |row| {
+            Ok(SessionRecord {
+                peer: row.get(0)?,
+                started: row.get(1)?,
+                ended: row.get(2)?,
+                received: row.get::<_, i64>(3)? as u64,
+                sent: row.get::<_, i64>(4)? as u64,
+                error: row.get(5)?,
+                rejected: row.get::<_, i64>(6)? as u64,
+            })
+        })
+        .context("Failed to query sync log")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to read sync log")?;
+    Ok(sessions)
+}
+
+/// A client machine that synced with this server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// Name the client introduced itself with, its hostname by default
+    pub name: String,
+    /// Unix times of its first and latest syncs
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// High-water mark of the last history it read, if any
+    pub last_mark: Option<u64>,
+    /// Where it last connected from
+    pub peer: String,
+}
+
+/// Register that `name` is syncing from `peer`
+pub fn device_seen(conn: &Connection, name: &str, peer: &str, now: i64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO devices (name, first_seen, last_seen, peer) VALUES (?1, ?3, ?3, ?2)
+         ON CONFLICT (name) DO UPDATE SET last_seen = ?3, peer = ?2",
+        params![name, peer, now],
+    )
+    .context("Failed to register device")?;
+    Ok(())
+}
+
+/// Record that `name` read history up to `mark`
+pub fn device_read(conn: &Connection, name: &str, mark: u64) -> Result<()> {
+    conn.execute(
+        "UPDATE devices SET last_mark = ?2 WHERE name = ?1",
+        params![name, mark as i64],
+    )
+    .context("Failed to record device high-water mark")?;
+    Ok(())
+}
+
+/// Every registered device, least recently seen first
+pub fn devices(conn: &Connection) -> Result<Vec<Device>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT name, first_seen, last_seen, last_mark, peer FROM devices
+             ORDER BY last_seen, name",
+        )
+        .context("Failed to prepare device query")?;
+    let devices = stmt
+        .query_map([], |row| {
+            Ok(Device {
+                name: row.get(0)?,
+                first_seen: row.get(1)?,
+                last_seen: row.get(2)?,
+                last_mark: row.get::<_, Option<i64>>(3)?.map(|mark| mark as u64),
+                peer: row.get(4)?,
+            })
+        })
+        .context("Failed to query devices")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to read devices")?;
+    Ok(devices)
+}
+
+/// A device asking for an API token: its code is shown to the admin
+/// approving it, and its secret only known to the device, which collects the
+/// token with it
+#[derive(Clone, PartialEq, Eq)]
+pub struct Enrollment {
+    pub code: String,
+    pub secret: String,
+    /// Name the device asked to upload as
+    pub device: String,
+    /// Where it asked from
+    pub peer: String,
+    pub requested_at: i64,
+    /// Set once approved, until collected
+    pub token: Option<String>,
+}
+
+impl std::fmt::Debug for Enrollment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Enrollment({:?}, {:?} from {:?} at {}, {})",
+            self.code,
+            self.device,
+            self.peer,
+            self.requested_at,
+            if self.token.is_some() {
+                "approved"
+            } else {
+                "pending"
+            }
+        )
+    }
+}
+
+/// Record a device's request for a token
+pub fn request_enrollment(conn: &Connection, enrollment: &Enrollment) -> Result<()> {
+    conn.execute(
+        "INSERT INTO enrollments (code, secret, device, peer, requested_at, token)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            enrollment.code,
+            enrollment.secret,
+            enrollment.device,
+            enrollment.peer,
+            enrollment.requested_at,
+            enrollment.token
+        ],
+    )
+    .context("Failed to record enrollment")?;
+    Ok(())
+}
+
+/// Every enrollment not collected yet, oldest first
+pub fn enrollments(conn: &Connection) -> Result<Vec<Enrollment>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT code, secret, device, peer, requested_at, token FROM enrollments
+             ORDER BY requested_at, code",
+        )
+        .context("Failed to prepare enrollment query")?;
+    let enrollments = stmt
+        .query_map([], |row| {
+            Ok(Enrollment {
+                code: row.get(0)?,
+                secret: row.get(1)?,
+                device: row.get(2)?,
+                peer: row.get(3)?,
+                requested_at: row.get(4)?,
+                token: row.get(5)?,
+            })
+        })
+        .context("Failed to query enrollments")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to read enrollments")?;
+    Ok(enrollments)
+}
+
+/// Give the pending enrollment `code` its token, returning whether there
+/// was one
+pub fn approve_enrollment(conn: &Connection, code: &str, token: &str) -> Result<bool> {
+    let approved = conn
+        .execute(
+            "UPDATE enrollments SET token = ?2 WHERE code = ?1 AND token IS NULL",
+            params![code, token],
+        )
+        .context("Failed to approve enrollment")?;
+    Ok(approved > 0)
+}
+
+/// Forget the enrollment `code`, returning whether there was one
+pub fn remove_enrollment(conn: &Connection, code: &str) -> Result<bool> {
+    let removed = conn
+        .execute("DELETE FROM enrollments WHERE code = ?1", [code])
+        .context("Failed to remove enrollment")?;
+    Ok(removed > 0)
+}
+
+/// Forget the enrollments requested before `before`, returning how many
+pub fn expire_enrollments(conn: &Connection, before: i64) -> Result<usize> {
+    conn.execute("DELETE FROM enrollments WHERE requested_at < ?1", [before])
+        .context("Failed to expire enrollments")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(conn: &Connection, request: &HistoryRequest) -> Vec<(String, String)> {
+        let (sql, query_params) = history_query(request);
+        let mut stmt = conn.prepare(&sql).unwrap();
+        stmt.query_map(params_from_iter(query_params), |row| {
+            Ok((
+                row.get(0)?,
+                row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+            ))
+        })
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+    }
+
+    #[test]
+    fn migrations_upgrade_unversioned_databases() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE history (\"when\" INTEGER, cmd TEXT, extra TEXT);
+             INSERT INTO history VALUES (1, 'ls', NULL), (1, 'ls', NULL), (1, 'ls', ''),
+               (2, NULL, '');",
+        )
+        .unwrap();
+        init_schema(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(dedupe(&conn).unwrap(), 0);
+        init_schema(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(query(&conn, &HistoryRequest::default()).len(), 1);
+
+        conn.execute(
+            "INSERT INTO schema_version VALUES (?1, 0)",
+            [MIGRATIONS.len() as i64 + 1],
+        )
+        .unwrap();
+        assert!(init_schema(&mut conn).is_err());
+    }
+
+    #[test]
+    fn history_query_filters_by_host_and_limit() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let pending = vec![
+            HistoryEntry::new("old".into(), 1, String::new()),
+            HistoryEntry::new("make".into(), 2, String::new()).with_host("ci".into()),
+            HistoryEntry::new("ls".into(), 3, String::new()).with_host("laptop".into()),
+            HistoryEntry::new("vim".into(), 4, String::new()).with_host("desktop".into()),
+        ];
+        insert_entries(&mut conn, &pending).unwrap();
+
+        let all = query(&conn, &HistoryRequest::default());
+        assert_eq!(all.len(), 4);
+
+        let request = HistoryRequest {
+            exclude_hosts: vec!["ci".into()],
+            ..Default::default()
+        };
+        let cmds: Vec<_> = query(&conn, &request).into_iter().map(|e| e.0).collect();
+        assert_eq!(cmds, vec!["old", "ls", "vim"]);
+
+        let request = HistoryRequest {
+            only_hosts: vec!["laptop".into(), "ci".into()],
+            limit: Some(2),
+            ..Default::default()
+        };
+        let cmds: Vec<_> = query(&conn, &request).into_iter().map(|e| e.0).collect();
+        assert_eq!(cmds, vec!["make", "ls"]);
+    }
+
+    #[test]
+    fn history_query_filters_by_namespace() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let mut shared = HistoryEntry::new("kubectl get pods".into(), 1, String::new());
+        shared.namespace = "team-infra".into();
+        let mut other = HistoryEntry::new("terraform plan".into(), 2, String::new());
+        other.namespace = "team-cloud".into();
+        let personal = HistoryEntry::new("ls".into(), 3, String::new());
+        insert_entries(&mut conn, &[shared, other, personal]).unwrap();
+
+        let request = HistoryRequest {
+            namespaces: vec!["team-infra".into()],
+            ..Default::default()
+        };
+        let cmds: Vec<_> = query(&conn, &request).into_iter().map(|e| e.0).collect();
+        assert_eq!(cmds, vec!["kubectl get pods"]);
+
+        // The default namespace is named ""
+        let request = HistoryRequest {
+            namespaces: vec![String::new(), "team-cloud".into()],
+            ..Default::default()
+        };
+        let cmds: Vec<_> = query(&conn, &request).into_iter().map(|e| e.0).collect();
+        assert_eq!(cmds, vec!["terraform plan", "ls"]);
+
+        let ls = entry_hash("ls", 3, "");
+        assert_eq!(set_namespace(&mut conn, &[ls], "team-infra").unwrap(), 1);
+        let namespaces = entry_namespaces(&conn).unwrap();
+        assert_eq!(namespaces.len(), 3);
+        assert_eq!(namespaces[&ls], "team-infra");
+        set_namespace(&mut conn, &[ls], "").unwrap();
+        assert!(!entry_namespaces(&conn).unwrap().contains_key(&ls));
+    }
+
+    #[test]
+    fn imports_merge_into_stored_runs() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let fish = HistoryEntry::new("make".into(), 5, "- Makefile".into());
+        insert_entries(&mut conn, &[fish]).unwrap();
+
+        let mut atuin =
+            HistoryEntry::new("make".into(), 5, String::new()).with_host("laptop".into());
+        atuin.cwd = "/src".into();
+        atuin.exit_code = Some(2);
+        let mut elsewhere = atuin.clone().with_host("desktop".into());
+        elsewhere.exit_code = Some(0);
+        let later = HistoryEntry::new("make".into(), 6, String::new());
+        let others =
+            merge_into_stored(&mut conn, vec![atuin, elsewhere.clone(), later.clone()]).unwrap();
+        // The desktop's differs from the now laptop's run
+        assert_eq!(others, [elsewhere, later]);
+
+        let mut entries = Vec::new();
+        for_each_page(&conn, &HistoryRequest::default(), |page| {
+            entries.extend(page);
+            Ok(())
+        })
+        .unwrap();
+        let merged = &entries[0];
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            (
+                merged.extra.as_str(),
+                merged.host.as_str(),
+                merged.cwd.as_str(),
+                merged.exit_code
+            ),
+            ("- Makefile", "laptop", "/src", Some(2))
+        );
+        assert_eq!(buckets(&conn).unwrap(), crate::buckets(&entries));
+    }
+
+    #[test]
+    fn repeats_within_the_collapse_window_are_skipped() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.into(), when, String::new());
+        insert_entries(&mut conn, &[entry("ls", 10)]).unwrap();
+        let collapse = Some(Collapse {
+            window: 2,
+            ignore_sudo: false,
+        });
+        let batch = [entry("ls", 8), entry("ls", 13), entry("make", 11)];
+        assert_eq!(
+            insert_entries_collapsing(&mut conn, &batch, collapse).unwrap(),
+            2
+        );
+        // Entries of the same batch collapse into each other too
+        let batch = [entry("pwd", 1), entry("pwd", 2)];
+        assert_eq!(
+            insert_entries_collapsing(&mut conn, &batch, collapse).unwrap(),
+            1
+        );
+        assert_eq!(insert_entries(&mut conn, &[entry("ls", 9)]).unwrap(), 1);
+        assert_eq!(count_entries(&conn).unwrap(), 5);
+
+        // With or without sudo, if asked to
+        let batch = [entry("sudo make", 12), entry("sudo pwd", 20)];
+        assert_eq!(
+            insert_entries_collapsing(&mut conn, &batch, collapse).unwrap(),
+            2
+        );
+        let collapse = collapse.map(|collapse| Collapse {
+            ignore_sudo: true,
+            ..collapse
+        });
+        let batch = [entry("sudo ls", 14), entry("pwd", 21), entry("make", 30)];
+        assert_eq!(
+            insert_entries_collapsing(&mut conn, &batch, collapse).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn entry_metadata_is_stored_and_returned() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let mut built = HistoryEntry::new("make".into(), 1, String::new());
+        built.cwd = "/src".into();
+        built.exit_code = Some(2);
+        built.duration_ms = Some(30_000);
+        built.session = "4242".into();
+        built.origin_device = "laptop".into();
+        built.received_at = Some(3);
+        built.namespace = "team-infra".into();
+        built.signature = vec![7; 96];
+        let plain = HistoryEntry::new("ls".into(), 2, String::new());
+        insert_entries(&mut conn, &[built.clone(), plain.clone()]).unwrap();
+
+        let mut entries = Vec::new();
+        for_each_entry(&conn, &HistoryRequest::default(), |entry| {
+            entries.push(entry);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(entries, [built.clone(), plain]);
+
+        let nulls: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM history
+                 WHERE cwd IS NULL AND session IS NULL AND origin_device IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(nulls, 1);
+
+        let query = SearchQuery {
+            text: "make".into(),
+            ..Default::default()
+        };
+        let mut found = Vec::new();
+        search(&conn, &query, |entry| {
+            found.push(entry);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(found, [built]);
+    }
+
+    #[test]
+    fn forgotten_commands_stay_deleted() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let secret = || HistoryEntry::new("export TOKEN=hunter2".into(), 1, String::new());
+        let pending = vec![secret(), HistoryEntry::new("ls".into(), 2, String::new())];
+        insert_entries(&mut conn, &pending).unwrap();
+
+        let deleted = forget_command(&mut conn, cmd_hash("export TOKEN=hunter2")).unwrap();
+        assert_eq!(deleted, 1);
+
+        let pending = vec![secret()];
+        insert_entries(&mut conn, &pending).unwrap();
+        let cmds: Vec<_> = query(&conn, &HistoryRequest::default())
+            .into_iter()
+            .map(|e| e.0)
+            .collect();
+        assert_eq!(cmds, vec!["ls"]);
+    }
+
+    #[test]
+    fn deleted_entries_stay_deleted_until_their_tombstones_expire() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entries = [
+            HistoryEntry::new("ls".into(), 1, String::new()),
+            HistoryEntry::new("ls".into(), 2, String::new()),
+        ];
+        insert_entries(&mut conn, &entries).unwrap();
+        let hash = entry_hash("ls", 1, "");
+        let day = 86400;
+        assert_eq!(delete_entries(&mut conn, &[hash], 10 * day).unwrap(), 1);
+        assert_eq!(entry_tombstones_since(&conn, 10 * day).unwrap(), [hash]);
+        assert!(entry_tombstones_since(&conn, 10 * day + 1)
+            .unwrap()
+            .is_empty());
+
+        insert_entries(&mut conn, &entries).unwrap();
+        assert_eq!(count_entries(&conn).unwrap(), 1);
+
+        let retention = Retention {
+            tombstone_days: Some(5),
+            ..Default::default()
+        };
+        let pruned = apply_retention(&mut conn, &retention, 14 * day).unwrap();
+        assert_eq!(pruned.tombstones, 0);
+        let pruned = apply_retention(&mut conn, &retention, 16 * day).unwrap();
+        assert_eq!(pruned.tombstones, 1);
+        insert_entries(&mut conn, &entries).unwrap();
+        assert_eq!(count_entries(&conn).unwrap(), 2);
+    }
+
+    #[test]
+    fn deletions_are_undone_from_the_trash() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        let entries = [
+            entry("make", 1),
+            entry("ls", 2),
+            entry("cargo", 3),
+            entry("git", 4),
+        ];
+        insert_entries(&mut conn, &entries).unwrap();
+        set_pinned(&conn, "cargo", true).unwrap();
+        // Without a trash, deletions are final
+        delete_entries(&mut conn, &[entry_hash("make", 1, "")], 100).unwrap();
+        assert_eq!(last_trashed(&conn).unwrap(), None);
+
+        set_trash_days(&conn, Some(7), 100).unwrap();
+        delete_entries(&mut conn, &[entry_hash("ls", 2, "")], 200).unwrap();
+        let retention = Retention {
+            max_age_days: Some(1),
+            ..Default::default()
+        };
+        apply_retention(&mut conn, &retention, 86400 + 300).unwrap();
+        forget_command(&mut conn, cmd_hash("cargo")).unwrap();
+        assert_eq!(count_entries(&conn).unwrap(), 0);
+
+        // The last deletion first, then by command, then the rest
+        let last = last_trashed(&conn).unwrap().unwrap();
+        assert_eq!(undelete(&mut conn, last, None).unwrap(), 1);
+        assert_eq!(set_pinned(&conn, "cargo", true).unwrap(), 1);
+        assert_eq!(undelete(&mut conn, 0, Some("ls")).unwrap(), 1);
+        assert_eq!(
+            entry_tombstones_since(&conn, 0).unwrap(),
+            [entry_hash("make", 1, "")]
+        );
+        assert_eq!(undelete(&mut conn, 0, None).unwrap(), 1);
+        assert_eq!(last_trashed(&conn).unwrap(), None);
+        let cmds: Vec<_> = query(&conn, &HistoryRequest::default())
+            .into_iter()
+            .map(|e| e.0)
+            .collect();
+        assert_eq!(cmds, ["ls", "cargo", "git"]);
+        assert_eq!(high_water_mark(&conn).unwrap(), 7);
+
+        // Entries leave the trash once their grace period is over
+        forget_command(&mut conn, cmd_hash("git")).unwrap();
+        assert!(last_trashed(&conn).unwrap().is_some());
+        set_trash_days(&conn, Some(7), unix_now() + 8 * 86400).unwrap();
+        assert_eq!(last_trashed(&conn).unwrap(), None);
+        set_trash_days(&conn, None, unix_now()).unwrap();
+        forget_command(&mut conn, cmd_hash("ls")).unwrap();
+        assert_eq!(last_trashed(&conn).unwrap(), None);
+    }
+
+    #[test]
+    fn suggestions_follow_the_history() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let now = 30 * 86400;
+        let entry = |cmd: &str, ago, cwd: &str| {
+            let mut entry = HistoryEntry::new(cmd.to_string(), now - ago, String::new());
+            entry.cwd = cwd.to_string();
+            entry
+        };
+        let entries = [
+            entry("git status", 20 * 86400, "~/a"),
+            entry("git status", 10 * 86400, "~/a"),
+            entry("git status", 9 * 86400, ""),
+            entry("git push", 7200, "~/b"),
+            entry("git log", 2 * 86400, "~/b"),
+            entry("ls", 10, "~/a"),
+        ];
+        insert_entries(&mut conn, &entries).unwrap();
+        let suggest_on =
+            |conn: &Connection, prefix: &str, cwd: Option<&str>, host: Option<&str>| {
+                let query = SuggestQuery {
+                    prefix: prefix.to_string(),
+                    cwd: cwd.map(str::to_string),
+                    host: host.map(str::to_string),
+                    limit: 10,
+                };
+                suggest(conn, &query, now).unwrap()
+            };
+        let suggest = |conn: &Connection, prefix: &str, cwd| suggest_on(conn, prefix, cwd, None);
+
+        // Three old uses score less than a recent one, unless in their directory
+        let found = suggest(&conn, "git ", None);
+        let cmds: Vec<_> = found.iter().map(|s| s.cmd.as_str()).collect();
+        assert_eq!(cmds, ["git push", "git status", "git log"]);
+        assert_eq!((found[1].uses, found[1].last_used), (3, now - 9 * 86400));
+        let cmds: Vec<_> = suggest(&conn, "git", Some("~/a"))
+            .into_iter()
+            .map(|s| s.cmd)
+            .collect();
+        assert_eq!(cmds, ["git status", "git push", "git log"]);
+        assert_eq!(suggest(&conn, "", None).len(), 4);
+        assert!(suggest(&conn, "%", None).is_empty());
+        conn.execute(
+            "UPDATE history SET host = 'server' WHERE cmd = 'git log'",
+            [],
+        )
+        .unwrap();
+        let cmds: Vec<_> = suggest_on(&conn, "git", None, Some("server"))
+            .into_iter()
+            .map(|s| s.cmd)
+            .collect();
+        assert_eq!(cmds, ["git push", "git log", "git status"]);
+
+        // Stats follow deletions and forgotten commands
+        delete_entries(
+            &mut conn,
+            &[entry_hash("git status", now - 9 * 86400, "")],
+            now,
+        )
+        .unwrap();
+        let status = &suggest(&conn, "git status", None)[0];
+        assert_eq!((status.uses, status.last_used), (2, now - 10 * 86400));
+        forget_command(&mut conn, cmd_hash("git push")).unwrap();
+        let cmds: Vec<_> = suggest(&conn, "git", Some("~/b"))
+            .into_iter()
+            .map(|s| s.cmd)
+            .collect();
+        assert_eq!(cmds, ["git log", "git status"]);
+        let dirs: i64 = conn
+            .query_row("SELECT COUNT(*) FROM command_dirs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(dirs, 3);
+        let hosts: (String, i64) = conn
+            .query_row("SELECT cmd, uses FROM command_hosts", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(hosts, ("git log".to_string(), 1));
+    }
+
+    #[test]
+    fn buckets_follow_inserts_and_deletes() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        let mut entries = vec![
+            entry("ls", -5),
+            entry("ls", 5),
+            entry("make", 6),
+            entry("ls", 86400),
+        ];
+        insert_entries(&mut conn, &entries).unwrap();
+        assert_eq!(buckets(&conn).unwrap(), crate::buckets(&entries));
+
+        delete_entries(&mut conn, &[entry_hash("make", 6, "")], 0).unwrap();
+        forget_command(&mut conn, cmd_hash("ls")).unwrap();
+        insert_entries(&mut conn, &[entry("pwd", 7)]).unwrap();
+        assert_eq!(buckets(&conn).unwrap(), crate::buckets(&[entry("pwd", 7)]));
+
+        // Databases that predate buckets have them filled in
+        entries.push(entry("pwd", 7));
+        conn.execute_batch(
+            "DROP TRIGGER history_buckets_insert;
+             DROP TRIGGER history_buckets_delete;
+             DROP TRIGGER history_buckets_update;
+             DROP TABLE buckets;",
+        )
+        .unwrap();
+        conn.execute("DELETE FROM tombstones", []).unwrap();
+        conn.execute("DELETE FROM entry_tombstones", []).unwrap();
+        insert_entries(&mut conn, &entries).unwrap();
+        create_buckets(&conn).unwrap();
+        assert_eq!(buckets(&conn).unwrap(), crate::buckets(&entries));
+    }
+
+    #[test]
+    fn pinned_commands_survive_pruning() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entries: Vec<_> = ["gnarly", "a", "b", "c"]
+            .iter()
+            .zip(1..)
+            .map(|(cmd, when)| HistoryEntry::new(cmd.to_string(), when, String::new()))
+            .collect();
+        insert_entries(&mut conn, &entries).unwrap();
+        assert_eq!(set_pinned(&conn, "gnarly", true).unwrap(), 1);
+
+        let mut request = HistoryRequest {
+            since: Some(3),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let cmds: Vec<_> = query(&conn, &request).into_iter().map(|e| e.0).collect();
+        assert_eq!(cmds, vec!["b", "c"]);
+
+        request.keep_pinned = true;
+        let mut pinned = Vec::new();
+        for_each_entry(&conn, &request, |entry| {
+            pinned.push((entry.cmd, entry.pinned));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            pinned,
+            vec![("gnarly".to_string(), true), ("c".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn retention_prunes_and_keeps_pruned_entries_out() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let day = 86400;
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.into(), when, String::new());
+        let entries = vec![
+            entry("pinned", 0),
+            entry("ancient", day),
+            entry("git status", 8 * day),
+            entry("make", 9 * day),
+            entry("git status", 10 * day),
+        ];
+        insert_entries(&mut conn, &entries).unwrap();
+        set_pinned(&conn, "pinned", true).unwrap();
+        let cmds = |conn: &Connection| -> Vec<_> {
+            query(conn, &HistoryRequest::default())
+                .into_iter()
+                .map(|e| e.0)
+                .collect()
+        };
+
+        let retention = Retention {
+            max_age_days: Some(5),
+            dedup: true,
+            ..Default::default()
+        };
+        let pruned = apply_retention(&mut conn, &retention, 12 * day).unwrap();
+        assert_eq!(
+            pruned,
+            Pruned {
+                expired: 1,
+                duplicates: 1,
+                tombstones: 0
+            }
+        );
+        assert_eq!(cmds(&conn), vec!["pinned", "make", "git status"]);
+
+        // Another machine uploading the pruned entries doesn't bring them back
+        insert_entries(&mut conn, &entries).unwrap();
+        assert_eq!(cmds(&conn), vec!["pinned", "make", "git status"]);
+
+        let retention = Retention {
+            max_entries: Some(1),
+            ..Default::default()
+        };
+        let pruned = apply_retention(&mut conn, &retention, 12 * day).unwrap();
+        assert_eq!(pruned.expired, 1);
+        assert_eq!(cmds(&conn), vec!["pinned", "git status"]);
+    }
+
+    #[test]
+    fn recent_sessions_are_listed_oldest_first() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        for (peer, started) in [("laptop", 1), ("desktop", 2), ("ci", 3)] {
+            let session = SessionRecord {
+                peer: peer.into(),
+                started,
+                ended: started + 1,
+                received: 5,
+                rejected: started as u64,
+                ..Default::default()
+            };
+            log_session(&conn, &session).unwrap();
+        }
+        let sessions: Vec<_> = recent_sessions(&conn, 2)
+            .unwrap()
+            .into_iter()
+            .map(|session| (session.peer, session.rejected))
+            .collect();
+        assert_eq!(
+            sessions,
+            vec![("desktop".to_string(), 2), ("ci".to_string(), 3)]
+        );
+        assert_eq!(received_since(&conn, 3).unwrap(), 10);
+    }
+
+    #[test]
+    fn enrollments_wait_for_approval() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let enrollment = |code: &str, requested_at| Enrollment {
+            code: code.into(),
+            secret: "s3cret".into(),
+            device: "phone".into(),
+            peer: "10.0.0.2".into(),
+            requested_at,
+            token: None,
+        };
+        request_enrollment(&conn, &enrollment("NEWER", 200)).unwrap();
+        request_enrollment(&conn, &enrollment("OLDER", 100)).unwrap();
+        assert!(request_enrollment(&conn, &enrollment("OLDER", 300)).is_err());
+        assert_eq!(
+            enrollments(&conn).unwrap(),
+            [enrollment("OLDER", 100), enrollment("NEWER", 200)]
+        );
+        assert_eq!(
+            format!("{:?}", enrollment("OLDER", 100)),
+            "Enrollment(\"OLDER\", \"phone\" from \"10.0.0.2\" at 100, pending)"
+        );
+
+        assert!(approve_enrollment(&conn, "NEWER", "t0ken").unwrap());
+        assert!(!approve_enrollment(&conn, "NEWER", "other").unwrap());
+        assert!(!approve_enrollment(&conn, "MISSING", "t0ken").unwrap());
+        assert_eq!(
+            enrollments(&conn).unwrap()[1].token.as_deref(),
+            Some("t0ken")
+        );
+        assert_eq!(expire_enrollments(&conn, 150).unwrap(), 1);
+        assert!(remove_enrollment(&conn, "NEWER").unwrap());
+        assert!(!remove_enrollment(&conn, "NEWER").unwrap());
+        assert_eq!(enrollments(&conn).unwrap(), []);
+    }
+
+    #[test]
+    fn devices_keep_their_first_sighting() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        device_seen(&conn, "laptop", "10.0.0.2", 100).unwrap();
+        device_seen(&conn, "desktop", "10.0.0.3", 150).unwrap();
+        device_seen(&conn, "laptop", "10.0.0.4", 200).unwrap();
+        device_read(&conn, "laptop", 42).unwrap();
+        let devices = devices(&conn).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "desktop");
+        assert_eq!(devices[0].last_mark, None);
+        assert_eq!(
+            devices[1],
+            Device {
+                name: "laptop".into(),
+                first_seen: 100,
+                last_seen: 200,
+                last_mark: Some(42),
+                peer: "10.0.0.4".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn search_matches_word_prefixes() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entries = vec![
+            HistoryEntry::new("git push origin".into(), 1, String::new()),
+            HistoryEntry::new("git pull".into(), 2, String::new()),
+            HistoryEntry::new("cargo build".into(), 3, "  paths:\n    - ~/src/git".into()),
+            HistoryEntry::new("echo \"quoted\" -x".into(), 4, String::new()),
+        ];
+        insert_entries(&mut conn, &entries).unwrap();
+        let search = |conn: &Connection, text: &str, limit| {
+            let mut cmds = Vec::new();
+            let query = SearchQuery {
+                text: text.to_string(),
+                limit,
+                ..Default::default()
+            };
+            search(conn, &query, |entry| {
+                cmds.push(entry.cmd);
+                Ok(())
+            })
+            .unwrap();
+            cmds
+        };
+        assert_eq!(
+            search(&conn, "git pu", None),
+            vec!["git push origin", "git pull"]
+        );
+        assert_eq!(
+            search(&conn, "git", Some(2)),
+            vec!["git pull", "cargo build"]
+        );
+        assert_eq!(
+            search(&conn, "\"quoted\" -x", None),
+            vec!["echo \"quoted\" -x"]
+        );
+
+        forget_command(&mut conn, cmd_hash("git pull")).unwrap();
+        vacuum(&conn).unwrap();
+        assert_eq!(search(&conn, "pull", None), Vec::<String>::new());
+        assert_eq!(search(&conn, "push", None), vec!["git push origin"]);
+    }
+
+    #[test]
+    fn searches_filter_order_and_page() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entry = |cmd: &str, when, host: &str, cwd: &str| {
+            let mut entry =
+                HistoryEntry::new(cmd.into(), when, String::new()).with_host(host.into());
+            entry.cwd = cwd.into();
+            entry
+        };
+        let entries = vec![
+            entry("make", 1, "laptop", "/src"),
+            entry("make test", 2, "ci", "/src/plenty"),
+            entry("ls", 3, "laptop", "/srcs"),
+            entry("make", 3, "laptop", "/"),
+            entry("make install", 4, "", ""),
+        ];
+        insert_entries(&mut conn, &entries).unwrap();
+        let search = |query: &SearchQuery| {
+            let mut cmds = Vec::new();
+            let next = search(&conn, query, |entry| {
+                cmds.push(format!("{} {}", entry.when, entry.cmd));
+                Ok(())
+            })
+            .unwrap();
+            (cmds, next)
+        };
+
+        let query = SearchQuery {
+            text: "ake".into(),
+            mode: MatchMode::Substring,
+            since: Some(2),
+            until: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(search(&query).0, ["2 make test", "3 make"]);
+        let query = SearchQuery {
+            mode: MatchMode::Substring,
+            cwd: Some("/src/".into()),
+            ..Default::default()
+        };
+        assert_eq!(search(&query).0, ["1 make", "2 make test"]);
+        let query = SearchQuery {
+            mode: MatchMode::Substring,
+            hosts: vec!["ci".into(), String::new()],
+            ..Default::default()
+        };
+        assert_eq!(search(&query).0, ["2 make test", "4 make install"]);
+
+        // Pages go back in time, each listed in the query's order
+        let mut query = SearchQuery {
+            text: "make".into(),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let (page, next) = search(&query);
+        assert_eq!(page, ["3 make", "4 make install"]);
+        query.after = next;
+        let (page, next) = search(&query);
+        assert_eq!(page, ["1 make", "2 make test"]);
+        query.after = next;
+        assert_eq!(search(&query), (vec![], None));
+
+        query.order = SearchOrder::Newest;
+        query.after = None;
+        let (page, next) = search(&query);
+        assert_eq!(page, ["4 make install", "3 make"]);
+        query.after = next;
+        assert_eq!(search(&query).0, ["2 make test", "1 make"]);
+
+        query.order = SearchOrder::Oldest;
+        query.limit = Some(3);
+        query.after = None;
+        let (page, next) = search(&query);
+        assert_eq!(page, ["1 make", "2 make test", "3 make"]);
+        query.after = next;
+        assert_eq!(search(&query), (vec!["4 make install".into()], None));
+    }
+
+    #[test]
+    fn pages_resume_after_ties() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entries: Vec<_> = (0..PAGE_SIZE * 2 + 10)
+            .map(|i| {
+                HistoryEntry::new(format!("cmd {}", i % 7), (i / 300) as i64, i.to_string())
+                    .with_host(["a", "b", ""][i % 3].to_string())
+            })
+            .collect();
+        insert_entries(&mut conn, &entries).unwrap();
+
+        for tie_break in [TieBreak::Insertion, TieBreak::Host, TieBreak::Command] {
+            for limit in [None, Some(PAGE_SIZE as u64 + 1)] {
+                let request = HistoryRequest {
+                    tie_break,
+                    limit,
+                    ..Default::default()
+                };
+                let mut paged = Vec::new();
+                for_each_entry(&conn, &request, |entry| {
+                    paged.push((entry.cmd, entry.host));
+                    Ok(())
+                })
+                .unwrap();
+                assert_eq!(paged, query(&conn, &request));
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_reads_only_return_entries_stored_since() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.into(), when, String::new());
+        insert_entries(&mut conn, &[entry("a", 10), entry("b", 20)]).unwrap();
+        let mark = high_water_mark(&conn).unwrap();
+        assert_eq!(mark, 2);
+
+        // Duplicates don't move the mark, and deleting the newest entry
+        // doesn't let later ones reuse its number
+        insert_entries(&mut conn, &[entry("a", 10)]).unwrap();
+        assert_eq!(high_water_mark(&conn).unwrap(), mark);
+        forget_command(&mut conn, cmd_hash("b")).unwrap();
+        insert_entries(&mut conn, &[entry("old", 1), entry("c", 30)]).unwrap();
+
+        let request = HistoryRequest {
+            after_seq: Some(mark),
+            ..Default::default()
+        };
+        let cmds: Vec<_> = query(&conn, &request).into_iter().map(|e| e.0).collect();
+        assert_eq!(cmds, vec!["old", "c"]);
+        assert_eq!(high_water_mark(&conn).unwrap(), 4);
+    }
+
+    #[test]
+    fn ties_are_broken_by_policy() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entries = vec![
+            HistoryEntry::new("zz".into(), 1, String::new()).with_host("a".into()),
+            HistoryEntry::new("aa".into(), 1, String::new()).with_host("b".into()),
+            HistoryEntry::new("mm".into(), 1, String::new()),
+        ];
+        insert_entries(&mut conn, &entries).unwrap();
+
+        let order = |tie_break| -> Vec<String> {
+            let request = HistoryRequest {
+                tie_break,
+                ..Default::default()
+            };
+            query(&conn, &request).into_iter().map(|e| e.0).collect()
+        };
+        assert_eq!(order(TieBreak::Insertion), vec!["zz", "aa", "mm"]);
+        assert_eq!(order(TieBreak::Host), vec!["mm", "zz", "aa"]);
+        assert_eq!(order(TieBreak::Command), vec!["aa", "mm", "zz"]);
+    }
+
+    #[test]
+    fn damaged_search_index_is_reported() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_schema(&mut conn).unwrap();
+        let entry = HistoryEntry::new("ls".to_string(), 1, String::new());
+        insert_entries(&mut conn, &[entry]).unwrap();
+        assert!(check_indexes(&conn).unwrap().is_empty());
+
+        conn.execute(
+            "INSERT INTO history_fts (history_fts) VALUES ('delete-all')",
+            [],
+        )
+        .unwrap();
+        let problems = check_indexes(&conn).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("search index"), "{}", problems[0]);
+    }
+}
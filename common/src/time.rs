@@ -0,0 +1,239 @@
+/// Points in time and durations as command lines give them: Unix timestamps,
+/// dates and times of day in local time, and durations ago
+use anyhow::{bail, Context, Result};
+
+const WEEKDAYS: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// Parse a point in time, `offset` seconds being how far local time is ahead
+/// of UTC:
+/// - a Unix timestamp, such as `1700000000`
+/// - `now`, or a duration before `now` such as `90d`, `2 weeks ago` or `an hour ago`
+/// - `today`, `yesterday`, a weekday for the last one before today (`friday`
+///   or `last friday`) or a date such as `2024-05-01`, at midnight or at a
+///   time of day such as `18:00`, or the time of day alone for today
+pub fn parse_time(value: &str, now: i64, offset: i64) -> Result<i64> {
+    let text = value.trim().to_ascii_lowercase();
+    if let Ok(timestamp) = text.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    if text == "now" {
+        return Ok(now);
+    }
+    let duration = text.strip_suffix("ago").unwrap_or(&text);
+    if let Ok(seconds) = parse_duration(duration) {
+        return Ok(now.saturating_sub(seconds));
+    }
+    match day_and_time(&text, now.saturating_add(offset)) {
+        Some(local) => Ok(local.saturating_sub(offset)),
+        None => bail!(
+            "Invalid time {:?}: expected a Unix timestamp, a date and time such as \
+             2024-05-01 18:00 or yesterday, or a duration ago such as 2 weeks ago or 90d",
+            value
+        ),
+    }
+}
+
+/// `parse_time` from the current time, in the local time zone
+#[cfg(feature = "sqlite")]
+pub fn parse(value: &str) -> Result<i64> {
+    parse_time(value, crate::store::unix_now(), utc_offset()?)
+}
+
+/// Parse a duration such as `90d`, `12h`, `30m`, `2w`, `3600s`, `2 weeks` or
+/// `an hour` into seconds; months are 30 days and years 365
+pub fn parse_duration(value: &str) -> Result<i64> {
+    let text = value.trim().to_ascii_lowercase();
+    let (amount, unit) = match text.split_once(' ') {
+        Some(("a" | "an", unit)) => (1, unit),
+        _ => {
+            let split = text
+                .find(|c: char| !c.is_ascii_digit())
+                .filter(|&i| i > 0)
+                .with_context(|| format!("Invalid duration {:?}", value))?;
+            let (amount, unit) = text.split_at(split);
+            let amount: i64 = amount
+                .parse()
+                .with_context(|| format!("Invalid duration {:?}", value))?;
+            (amount, unit)
+        }
+    };
+    let seconds = match unit.trim() {
+        "s" | "sec" | "secs" | "second" | "seconds" => 1,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 3600,
+        "d" | "day" | "days" => 86400,
+        "w" | "week" | "weeks" => 7 * 86400,
+        "mo" | "month" | "months" => 30 * 86400,
+        "y" | "year" | "years" => 365 * 86400,
+        unit => bail!("Invalid duration {:?}: unknown unit {:?}", value, unit),
+    };
+    Ok(amount.saturating_mul(seconds))
+}
+
+/// Seconds local time is ahead of UTC, as SQLite knows it
+#[cfg(feature = "sqlite")]
+pub fn utc_offset() -> Result<i64> {
+    let conn = rusqlite::Connection::open_in_memory().context("Failed to open SQLite")?;
+    conn.query_row(
+        "SELECT CAST(strftime('%s', 'now', 'localtime') AS INTEGER)
+              - CAST(strftime('%s', 'now') AS INTEGER)",
+        [],
+        |row| row.get(0),
+    )
+    .context("Failed to read the local time zone")
+}
+
+/// The local time a day, a time of day or both name, `local_now` being the
+/// current local time in seconds since the epoch
+fn day_and_time(text: &str, local_now: i64) -> Option<i64> {
+    let mut words: Vec<&str> = text.split_whitespace().collect();
+    // As in 2024-05-01T18:00
+    if let [word] = words[..] {
+        if let Some((day, time)) = word
+            .split_once('t')
+            .filter(|(day, _)| date_days(day).is_some())
+        {
+            words = vec![day, time];
+        }
+    }
+    let time = words.last().and_then(|word| time_of_day(word));
+    if time.is_some() {
+        words.pop();
+    }
+    let today = local_now.div_euclid(86400);
+    let day = match words[..] {
+        [] if time.is_some() => today,
+        ["today"] => today,
+        ["yesterday"] => today - 1,
+        [weekday] | ["last", weekday] if weekday_number(weekday).is_some() => {
+            let weekday = weekday_number(weekday)?;
+            // 1970-01-01 was a Thursday
+            let current = (today + 3).rem_euclid(7);
+            today - (current - weekday - 1).rem_euclid(7) - 1
+        }
+        [date] => date_days(date)?,
+        _ => return None,
+    };
+    Some(day * 86400 + time.unwrap_or(0))
+}
+
+/// Days since Monday of the week day `text` names, in full or abbreviated
+fn weekday_number(text: &str) -> Option<i64> {
+    if text.len() < 3 {
+        return None;
+    }
+    WEEKDAYS
+        .iter()
+        .position(|day| day.starts_with(text))
+        .map(|day| day as i64)
+}
+
+/// Seconds since midnight at a time of day such as `18:00` or `18:00:30`
+fn time_of_day(text: &str) -> Option<i64> {
+    let parts: Vec<i64> = text
+        .split(':')
+        .map(|part| {
+            part.bytes()
+                .all(|b| b.is_ascii_digit())
+                .then(|| part.parse().ok())
+                .flatten()
+        })
+        .collect::<Option<_>>()?;
+    match parts[..] {
+        [hours, minutes] if hours < 24 && minutes < 60 => Some(hours * 3600 + minutes * 60),
+        [hours, minutes, seconds] if hours < 24 && minutes < 60 && seconds < 60 => {
+            Some(hours * 3600 + minutes * 60 + seconds)
+        }
+        _ => None,
+    }
+}
+
+/// Days since the epoch of a date such as `2024-05-01`
+fn date_days(text: &str) -> Option<i64> {
+    let parts: Vec<i64> = text
+        .split('-')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    let [year, month, day] = parts[..] else {
+        return None;
+    };
+    // Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    // Rejects days past the end of their month
+    (date(days * 86400) == text).then_some(days)
+}
+
+/// The UTC date of the day containing `time`, as YYYY-MM-DD
+pub fn date(time: i64) -> String {
+    // Howard Hinnant's civil_from_days
+    let days = time.div_euclid(86400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_are_parsed() {
+        // Wednesday 2026-10-14 12:00 UTC, 14:00 two hours east
+        let now = 1_791_979_200;
+        let east = 7200;
+        let at = |text: &str| parse_time(text, now, east).unwrap();
+        assert_eq!(at("1700000000"), 1_700_000_000);
+        assert_eq!(at("now"), now);
+        assert_eq!(at("90d"), now - 90 * 86400);
+        assert_eq!(at("2 weeks ago"), now - 14 * 86400);
+        assert_eq!(at("an hour ago"), now - 3600);
+        assert_eq!(at("3y"), now - 3 * 365 * 86400);
+        // Local midnight is 22:00 UTC the day before
+        assert_eq!(at("today"), 1_791_928_800);
+        assert_eq!(at("Yesterday 18:00"), 1_791_928_800 - 86400 + 18 * 3600);
+        assert_eq!(at("13:30:15"), 1_791_928_800 + 13 * 3600 + 30 * 60 + 15);
+        assert_eq!(at("monday"), 1_791_928_800 - 2 * 86400);
+        assert_eq!(at("last wed"), 1_791_928_800 - 7 * 86400);
+        assert_eq!(at("2026-10-16"), 1_792_108_800 - east);
+        assert_eq!(at("2026-10-16T08:00"), 1_792_108_800 - east + 8 * 3600);
+
+        for invalid in ["d", "3x", "tomorrow", "2026-02-30", "25:00", "we", ""] {
+            assert!(parse_time(invalid, now, east).is_err(), "{}", invalid);
+        }
+        assert_eq!(parse_duration("3600s").unwrap(), 3600);
+        assert_eq!(parse_duration("1 month").unwrap(), 30 * 86400);
+    }
+
+    #[test]
+    fn dates_are_utc_days() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(86399), "1970-01-01");
+        assert_eq!(date(-1), "1969-12-31");
+        assert_eq!(date(951_782_400), "2000-02-29");
+        assert_eq!(date(1_792_108_800), "2026-10-16");
+    }
+}
@@ -0,0 +1,106 @@
+/// Golden vectors of the wire format PROTOCOL.md specifies: HistoryEntry
+/// frames and the entries they hold, for other implementations of the
+/// protocol to check theirs against
+use crate::json::{self, Value};
+use crate::HistoryEntry;
+use anyhow::{bail, Context, Result};
+
+/// The vectors, one JSON object per line: `vector` names it and `frame` is a
+/// whole message in hex, followed by the entry it holds, with the fields of
+/// the jsonl export, or by `error` for frames receivers reject. An entry is
+/// encoded into its frame byte for byte, unless `canonical` is false: such
+/// frames, as older peers or future versions send them, only decode to it
+pub const VECTORS: &str = include_str!("../vectors.jsonl");
+
+/// A frame, and what receiving it gives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vector {
+    pub name: String,
+    pub frame: Vec<u8>,
+    pub expected: Expected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    /// The entry the frame decodes to, and is the encoding of
+    Canonical(HistoryEntry),
+    /// The entry the frame decodes to, though it is encoded otherwise
+    Decodes(HistoryEntry),
+    /// Why receivers reject the frame
+    Rejected(String),
+}
+
+/// The vectors of `VECTORS`
+pub fn vectors() -> Result<Vec<Vector>> {
+    VECTORS
+        .lines()
+        .enumerate()
+        .map(|(number, line)| {
+            parse(line).with_context(|| format!("Invalid vector on line {}", number + 1))
+        })
+        .collect()
+}
+
+fn parse(line: &str) -> Result<Vector> {
+    let (mut name, mut frame, mut canonical, mut error) = (None, None, true, None);
+    for (key, value) in json::parse_object(line)? {
+        match (key.as_str(), value) {
+            ("vector", Value::String(value)) => name = Some(value),
+            ("frame", Value::String(hex)) => frame = Some(json::unhex(&hex)?),
+            ("canonical", Value::Bool(value)) => canonical = value,
+            ("error", Value::String(value)) => error = Some(value),
+            ("vector" | "frame" | "canonical" | "error", value) => {
+                bail!("Invalid {}: {:?}", key, value)
+            }
+            _ => {}
+        }
+    }
+    let expected = match error {
+        Some(error) => Expected::Rejected(error),
+        None if canonical => Expected::Canonical(json::parse_entry(line)?),
+        None => Expected::Decodes(json::parse_entry(line)?),
+    };
+    Ok(Vector {
+        name: name.context("Vectors need a name")?,
+        frame: frame.context("Vectors need a frame")?,
+        expected,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, MessageType};
+
+    /// The entry in a frame, as receivers read it
+    fn receive(frame: &[u8]) -> Result<HistoryEntry> {
+        let mut reader = frame;
+        let msg = Message::read_from(&mut reader)?;
+        if msg.msg_type != MessageType::HistoryEntry || !reader.is_empty() {
+            bail!("Not one HistoryEntry message");
+        }
+        HistoryEntry::decode(&msg.data)
+    }
+
+    #[test]
+    fn vectors_hold() {
+        let vectors = vectors().unwrap();
+        assert!(vectors.len() >= 10);
+        for vector in vectors {
+            match vector.expected {
+                Expected::Canonical(entry) => {
+                    assert_eq!(receive(&vector.frame).unwrap(), entry, "{}", vector.name);
+                    let mut frame = Vec::new();
+                    entry.write_messages(&mut frame, true).unwrap();
+                    assert_eq!(frame, vector.frame, "{}", vector.name);
+                }
+                Expected::Decodes(entry) => {
+                    assert_eq!(receive(&vector.frame).unwrap(), entry, "{}", vector.name);
+                }
+                Expected::Rejected(_) => {
+                    assert!(receive(&vector.frame).is_err(), "{}", vector.name);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use crate::connection::{self, recv_from, send_to, Connection, RemoteCommand};
+use crate::paths;
+use anyhow::{bail, Context, Result};
+use plenty_common::{Hello, Message, MessageType, SuggestQuery, Suggestion};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// How long `plenty agentd` waits on a client that went quiet mid-request
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Socket of the agent holding a connection to `host`
+pub fn socket_path(host: &str) -> Result<PathBuf> {
+    Ok(paths::plenty_dir()?
+        .join("agent")
+        .join(format!("{}.sock", host.replace('/', "_"))))
+}
+
+/// Whether the agent relays requests of this type, and whether their answer
+/// spans several messages: entries until End or Error
+fn relayed(msg_type: MessageType) -> Option<bool> {
+    match msg_type {
+        MessageType::Suggest
+        | MessageType::GetStats
+        | MessageType::GetServerInfo
+        | MessageType::GetBuckets => Some(false),
+        MessageType::Query => Some(true),
+        _ => None,
+    }
+}
+
+/// `plenty agentd`: one ssh session with the server, kept open, and the
+/// requests of local clients relayed over it
+struct Agent {
+    host: String,
+    remote: RemoteCommand,
+    server: Mutex<Option<(Connection, Hello)>>,
+}
+
+impl Agent {
+    fn connect(&self) -> Result<(Connection, Hello)> {
+        let mut connection = Connection::open(&self.host, &self.remote)?;
+        let hello = connection.handshake()?;
+        eprintln!("Connected to plentys {} on {}", hello.version, self.host);
+        Ok((connection, hello))
+    }
+
+    /// The server's Hello, connecting first if needed
+    fn hello(&self) -> Result<Hello> {
+        let mut server = self.server.lock().unwrap_or_else(PoisonError::into_inner);
+        let (_, hello) = match server.as_mut() {
+            Some(server) => server,
+            None => server.insert(self.connect()?),
+        };
+        Ok(hello.clone())
+    }
+
+    /// The server's answer to `request`, reconnecting once if the session
+    /// broke since the last one
+    fn relay(&self, request: &Message, multi: bool) -> Result<Vec<Message>> {
+        let mut server = self.server.lock().unwrap_or_else(PoisonError::into_inner);
+        for retry in [true, false] {
+            let (connection, _) = match server.as_mut() {
+                Some(server) => server,
+                None => server.insert(self.connect()?),
+            };
+            match exchange(
+                &mut connection.reader,
+                &mut connection.writer,
+                request,
+                multi,
+            ) {
+                Ok(replies) => return Ok(replies),
+                Err(e) => {
+                    eprintln!("Lost connection to {}: {:#}", self.host, e);
+                    if let Some((connection, _)) = server.take() {
+                        connection.abort();
+                    }
+                    if !retry {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    /// Answer one client until it sends End or hangs up
+    fn serve(&self, stream: UnixStream) -> Result<()> {
+        stream
+            .set_read_timeout(Some(CLIENT_TIMEOUT))
+            .context("Failed to set client timeout")?;
+        let mut reader = BufReader::new(stream.try_clone().context("Failed to clone socket")?);
+        let mut writer = BufWriter::new(stream);
+        loop {
+            let request = match Message::read_from(&mut reader) {
+                Ok(request) => request,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e).context("Failed to read from client"),
+            };
+            let replies = match (request.msg_type, relayed(request.msg_type)) {
+                (MessageType::End, _) => return Ok(()),
+                (MessageType::Hello, _) => self
+                    .hello()
+                    .map(|hello| vec![Message::new(MessageType::Hello, hello.encode())]),
+                (_, Some(multi)) => self.relay(&request, multi),
+                (msg_type, None) => Err(anyhow::anyhow!(
+                    "plenty agentd doesn't relay {:?} messages",
+                    msg_type
+                )),
+            };
+            let replies = replies.unwrap_or_else(|e| {
+                vec![Message::new(
+                    MessageType::Error,
+                    format!("{:#}", e).into_bytes(),
+                )]
+            });
+            for reply in replies {
+                reply
+                    .write_unflushed(&mut writer)
+                    .context("Failed to write to client")?;
+            }
+            writer.flush().context("Failed to write to client")?;
+        }
+    }
+}
+
+/// Send `request`, and read the messages answering it
+fn exchange(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    request: &Message,
+    multi: bool,
+) -> Result<Vec<Message>> {
+    request
+        .write_to(writer)
+        .context("Failed to send request to server")?;
+    let mut replies = Vec::new();
+    loop {
+        let reply = Message::read_joined(reader).context("Failed to read reply from server")?;
+        let more = multi && reply.msg_type == MessageType::HistoryEntry;
+        replies.push(reply);
+        if !more {
+            return Ok(replies);
+        }
+    }
+}
+
+/// Run `plenty agentd` for `host` until killed, connecting to the server
+/// right away so that the first request doesn't wait for ssh
+pub fn run(host: &str, remote: &RemoteCommand) -> Result<()> {
+    let path = socket_path(host)?;
+    let dir = path.parent().context("Socket path has no parent")?;
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .context("Failed to create agent directory")?;
+    if UnixStream::connect(&path).is_ok() {
+        bail!("plenty agentd is already running for {}", host);
+    }
+    // Left behind by an agent that didn't exit cleanly
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .context("Failed to restrict agent socket")?;
+    let agent = Agent {
+        host: host.to_string(),
+        remote: remote.clone(),
+        server: Mutex::new(None),
+    };
+    if let Err(e) = agent.hello() {
+        eprintln!("Failed to connect to {}, will retry: {:#}", host, e);
+    }
+    eprintln!("Listening on {}", path.display());
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let agent = &agent;
+                    scope.spawn(move || {
+                        if let Err(e) = agent.serve(stream) {
+                            eprintln!("Client failed: {:#}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept client: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// A session with the agent for `host`, if one is running
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: BufWriter<UnixStream>,
+    pub hello: Hello,
+}
+
+impl Client {
+    pub fn open(host: &str) -> Result<Option<Self>> {
+        let Ok(stream) = UnixStream::connect(socket_path(host)?) else {
+            return Ok(None);
+        };
+        let mut reader = BufReader::new(stream.try_clone().context("Failed to clone socket")?);
+        let mut writer = BufWriter::new(stream);
+        send_to(&mut writer, MessageType::Hello, Hello::current().encode())?;
+        let msg = recv_from(&mut reader)?;
+        if msg.msg_type != MessageType::Hello {
+            bail!("Expected Hello from plenty agentd, got {:?}", msg.msg_type);
+        }
+        let hello = Hello::decode(&msg.data).context("Failed to decode server hello")?;
+        Ok(Some(Client {
+            reader,
+            writer,
+            hello,
+        }))
+    }
+
+    /// Ask the server, through the agent, for the commands best worth
+    /// suggesting for `query`
+    pub fn suggest(&mut self, query: &SuggestQuery) -> Result<Vec<Suggestion>> {
+        connection::suggest(&mut self.reader, &mut self.writer, query)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = send_to(&mut self.writer, MessageType::End, Vec::new());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::HistoryEntry;
+
+    #[test]
+    fn queries_are_answered_until_their_end() {
+        let mut server = Vec::new();
+        for cmd in ["ls", "make"] {
+            let entry = HistoryEntry::new(cmd.to_string(), 1, String::new());
+            Message::new(MessageType::HistoryEntry, entry.encode())
+                .write_to(&mut server)
+                .unwrap();
+        }
+        for msg_type in [MessageType::End, MessageType::Stats] {
+            Message::new(msg_type, Vec::new())
+                .write_to(&mut server)
+                .unwrap();
+        }
+        let query = Message::new(MessageType::Query, b"\0ls".to_vec());
+        let mut reader = &server[..];
+        let mut sent = Vec::new();
+        let replies = exchange(&mut reader, &mut sent, &query, true).unwrap();
+        let types: Vec<_> = replies.iter().map(|reply| reply.msg_type).collect();
+        assert_eq!(
+            types,
+            [
+                MessageType::HistoryEntry,
+                MessageType::HistoryEntry,
+                MessageType::End
+            ]
+        );
+        assert_eq!(sent[0], MessageType::Query as u8);
+
+        let stats = Message::new(MessageType::GetStats, Vec::new());
+        let replies = exchange(&mut reader, &mut sent, &stats, false).unwrap();
+        assert_eq!(replies[0].msg_type, MessageType::Stats);
+        // The server hanging up is an error, to reconnect on
+        assert!(exchange(&mut reader, &mut sent, &stats, false).is_err());
+        assert_eq!(relayed(MessageType::DeleteEntry), None);
+    }
+}
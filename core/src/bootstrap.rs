@@ -0,0 +1,112 @@
+use crate::confirm;
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// How a sync treats the local and server histories
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bootstrap {
+    /// Upload local entries and write back the server's: the normal sync
+    Merge,
+    /// Replace local history with the server's, uploading nothing
+    Server,
+    /// Upload local history without writing anything back
+    Local,
+}
+
+impl FromStr for Bootstrap {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "server" => Ok(Bootstrap::Server),
+            "local" => Ok(Bootstrap::Local),
+            _ => bail!("--bootstrap-from must be server or local, not {:?}", value),
+        }
+    }
+}
+
+/// Safeguards for the first sync with a host, where a mistyped host name
+/// would otherwise silently mix two unrelated histories
+#[derive(Debug, Clone, Default)]
+pub struct FirstSync {
+    /// Merge on first contact without asking
+    pub yes: bool,
+    /// Explicit choice of which side's history wins
+    pub bootstrap: Option<Bootstrap>,
+}
+
+impl FirstSync {
+    /// Whether the server's entry count is needed to decide how to sync
+    pub fn needs_summary(&self, first_contact: bool) -> bool {
+        first_contact || self.bootstrap.is_some()
+    }
+
+    /// Decide how to sync with `host`, showing both sides' sizes and asking
+    /// for confirmation before merging histories on first contact
+    pub fn resolve(
+        &self,
+        host: &str,
+        first_contact: bool,
+        local_entries: usize,
+        server_entries: u64,
+    ) -> Result<Bootstrap> {
+        if !self.needs_summary(first_contact) {
+            return Ok(Bootstrap::Merge);
+        }
+        eprintln!(
+            "{} with {}: {} local entries, {} entries on the server",
+            if first_contact { "First sync" } else { "Sync" },
+            host,
+            local_entries,
+            server_entries
+        );
+        if let Some(bootstrap) = self.bootstrap {
+            return Ok(bootstrap);
+        }
+        if self.yes || (local_entries == 0 && server_entries == 0) {
+            return Ok(Bootstrap::Merge);
+        }
+        match confirm("Merge both histories?")? {
+            Some(true) => Ok(Bootstrap::Merge),
+            Some(false) => bail!("Sync with {} cancelled", host),
+            None => bail!(
+                "First sync with {} needs confirmation: pass --yes to merge, or --bootstrap-from server|local",
+                host
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_contact_requires_a_decision() {
+        let first_sync = FirstSync::default();
+        assert_eq!(
+            first_sync.resolve("h", false, 10, 10).unwrap(),
+            Bootstrap::Merge
+        );
+        assert_eq!(
+            first_sync.resolve("h", true, 0, 0).unwrap(),
+            Bootstrap::Merge
+        );
+
+        let yes = FirstSync {
+            yes: true,
+            ..Default::default()
+        };
+        assert_eq!(yes.resolve("h", true, 10, 0).unwrap(), Bootstrap::Merge);
+
+        let from_server = FirstSync {
+            bootstrap: Some("server".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            from_server.resolve("h", false, 10, 0).unwrap(),
+            Bootstrap::Server
+        );
+        assert!("remote".parse::<Bootstrap>().is_err());
+    }
+}
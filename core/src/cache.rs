@@ -0,0 +1,127 @@
+use crate::paths;
+use anyhow::{bail, Context, Result};
+use plenty_common::{store, HistoryEntry, HistoryRequest, SuggestQuery, Suggestion};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const INSERT_BATCH_SIZE: usize = 1000;
+
+/// Local SQLite mirror of the merged history, using the server schema, so
+/// that searching doesn't need the server or a pass over fish_history
+pub struct Cache {
+    conn: Connection,
+    pending: Vec<HistoryEntry>,
+    pending_pins: Vec<(String, bool)>,
+}
+
+impl Cache {
+    pub fn path() -> Result<PathBuf> {
+        Ok(paths::plenty_dir()?.join("cache.db"))
+    }
+
+    /// Open the cache, creating it if needed
+    pub fn open() -> Result<Self> {
+        Self::open_in(&paths::plenty_dir()?)
+    }
+
+    /// Open the cache in the data directory `data_dir`, creating both if needed
+    pub fn open_in(data_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(data_dir).context("Failed to create plenty data directory")?;
+        Self::open_at(&data_dir.join("cache.db"))
+    }
+
+    /// Open the cache for reading, failing if no sync has created it yet
+    pub fn open_existing() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            bail!(
+                "No history cache at {}, run plenty sync first",
+                path.display()
+            );
+        }
+        Self::open_at(&path)
+    }
+
+    fn open_at(path: &Path) -> Result<Self> {
+        let mut conn = Connection::open(path)
+            .with_context(|| format!("Failed to open history cache {}", path.display()))?;
+        store::init_schema(&mut conn)?;
+        Ok(Cache {
+            conn,
+            pending: Vec::new(),
+            pending_pins: Vec::new(),
+        })
+    }
+
+    /// Queue an entry, writing queued entries out in batches
+    pub fn add(&mut self, entry: &HistoryEntry) -> Result<()> {
+        self.pending.push(entry.clone());
+        if self.pending.len() >= INSERT_BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Queue an entry received from the server, whose pinned flag is authoritative
+    pub fn add_received(&mut self, entry: &HistoryEntry) -> Result<()> {
+        self.pending_pins.push((entry.cmd.clone(), entry.pinned));
+        self.add(entry)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        store::insert_entries(&mut self.conn, &self.pending)
+            .context("Failed to update history cache")?;
+        self.pending.clear();
+        store::apply_pins(&mut self.conn, &self.pending_pins)
+            .context("Failed to update pins in history cache")?;
+        self.pending_pins.clear();
+        Ok(())
+    }
+
+    /// Pin or unpin a command
+    pub fn set_pinned(&mut self, cmd: &str, pinned: bool) -> Result<()> {
+        self.flush()?;
+        store::set_pinned(&self.conn, cmd, pinned)?;
+        Ok(())
+    }
+
+    /// Drop every entry for a forgotten command
+    pub fn forget(&mut self, hash: u64) -> Result<usize> {
+        self.flush()?;
+        store::forget_command(&mut self.conn, hash)
+    }
+
+    /// Drop the entries deleted on the server, by content hash
+    pub fn delete_entries(&mut self, hashes: &[u64]) -> Result<usize> {
+        self.flush()?;
+        store::delete_entries(&mut self.conn, hashes, store::unix_now())
+    }
+
+    /// Move the entries with these content hashes to `namespace`
+    pub fn set_namespace(&mut self, hashes: &[u64], namespace: &str) -> Result<usize> {
+        self.flush()?;
+        store::set_namespace(&mut self.conn, hashes, namespace)
+    }
+
+    /// The namespace of each cached entry outside the default one, by
+    /// content hash: shared ones and those received from shared hosts
+    pub fn entry_namespaces(&self) -> Result<HashMap<u64, String>> {
+        store::entry_namespaces(&self.conn)
+    }
+
+    /// Call `on_entry` for each cached entry selected by `request`, oldest first
+    pub fn for_each_entry(
+        &self,
+        request: &HistoryRequest,
+        on_entry: impl FnMut(HistoryEntry) -> Result<()>,
+    ) -> Result<()> {
+        store::for_each_entry(&self.conn, request, on_entry)
+    }
+
+    /// The cached commands best worth suggesting for `query`, as the server
+    /// ranks them
+    pub fn suggest(&self, query: &SuggestQuery) -> Result<Vec<Suggestion>> {
+        store::suggest(&self.conn, query, store::unix_now())
+    }
+}
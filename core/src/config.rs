@@ -0,0 +1,416 @@
+use crate::connection::{RemoteCommand, SshHost};
+use crate::hooks::Hooks;
+use crate::order::HistoryOrder;
+use crate::service::ServiceOptions;
+use crate::throttle;
+use anyhow::{bail, Context, Result};
+use plenty_common::config::{Document, Value};
+use plenty_common::{json, time, HistoryRequest, TieBreak};
+use std::path::PathBuf;
+
+/// Limits applied to the locally written fish_history; the server keeps everything
+#[derive(Debug, Clone, Default)]
+pub struct LocalPolicy {
+    /// Keep only the most recent N entries
+    pub max_entries: Option<usize>,
+    /// Keep only entries from the last M days
+    pub max_age_days: Option<u64>,
+    /// Keep only entries uploaded from these hosts (untagged entries are always kept)
+    pub only_hosts: Vec<String>,
+    /// Drop entries uploaded from these hosts
+    pub exclude_hosts: Vec<String>,
+}
+
+impl LocalPolicy {
+    /// The GetHistory request selecting what should be written locally at time `now`
+    pub fn request(&self, now: i64) -> HistoryRequest {
+        HistoryRequest {
+            since: self
+                .max_age_days
+                .map(|days| now.saturating_sub((days as i64).saturating_mul(86400))),
+            limit: self.max_entries.map(|max| max as u64),
+            only_hosts: self.only_hosts.clone(),
+            exclude_hosts: self.exclude_hosts.clone(),
+            // Pinned commands survive the local caps
+            keep_pinned: self.max_entries.is_some() || self.max_age_days.is_some(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Sync behaviour, configured in the `[sync]` section
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// Largest tolerated difference between client and server clocks, in seconds
+    pub max_clock_skew: i64,
+    /// Abort instead of warning when the clocks differ by more than `max_clock_skew`
+    pub abort_on_clock_skew: bool,
+    /// Name uploaded entries are tagged with, instead of the system hostname
+    pub hostname: Option<String>,
+    /// Commands starting with any of these are kept local and never uploaded
+    pub private_prefixes: Vec<String>,
+    /// Largest upload rate, in bytes per second
+    pub limit_rate: Option<u64>,
+    /// Number of hosts synced at once
+    pub jobs: usize,
+    /// Fail when any host fails, rather than only when all of them do
+    pub fail_on_any: bool,
+    /// Order of entries sharing the same `when`
+    pub tie_break: TieBreak,
+    /// Order of the fish_history syncs write
+    pub order: HistoryOrder,
+}
+
+impl SyncOptions {
+    /// The host name uploaded entries are tagged with
+    pub fn hostname(&self) -> Result<String> {
+        if let Some(hostname) = &self.hostname {
+            return Ok(hostname.clone());
+        }
+        let hostname = nix::unistd::gethostname().context("Failed to get hostname")?;
+        Ok(hostname.to_string_lossy().into_owned())
+    }
+
+    /// Whether `cmd` must stay on this machine
+    pub fn is_private(&self, cmd: &str) -> bool {
+        self.private_prefixes
+            .iter()
+            .any(|prefix| !prefix.is_empty() && cmd.starts_with(prefix.as_str()))
+    }
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            max_clock_skew: 300,
+            abort_on_clock_skew: false,
+            hostname: None,
+            // fish itself does not record commands starting with a space
+            private_prefixes: vec![" ".to_string()],
+            limit_rate: None,
+            jobs: 4,
+            fail_on_any: false,
+            tie_break: TieBreak::default(),
+            order: HistoryOrder::default(),
+        }
+    }
+}
+
+/// Signatures of uploaded entries, configured in the `[signing]` section
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SigningOptions {
+    /// Sign each uploaded entry with this device's key
+    pub sign: bool,
+    /// Public keys of the other devices whose signatures `plenty verify`
+    /// trusts
+    pub trusted_keys: Vec<[u8; 32]>,
+}
+
+/// A namespace entries are shared in, configured in a `[namespaces.<name>]`
+/// section
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Namespace {
+    pub name: String,
+    /// Commands starting with any of these belong to the namespace
+    pub prefixes: Vec<String>,
+    /// Hosts the namespace is shared with, which get its entries only
+    pub hosts: Vec<String>,
+}
+
+/// Client configuration, read from `$XDG_CONFIG_HOME/plenty/config.toml`
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Hosts synced when none are given on the command line
+    pub hosts: Vec<String>,
+    pub local: LocalPolicy,
+    pub hooks: Hooks,
+    pub sync: SyncOptions,
+    pub service: ServiceOptions,
+    pub signing: SigningOptions,
+    /// In the order of their names
+    pub namespaces: Vec<Namespace>,
+    /// How plentys is run for each host
+    pub remote: RemoteCommand,
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf> {
+        let config_dir = if let Ok(xdg_config_home) = std::env::var("XDG_CONFIG_HOME") {
+            PathBuf::from(xdg_config_home)
+        } else {
+            let home = std::env::var("HOME").context("HOME environment variable not set")?;
+            PathBuf::from(home).join(".config")
+        };
+        Ok(config_dir.join("plenty/config.toml"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        let doc = Document::load(&path)
+            .with_context(|| format!("Failed to load config from {}", path.display()))?;
+        Self::from_document(&doc).with_context(|| format!("Invalid config in {}", path.display()))
+    }
+
+    pub fn from_document(doc: &Document) -> Result<Self> {
+        let max_entries = doc
+            .get_int("local", "max_entries")?
+            .map(usize::try_from)
+            .transpose()
+            .context("local.max_entries must not be negative")?;
+        let max_age_days = doc
+            .get_int("local", "max_age_days")?
+            .map(u64::try_from)
+            .transpose()
+            .context("local.max_age_days must not be negative")?;
+
+        let mut sync = SyncOptions::default();
+        if let Some(skew) = doc.get_int("sync", "max_clock_skew")? {
+            sync.max_clock_skew = skew.abs();
+        }
+        sync.hostname = doc.get_str("sync", "hostname")?.map(str::to_string);
+        if let Some(prefixes) = doc.get_str_array("sync", "private_prefixes")? {
+            sync.private_prefixes = prefixes;
+        }
+        if let Some(jobs) = doc.get_int("sync", "jobs")? {
+            sync.jobs = usize::try_from(jobs)
+                .ok()
+                .filter(|jobs| *jobs > 0)
+                .context("sync.jobs must be positive")?;
+        }
+        match doc.get_str("sync", "fail_on")? {
+            None | Some("all") => {}
+            Some("any") => sync.fail_on_any = true,
+            Some(other) => bail!("sync.fail_on must be \"all\" or \"any\", not {:?}", other),
+        }
+        sync.tie_break = match doc.get_str("sync", "tie_break")? {
+            None | Some("insertion") => TieBreak::Insertion,
+            Some("host") => TieBreak::Host,
+            Some("command") => TieBreak::Command,
+            Some(other) => bail!(
+                "sync.tie_break must be \"insertion\", \"host\" or \"command\", not {:?}",
+                other
+            ),
+        };
+        if let Some(order) = doc.get_str("sync", "order")? {
+            sync.order = HistoryOrder::parse(order)?;
+        }
+        sync.limit_rate = match doc.get("sync", "limit_rate") {
+            None => None,
+            Some(Value::Integer(rate)) if *rate > 0 => Some(*rate as u64),
+            Some(Value::String(rate)) => Some(throttle::parse_rate(rate)?),
+            Some(_) => bail!("sync.limit_rate must be a positive number of bytes per second"),
+        };
+        match doc.get_str("sync", "clock_skew")? {
+            None | Some("warn") => {}
+            Some("abort") => sync.abort_on_clock_skew = true,
+            Some(other) => bail!(
+                "sync.clock_skew must be \"warn\" or \"abort\", not {:?}",
+                other
+            ),
+        }
+
+        let mut service = ServiceOptions::default();
+        if let Some(interval) = doc.get_str("service", "interval")? {
+            service.interval = time::parse_duration(interval)
+                .context("service.interval must be a duration like 15m")?;
+            if service.interval <= 0 {
+                bail!("service.interval must be positive");
+            }
+        }
+
+        let mut signing = SigningOptions {
+            sign: doc.get_bool("signing", "sign")?.unwrap_or(false),
+            ..Default::default()
+        };
+        for key in doc
+            .get_str_array("signing", "trusted_keys")?
+            .unwrap_or_default()
+        {
+            let parsed = json::unhex(&key)
+                .ok()
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .with_context(|| {
+                    format!(
+                        "signing.trusted_keys holds public keys in hex, as plenty verify \
+                         --public-key prints them, not {:?}",
+                        key
+                    )
+                })?;
+            signing.trusted_keys.push(parsed);
+        }
+
+        let mut remote = match doc.get_str("remote", "command")? {
+            None => RemoteCommand::default(),
+            Some(template) => RemoteCommand::parse(template).context("Invalid remote.command")?,
+        };
+        if let Some(persist) = doc.get_str("remote", "persist")? {
+            let persist = time::parse_duration(persist)
+                .context("remote.persist must be a duration like 10m")?;
+            if persist <= 0 {
+                bail!("remote.persist must be positive");
+            }
+            let control_path = doc.get_str("remote", "control_path")?.map(str::to_string);
+            remote = remote
+                .persist(persist, control_path)
+                .context("Invalid remote.persist")?;
+        } else if doc.get("remote", "control_path").is_some() {
+            bail!("remote.control_path needs remote.persist");
+        }
+        for section in doc.sections() {
+            let Some(host) = section.strip_prefix("ssh.") else {
+                continue;
+            };
+            let port = doc
+                .get_int(section, "port")?
+                .map(u16::try_from)
+                .transpose()
+                .with_context(|| format!("{}.port must be a port number", section))?;
+            let options = SshHost {
+                proxy_jump: doc.get_str(section, "proxy_jump")?.map(str::to_string),
+                port,
+                user: doc.get_str(section, "user")?.map(str::to_string),
+                identity: doc.get_str(section, "identity")?.map(str::to_string),
+            };
+            remote = remote
+                .ssh_host(host, options)
+                .with_context(|| format!("Invalid [{}]", section))?;
+        }
+
+        let mut namespaces = Vec::new();
+        for section in doc.sections() {
+            let Some(name) = section.strip_prefix("namespaces.") else {
+                continue;
+            };
+            if name.is_empty() {
+                bail!("Namespaces need a name, as in [namespaces.team-infra]");
+            }
+            namespaces.push(Namespace {
+                name: name.to_string(),
+                prefixes: doc.get_str_array(section, "prefixes")?.unwrap_or_default(),
+                hosts: doc.get_str_array(section, "hosts")?.unwrap_or_default(),
+            });
+        }
+
+        Ok(Config {
+            hosts: doc.get_str_array("", "hosts")?.unwrap_or_default(),
+            local: LocalPolicy {
+                max_entries,
+                max_age_days,
+                only_hosts: doc
+                    .get_str_array("local", "only_hosts")?
+                    .unwrap_or_default(),
+                exclude_hosts: doc
+                    .get_str_array("local", "exclude_hosts")?
+                    .unwrap_or_default(),
+            },
+            hooks: Hooks {
+                pre_sync: doc.get_str("hooks", "pre_sync")?.map(str::to_string),
+                post_sync: doc.get_str("hooks", "post_sync")?.map(str::to_string),
+            },
+            sync,
+            service,
+            signing,
+            namespaces,
+            remote,
+        })
+    }
+
+    /// The namespace `cmd` is uploaded in: the first whose prefixes it starts
+    /// with, or the default one, `""`
+    pub fn namespace_of(&self, cmd: &str) -> &str {
+        self.namespaces
+            .iter()
+            .find(|namespace| {
+                namespace
+                    .prefixes
+                    .iter()
+                    .any(|prefix| !prefix.is_empty() && cmd.starts_with(prefix.as_str()))
+            })
+            .map_or("", |namespace| namespace.name.as_str())
+    }
+
+    /// The namespaces shared with `host`, the only ones synced with it; none
+    /// for hosts of one's own, which get every entry
+    pub fn shared_with(&self, host: &str) -> Vec<String> {
+        self.namespaces
+            .iter()
+            .filter(|namespace| namespace.hosts.iter().any(|h| h == host))
+            .map(|namespace| namespace.name.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_sections_configure_the_command() {
+        let doc = Document::parse(
+            "[remote]\ncommand = \"ssh {host} plentys --user me\"\npersist = \"10m\"\n\
+             [ssh.\"history.example.com\"]\nport = 2222\n",
+        )
+        .unwrap();
+        let config = Config::from_document(&doc).unwrap();
+        let args = config.remote.args("history.example.com").unwrap();
+        assert_eq!(args[..2], ["ssh", "-o"]);
+        assert!(args.contains(&"ControlPersist=600".to_string()));
+        assert_eq!(
+            args[args.len() - 6..],
+            [
+                "-p",
+                "2222",
+                "history.example.com",
+                "plentys",
+                "--user",
+                "me"
+            ]
+        );
+
+        for config in [
+            "[remote]\ncommand = \"ssh {hots} plentys\"\n",
+            "[remote]\ncommand = \"docker exec -i {host} plentys\"\npersist = \"10m\"\n",
+            "[remote]\ncontrol_path = \"~/.ssh/cm-%C\"\n",
+            "[remote]\ncommand = \"docker exec -i {host} plentys\"\n[ssh.h]\nport = 22\n",
+            "[ssh.h]\nport = 65536\n",
+        ] {
+            let doc = Document::parse(config).unwrap();
+            assert!(Config::from_document(&doc).is_err());
+        }
+    }
+
+    #[test]
+    fn namespaces_pick_commands_and_hosts() {
+        let doc = Document::parse(
+            "hosts = [\"me@home\", \"oncall@bastion\"]\n\
+             [namespaces.team-infra]\n\
+             prefixes = [\"kubectl \", \"systemctl \"]\n\
+             hosts = [\"oncall@bastion\"]\n",
+        )
+        .unwrap();
+        let config = Config::from_document(&doc).unwrap();
+        assert_eq!(config.namespace_of("kubectl get pods"), "team-infra");
+        assert_eq!(config.namespace_of("kubectl"), "");
+        assert_eq!(config.shared_with("oncall@bastion"), ["team-infra"]);
+        assert!(config.shared_with("me@home").is_empty());
+
+        let doc = Document::parse("[namespaces.]\n").unwrap();
+        assert!(Config::from_document(&doc).is_err());
+    }
+
+    #[test]
+    fn trusted_keys_are_configured_in_hex() {
+        let key = "03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+        let doc = Document::parse(&format!(
+            "[signing]\nsign = true\ntrusted_keys = [\"{}\"]\n",
+            key
+        ))
+        .unwrap();
+        let signing = Config::from_document(&doc).unwrap().signing;
+        assert!(signing.sign);
+        assert_eq!(json::hex(&signing.trusted_keys[0]), key);
+
+        let doc = Document::parse("[signing]\ntrusted_keys = [\"03a1\"]\n").unwrap();
+        assert!(Config::from_document(&doc).is_err());
+    }
+}
@@ -0,0 +1,531 @@
+use crate::paths;
+use crate::throttle::Throttle;
+use anyhow::{bail, Context, Result};
+use plenty_common::{
+    decode_buckets, decode_suggestions, decode_u64, Bucket, FlushPolicy, Flushing, Hello,
+    HistoryEntry, Message, MessageType, NotStored, QuotaExceeded, SearchQuery, ServerInfo,
+    ServerStats, SuggestQuery, Suggestion, PROTOCOL_VERSION,
+};
+use std::collections::BTreeMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+/// What a connection sends on
+pub type Sender = Box<dyn Write + Send>;
+/// What a connection receives on
+pub type Receiver = Box<dyn Read + Send>;
+
+/// Bytes buffered before being written out, unless flushed earlier
+const WRITE_BUFFER: usize = 256 * 1024;
+
+/// How plentys is run for a host, configured as `command` in the `[remote]`
+/// section: words split on whitespace, in which `{host}` stands for the host
+/// and `{user}` for the local user name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteCommand {
+    words: Vec<String>,
+    /// Seconds ssh keeps its connection to a host open after a session, for
+    /// the next ones to reuse rather than connect again
+    persist: Option<i64>,
+    /// Socket of the shared connections, plenty's own unless configured
+    control_path: Option<String>,
+    /// How ssh reaches hosts, by host
+    ssh_hosts: BTreeMap<String, SshHost>,
+}
+
+/// How ssh reaches a host, configured in a `[ssh.<host>]` section rather
+/// than in `~/.ssh/config` on every machine
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SshHost {
+    /// Hosts to jump through, as ssh's ProxyJump takes them
+    pub proxy_jump: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// Private key file
+    pub identity: Option<String>,
+}
+
+impl Default for RemoteCommand {
+    fn default() -> Self {
+        RemoteCommand {
+            words: ["ssh", "{host}", "plentys"].map(str::to_string).to_vec(),
+            persist: None,
+            control_path: None,
+            ssh_hosts: BTreeMap::new(),
+        }
+    }
+}
+
+impl RemoteCommand {
+    pub fn parse(template: &str) -> Result<Self> {
+        let words: Vec<String> = template.split_whitespace().map(str::to_string).collect();
+        if words.is_empty() {
+            bail!("The remote command is empty");
+        }
+        for word in &words {
+            expand(word, "host", "user")?;
+        }
+        Ok(RemoteCommand {
+            words,
+            ..Default::default()
+        })
+    }
+
+    /// Share one ssh connection per host between sessions through a
+    /// ControlMaster socket, at `control_path` or in plenty's directory,
+    /// reusing the connection open there or opening one kept `persist`
+    /// seconds after the last session, so that frequent syncs skip ssh's
+    /// connection setup
+    pub fn persist(self, persist: i64, control_path: Option<String>) -> Result<Self> {
+        if self.words[0] != "ssh" {
+            bail!("Only ssh connections persist, not {}'s", self.words[0]);
+        }
+        Ok(RemoteCommand {
+            persist: Some(persist),
+            control_path,
+            ..self
+        })
+    }
+
+    /// Reach `host` over ssh as `options` say
+    pub fn ssh_host(mut self, host: &str, options: SshHost) -> Result<Self> {
+        if self.words[0] != "ssh" {
+            bail!("ssh options don't apply to {}", self.words[0]);
+        }
+        self.ssh_hosts.insert(host.to_string(), options);
+        Ok(self)
+    }
+
+    /// Whether plentys is reached over plain ssh, as without a template
+    pub fn is_default(&self) -> bool {
+        self.words == RemoteCommand::default().words
+    }
+
+    /// The program and arguments running plentys for `host`
+    pub fn args(&self, host: &str) -> Result<Vec<String>> {
+        let user = if self.words.iter().any(|word| word.contains("{user}")) {
+            std::env::var("USER").context("USER environment variable not set")?
+        } else {
+            String::new()
+        };
+        let mut args = self
+            .words
+            .iter()
+            .map(|word| expand(word, host, &user))
+            .collect::<Result<Vec<_>>>()?;
+        if args[0] == "ssh" {
+            args.splice(1..1, self.ssh_options(host)?);
+        }
+        Ok(args)
+    }
+
+    /// The options ssh is given for `host`, before its other arguments
+    pub fn ssh_options(&self, host: &str) -> Result<Vec<String>> {
+        let mut options = Vec::new();
+        if let Some(persist) = self.persist {
+            let control_path = match &self.control_path {
+                Some(path) => path.clone(),
+                None => control_dir()?.join("%C").to_string_lossy().into_owned(),
+            };
+            for option in [
+                "ControlMaster=auto".to_string(),
+                format!("ControlPath={}", control_path),
+                format!("ControlPersist={}", persist),
+            ] {
+                options.extend(["-o".to_string(), option]);
+            }
+        }
+        if let Some(ssh_host) = self.ssh_hosts.get(host) {
+            let flags = [
+                ("-J", ssh_host.proxy_jump.clone()),
+                ("-p", ssh_host.port.map(|port| port.to_string())),
+                ("-l", ssh_host.user.clone()),
+                ("-i", ssh_host.identity.clone()),
+            ];
+            for (flag, value) in flags {
+                if let Some(value) = value {
+                    options.extend([flag.to_string(), value]);
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl std::fmt::Display for RemoteCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.words.join(" "))
+    }
+}
+
+/// Directory of plenty's ssh control sockets, created private
+fn control_dir() -> Result<PathBuf> {
+    let dir = paths::plenty_dir()?.join("ssh");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// `word` with its placeholders replaced
+fn expand(word: &str, host: &str, user: &str) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = word;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unclosed {{ in remote command word {:?}", word);
+        };
+        match &rest[start + 1..start + end] {
+            "host" => expanded.push_str(host),
+            "user" => expanded.push_str(user),
+            other => bail!(
+                "Unknown placeholder {{{}}} in remote command; use {{host}} or {{user}}",
+                other
+            ),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// A protocol session with `plentys` on a remote host, over ssh, or over
+/// other streams
+pub struct Connection {
+    /// ssh, if the session goes through it
+    child: Option<Child>,
+    pub writer: Flushing<BufWriter<Throttle<Sender>>>,
+    pub reader: BufReader<Receiver>,
+    /// Whether the server takes large entries in chunks, once handshaken
+    pub chunked: bool,
+    /// Where entries sent are encoded, reused across them
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    /// A session with plentys on `host`, run as `remote` says
+    pub fn open(host: &str, remote: &RemoteCommand) -> Result<Self> {
+        let args = remote.args(host)?;
+        let (program, args) = args.split_first().context("The remote command is empty")?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("Failed to start {}", program))?;
+
+        let stdin = child.stdin.take().context("Failed to get remote stdin")?;
+        let stdout = child.stdout.take().context("Failed to get remote stdout")?;
+
+        let mut connection = Connection::over(stdout, stdin);
+        connection.child = Some(child);
+        Ok(connection)
+    }
+
+    /// A session with a server reading what is written to `writer` and
+    /// answering on `reader`, such as pipes to one running in-process
+    pub fn over(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> Self {
+        Connection {
+            child: None,
+            writer: Flushing::new(
+                BufWriter::with_capacity(WRITE_BUFFER, Throttle::new(Box::new(writer))),
+                FlushPolicy::Batch,
+            ),
+            reader: BufReader::new(Box::new(reader)),
+            chunked: false,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Limit what we send to `rate` bytes per second, or lift the limit with `None`
+    pub fn limit_rate(&mut self, rate: Option<u64>) {
+        self.writer.get_mut().get_mut().set_rate(rate);
+    }
+
+    /// Flush what is sent as `policy` says, besides after each request
+    pub fn set_flush(&mut self, policy: FlushPolicy) {
+        self.writer.set_policy(policy);
+    }
+
+    pub fn send(&mut self, msg_type: MessageType, data: Vec<u8>) -> Result<()> {
+        send_to(&mut self.writer, msg_type, data)
+    }
+
+    /// Send an entry, in chunks if it is large and the server takes them
+    pub fn send_entry(&mut self, entry: &HistoryEntry) -> Result<()> {
+        send_entry_to(&mut self.writer, entry, self.chunked, &mut self.buf)
+    }
+
+    /// Read the next message, turning server Error messages into errors
+    pub fn recv(&mut self) -> Result<Message> {
+        recv_from(&mut self.reader)
+    }
+
+    /// Exchange Hello messages, failing if the server speaks another protocol version
+    pub fn handshake(&mut self) -> Result<Hello> {
+        self.handshake_hello(Hello::current())
+    }
+
+    /// Handshake as `device`, which the server registers as syncing
+    pub fn handshake_as(&mut self, device: String) -> Result<Hello> {
+        self.handshake_hello(Hello::current().with_device(device))
+    }
+
+    fn handshake_hello(&mut self, mut hello: Hello) -> Result<Hello> {
+        hello.capabilities = vec!["entry-chunks".to_string(), "acks".to_string()];
+        self.send(MessageType::Hello, hello.encode())?;
+        let msg = self.recv()?;
+        if msg.msg_type != MessageType::Hello {
+            bail!("Expected Hello from server, got {:?}", msg.msg_type);
+        }
+        let server = Hello::decode(&msg.data).context("Failed to decode server hello")?;
+        if server.protocol != PROTOCOL_VERSION {
+            bail!(
+                "Server plentys {} speaks protocol {}, expected {}",
+                server.version,
+                server.protocol,
+                PROTOCOL_VERSION
+            );
+        }
+        self.chunked = server.supports("entry-chunks");
+        Ok(server)
+    }
+
+    /// Ask the server for its statistics
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        self.send(MessageType::GetStats, Vec::new())?;
+        let msg = self.recv()?;
+        if msg.msg_type != MessageType::Stats {
+            bail!("Unexpected message type from server: {:?}", msg.msg_type);
+        }
+        ServerStats::decode(&msg.data).context("Failed to decode server stats")
+    }
+
+    /// Ask the server for its version, schema, entry count and capabilities
+    pub fn server_info(&mut self) -> Result<ServerInfo> {
+        self.send(MessageType::GetServerInfo, Vec::new())?;
+        let msg = self.recv()?;
+        if msg.msg_type != MessageType::ServerInfo {
+            bail!("Unexpected message type from server: {:?}", msg.msg_type);
+        }
+        ServerInfo::decode(&msg.data).context("Failed to decode server info")
+    }
+
+    /// Ask the server for the summaries of its entries per bucket of time
+    pub fn buckets(&mut self) -> Result<Vec<Bucket>> {
+        self.send(MessageType::GetBuckets, Vec::new())?;
+        let msg = self.recv()?;
+        if msg.msg_type != MessageType::Buckets {
+            bail!("Unexpected message type from server: {:?}", msg.msg_type);
+        }
+        decode_buckets(&msg.data).context("Failed to decode buckets")
+    }
+
+    /// Ask the server for the entries matching `query`, passing them to
+    /// `on_entry` in its order
+    pub fn search(
+        &mut self,
+        query: &SearchQuery,
+        mut on_entry: impl FnMut(HistoryEntry) -> Result<()>,
+    ) -> Result<()> {
+        self.send(MessageType::Query, query.encode())?;
+        loop {
+            let msg = self.recv()?;
+            match msg.msg_type {
+                MessageType::HistoryEntry => on_entry(
+                    HistoryEntry::decode(&msg.data).context("Failed to decode history entry")?,
+                )?,
+                MessageType::End => return Ok(()),
+                other => bail!("Unexpected message type from server: {:?}", other),
+            }
+        }
+    }
+
+    /// Ask the server for the commands best worth suggesting for `query`
+    pub fn suggest(&mut self, query: &SuggestQuery) -> Result<Vec<Suggestion>> {
+        suggest(&mut self.reader, &mut self.writer, query)
+    }
+
+    /// Send End, then wait for the remote side to exit cleanly
+    pub fn close(mut self) -> Result<()> {
+        self.send(MessageType::End, Vec::new())?;
+        drop(self.writer);
+
+        let Some(mut child) = self.child else {
+            return Ok(());
+        };
+        let status = child.wait().context("Failed to wait for remote command")?;
+        if !status.success() {
+            bail!("Remote command exited with status: {}", status);
+        }
+        Ok(())
+    }
+
+    /// Give up on a broken session, ending ssh
+    pub fn abort(self) {
+        if let Some(mut child) = self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Ask for the commands best worth suggesting for `query` on one half of a
+/// split connection, answered on the other
+pub fn suggest<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    query: &SuggestQuery,
+) -> Result<Vec<Suggestion>> {
+    send_to(writer, MessageType::Suggest, query.encode())?;
+    let msg = recv_from(reader)?;
+    if msg.msg_type != MessageType::Suggestions {
+        bail!("Unexpected message type from server: {:?}", msg.msg_type);
+    }
+    decode_suggestions(&msg.data).context("Failed to decode suggestions")
+}
+
+/// Wait on one half of a split connection for the answer to GetStats, sent
+/// after uploads, by which time the server has committed them all, passing
+/// its acknowledgements to `on_ack` meanwhile
+pub fn await_commit<R: Read>(reader: &mut R, mut on_ack: impl FnMut(u64)) -> Result<()> {
+    loop {
+        let msg = recv_from(reader)?;
+        match msg.msg_type {
+            MessageType::Stored => on_ack(decode_u64(&msg.data)?),
+            MessageType::Stats => return Ok(()),
+            other => bail!("Unexpected message type from server: {:?}", other),
+        }
+    }
+}
+
+/// Send a message on one half of a split connection
+pub fn send_to<W: Write>(writer: &mut W, msg_type: MessageType, data: Vec<u8>) -> Result<()> {
+    Message::new(msg_type, data)
+        .write_to(writer)
+        .with_context(|| format!("Failed to send {:?} message to server", msg_type))
+}
+
+/// Send an entry on one half of a split connection, in chunks if it is large
+/// and `chunked`, encoding it into `buf`; it is left in the writer's buffer,
+/// flushed with the next message sent with `send_to`
+pub fn send_entry_to<W: Write>(
+    writer: &mut W,
+    entry: &HistoryEntry,
+    chunked: bool,
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    entry
+        .write_messages_with(writer, chunked, buf)
+        .context("Failed to send HistoryEntry message to server")
+}
+
+/// Receive a message on one half of a split connection, joining entry chunks
+/// and turning server Error, QuotaExceeded and NotStored messages into errors
+pub fn recv_from<R: Read>(reader: &mut R) -> Result<Message> {
+    let msg = Message::read_joined(reader).context("Failed to read message from server")?;
+    match msg.msg_type {
+        MessageType::Error => bail!("Server error: {}", String::from_utf8_lossy(&msg.data)),
+        MessageType::QuotaExceeded => Err(QuotaExceeded::decode(&msg.data)?.into()),
+        MessageType::NotStored => Err(NotStored::decode(&msg.data)?.into()),
+        _ => Ok(msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_commands_are_templated() {
+        let remote = RemoteCommand::default();
+        assert!(remote.is_default());
+        assert_eq!(remote.args("h").unwrap(), ["ssh", "h", "plentys"]);
+
+        let remote =
+            RemoteCommand::parse("ssh {host} doas -u history plentys --db-path /srv/{host}.db")
+                .unwrap();
+        assert!(!remote.is_default());
+        assert_eq!(
+            remote.args("me@home").unwrap(),
+            [
+                "ssh",
+                "me@home",
+                "doas",
+                "-u",
+                "history",
+                "plentys",
+                "--db-path",
+                "/srv/me@home.db"
+            ]
+        );
+    }
+
+    #[test]
+    fn ssh_connections_persist() {
+        let remote = RemoteCommand::default()
+            .persist(600, Some("~/.ssh/cm-%C".to_string()))
+            .unwrap();
+        assert!(remote.is_default());
+        assert_eq!(
+            remote.args("h").unwrap(),
+            [
+                "ssh",
+                "-o",
+                "ControlMaster=auto",
+                "-o",
+                "ControlPath=~/.ssh/cm-%C",
+                "-o",
+                "ControlPersist=600",
+                "h",
+                "plentys"
+            ]
+        );
+    }
+
+    #[test]
+    fn hosts_are_reached_with_their_ssh_options() {
+        let options = SshHost {
+            proxy_jump: Some("bastion".to_string()),
+            port: Some(2222),
+            user: Some("me".to_string()),
+            identity: Some("~/.ssh/history".to_string()),
+        };
+        let remote = RemoteCommand::default()
+            .ssh_host("history.example.com", options)
+            .unwrap();
+        assert_eq!(
+            remote.args("history.example.com").unwrap(),
+            [
+                "ssh",
+                "-J",
+                "bastion",
+                "-p",
+                "2222",
+                "-l",
+                "me",
+                "-i",
+                "~/.ssh/history",
+                "history.example.com",
+                "plentys"
+            ]
+        );
+        assert_eq!(remote.args("h").unwrap(), ["ssh", "h", "plentys"]);
+    }
+
+    #[test]
+    fn invalid_remote_commands_are_refused() {
+        for template in ["", "ssh {hots} plentys", "ssh {host plentys"] {
+            assert!(RemoteCommand::parse(template).is_err());
+        }
+        let docker = RemoteCommand::parse("docker exec -i {host} plentys").unwrap();
+        assert!(docker.clone().persist(600, None).is_err());
+        assert!(docker.ssh_host("h", SshHost::default()).is_err());
+    }
+}
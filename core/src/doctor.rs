@@ -0,0 +1,178 @@
+use crate::config::Config;
+use crate::connection::{Connection, RemoteCommand};
+use crate::paths;
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::{access, AccessFlags};
+use plenty_common::fish;
+use std::process::{Command, Stdio};
+
+/// Run environment diagnostics, printing one line per check.
+/// Returns whether every check passed.
+pub fn run(hosts: &[String]) -> bool {
+    let mut all_ok = true;
+    let mut report = |name: &str, result: Result<String>| match result {
+        Ok(detail) => println!("ok    {}: {}", name, detail),
+        Err(e) => {
+            println!("FAIL  {}: {:#}", name, e);
+            all_ok = false;
+        }
+    };
+
+    let config = Config::load();
+    report(
+        "config",
+        match &config {
+            Ok(_) => Config::path().map(|p| p.display().to_string()),
+            Err(e) => Err(anyhow::anyhow!("{:#}", e)),
+        },
+    );
+
+    report("fish directory", check_fish_dir());
+    report("fish_history", check_fish_history());
+    report("lock", check_lock());
+
+    let (configured, remote) = config.map(|c| (c.hosts, c.remote)).unwrap_or_default();
+    let hosts = if hosts.is_empty() {
+        configured
+    } else {
+        hosts.to_vec()
+    };
+    if hosts.is_empty() {
+        report("hosts", Err(anyhow::anyhow!("none given or configured")));
+    }
+    for host in &hosts {
+        // Templates run plentys their own way, which only a session tests
+        if remote.is_default() {
+            let reachable = check_ssh(host, &remote);
+            let reachable_ok = reachable.is_ok();
+            report(&format!("{}: ssh", host), reachable);
+            if !reachable_ok {
+                continue;
+            }
+            report(
+                &format!("{}: plentys", host),
+                check_remote_plentys(host, &remote),
+            );
+        } else {
+            report(
+                &format!("{}: remote command", host),
+                remote.args(host).map(|args| args.join(" ")),
+            );
+        }
+        report(
+            &format!("{}: handshake", host),
+            check_handshake(host, &remote),
+        );
+        report(
+            &format!("{}: server", host),
+            check_server_info(host, &remote),
+        );
+    }
+
+    all_ok
+}
+
+fn check_fish_dir() -> Result<String> {
+    let dir = paths::fish_dir()?;
+    if !dir.exists() {
+        return Ok(format!("{} (will be created)", dir.display()));
+    }
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    access(
+        &dir,
+        AccessFlags::R_OK | AccessFlags::W_OK | AccessFlags::X_OK,
+    )
+    .with_context(|| format!("{} is not accessible", dir.display()))?;
+    Ok(dir.display().to_string())
+}
+
+fn check_fish_history() -> Result<String> {
+    let path = paths::fish_dir()?.join("fish_history");
+    if !path.exists() {
+        return Ok(format!("{} (will be created)", path.display()));
+    }
+    access(&path, AccessFlags::R_OK | AccessFlags::W_OK)
+        .with_context(|| format!("{} is not readable and writable", path.display()))?;
+    let content =
+        std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let entries = fish::parse_history(&content).context("Failed to parse fish_history")?;
+    Ok(format!("{} entries", entries.len()))
+}
+
+fn check_lock() -> Result<String> {
+    let dir = paths::fish_dir()?;
+    if !dir.exists() {
+        return Ok("fish directory does not exist yet".to_string());
+    }
+    let file = std::fs::File::open(&dir).context("Failed to open fish directory")?;
+    match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(lock) => {
+            lock.unlock()
+                .map_err(|(_, errno)| errno)
+                .context("Failed to release lock")?;
+            Ok("available".to_string())
+        }
+        Err((_, Errno::EWOULDBLOCK)) => bail!("held by another process (a sync in progress?)"),
+        Err((_, errno)) => Err(errno).context("Failed to lock fish directory"),
+    }
+}
+
+fn remote(host: &str, remote: &RemoteCommand, command: &str) -> Result<String> {
+    let output = Command::new("ssh")
+        .args(["-o", "BatchMode=yes", "-o", "ConnectTimeout=10"])
+        .args(remote.ssh_options(host)?)
+        .args([host, command])
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run ssh")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{} ({})", output.status, stderr.trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn check_ssh(host: &str, remote_command: &RemoteCommand) -> Result<String> {
+    remote(host, remote_command, "true")?;
+    Ok("reachable".to_string())
+}
+
+fn check_remote_plentys(host: &str, remote_command: &RemoteCommand) -> Result<String> {
+    let path = remote(host, remote_command, "command -v plentys")
+        .context("plentys not found in remote PATH")?;
+    let version = remote(host, remote_command, "plentys --version")?;
+    Ok(format!("{} ({})", version, path))
+}
+
+fn check_handshake(host: &str, remote: &RemoteCommand) -> Result<String> {
+    let mut connection = Connection::open(host, remote)?;
+    let server = connection.handshake()?;
+    connection.close()?;
+    let skew = match server.time {
+        Some(time) => format!("{}s", time - crate::now()?),
+        None => "unknown".to_string(),
+    };
+    Ok(format!(
+        "protocol {}, plentys {}, clock skew {}",
+        server.protocol, server.version, skew
+    ))
+}
+
+fn check_server_info(host: &str, remote: &RemoteCommand) -> Result<String> {
+    let mut connection = Connection::open(host, remote)?;
+    connection.handshake()?;
+    let info = connection
+        .server_info()
+        .context("Failed to get server info (plentys too old?)")?;
+    connection.close()?;
+    Ok(format!(
+        "schema {}, {} entries, capabilities: {}",
+        info.schema_version,
+        info.entries,
+        info.capabilities.join(" ")
+    ))
+}
@@ -0,0 +1,215 @@
+/// `plenty enroll`: ask the `plentys web` of a server for an API token, wait
+/// for an admin to approve the code it shows, and keep the token for the
+/// tools using the API, rather than having tokens handed around
+use crate::paths;
+use anyhow::{bail, Context, Result};
+use plenty_common::json::{self, Value};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Time between asking whether the enrollment was approved
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+/// Time to wait for approval, as long as servers keep enrollments
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(86400);
+/// Time each request may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Enroll as `device` with the `plentys web` at `url`, saving the token it
+/// hands out once approved, and returning where
+pub fn run(url: &str, device: &str) -> Result<PathBuf> {
+    let token = enroll(url, device, POLL_INTERVAL)?;
+    let path = token_path(url)?;
+    let dir = path.parent().context("Token path has no parent")?;
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    writeln!(file, "{}", token).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// Where the token of the server at `url` is kept: under the tokens
+/// directory of plenty's, named after its host and port
+pub fn token_path(url: &str) -> Result<PathBuf> {
+    let authority = url
+        .split_once("://")
+        .map_or(url, |(_, rest)| rest)
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default();
+    let authority = authority.rsplit('@').next().unwrap_or_default();
+    if authority.is_empty() || authority.starts_with('.') {
+        bail!("No server in {:?}", url);
+    }
+    Ok(paths::plenty_dir()?.join("tokens").join(authority))
+}
+
+/// Ask for a token, then every `poll` until approved, denied or expired
+fn enroll(url: &str, device: &str, poll: Duration) -> Result<String> {
+    let base = url.trim_end_matches('/');
+    if !base.starts_with("http://") && !base.starts_with("https://") {
+        bail!(
+            "Enroll with the http:// or https:// address of plentys web, not {:?}",
+            url
+        );
+    }
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .http_status_as_error(false)
+        .timeout_global(Some(REQUEST_TIMEOUT))
+        .build()
+        .into();
+    let (status, body) = answer(
+        agent
+            .post(&format!("{}/api/enroll", base))
+            .header("Content-Type", "application/json")
+            .send(format!("{{\"device\":{}}}", json::string(device))),
+        base,
+    )?;
+    if status != 200 {
+        bail!("{} refused to enroll {}: {}", base, device, body.trim());
+    }
+    let code = field(&body, "code")?;
+    let secret = field(&body, "secret")?;
+    eprintln!(
+        "Asked to enroll {} with code {}, for an admin of the server to approve with:\n  \
+         plentys devices approve {}",
+        device, code, code
+    );
+    let deadline = Instant::now() + APPROVAL_TIMEOUT;
+    loop {
+        let (status, body) = answer(
+            agent
+                .get(&format!("{}/api/enroll/{}", base, code))
+                .header("Authorization", &format!("Bearer {}", secret))
+                .call(),
+            base,
+        )?;
+        match status {
+            200 => return field(&body, "token"),
+            202 => {}
+            404 => bail!("Enrollment {} was denied or expired", code),
+            _ => bail!(
+                "{} failed to tell about enrollment {}: {}",
+                base,
+                code,
+                body.trim()
+            ),
+        }
+        if Instant::now() >= deadline {
+            bail!("Enrollment {} wasn't approved in time", code);
+        }
+        std::thread::sleep(poll);
+    }
+}
+
+/// The status and body of a response
+fn answer(
+    response: Result<ureq::http::Response<ureq::Body>, ureq::Error>,
+    base: &str,
+) -> Result<(u16, String)> {
+    let mut response = response.with_context(|| format!("Failed to reach {}", base))?;
+    let body = response
+        .body_mut()
+        .read_to_string()
+        .with_context(|| format!("Failed to read the answer of {}", base))?;
+    Ok((response.status().as_u16(), body))
+}
+
+/// The string `key` of a JSON object
+fn field(body: &str, key: &str) -> Result<String> {
+    json::parse_object(body)?
+        .into_iter()
+        .find(|(name, _)| name == key)
+        .and_then(|(_, value)| match value {
+            Value::String(value) => Some(value),
+            _ => None,
+        })
+        .with_context(|| format!("No {} in {}", key, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+
+    #[test]
+    fn tokens_are_kept_per_server() {
+        let tokens = paths::plenty_dir().unwrap().join("tokens");
+        assert_eq!(
+            token_path("https://history.example.com/").unwrap(),
+            tokens.join("history.example.com")
+        );
+        assert_eq!(
+            token_path("http://me@localhost:8080/plenty?x").unwrap(),
+            tokens.join("localhost:8080")
+        );
+        assert!(token_path("http://").is_err());
+        assert!(token_path("http://../").is_err());
+    }
+
+    #[test]
+    fn enrollment_waits_for_approval() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let replies = [
+                "200 OK\r\n\r\n{\"code\":\"ABCD-EFGH\",\"secret\":\"s3cret\"}",
+                "202 Accepted\r\n\r\n{\"status\":\"pending\"}",
+                "200 OK\r\n\r\n{\"status\":\"approved\",\"token\":\"t0ken\"}",
+            ];
+            let mut requests = Vec::new();
+            for reply in replies {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = Vec::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push(line.trim_end().to_string());
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push((head, String::from_utf8(body).unwrap()));
+                let (status, body) = reply.split_once("\r\n\r\n").unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                    status.trim_end(),
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            requests
+        });
+
+        assert_eq!(enroll(&url, "laptop", Duration::ZERO).unwrap(), "t0ken");
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].0[0], "POST /api/enroll HTTP/1.1");
+        assert_eq!(requests[0].1, "{\"device\":\"laptop\"}");
+        assert_eq!(requests[2].0[0], "GET /api/enroll/ABCD-EFGH HTTP/1.1");
+        assert!(requests[2]
+            .0
+            .iter()
+            .any(|line| line.eq_ignore_ascii_case("authorization: Bearer s3cret")));
+        assert!(enroll("localhost:8080", "laptop", Duration::ZERO).is_err());
+    }
+}
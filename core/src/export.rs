@@ -0,0 +1,221 @@
+use crate::cache::Cache;
+use crate::paths;
+use anyhow::{bail, Context, Result};
+use plenty_common::{cmd_hash, entry_hash, fish, HistoryEntry, HistoryRequest};
+use rusqlite::{params, Connection, OpenFlags};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// How `plenty export` writes the cached history
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// fish_history, to a file or stdout
+    #[default]
+    Fish,
+    /// Rows added to atuin's history database
+    Atuin,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Self> {
+        Ok(match format {
+            "fish" => Format::Fish,
+            "atuin" => Format::Atuin,
+            _ => bail!("format must be \"fish\" or \"atuin\", not {:?}", format),
+        })
+    }
+}
+
+/// Write every cached entry, oldest first, in `format` to `path`: stdout or
+/// atuin's own database if not given
+pub fn run(format: Format, path: Option<PathBuf>, hostname: &str) -> Result<()> {
+    let cache = Cache::open_existing()?;
+    match format {
+        Format::Fish => {
+            let out: Box<dyn Write> = match &path {
+                Some(path) => Box::new(
+                    File::create(path)
+                        .with_context(|| format!("Failed to create {}", path.display()))?,
+                ),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let mut out = BufWriter::new(out);
+            cache.for_each_entry(&HistoryRequest::default(), |entry| {
+                fish::write_entry(&mut out, &entry).context("Failed to write history entry")
+            })?;
+            out.flush().context("Failed to flush export")
+        }
+        Format::Atuin => {
+            let path = match path {
+                Some(path) => path,
+                None => paths::atuin_db()?,
+            };
+            let mut conn = open_atuin(&path)?;
+            let user = std::env::var("USER").unwrap_or_default();
+            let mut entries = Vec::new();
+            cache.for_each_entry(&HistoryRequest::default(), |entry| {
+                entries.push(entry);
+                Ok(())
+            })?;
+            let added = write_atuin(&mut conn, &entries, hostname, &user)?;
+            eprintln!(
+                "Added {} of {} entries to {}",
+                added,
+                entries.len(),
+                path.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// atuin's database at `path`, which atuin must have created, as it
+/// migrates its schema itself
+fn open_atuin(path: &Path) -> Result<Connection> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE).with_context(
+        || {
+            format!(
+                "Failed to open atuin's database {}; run atuin once to create it",
+                path.display()
+            )
+        },
+    )?;
+    let has_history = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'history'")?
+        .exists([])?;
+    if !has_history {
+        bail!("{} isn't an atuin history database", path.display());
+    }
+    Ok(conn)
+}
+
+/// Add `entries` to atuin's history table, skipping those it already has,
+/// returning how many were added. atuin records times in nanoseconds, -1 for
+/// unknown exit codes and durations, and `host:user` as hostname; entries
+/// without a host are this machine's.
+fn write_atuin(
+    conn: &mut Connection,
+    entries: &[HistoryEntry],
+    hostname: &str,
+    user: &str,
+) -> Result<usize> {
+    let tx = conn
+        .transaction()
+        .context("Failed to begin writing to atuin's database")?;
+    let mut added = 0;
+    {
+        let mut insert = tx
+            .prepare(
+                "INSERT OR IGNORE INTO history
+                   (id, timestamp, duration, exit, command, cwd, session, hostname)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )
+            .context("Failed to prepare atuin history insert")?;
+        for entry in entries {
+            // Derived from the entry, so that exporting again adds nothing
+            let hash = entry_hash(&entry.cmd, entry.when, &entry.extra);
+            let id = format!("{:016x}{:016x}", hash, cmd_hash(&entry.cmd));
+            // atuin needs one; entries without share an all-zero session
+            let session = match entry.session.as_str() {
+                "" => format!("{:032x}", 0),
+                session => session.to_string(),
+            };
+            let host = match entry.host.as_str() {
+                "" => hostname,
+                host => host,
+            };
+            let duration = entry.duration_ms.map_or(-1, |d| {
+                d.min(i64::MAX as u64 / 1_000_000) as i64 * 1_000_000
+            });
+            let cwd = match entry.cwd.as_str() {
+                "" => "unknown",
+                cwd => cwd,
+            };
+            added += insert
+                .execute(params![
+                    id,
+                    entry.when.saturating_mul(1_000_000_000),
+                    duration,
+                    entry.exit_code.unwrap_or(-1),
+                    &entry.cmd,
+                    cwd,
+                    session,
+                    format!("{}:{}", host, user),
+                ])
+                .with_context(|| {
+                    format!(
+                        "Failed to add entry to atuin's database (cmd='{}')",
+                        entry.cmd
+                    )
+                })?;
+        }
+    }
+    tx.commit()
+        .context("Failed to commit to atuin's database")?;
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_become_atuin_rows_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE history (
+               id TEXT PRIMARY KEY,
+               timestamp INTEGER NOT NULL,
+               duration INTEGER NOT NULL,
+               exit INTEGER NOT NULL,
+               command TEXT NOT NULL,
+               cwd TEXT NOT NULL,
+               session TEXT NOT NULL,
+               hostname TEXT NOT NULL,
+               deleted_at INTEGER,
+               UNIQUE(timestamp, cwd, command)
+             );",
+        )
+        .unwrap();
+        let mut built = HistoryEntry::new("cargo build".to_string(), 2, String::new())
+            .with_host("desktop".to_string());
+        built.cwd = "/src".to_string();
+        built.exit_code = Some(101);
+        built.duration_ms = Some(1500);
+        let entries = [HistoryEntry::new("ls".to_string(), 1, String::new()), built];
+        assert_eq!(write_atuin(&mut conn, &entries, "laptop", "me").unwrap(), 2);
+        assert_eq!(write_atuin(&mut conn, &entries, "laptop", "me").unwrap(), 0);
+
+        let rows: Vec<(i64, i64, i64, String, String)> = conn
+            .prepare(
+                "SELECT timestamp, duration, exit, cwd, hostname FROM history ORDER BY timestamp",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                (1_000_000_000, -1, -1, "unknown".into(), "laptop:me".into()),
+                (
+                    2_000_000_000,
+                    1_500_000_000,
+                    101,
+                    "/src".into(),
+                    "desktop:me".into()
+                ),
+            ]
+        );
+    }
+}
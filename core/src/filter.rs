@@ -0,0 +1,53 @@
+use plenty_common::{HistoryEntry, HistoryRequest};
+
+/// Restricts which entries a sync exchanges, from `--since`, `--until` and `--match`.
+/// Local entries outside the filter are neither uploaded nor replaced.
+#[derive(Debug, Clone, Default)]
+pub struct SyncFilter {
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub pattern: Option<String>,
+}
+
+impl SyncFilter {
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.since.is_none_or(|since| entry.when >= since)
+            && self.until.is_none_or(|until| entry.when < until)
+            && self
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| entry.cmd.contains(pattern.as_str()))
+    }
+
+    /// Narrow a GetHistory request to entries matching this filter
+    pub fn restrict(&self, request: &mut HistoryRequest) {
+        if let Some(since) = self.since {
+            request.since = Some(request.since.map_or(since, |s| s.max(since)));
+        }
+        request.until = self.until;
+        request.pattern = self.pattern.clone();
+        // Explicit filters are exact, pinned or not
+        if self.since.is_some() || self.until.is_some() || self.pattern.is_some() {
+            request.keep_pinned = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_matches_range_and_pattern() {
+        let filter = SyncFilter {
+            since: Some(10),
+            until: Some(20),
+            pattern: Some("git".to_string()),
+        };
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        assert!(filter.matches(&entry("git status", 10)));
+        assert!(!filter.matches(&entry("git status", 20)));
+        assert!(!filter.matches(&entry("ls", 15)));
+        assert!(SyncFilter::default().matches(&entry("ls", 0)));
+    }
+}
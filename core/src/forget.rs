@@ -0,0 +1,141 @@
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::connection::{Connection, RemoteCommand};
+use crate::sync::{
+    read_local_history, receive_history, refresh_fish, with_history_locked, HistoryWriter, Received,
+};
+use crate::{confirm, Local};
+use anyhow::{bail, Context, Result};
+use plenty_common::{cmd_hash, decode_u64, HistoryRequest, MessageType};
+use std::collections::BTreeMap;
+
+/// What `plenty forget` removes: every entry for the matching commands
+#[derive(Debug, Clone, Default)]
+pub struct ForgetOptions {
+    /// Forget commands containing this text
+    pub pattern: Option<String>,
+    /// Forget the command with this hash
+    pub hash: Option<u64>,
+    /// Only list what would be forgotten
+    pub dry_run: bool,
+    /// Don't ask for confirmation
+    pub yes: bool,
+}
+
+impl ForgetOptions {
+    fn matches(&self, cmd: &str) -> bool {
+        self.pattern
+            .as_ref()
+            .is_some_and(|p| cmd.contains(p.as_str()))
+            || self.hash.is_some_and(|h| cmd_hash(cmd) == h)
+    }
+}
+
+/// Remove matching commands locally and on every host; the servers keep
+/// tombstones so other machines drop them on their next sync.
+pub fn run(config: &Config, hosts: &[String], options: &ForgetOptions) -> Result<()> {
+    let history_path = Local::current()?.history;
+    with_history_locked(&history_path, || {
+        let (local_entries, read) = read_local_history(&history_path)?;
+
+        // Commands to forget, by hash
+        let mut commands: BTreeMap<u64, Option<String>> = BTreeMap::new();
+        if let Some(hash) = options.hash {
+            commands.insert(hash, None);
+        }
+        for entry in &local_entries {
+            if options.matches(&entry.cmd) {
+                commands.insert(cmd_hash(&entry.cmd), Some(entry.cmd.clone()));
+            }
+        }
+        if let Some(pattern) = &options.pattern {
+            for host in hosts {
+                for cmd in server_matches(host, &config.remote, pattern)? {
+                    commands.insert(cmd_hash(&cmd), Some(cmd));
+                }
+            }
+        }
+
+        if commands.is_empty() {
+            eprintln!("No matching commands.");
+            return Ok(());
+        }
+        for (hash, cmd) in &commands {
+            println!(
+                "{:016x}  {}",
+                hash,
+                cmd.as_deref().unwrap_or("(not in local history)")
+            );
+        }
+        if options.dry_run {
+            return Ok(());
+        }
+        if !options.yes {
+            let question = format!("Forget these {} commands everywhere?", commands.len());
+            match confirm(&question)? {
+                Some(true) => {}
+                Some(false) => return Ok(()),
+                None => bail!(
+                    "Refusing to forget {} commands without --yes",
+                    commands.len()
+                ),
+            }
+        }
+
+        for host in hosts {
+            eprintln!("Forgetting {} commands on {}…", commands.len(), host);
+            let mut connection = Connection::open(host, &config.remote)?;
+            connection.handshake()?;
+            let mut deleted = 0;
+            for hash in commands.keys() {
+                connection.send(MessageType::DeleteEntry, hash.to_be_bytes().to_vec())?;
+                let msg = connection.recv()?;
+                if msg.msg_type != MessageType::Deleted {
+                    bail!("Unexpected message type from server: {:?}", msg.msg_type);
+                }
+                deleted += decode_u64(&msg.data).context("Failed to decode deletion count")?;
+            }
+            connection.close()?;
+            eprintln!("Deleted {} entries on {}", deleted, host);
+        }
+
+        let mut history_writer = HistoryWriter::create(&history_path, read)?;
+        let mut removed = 0;
+        for entry in &local_entries {
+            if commands.contains_key(&cmd_hash(&entry.cmd)) {
+                removed += 1;
+            } else {
+                history_writer.write(entry)?;
+            }
+        }
+        history_writer.commit()?;
+        eprintln!("Deleted {} local entries", removed);
+
+        let mut cache = Cache::open()?;
+        for hash in commands.keys() {
+            cache.forget(*hash)?;
+        }
+
+        refresh_fish()
+    })
+}
+
+/// Commands on the server containing `pattern`
+pub fn server_matches(host: &str, remote: &RemoteCommand, pattern: &str) -> Result<Vec<String>> {
+    let mut connection = Connection::open(host, remote)?;
+    connection.handshake()?;
+    let request = HistoryRequest {
+        pattern: Some(pattern.to_string()),
+        ..Default::default()
+    };
+    connection.send(MessageType::GetHistory, request.encode())?;
+    let mut cmds = Vec::new();
+    receive_history(&mut connection.reader, |received| {
+        if let Received::Entry(entry) = received {
+            cmds.push(entry.cmd);
+        }
+        Ok(())
+    })?;
+    connection.close()?;
+    Ok(cmds)
+}
@@ -0,0 +1,63 @@
+use crate::SyncReport;
+use anyhow::{bail, Context, Result};
+use std::process::Command;
+
+/// Shell commands run around a sync, configured in the `[hooks]` section
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    pub pre_sync: Option<String>,
+    pub post_sync: Option<String>,
+}
+
+impl Hooks {
+    /// Run the pre-sync hook; a failing hook aborts the sync
+    pub fn run_pre_sync(&self, host: &str) -> Result<()> {
+        let Some(command) = &self.pre_sync else {
+            return Ok(());
+        };
+        eprintln!("Running pre-sync hook…");
+        let status = shell(command)
+            .env("PLENTY_HOST", host)
+            .status()
+            .context("Failed to run pre-sync hook")?;
+        if !status.success() {
+            bail!("pre-sync hook exited with status: {}", status);
+        }
+        Ok(())
+    }
+
+    /// Run the post-sync hook with the outcome of the sync exposed as `PLENTY_*` variables
+    pub fn run_post_sync(&self, host: &str, outcome: &Result<SyncReport>) {
+        let Some(command) = &self.post_sync else {
+            return;
+        };
+        eprintln!("Running post-sync hook…");
+        let mut cmd = shell(command);
+        cmd.env("PLENTY_HOST", host);
+        match outcome {
+            Ok(report) => {
+                cmd.env("PLENTY_STATUS", "ok")
+                    .env("PLENTY_UPLOADED", report.uploaded.to_string())
+                    .env("PLENTY_RECEIVED", report.received.to_string())
+                    .env("PLENTY_WRITTEN", report.written.to_string());
+            }
+            Err(e) => {
+                cmd.env("PLENTY_STATUS", "error")
+                    .env("PLENTY_ERROR", format!("{:#}", e));
+            }
+        }
+        match cmd.status() {
+            Ok(status) if !status.success() => {
+                eprintln!("Warning: post-sync hook exited with status: {}", status)
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Warning: failed to run post-sync hook: {}", e),
+        }
+    }
+}
+
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
@@ -0,0 +1,197 @@
+use crate::config::Config;
+use crate::paths;
+use crate::signing::Signer;
+use crate::upload;
+use anyhow::{bail, Context, Result};
+use plenty_common::{fish, merge_same_runs, HistoryEntry};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Shells whose history files `plenty import` reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Fish,
+    Zsh,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Self> {
+        Ok(match format {
+            "fish" => Format::Fish,
+            "zsh" => Format::Zsh,
+            _ => bail!("format must be \"fish\" or \"zsh\", not {:?}", format),
+        })
+    }
+}
+
+/// Upload the history file at `path`, e.g. copied from a decommissioned
+/// machine, to every host, leaving fish_history alone: the entries come back
+/// with the next sync. They are tagged with `hostname`, if given.
+pub fn run(
+    config: &Config,
+    format: Format,
+    path: &Path,
+    hosts: &[String],
+    hostname: Option<&str>,
+) -> Result<()> {
+    let content =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut entries = match format {
+        Format::Fish => fish::parse_history(&content)?,
+        Format::Zsh => {
+            let modified = std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let mtime = modified
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+            parse_zsh(&content, mtime)
+        }
+    };
+    entries.retain(|entry| !config.sync.is_private(&entry.cmd));
+    if entries.is_empty() {
+        bail!("No history entries in {}", path.display());
+    }
+    for entry in &mut entries {
+        entry.host = hostname.unwrap_or_default().to_string();
+        entry.namespace = config.namespace_of(&entry.cmd).to_string();
+    }
+    let mut entries = merge_same_runs(entries);
+    if config.signing.sign {
+        let signer = Signer::load_in(&paths::plenty_dir()?)?;
+        for entry in &mut entries {
+            signer.sign(entry);
+        }
+    }
+
+    let device = config.sync.hostname()?;
+    for host in hosts {
+        // Hosts namespaces are shared with only get their entries
+        let shared = config.shared_with(host);
+        let uploads: Vec<_> = entries
+            .iter()
+            .filter(|entry| shared.is_empty() || shared.contains(&entry.namespace))
+            .cloned()
+            .collect();
+        if uploads.is_empty() {
+            eprintln!("Nothing in {} to upload to {}", path.display(), host);
+            continue;
+        }
+        let count = uploads.len();
+        upload::upload(
+            config,
+            &paths::plenty_dir()?,
+            host,
+            &device,
+            uploads,
+            |server| {
+                if !shared.is_empty() && !server.supports("namespaces") {
+                    bail!(
+                        "plentys {} on {} can't keep namespaces apart; upgrade it",
+                        server.version,
+                        host
+                    );
+                }
+                Ok(())
+            },
+        )?;
+        eprintln!(
+            "Uploaded {} entries from {} to {}",
+            count,
+            path.display(),
+            host
+        );
+    }
+    Ok(())
+}
+
+/// Parse a zsh history file: `: <start>:<elapsed>;<command>` lines with
+/// EXTENDED_HISTORY, plain commands otherwise, which get `mtime` as zsh
+/// doesn't record when they ran. Lines ending with a backslash continue on
+/// the next, and zsh's metafied bytes are restored.
+pub fn parse_zsh(content: &[u8], mtime: i64) -> Vec<HistoryEntry> {
+    let content = String::from_utf8_lossy(&unmetafy(content)).into_owned();
+    let mut entries = Vec::new();
+    let mut lines = content.lines();
+    while let Some(first) = lines.next() {
+        let mut text = first.to_string();
+        while text.ends_with('\\') {
+            let Some(next) = lines.next() else {
+                break;
+            };
+            text.pop();
+            text.push('\n');
+            text.push_str(next);
+        }
+
+        let (when, elapsed, cmd) = match extended(&text) {
+            Some((when, elapsed, cmd)) => (when, elapsed, cmd),
+            None => (mtime, 0, text.as_str()),
+        };
+        if cmd.trim().is_empty() {
+            continue;
+        }
+        let mut entry = HistoryEntry::new(fish::escape_cmd(cmd), when, String::new());
+        // zsh records 0 when it didn't time the command
+        if elapsed > 0 {
+            entry.duration_ms = Some(elapsed.saturating_mul(1000));
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+/// The start, elapsed seconds and command of an EXTENDED_HISTORY line
+fn extended(line: &str) -> Option<(i64, u64, &str)> {
+    let (header, cmd) = line.strip_prefix(": ")?.split_once(';')?;
+    let (start, elapsed) = header.split_once(':')?;
+    Some((start.trim().parse().ok()?, elapsed.parse().ok()?, cmd))
+}
+
+/// zsh writes bytes it treats specially as 0x83 followed by the byte xor 32
+fn unmetafy(content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len());
+    let mut bytes = content.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            0x83 => {
+                if let Some(&next) = bytes.next() {
+                    out.push(next ^ 32);
+                }
+            }
+            _ => out.push(byte),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zsh_histories_are_parsed() {
+        let content = b": 1700000000:0;git status\n\
+                        : 1700000005:12;for f in *; do\\\n  echo $f\\\ndone\n\
+                        make install\n\
+                        : 1700000010:0;echo \xc4\x83\xa4\n";
+        let entries = parse_zsh(content, 42);
+        let cmds: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.cmd.as_str(), entry.when, entry.duration_ms))
+            .collect();
+        assert_eq!(
+            cmds,
+            [
+                ("git status", 1700000000, None),
+                (
+                    "for f in *; do\\n  echo $f\\ndone",
+                    1700000005,
+                    Some(12_000)
+                ),
+                ("make install", 42, None),
+                ("echo \u{104}", 1700000010, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,20 @@
+# plenty's fish integration; add `plenty init fish | source` to config.fish
+
+# Pick among the commands plenty suggests for what's typed so far, ranked by
+# how often and how recently they ran across every synced machine, with fzf
+# if installed, or take the best one otherwise
+function plenty_suggest
+    set -l line (commandline | string collect)
+    set -l suggestions (plenty suggest --null --limit 100 --prefix "$line" --cwd "$PWD" 2>/dev/null | string split0)
+    if set -q suggestions[1]
+        set -l picked $suggestions[1]
+        if type -q fzf
+            set picked (printf '%s\0' $suggestions | fzf --read0 --print0 --height 40% --no-sort --query "$line" | string split0)
+        end
+        test -n "$picked"; and commandline --replace -- $picked
+    end
+    commandline --function repaint
+end
+
+bind \cr plenty_suggest
+bind -M insert \cr plenty_suggest
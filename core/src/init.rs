@@ -0,0 +1,13 @@
+use anyhow::{bail, Result};
+
+/// Functions and key bindings for fish, printed by `plenty init fish`
+const FISH: &str = include_str!("init.fish");
+
+/// Print the integration script for `shell`
+pub fn run(shell: &str) -> Result<()> {
+    match shell {
+        "fish" => print!("{}", FISH),
+        _ => bail!("Unsupported shell {:?}; plenty only knows fish", shell),
+    }
+    Ok(())
+}
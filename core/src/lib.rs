@@ -0,0 +1,57 @@
+/// The plenty client's logic, under the `plenty` command line and reusable
+/// by other front ends: syncing a fish history over any connection to a
+/// server, and what every other command does
+pub mod agent;
+pub mod bootstrap;
+pub mod cache;
+pub mod config;
+pub mod connection;
+pub mod doctor;
+pub mod enroll;
+pub mod export;
+pub mod filter;
+pub mod forget;
+pub mod hooks;
+pub mod import;
+pub mod init;
+pub mod order;
+pub mod paths;
+pub mod pin;
+pub mod search;
+pub mod service;
+pub mod share;
+pub mod signing;
+pub mod state;
+pub mod status;
+pub mod suggest;
+pub mod sync;
+pub mod throttle;
+pub mod top;
+pub mod upload;
+
+use anyhow::{Context, Result};
+use std::io::{BufRead, IsTerminal, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use sync::{sync, Direction, Local, SyncArgs, SyncReport};
+
+/// Ask a yes/no question on the terminal; `None` if stdin is not a terminal
+pub fn confirm(question: &str) -> Result<Option<bool>> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Ok(None);
+    }
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    Ok(Some(matches!(answer.trim(), "y" | "Y" | "yes")))
+}
+
+/// Current Unix time in seconds
+pub fn now() -> Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("System clock is before the Unix epoch")?
+        .as_secs() as i64)
+}
@@ -0,0 +1,148 @@
+use anyhow::{bail, Result};
+use plenty_common::{entry_hash, HistoryEntry};
+use std::collections::{HashMap, HashSet};
+
+/// How a sync orders the fish_history it writes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HistoryOrder {
+    /// Oldest first, entries sharing a timestamp as `tie_break` says: the same
+    /// on every machine unless that is by insertion
+    #[default]
+    When,
+    /// Local entries where they were in the file, received ones inserted
+    /// before the first later local entry
+    Local,
+    /// Each command once, at its latest run, oldest first
+    Latest,
+}
+
+impl HistoryOrder {
+    pub fn parse(order: &str) -> Result<Self> {
+        Ok(match order {
+            "when" => HistoryOrder::When,
+            "local" => HistoryOrder::Local,
+            "latest" => HistoryOrder::Latest,
+            _ => bail!(
+                "sync.order must be \"when\", \"local\" or \"latest\", not {:?}",
+                order
+            ),
+        })
+    }
+
+    /// Put `entries`, in `when` order, in this order instead, without exact
+    /// duplicates; `local` has the position in the local file of the entries
+    /// read from it, by hash
+    pub fn arrange(
+        self,
+        entries: Vec<HistoryEntry>,
+        local: &HashMap<u64, usize>,
+    ) -> Vec<HistoryEntry> {
+        let mut seen = HashSet::new();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|entry| (entry_hash(&entry.cmd, entry.when, &entry.extra), entry))
+            .filter(|(hash, _)| seen.insert(*hash))
+            .collect();
+        match self {
+            HistoryOrder::When => entries.into_iter().map(|(_, entry)| entry).collect(),
+            HistoryOrder::Local => {
+                let (mut read, received): (Vec<_>, Vec<_>) = entries
+                    .into_iter()
+                    .partition(|(hash, _)| local.contains_key(hash));
+                read.sort_by_key(|(hash, _)| local[hash]);
+                let mut received = received.into_iter().map(|(_, entry)| entry).peekable();
+                let mut arranged = Vec::with_capacity(read.len() + received.len());
+                for (_, entry) in read {
+                    arranged.extend(std::iter::from_fn(|| {
+                        received.next_if(|received| received.when < entry.when)
+                    }));
+                    arranged.push(entry);
+                }
+                arranged.extend(received);
+                arranged
+            }
+            HistoryOrder::Latest => {
+                let latest: HashMap<&str, usize> = entries
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, entry))| (entry.cmd.as_str(), i))
+                    .collect();
+                let keep: Vec<bool> = entries
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, entry))| latest[entry.cmd.as_str()] == i)
+                    .collect();
+                entries
+                    .into_iter()
+                    .zip(keep)
+                    .filter(|(_, keep)| *keep)
+                    .map(|((_, entry), _)| entry)
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histories_are_arranged_in_order() {
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        // As merged: received and local entries by time
+        let merged = vec![
+            entry("ls", 1),
+            entry("make", 2),
+            entry("ls", 3),
+            entry("ls", 3),
+            entry("git status", 4),
+            entry("make", 5),
+        ];
+        // The local file had make at 5 before ls at 3
+        let local: HashMap<u64, usize> = [("make", 5), ("ls", 3)]
+            .iter()
+            .enumerate()
+            .map(|(i, (cmd, when))| (entry_hash(cmd, *when, ""), i))
+            .collect();
+        let cmds = |order: HistoryOrder| -> Vec<(String, i64)> {
+            order
+                .arrange(merged.clone(), &local)
+                .into_iter()
+                .map(|entry| (entry.cmd, entry.when))
+                .collect()
+        };
+        let expect = |expected: &[(&str, i64)]| -> Vec<(String, i64)> {
+            expected
+                .iter()
+                .map(|(cmd, when)| (cmd.to_string(), *when))
+                .collect()
+        };
+
+        assert_eq!(
+            cmds(HistoryOrder::When),
+            expect(&[
+                ("ls", 1),
+                ("make", 2),
+                ("ls", 3),
+                ("git status", 4),
+                ("make", 5)
+            ])
+        );
+        assert_eq!(
+            cmds(HistoryOrder::Local),
+            expect(&[
+                ("ls", 1),
+                ("make", 2),
+                ("git status", 4),
+                ("make", 5),
+                ("ls", 3)
+            ])
+        );
+        assert_eq!(
+            cmds(HistoryOrder::Latest),
+            expect(&[("ls", 3), ("git status", 4), ("make", 5)])
+        );
+        assert!(HistoryOrder::parse("newest").is_err());
+    }
+}
@@ -0,0 +1,26 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+
+fn data_home() -> Result<PathBuf> {
+    if let Ok(xdg_data_home) = std::env::var("XDG_DATA_HOME") {
+        Ok(PathBuf::from(xdg_data_home))
+    } else {
+        let home = std::env::var("HOME").context("HOME environment variable not set")?;
+        Ok(PathBuf::from(home).join(".local/share"))
+    }
+}
+
+/// fish's data directory, holding `fish_history`
+pub fn fish_dir() -> Result<PathBuf> {
+    Ok(data_home()?.join("fish"))
+}
+
+/// plenty's own client-side data directory
+pub fn plenty_dir() -> Result<PathBuf> {
+    Ok(data_home()?.join("plenty"))
+}
+
+/// atuin's history database, where it keeps it by default
+pub fn atuin_db() -> Result<PathBuf> {
+    Ok(data_home()?.join("atuin/history.db"))
+}
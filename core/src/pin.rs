@@ -0,0 +1,83 @@
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::confirm;
+use crate::connection::Connection;
+use crate::forget::server_matches;
+use anyhow::{bail, Context, Result};
+use plenty_common::{decode_u64, HistoryRequest, MessageType, PinRequest};
+use std::collections::BTreeSet;
+
+/// Pin (or unpin) every command containing `pattern`, in the local cache
+/// and on every host
+pub fn run(
+    config: &Config,
+    hosts: &[String],
+    pattern: &str,
+    pinned: bool,
+    yes: bool,
+) -> Result<()> {
+    let mut cache = Cache::open()?;
+
+    let mut commands = BTreeSet::new();
+    let request = HistoryRequest {
+        pattern: Some(pattern.to_string()),
+        ..Default::default()
+    };
+    cache.for_each_entry(&request, |entry| {
+        // Only pinned commands can be unpinned
+        if pinned || entry.pinned {
+            commands.insert(entry.cmd);
+        }
+        Ok(())
+    })?;
+    if pinned {
+        for host in hosts {
+            commands.extend(server_matches(host, &config.remote, pattern)?);
+        }
+    }
+
+    if commands.is_empty() {
+        eprintln!("No matching commands.");
+        return Ok(());
+    }
+    for cmd in &commands {
+        println!("{}", cmd);
+    }
+    let action = if pinned { "Pin" } else { "Unpin" };
+    if commands.len() > 1 && !yes {
+        let question = format!("{} these {} commands?", action, commands.len());
+        match confirm(&question)? {
+            Some(true) => {}
+            Some(false) => return Ok(()),
+            None => bail!(
+                "Refusing to {} {} commands without --yes",
+                action.to_lowercase(),
+                commands.len()
+            ),
+        }
+    }
+
+    for host in hosts {
+        eprintln!("{}ning {} commands on {}…", action, commands.len(), host);
+        let mut connection = Connection::open(host, &config.remote)?;
+        connection.handshake()?;
+        for cmd in &commands {
+            let pin = PinRequest {
+                cmd: cmd.clone(),
+                pinned,
+            };
+            connection.send(MessageType::PinEntry, pin.encode())?;
+            let msg = connection.recv()?;
+            if msg.msg_type != MessageType::Pinned {
+                bail!("Unexpected message type from server: {:?}", msg.msg_type);
+            }
+            decode_u64(&msg.data).context("Failed to decode pinned entry count")?;
+        }
+        connection.close()?;
+    }
+
+    for cmd in &commands {
+        cache.set_pinned(cmd, pinned)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,310 @@
+use crate::cache::Cache;
+use anyhow::{bail, Context, Result};
+use plenty_common::{
+    glob_regex, json, required_substring, time, HistoryEntry, HistoryRequest, MatchMode,
+};
+use regex_lite::{Captures, Regex};
+use std::io::{IsTerminal, Write};
+
+const HIGHLIGHT: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+/// How `plenty search` prints the entries it finds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Commands only
+    #[default]
+    Cmd,
+    /// The chosen columns of each entry, then its command
+    Long,
+    /// One JSON object per line, as `plentys export --format jsonl` writes them
+    Json,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Self> {
+        Ok(match format {
+            "cmd" => Format::Cmd,
+            "long" => Format::Long,
+            "json" => Format::Json,
+            _ => bail!(
+                "format must be \"cmd\", \"long\" or \"json\", not {:?}",
+                format
+            ),
+        })
+    }
+}
+
+/// What the long format shows before commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// When the command ran, in local time
+    Timestamp,
+    Host,
+    Cwd,
+    Exit,
+}
+
+impl Column {
+    pub const ALL: [Column; 4] = [Column::Timestamp, Column::Host, Column::Cwd, Column::Exit];
+
+    /// Parse a comma-separated list of columns, such as `timestamp,exit`
+    pub fn parse_list(columns: &str) -> Result<Vec<Self>> {
+        columns
+            .split(',')
+            .map(|column| {
+                Ok(match column.trim() {
+                    "timestamp" => Column::Timestamp,
+                    "host" => Column::Host,
+                    "cwd" => Column::Cwd,
+                    "exit" => Column::Exit,
+                    _ => bail!(
+                        "columns are \"timestamp\", \"host\", \"cwd\" and \"exit\", not {:?}",
+                        column
+                    ),
+                })
+            })
+            .collect()
+    }
+}
+
+/// What `plenty search` looks for and how it prints it
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// Only commands matching this
+    pub pattern: Option<String>,
+    /// How `pattern` matches commands: as a substring unless
+    /// `MatchMode::Regex` or `MatchMode::Glob`
+    pub mode: MatchMode,
+    /// Only entries of this namespace, `""` being the personal one
+    pub namespace: Option<String>,
+    /// Only entries run at or after this time
+    pub since: Option<i64>,
+    /// Only entries run before this time
+    pub until: Option<i64>,
+    pub format: Format,
+    /// Columns of the long format; all of them if empty
+    pub columns: Vec<Column>,
+    /// Whether to highlight matches; if stdout is a terminal if not given
+    pub color: Option<bool>,
+    /// Only the most recent matches
+    pub limit: Option<u64>,
+    /// Newest first rather than oldest first
+    pub reverse: bool,
+}
+
+/// Print the cached entries `options` asks for, pinned ones first, then
+/// oldest first unless reversed; entries without a host are `hostname`'s
+pub fn run(options: &SearchOptions, hostname: &str) -> Result<()> {
+    let cache = Cache::open_existing()?;
+    let pattern = options.pattern.as_deref().unwrap_or_default();
+    let regex = matcher(pattern, options.mode)?;
+    // Regexes and globs are matched here, the cache narrowing candidates
+    // down to those containing text every match does
+    let filtered =
+        !pattern.is_empty() && matches!(options.mode, MatchMode::Regex | MatchMode::Glob);
+    let request = HistoryRequest {
+        pattern: if filtered {
+            required_substring(pattern, options.mode)
+        } else {
+            options.pattern.clone()
+        },
+        namespaces: options.namespace.clone().into_iter().collect(),
+        since: options.since,
+        until: options.until,
+        limit: options.limit.filter(|_| !filtered),
+        ..Default::default()
+    };
+    let mut entries = Vec::new();
+    cache.for_each_entry(&request, |entry| {
+        if !filtered || regex.is_match(&entry.cmd) {
+            entries.push(entry);
+        }
+        Ok(())
+    })?;
+    if let (true, Some(limit)) = (filtered, options.limit) {
+        let excess = entries.len().saturating_sub(limit as usize);
+        entries.drain(..excess);
+    }
+    let (mut pinned, mut others): (Vec<_>, Vec<_>) =
+        entries.into_iter().partition(|entry| entry.pinned);
+    if options.reverse {
+        pinned.reverse();
+        others.reverse();
+    }
+    let entries: Vec<_> = pinned.into_iter().chain(others).collect();
+
+    let color = options
+        .color
+        .unwrap_or_else(|| std::io::stdout().is_terminal());
+    let printer = Printer {
+        columns: if options.columns.is_empty() {
+            Column::ALL.to_vec()
+        } else {
+            options.columns.clone()
+        },
+        highlight: (color && !pattern.is_empty()).then_some(regex),
+        hostname,
+        offset: time::utc_offset()?,
+    };
+    let mut out = std::io::stdout().lock();
+    printer.write(&mut out, options.format, &entries)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// The regex finding the matches of `pattern` in commands
+fn matcher(pattern: &str, mode: MatchMode) -> Result<Regex> {
+    let regex = match mode {
+        MatchMode::Regex => pattern.to_string(),
+        MatchMode::Glob => glob_regex(pattern),
+        MatchMode::Words | MatchMode::Substring => regex_lite::escape(pattern),
+    };
+    Regex::new(&regex).with_context(|| format!("Invalid pattern {:?}", pattern))
+}
+
+/// Writes the entries found in a format
+struct Printer<'a> {
+    columns: Vec<Column>,
+    /// Matches to highlight in commands, if any
+    highlight: Option<Regex>,
+    hostname: &'a str,
+    /// Seconds local time is ahead of UTC
+    offset: i64,
+}
+
+impl Printer<'_> {
+    fn write(&self, out: &mut impl Write, format: Format, entries: &[HistoryEntry]) -> Result<()> {
+        match format {
+            Format::Cmd => {
+                for entry in entries {
+                    writeln!(out, "{}", self.cmd(&entry.cmd))?;
+                }
+            }
+            Format::Json => {
+                for entry in entries {
+                    writeln!(out, "{}", json::entry(entry))?;
+                }
+            }
+            Format::Long => {
+                let rows: Vec<Vec<String>> = entries
+                    .iter()
+                    .map(|entry| self.columns.iter().map(|c| self.cell(*c, entry)).collect())
+                    .collect();
+                let widths: Vec<usize> = (0..self.columns.len())
+                    .map(|i| {
+                        rows.iter()
+                            .map(|row| row[i].chars().count())
+                            .max()
+                            .unwrap_or(0)
+                    })
+                    .collect();
+                for (entry, row) in entries.iter().zip(rows) {
+                    for (cell, width) in row.iter().zip(&widths) {
+                        write!(out, "{:<width$}  ", cell, width = width)?;
+                    }
+                    writeln!(out, "{}", self.cmd(&entry.cmd))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cell(&self, column: Column, entry: &HistoryEntry) -> String {
+        match column {
+            Column::Timestamp => {
+                let local = entry.when.saturating_add(self.offset);
+                let seconds = local.rem_euclid(86400);
+                format!(
+                    "{} {:02}:{:02}:{:02}",
+                    time::date(local),
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                )
+            }
+            Column::Host => match entry.host.as_str() {
+                "" => self.hostname.to_string(),
+                host => host.to_string(),
+            },
+            Column::Cwd => match entry.cwd.as_str() {
+                "" => "-".to_string(),
+                cwd => cwd.to_string(),
+            },
+            Column::Exit => entry
+                .exit_code
+                .map_or_else(|| "-".to_string(), |code| code.to_string()),
+        }
+    }
+
+    /// `cmd`, with the matches highlighted if asked to
+    fn cmd(&self, cmd: &str) -> String {
+        match &self.highlight {
+            Some(regex) => regex
+                .replace_all(cmd, |captures: &Captures| match &captures[0] {
+                    "" => String::new(),
+                    text => format!("{}{}{}", HIGHLIGHT, text, RESET),
+                })
+                .into_owned(),
+            None => cmd.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_printed_in_columns() {
+        let mut build = HistoryEntry::new("cargo build".to_string(), 86_399, String::new())
+            .with_host("desktop".to_string());
+        build.cwd = "/src".to_string();
+        build.exit_code = Some(101);
+        let entries = [
+            build,
+            HistoryEntry::new("cargo test".to_string(), 0, String::new()),
+        ];
+        let printer = Printer {
+            columns: Column::parse_list("timestamp,host,exit").unwrap(),
+            highlight: None,
+            hostname: "laptop",
+            offset: 3600,
+        };
+        let print = |printer: &Printer, format| {
+            let mut out = Vec::new();
+            printer.write(&mut out, format, &entries).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            print(&printer, Format::Long),
+            "1970-01-02 00:59:59  desktop  101  cargo build\n\
+             1970-01-01 01:00:00  laptop   -    cargo test\n"
+        );
+        assert!(
+            print(&printer, Format::Json).starts_with("{\"cmd\":\"cargo build\",\"when\":86399,")
+        );
+
+        let printer = Printer {
+            highlight: Some(matcher("cargo", MatchMode::Substring).unwrap()),
+            ..printer
+        };
+        assert_eq!(
+            print(&printer, Format::Cmd),
+            "\x1b[1;31mcargo\x1b[0m build\n\x1b[1;31mcargo\x1b[0m test\n"
+        );
+        let printer = Printer {
+            highlight: Some(matcher("t?", MatchMode::Regex).unwrap()),
+            ..printer
+        };
+        assert_eq!(
+            print(&printer, Format::Cmd),
+            "cargo build\ncargo \x1b[1;31mt\x1b[0mes\x1b[1;31mt\x1b[0m\n"
+        );
+        assert!(matcher("cargo *", MatchMode::Glob)
+            .unwrap()
+            .is_match("cargo test"));
+        assert!(matcher("(", MatchMode::Regex).is_err());
+        assert!(Column::parse_list("when").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SYSTEMD_UNIT: &str = "plenty-sync";
+const LAUNCHD_LABEL: &str = "net.plenty.sync";
+
+/// Background sync schedule, configured in the `[service]` section
+#[derive(Debug, Clone)]
+pub struct ServiceOptions {
+    /// Seconds between syncs
+    pub interval: i64,
+}
+
+impl Default for ServiceOptions {
+    fn default() -> Self {
+        ServiceOptions { interval: 15 * 60 }
+    }
+}
+
+/// Write a user service running `plenty sync` every `options.interval`,
+/// then enable it if asked: a systemd user timer, or a launchd agent on macOS
+pub fn install(options: &ServiceOptions, enable: bool) -> Result<()> {
+    let exe = std::env::current_exe().context("Failed to locate the plenty executable")?;
+    let home = PathBuf::from(std::env::var("HOME").context("HOME environment variable not set")?);
+
+    if cfg!(target_os = "macos") {
+        let path = home.join(format!("Library/LaunchAgents/{}.plist", LAUNCHD_LABEL));
+        write_file(&path, &launchd_plist(&exe, options.interval))?;
+        if enable {
+            run(Command::new("launchctl").arg("load").arg("-w").arg(&path))?;
+        }
+        return Ok(());
+    }
+
+    let config_home = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => home.join(".config"),
+    };
+    let unit_dir = config_home.join("systemd/user");
+    write_file(
+        &unit_dir.join(format!("{}.service", SYSTEMD_UNIT)),
+        &systemd_service(&exe),
+    )?;
+    write_file(
+        &unit_dir.join(format!("{}.timer", SYSTEMD_UNIT)),
+        &systemd_timer(options.interval),
+    )?;
+    if enable {
+        run(Command::new("systemctl").args(["--user", "daemon-reload"]))?;
+        run(Command::new("systemctl").args([
+            "--user",
+            "enable",
+            "--now",
+            &format!("{}.timer", SYSTEMD_UNIT),
+        ]))?;
+    } else {
+        eprintln!(
+            "Enable it with: systemctl --user enable --now {}.timer",
+            SYSTEMD_UNIT
+        );
+    }
+    Ok(())
+}
+
+fn systemd_service(exe: &Path) -> String {
+    format!(
+        "[Unit]\n\
+         Description=Sync fish history with plenty\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart=\"{}\" sync\n",
+        exe.display()
+    )
+}
+
+fn systemd_timer(interval: i64) -> String {
+    format!(
+        "[Unit]\n\
+         Description=Sync fish history with plenty every {}s\n\
+         \n\
+         [Timer]\n\
+         OnBootSec=1min\n\
+         OnUnitActiveSec={}s\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n",
+        interval, interval
+    )
+}
+
+fn launchd_plist(exe: &Path, interval: i64) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>{}</string>
+  <key>ProgramArguments</key>
+  <array>
+    <string>{}</string>
+    <string>sync</string>
+  </array>
+  <key>StartInterval</key>
+  <integer>{}</integer>
+  <key>RunAtLoad</key>
+  <true/>
+</dict>
+</plist>
+"#,
+        LAUNCHD_LABEL,
+        xml_escape(&exe.display().to_string()),
+        interval
+    )
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    let dir = path.parent().context("Service path has no parent")?;
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    std::fs::write(path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+    eprintln!("Wrote {}", path.display());
+    Ok(())
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run {:?}", command))?;
+    if !status.success() {
+        bail!("{:?} exited with status: {}", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units_run_sync_on_the_interval() {
+        let service = systemd_service(Path::new("/usr/bin/plenty"));
+        assert!(service.contains("ExecStart=\"/usr/bin/plenty\" sync\n"));
+        assert!(systemd_timer(900).contains("OnUnitActiveSec=900s\n"));
+        let plist = launchd_plist(Path::new("/opt/a&b/plenty"), 600);
+        assert!(plist.contains("<string>/opt/a&amp;b/plenty</string>"));
+        assert!(plist.contains("<integer>600</integer>"));
+    }
+}
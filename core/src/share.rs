@@ -0,0 +1,210 @@
+use crate::cache::Cache;
+use crate::config::{Config, Namespace};
+use crate::confirm;
+use crate::paths;
+use crate::signing::Signer;
+use crate::upload;
+use anyhow::{bail, Context, Result};
+use plenty_common::{entry_hash, HistoryEntry, HistoryRequest};
+use std::collections::{BTreeSet, HashSet};
+use std::io::{ErrorKind, Write};
+use std::process::{Command, Stdio};
+
+/// What `plenty share` publishes
+#[derive(Debug, Clone, Default)]
+pub struct ShareOptions {
+    /// Namespace to share in; the only configured one if not given
+    pub namespace: Option<String>,
+    /// Share commands containing this text
+    pub pattern: Option<String>,
+    /// Pick the commands to share in fzf
+    pub pick: bool,
+    /// Don't ask for confirmation
+    pub yes: bool,
+}
+
+/// Share the personal entries of the matching or picked commands in a
+/// namespace: upload them to `hosts`, or the namespace's, and mark them in
+/// the cache so that later syncs keep them there
+pub fn run(config: &Config, hosts: &[String], options: &ShareOptions) -> Result<()> {
+    let namespace = pick_namespace(config, options.namespace.as_deref())?;
+    let hosts = if hosts.is_empty() {
+        &namespace.hosts
+    } else {
+        hosts
+    };
+    if hosts.is_empty() {
+        bail!("Namespace {} isn't shared with any host", namespace.name);
+    }
+
+    let mut cache = Cache::open_existing()?;
+    let request = HistoryRequest {
+        pattern: options.pattern.clone(),
+        namespaces: vec![String::new()],
+        ..Default::default()
+    };
+    let mut entries = Vec::new();
+    cache.for_each_entry(&request, |entry| {
+        if !config.sync.is_private(&entry.cmd) {
+            entries.push(entry);
+        }
+        Ok(())
+    })?;
+    if options.pick && !entries.is_empty() {
+        let picked = pick(&entries)?;
+        entries.retain(|entry| picked.contains(&entry.cmd));
+    }
+
+    if entries.is_empty() {
+        eprintln!("No matching commands.");
+        return Ok(());
+    }
+    let commands: BTreeSet<_> = entries.iter().map(|entry| entry.cmd.as_str()).collect();
+    for cmd in &commands {
+        println!("{}", cmd);
+    }
+    // Picking them is confirmation enough
+    if !options.yes && !options.pick {
+        let question = format!(
+            "Share these {} commands ({} entries) in {}?",
+            commands.len(),
+            entries.len(),
+            namespace.name
+        );
+        match confirm(&question)? {
+            Some(true) => {}
+            Some(false) => return Ok(()),
+            None => bail!(
+                "Refusing to share {} commands without --yes",
+                commands.len()
+            ),
+        }
+    }
+
+    let hostname = config.sync.hostname()?;
+    let signer = match config.signing.sign {
+        true => Some(Signer::load_in(&paths::plenty_dir()?)?),
+        false => None,
+    };
+    for host in hosts {
+        eprintln!(
+            "Sharing {} entries in {} with {}…",
+            entries.len(),
+            namespace.name,
+            host
+        );
+        let mut uploads = entries.clone();
+        for entry in &mut uploads {
+            if entry.host.is_empty() {
+                entry.host = hostname.clone();
+            }
+            entry.namespace = namespace.name.clone();
+            if let Some(signer) = &signer {
+                signer.sign(entry);
+            }
+        }
+        upload::upload(
+            config,
+            &paths::plenty_dir()?,
+            host,
+            &hostname,
+            uploads,
+            |server| {
+                if !server.supports("namespaces") {
+                    bail!(
+                        "plentys {} on {} can't keep namespaces apart; upgrade it",
+                        server.version,
+                        host
+                    );
+                }
+                Ok(())
+            },
+        )?;
+    }
+
+    let hashes: Vec<_> = entries
+        .iter()
+        .map(|entry| entry_hash(&entry.cmd, entry.when, &entry.extra))
+        .collect();
+    cache.set_namespace(&hashes, &namespace.name)?;
+    eprintln!("Shared {} entries in {}", entries.len(), namespace.name);
+    Ok(())
+}
+
+/// The configured namespace named `name`, or the only one configured
+fn pick_namespace<'a>(config: &'a Config, name: Option<&str>) -> Result<&'a Namespace> {
+    match (name, config.namespaces.as_slice()) {
+        (Some(name), namespaces) => namespaces
+            .iter()
+            .find(|namespace| namespace.name == name)
+            .with_context(|| format!("No [namespaces.{}] section in the config", name)),
+        (None, [namespace]) => Ok(namespace),
+        (None, []) => bail!("No namespaces configured; add a [namespaces.<name>] section"),
+        (None, _) => bail!("Several namespaces are configured; pick one with --namespace"),
+    }
+}
+
+/// The commands of `entries` picked in fzf, most recent first
+fn pick(entries: &[HistoryEntry]) -> Result<HashSet<String>> {
+    let mut listed = HashSet::new();
+    let mut candidates = Vec::new();
+    for entry in entries.iter().rev() {
+        if listed.insert(entry.cmd.as_str()) {
+            candidates.extend_from_slice(entry.cmd.as_bytes());
+            candidates.push(0);
+        }
+    }
+    let mut fzf = match Command::new("fzf")
+        .args(["--multi", "--read0", "--print0", "--prompt", "share> "])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(fzf) => fzf,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("--pick needs fzf; use --match instead")
+        }
+        Err(e) => return Err(e).context("Failed to start fzf"),
+    };
+    let mut stdin = fzf.stdin.take().context("Failed to get fzf stdin")?;
+    // fzf may exit before reading everything
+    let _ = stdin.write_all(&candidates);
+    drop(stdin);
+    let output = fzf.wait_with_output().context("Failed to wait for fzf")?;
+    // Nothing picked, or cancelled
+    if !output.status.success() {
+        return Ok(HashSet::new());
+    }
+    Ok(output
+        .stdout
+        .split(|&b| b == 0)
+        .filter(|cmd| !cmd.is_empty())
+        .map(|cmd| String::from_utf8_lossy(cmd).into_owned())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_only_namespace_is_picked_by_default() {
+        let mut config = Config::default();
+        assert!(pick_namespace(&config, None).is_err());
+        config.namespaces.push(Namespace {
+            name: "team-infra".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(pick_namespace(&config, None).unwrap().name, "team-infra");
+        assert!(pick_namespace(&config, Some("team-cloud")).is_err());
+        config.namespaces.push(Namespace {
+            name: "team-cloud".to_string(),
+            ..Default::default()
+        });
+        assert!(pick_namespace(&config, None).is_err());
+        assert_eq!(
+            pick_namespace(&config, Some("team-cloud")).unwrap().name,
+            "team-cloud"
+        );
+    }
+}
@@ -0,0 +1,253 @@
+/// Signed entries, for tamper evidence: with `[signing] sign = true`, each
+/// entry a device uploads carries its ed25519 signature, which the server
+/// stores along with it, and `plenty verify` checks the entries on servers
+/// against this device's key and the trusted ones, to find those injected or
+/// modified server-side
+use crate::config::Config;
+use crate::connection::{Connection, RemoteCommand};
+use crate::paths;
+use crate::sync::{receive_history, Received};
+use anyhow::{anyhow, bail, Context, Result};
+use plenty_common::{json, HistoryEntry, HistoryRequest, MessageType};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Bytes of a public key, then of a signature
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+/// A device's signing key
+pub struct Signer {
+    pair: Ed25519KeyPair,
+}
+
+impl Signer {
+    /// The key kept in `dir`, plenty's data directory, generated the first
+    /// time, and only readable by its owner
+    pub fn load_in(dir: &Path) -> Result<Self> {
+        let path = dir.join("signing.key");
+        let pkcs8 = match std::fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .map_err(|_| anyhow!("Failed to generate a signing key"))?;
+                let created = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&path);
+                match created {
+                    Ok(mut file) => file
+                        .write_all(pkcs8.as_ref())
+                        .with_context(|| format!("Failed to write {}", path.display()))?,
+                    // Another plenty generated one meanwhile
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                        return Self::load_in(dir)
+                    }
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("Failed to create {}", path.display()))
+                    }
+                }
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| anyhow!("Invalid signing key in {}: {}", path.display(), e))?;
+        Ok(Signer { pair })
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        let mut key = [0; PUBLIC_KEY_LEN];
+        key.copy_from_slice(self.pair.public_key().as_ref());
+        key
+    }
+
+    /// Sign `entry` as uploaded, once its host is set
+    pub fn sign(&self, entry: &mut HistoryEntry) {
+        let signature = self.pair.sign(&entry.signed_content());
+        entry.signature = [self.pair.public_key().as_ref(), signature.as_ref()].concat();
+    }
+}
+
+/// What checking an entry's signature finds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Verified,
+    Unsigned,
+    /// Validly signed, by a key that isn't trusted
+    UnknownKey,
+    /// Its signature doesn't match the entry
+    Invalid,
+}
+
+impl Verdict {
+    fn name(self) -> &'static str {
+        match self {
+            Verdict::Verified => "verified",
+            Verdict::Unsigned => "unsigned",
+            Verdict::UnknownKey => "unknown key",
+            Verdict::Invalid => "invalid",
+        }
+    }
+}
+
+/// Check the signature of `entry` against the `trusted` keys
+pub fn verify(entry: &HistoryEntry, trusted: &[[u8; PUBLIC_KEY_LEN]]) -> Verdict {
+    if entry.signature.is_empty() {
+        return Verdict::Unsigned;
+    }
+    if entry.signature.len() != PUBLIC_KEY_LEN + SIGNATURE_LEN {
+        return Verdict::Invalid;
+    }
+    let (key, signature) = entry.signature.split_at(PUBLIC_KEY_LEN);
+    if UnparsedPublicKey::new(&ED25519, key)
+        .verify(&entry.signed_content(), signature)
+        .is_err()
+    {
+        return Verdict::Invalid;
+    }
+    if trusted.iter().any(|trusted| trusted == key) {
+        Verdict::Verified
+    } else {
+        Verdict::UnknownKey
+    }
+}
+
+/// How many entries of a server got each verdict
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Report {
+    pub verified: usize,
+    pub unsigned: usize,
+    pub unknown_key: usize,
+    pub invalid: usize,
+}
+
+impl Report {
+    pub fn failed(&self) -> usize {
+        self.unsigned + self.unknown_key + self.invalid
+    }
+}
+
+/// `plenty verify`: check the entries of each host run at or after `since`,
+/// printing those that fail, one per line as their verdict, a tab and the
+/// entry in JSON; returns whether every entry was verified
+pub fn run(config: &Config, hosts: &[String], since: Option<i64>) -> Result<bool> {
+    if hosts.is_empty() {
+        bail!("No hosts to verify; configure hosts or give them as arguments");
+    }
+    let mut trusted = config.signing.trusted_keys.clone();
+    trusted.push(Signer::load_in(&paths::plenty_dir()?)?.public_key());
+    let mut all_verified = true;
+    for host in hosts {
+        let report = verify_host(host, &config.remote, since, &trusted)
+            .with_context(|| format!("Failed to verify {}", host))?;
+        eprintln!(
+            "{}: {} verified, {} unsigned, {} signed by unknown keys, {} invalid",
+            host, report.verified, report.unsigned, report.unknown_key, report.invalid
+        );
+        all_verified &= report.failed() == 0;
+    }
+    Ok(all_verified)
+}
+
+fn verify_host(
+    host: &str,
+    remote: &RemoteCommand,
+    since: Option<i64>,
+    trusted: &[[u8; PUBLIC_KEY_LEN]],
+) -> Result<Report> {
+    let mut connection = Connection::open(host, remote)?;
+    connection.handshake()?;
+    let mut stdout = std::io::stdout().lock();
+    let report = check(&mut connection, since, trusted, |verdict, entry| {
+        writeln!(stdout, "{}\t{}", verdict.name(), json::entry(entry))
+            .context("Failed to write entry")
+    })?;
+    connection.close()?;
+    Ok(report)
+}
+
+/// Check the entries of the server on `connection` run at or after `since`,
+/// passing those failing to `on_failure`
+pub fn check(
+    connection: &mut Connection,
+    since: Option<i64>,
+    trusted: &[[u8; PUBLIC_KEY_LEN]],
+    mut on_failure: impl FnMut(Verdict, &HistoryEntry) -> Result<()>,
+) -> Result<Report> {
+    let request = HistoryRequest {
+        since,
+        ..Default::default()
+    };
+    connection.send(MessageType::GetHistory, request.encode())?;
+    let mut report = Report::default();
+    receive_history(&mut connection.reader, |received| {
+        let Received::Entry(entry) = received else {
+            return Ok(());
+        };
+        let verdict = verify(&entry, trusted);
+        match verdict {
+            Verdict::Verified => report.verified += 1,
+            Verdict::Unsigned => report.unsigned += 1,
+            Verdict::UnknownKey => report.unknown_key += 1,
+            Verdict::Invalid => report.invalid += 1,
+        }
+        if verdict != Verdict::Verified {
+            on_failure(verdict, &entry)?;
+        }
+        Ok(())
+    })?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::vectors::{vectors, Expected};
+
+    #[test]
+    fn signatures_hold_until_entries_change() {
+        let dir = std::env::temp_dir().join(format!("plenty-signing-test-{}", std::process::id()));
+        let signer = Signer::load_in(&dir).unwrap();
+        assert_eq!(
+            Signer::load_in(&dir).unwrap().public_key(),
+            signer.public_key()
+        );
+        let trusted = [signer.public_key()];
+
+        let mut entry =
+            HistoryEntry::new("make".to_string(), 1, String::new()).with_host("laptop".to_string());
+        assert_eq!(verify(&entry, &trusted), Verdict::Unsigned);
+        signer.sign(&mut entry);
+        assert_eq!(verify(&entry, &trusted), Verdict::Verified);
+        assert_eq!(verify(&entry, &[]), Verdict::UnknownKey);
+        // Servers record where and when entries came from
+        entry.received_at = Some(2);
+        entry.namespace = "team-infra".to_string();
+        assert_eq!(verify(&entry, &trusted), Verdict::Verified);
+        entry.cmd = "make deploy".to_string();
+        assert_eq!(verify(&entry, &trusted), Verdict::Invalid);
+        entry.signature.pop();
+        assert_eq!(verify(&entry, &trusted), Verdict::Invalid);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The protocol's own signed vector
+        let signed = vectors()
+            .unwrap()
+            .into_iter()
+            .find(|vector| vector.name == "signed")
+            .unwrap();
+        let Expected::Canonical(entry) = signed.expected else {
+            panic!("The signed vector isn't canonical");
+        };
+        let key: [u8; 32] = entry.signature[..32].try_into().unwrap();
+        assert_eq!(verify(&entry, &[key]), Verdict::Verified);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::paths;
+use anyhow::{Context, Result};
+use plenty_common::config::{Document, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Client state persisted between runs in `state.toml` of plenty's data
+/// directory, `$XDG_DATA_HOME/plenty`
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    /// Unix time of the last successful sync, per host
+    pub last_sync: BTreeMap<String, i64>,
+    /// The server's high-water mark at the last successful sync, per host
+    pub high_water_mark: BTreeMap<String, u64>,
+}
+
+impl State {
+    fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join("state.toml")
+    }
+
+    pub fn load() -> Result<Self> {
+        Self::load_in(&paths::plenty_dir()?)
+    }
+
+    /// The state kept in the data directory `data_dir`
+    pub fn load_in(data_dir: &Path) -> Result<Self> {
+        let path = Self::path(data_dir);
+        let doc = Document::load(&path)
+            .with_context(|| format!("Failed to load state from {}", path.display()))?;
+
+        let mut state = State::default();
+        for host in doc.keys("last_sync") {
+            if let Some(Value::Integer(when)) = doc.get("last_sync", host) {
+                state.last_sync.insert(host.to_string(), *when);
+            }
+        }
+        for host in doc.keys("high_water_mark") {
+            if let Some(Value::Integer(mark)) = doc.get("high_water_mark", host) {
+                state
+                    .high_water_mark
+                    .insert(host.to_string(), (*mark).max(0) as u64);
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn save_in(&self, data_dir: &Path) -> Result<()> {
+        let path = Self::path(data_dir);
+        let dir = path.parent().context("State path has no parent")?;
+        std::fs::create_dir_all(dir).context("Failed to create plenty data directory")?;
+
+        let mut content = String::from("[last_sync]\n");
+        for (host, when) in &self.last_sync {
+            content.push_str(&format!("{:?} = {}\n", host, when));
+        }
+        content.push_str("\n[high_water_mark]\n");
+        for (host, mark) in &self.high_water_mark {
+            content.push_str(&format!("{:?} = {}\n", host, mark));
+        }
+
+        let tmp_path = path.with_extension("toml.tmp");
+        std::fs::write(&tmp_path, content).context("Failed to write state file")?;
+        std::fs::rename(&tmp_path, &path).context("Failed to replace state file")?;
+        Ok(())
+    }
+
+    /// Record a successful sync with `host` at `when` in the data directory
+    /// `data_dir`, with the high-water mark the server ended it with
+    pub fn record_sync(data_dir: &Path, host: &str, when: i64, mark: Option<u64>) -> Result<()> {
+        // Hosts synced concurrently must not overwrite each other's updates
+        static LOCK: Mutex<()> = Mutex::new(());
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut state = Self::load_in(data_dir)?;
+        state.last_sync.insert(host.to_string(), when);
+        if let Some(mark) = mark {
+            state.high_water_mark.insert(host.to_string(), mark);
+        }
+        state.save_in(data_dir)
+    }
+}
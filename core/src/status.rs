@@ -0,0 +1,70 @@
+use crate::config::Config;
+use crate::connection::{Connection, RemoteCommand};
+use crate::now;
+use crate::paths;
+use crate::state::State;
+use anyhow::{Context, Result};
+use plenty_common::fish;
+
+/// Print a read-only summary of the local and remote sync state
+pub fn run(config: &Config) -> Result<()> {
+    let state = State::load()?;
+    let now = now()?;
+
+    let history_path = paths::fish_dir()?.join("fish_history");
+    match std::fs::read(&history_path) {
+        Ok(content) => {
+            let entries = fish::parse_history(&content).context("Failed to parse fish_history")?;
+            println!(
+                "Local entries: {} ({})",
+                entries.len(),
+                history_path.display()
+            );
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!(
+                "Local entries: 0 ({} does not exist)",
+                history_path.display()
+            );
+        }
+        Err(e) => return Err(e).context("Failed to read fish_history"),
+    }
+
+    if config.hosts.is_empty() {
+        println!("Configured hosts: none");
+    }
+    for host in &config.hosts {
+        println!("Host {}:", host);
+        match state.last_sync.get(host) {
+            Some(when) => println!("  Last successful sync: {}", format_age(now - when)),
+            None => println!("  Last successful sync: never"),
+        }
+        if let Some(mark) = state.high_water_mark.get(host) {
+            println!("  Server high-water mark: {}", mark);
+        }
+        match server_entry_count(host, &config.remote) {
+            Ok(count) => println!("  Server entries: {}", count),
+            Err(e) => println!("  Server entries: unavailable ({:#})", e),
+        }
+    }
+
+    Ok(())
+}
+
+fn server_entry_count(host: &str, remote: &RemoteCommand) -> Result<u64> {
+    let mut connection = Connection::open(host, remote)?;
+    connection.handshake()?;
+    let stats = connection.stats()?;
+    connection.close()?;
+    Ok(stats.entries)
+}
+
+fn format_age(seconds: i64) -> String {
+    match seconds {
+        s if s < 0 => "in the future (clock skew?)".to_string(),
+        s if s < 120 => format!("{}s ago", s),
+        s if s < 7200 => format!("{}m ago", s / 60),
+        s if s < 172800 => format!("{}h ago", s / 3600),
+        s => format!("{}d ago", s / 86400),
+    }
+}
@@ -0,0 +1,65 @@
+use crate::agent;
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::connection::{Connection, RemoteCommand};
+use anyhow::{bail, Result};
+use plenty_common::{SuggestQuery, Suggestion};
+use std::io::Write;
+
+/// Print the commands best worth suggesting for `query`, best first, each
+/// followed by `separator`: from the local cache unless `remote`, as it
+/// answers without a round trip, or from the first of `hosts` if there is
+/// no cache yet
+pub fn run(
+    config: &Config,
+    hosts: &[String],
+    query: &SuggestQuery,
+    remote: bool,
+    separator: u8,
+) -> Result<()> {
+    let suggestions = if !remote && Cache::path()?.exists() {
+        Cache::open_existing()?.suggest(query)?
+    } else {
+        match hosts.first() {
+            Some(host) => from_server(host, &config.remote, query)?,
+            None => bail!("No history cache and no host to ask; run plenty sync first"),
+        }
+    };
+    let mut out = std::io::stdout().lock();
+    for suggestion in suggestions {
+        out.write_all(suggestion.cmd.as_bytes())?;
+        out.write_all(&[separator])?;
+    }
+    Ok(())
+}
+
+/// The commands `host` suggests for `query`, through its `plenty agentd` if
+/// one is running, or over a new ssh session
+fn from_server(
+    host: &str,
+    remote: &RemoteCommand,
+    query: &SuggestQuery,
+) -> Result<Vec<Suggestion>> {
+    if let Some(mut agent) = agent::Client::open(host)? {
+        if !agent.hello.supports("suggest") {
+            bail!(
+                "plentys {} on {} can't suggest commands; upgrade it",
+                agent.hello.version,
+                host
+            );
+        }
+        return agent.suggest(query);
+    }
+    let mut connection = Connection::open(host, remote)?;
+    let hello = connection.handshake()?;
+    if !hello.supports("suggest") {
+        bail!(
+            "plentys {} on {} can't suggest commands; upgrade it",
+            hello.version,
+            host
+        );
+    }
+    let suggestions = connection.suggest(query)?;
+    connection.close()?;
+    Ok(suggestions)
+}
@@ -0,0 +1,1004 @@
+use crate::bootstrap::{Bootstrap, FirstSync};
+use crate::cache::Cache;
+use crate::config::{Config, SyncOptions};
+use crate::connection::{self, Connection};
+use crate::filter::SyncFilter;
+use crate::now;
+use crate::order::HistoryOrder;
+use crate::paths;
+use crate::signing::Signer;
+use crate::state::State;
+use crate::upload;
+use anyhow::{bail, Context, Result};
+use nix::fcntl::{Flock, FlockArg};
+use plenty_common::{
+    buckets, decode_hashes, entry_hash, fish, Bucket, FlushPolicy, Hello, HistoryEntry, Message,
+    MessageType, SessionSummary, TieBreak, ENTRY_CHUNK_SIZE,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Seconds before the last sync from which deletions are asked for, so that
+/// those made during it, or behind a skewed clock, aren't missed
+const TOMBSTONE_OVERLAP: i64 = 3600;
+/// Times a commit looks for the current fish_history again after finding it
+/// was replaced while it waited for its lock
+const COMMIT_ATTEMPTS: usize = 5;
+/// Bytes of uploads sent at a time, so the server stores them while more
+/// come, in writes large enough that small entries don't cost a syscall each
+const UPLOAD_FLUSH: usize = ENTRY_CHUNK_SIZE;
+
+/// fish_history as a sync or forget read it, so that what fish writes to it
+/// meanwhile is merged in rather than overwritten: fish appends entries
+/// holding a lock on the file, and rewrites it through a temporary file moved
+/// over it once it has checked the file didn't change since it read it
+pub(crate) struct HistoryVersion {
+    /// Device, inode, size and modification time of the file read
+    id: (u64, u64, u64, i64, i64),
+    /// Hashes of the entries it held
+    hashes: HashSet<u64>,
+}
+
+impl HistoryVersion {
+    fn id_of(metadata: &std::fs::Metadata) -> (u64, u64, u64, i64, i64) {
+        (
+            metadata.dev(),
+            metadata.ino(),
+            metadata.len(),
+            metadata.mtime(),
+            metadata.mtime_nsec(),
+        )
+    }
+}
+
+/// Writes a new fish_history next to the current one, replacing it atomically on commit.
+/// The temporary file is removed if the writer is dropped without committing.
+/// Entries must come in `when` order; exact duplicates are written once.
+pub(crate) struct HistoryWriter {
+    target: PathBuf,
+    tmp_path: PathBuf,
+    /// The history the entries written came from
+    read: HistoryVersion,
+    out: Option<BufWriter<File>>,
+    written: usize,
+    /// Time of the last entry written, and the (cmd, extra) written at that time
+    last_when: Option<i64>,
+    at_last_when: HashSet<(String, String)>,
+}
+
+impl HistoryWriter {
+    pub(crate) fn create(history_path: &Path, read: HistoryVersion) -> Result<Self> {
+        // Write next to the real file so a symlinked fish_history keeps working
+        let target =
+            std::fs::canonicalize(history_path).context("Failed to resolve fish_history path")?;
+        let tmp_path = target.with_extension("plenty-tmp");
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .context("Failed to create temporary fish_history")?;
+        Ok(HistoryWriter {
+            target,
+            tmp_path,
+            read,
+            out: Some(BufWriter::new(file)),
+            written: 0,
+            last_when: None,
+            at_last_when: HashSet::new(),
+        })
+    }
+
+    pub(crate) fn write(&mut self, entry: &HistoryEntry) -> Result<()> {
+        let out = self
+            .out
+            .as_mut()
+            .context("History writer already committed")?;
+        if self.last_when != Some(entry.when) {
+            self.last_when = Some(entry.when);
+            self.at_last_when.clear();
+        }
+        if !self
+            .at_last_when
+            .insert((entry.cmd.clone(), entry.extra.clone()))
+        {
+            return Ok(());
+        }
+        fish::write_entry(out, entry).context("Failed to write fish_history")?;
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Flush, sync and move the new history into place, returning the number
+    /// of entries and those fish added to the history since it was read,
+    /// which are merged in
+    pub(crate) fn commit(mut self) -> Result<(usize, Vec<HistoryEntry>)> {
+        let out = self
+            .out
+            .take()
+            .context("History writer already committed")?;
+        let file = out
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .context("Failed to write fish_history")?;
+        file.sync_all()
+            .context("Failed to sync fish_history to disk")?;
+        let result = self.replace();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+        result
+    }
+
+    /// Move the new history over the current one, holding the lock fish
+    /// appends with, after merging in what fish added since it was read
+    fn replace(&mut self) -> Result<(usize, Vec<HistoryEntry>)> {
+        for _ in 0..COMMIT_ATTEMPTS {
+            let current = File::open(&self.target).context("Failed to open fish_history")?;
+            let current = Flock::lock(current, FlockArg::LockExclusive)
+                .map_err(|(_, errno)| errno)
+                .context("Failed to lock fish_history")?;
+            let metadata = current
+                .metadata()
+                .context("Failed to read fish_history metadata")?;
+            let path_metadata =
+                std::fs::metadata(&self.target).context("Failed to read fish_history metadata")?;
+            // Replaced while this waited for the lock: lock the new one
+            if (metadata.dev(), metadata.ino()) != (path_metadata.dev(), path_metadata.ino()) {
+                continue;
+            }
+            let mut added = Vec::new();
+            if HistoryVersion::id_of(&metadata) != self.read.id {
+                let mut content = Vec::new();
+                (&*current)
+                    .read_to_end(&mut content)
+                    .context("Failed to read fish_history")?;
+                added = fish::parse_history(&content)
+                    .context("Failed to parse fish_history")?
+                    .into_iter()
+                    .filter(|entry| {
+                        !self.read.hashes.contains(&entry_hash(
+                            &entry.cmd,
+                            entry.when,
+                            &entry.extra,
+                        ))
+                    })
+                    .collect();
+                if !added.is_empty() {
+                    eprintln!(
+                        "Merging {} entries fish added to its history meanwhile",
+                        added.len()
+                    );
+                    self.merge(&added)?;
+                }
+            }
+            std::fs::rename(&self.tmp_path, &self.target)
+                .context("Failed to replace fish_history")?;
+            return Ok((self.written, added));
+        }
+        bail!("fish_history kept being replaced while waiting for its lock; try again")
+    }
+
+    /// Rewrite the new history with `added` among its entries, in `when` order
+    fn merge(&mut self, added: &[HistoryEntry]) -> Result<()> {
+        let content = std::fs::read(&self.tmp_path).context("Failed to read new fish_history")?;
+        let written = fish::parse_history(&content).context("Failed to parse new fish_history")?;
+        let file = File::create(&self.tmp_path).context("Failed to rewrite new fish_history")?;
+        let mut out = BufWriter::new(file);
+        let mut added = added.iter().peekable();
+        for entry in &written {
+            while let Some(late) = added.next_if(|late| late.when < entry.when) {
+                fish::write_entry(&mut out, late).context("Failed to write fish_history")?;
+                self.written += 1;
+            }
+            fish::write_entry(&mut out, entry).context("Failed to write fish_history")?;
+        }
+        for late in added {
+            fish::write_entry(&mut out, late).context("Failed to write fish_history")?;
+            self.written += 1;
+        }
+        out.into_inner()
+            .map_err(|e| e.into_error())
+            .context("Failed to write fish_history")?
+            .sync_all()
+            .context("Failed to sync fish_history to disk")
+    }
+}
+
+impl Drop for HistoryWriter {
+    fn drop(&mut self) {
+        if self.out.is_some() {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Outcome of a successful sync, exposed to the post-sync hook
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub uploaded: usize,
+    pub received: usize,
+    pub written: usize,
+    /// What the server did with the uploads, unless only pushing
+    pub summary: Option<SessionSummary>,
+}
+
+/// Which way a sync exchanges history
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    /// Upload local entries, then write back the server's
+    #[default]
+    Both,
+    /// Merge the server's entries into the local history, uploading nothing
+    Pull,
+    /// Upload local entries, leaving the local history untouched
+    Push,
+}
+
+/// How a sync run exchanges history, from the command line
+#[derive(Debug, Clone, Default)]
+pub struct SyncArgs {
+    pub filter: SyncFilter,
+    pub first_sync: FirstSync,
+    pub direction: Direction,
+}
+
+/// The machine's side of a sync: its fish history, and where plenty keeps
+/// what it learns from syncs
+#[derive(Debug, Clone)]
+pub struct Local {
+    /// fish_history, created if missing; its directory is locked while syncing
+    pub history: PathBuf,
+    /// plenty's data directory, holding the cache and the sync state
+    pub data_dir: PathBuf,
+    /// Whether to have fish reload the history once rewritten
+    pub refresh_fish: bool,
+}
+
+impl Local {
+    /// This user's fish history and plenty data directory
+    pub fn current() -> Result<Self> {
+        Ok(Local {
+            history: paths::fish_dir()?.join("fish_history"),
+            data_dir: paths::plenty_dir()?,
+            refresh_fish: true,
+        })
+    }
+}
+
+/// Sync with every host, up to `config.sync.jobs` at once, then summarize
+/// the outcome per host when there are several
+pub fn sync_hosts(hosts: &[String], config: &Config, args: &SyncArgs) -> Result<()> {
+    let local = Local::current()?;
+    if let [host] = hosts {
+        return sync_host(host, &local, config, args).map(|_| ());
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<SyncReport>>>> =
+        Mutex::new(hosts.iter().map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..config.sync.jobs.clamp(1, hosts.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(host) = hosts.get(i) else {
+                    break;
+                };
+                let result = sync_host(host, &local, config, args);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    let mut failed = 0;
+    eprintln!("Summary:");
+    for (host, result) in hosts.iter().zip(results.into_inner().unwrap()) {
+        match result.unwrap_or_else(|| Err(anyhow::anyhow!("Sync thread panicked"))) {
+            Ok(report) => eprintln!(
+                "ok    {}: uploaded {}{}, received {}, wrote {}",
+                host,
+                report.uploaded,
+                report.summary.as_ref().map_or_else(String::new, stored),
+                report.received,
+                report.written
+            ),
+            Err(e) => {
+                failed += 1;
+                eprintln!("FAIL  {}: {:#}", host, e);
+            }
+        }
+    }
+    if failed == hosts.len() || (failed > 0 && config.sync.fail_on_any) {
+        bail!("Sync failed with {} of {} hosts", failed, hosts.len());
+    }
+    Ok(())
+}
+
+/// What the server did with the uploads, as shown after their count
+fn stored(summary: &SessionSummary) -> String {
+    match (summary.duplicates, summary.rejected) {
+        (Some(duplicates), Some(rejected)) => format!(
+            " (stored {}, {} already there, {} rejected)",
+            summary.stored, duplicates, rejected
+        ),
+        _ => format!(" (stored {})", summary.stored),
+    }
+}
+
+fn sync_host(host: &str, local: &Local, config: &Config, args: &SyncArgs) -> Result<SyncReport> {
+    config.hooks.run_pre_sync(host)?;
+    // What earlier uploads couldn't get stored goes first
+    let device = config.sync.hostname()?;
+    if let Err(e) = upload::upload(config, &local.data_dir, host, &device, Vec::new(), |_| {
+        Ok(())
+    }) {
+        eprintln!("Failed to send the entries spooled for {}: {:#}", host, e);
+    }
+    // Connect before taking the lock, so that syncs with several hosts at
+    // once only wait on each other for the exchange itself
+    eprintln!("Connecting to {}…", host);
+    let result = Connection::open(host, &config.remote).and_then(|mut connection| {
+        connection.limit_rate(config.sync.limit_rate);
+        sync(connection, local, host, config, args)
+    });
+    config.hooks.run_post_sync(host, &result);
+    result
+}
+
+/// Sync `local` with the server at the other end of `connection`, `host`
+/// naming it in the configuration and the sync state: the handshake, the
+/// exchange, fish_history rewritten and the sync recorded
+pub fn sync(
+    mut connection: Connection,
+    local: &Local,
+    host: &str,
+    config: &Config,
+    args: &SyncArgs,
+) -> Result<SyncReport> {
+    let server = connection.handshake_as(config.sync.hostname()?)?;
+    check_clock_skew(&config.sync, server.time, now()?)?;
+
+    let report = with_history_locked(&local.history, || {
+        sync_with_server(host, &server, connection, local, config, args)
+    })?;
+    let mark = report
+        .summary
+        .as_ref()
+        .map(|summary| summary.high_water_mark);
+    State::record_sync(&local.data_dir, host, now()?, mark)
+        .context("Failed to record sync state")?;
+    Ok(report)
+}
+
+/// Run `f` with the directory of fish_history locked, creating the file if
+/// missing
+pub(crate) fn with_history_locked<T>(
+    history_path: &Path,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let fish_dir = history_path
+        .parent()
+        .context("fish_history path has no parent")?;
+
+    std::fs::create_dir_all(fish_dir).context("Failed to create fish directory")?;
+
+    let lock_dir =
+        std::fs::File::open(fish_dir).context("Failed to open fish directory for locking")?;
+
+    eprintln!("Acquiring lock on fish directory…");
+    let lock = Flock::lock(lock_dir, FlockArg::LockExclusive)
+        .map_err(|(_, errno)| errno)
+        .context("Failed to acquire lock on fish directory")?;
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(history_path)
+        .context("Failed to open fish_history file")?;
+
+    let result = f();
+
+    lock.unlock()
+        .map_err(|(_, errno)| errno)
+        .context("Failed to release lock on fish directory")?;
+
+    result
+}
+
+/// The entries of fish_history, read holding the lock fish appends with so
+/// that none is half-written, and the version of the file they come from
+pub(crate) fn read_local_history(
+    history_path: &Path,
+) -> Result<(Vec<HistoryEntry>, HistoryVersion)> {
+    eprintln!("Reading local fish history…");
+    let file = File::open(history_path).context("Failed to open fish_history file")?;
+    let file = Flock::lock(file, FlockArg::LockShared)
+        .map_err(|(_, errno)| errno)
+        .context("Failed to lock fish_history")?;
+    let mut content = Vec::new();
+    let mut reader = BufReader::new(&*file);
+    reader
+        .read_to_end(&mut content)
+        .context("Failed to read fish_history")?;
+    let metadata = file
+        .metadata()
+        .context("Failed to read fish_history metadata")?;
+    drop(file);
+
+    let local_entries = fish::parse_history(&content).context("Failed to parse fish_history")?;
+    let version = HistoryVersion {
+        id: HistoryVersion::id_of(&metadata),
+        hashes: local_entries
+            .iter()
+            .map(|entry| entry_hash(&entry.cmd, entry.when, &entry.extra))
+            .collect(),
+    };
+
+    eprintln!("Found {} local history entries", local_entries.len());
+    Ok((local_entries, version))
+}
+
+/// Have fish pick up the rewritten history file
+pub(crate) fn refresh_fish() -> Result<()> {
+    eprintln!("Running 'fish -c \"history merge\"' to refresh fish state…");
+    let status = Command::new("fish")
+        .args(["-c", "history merge"])
+        .status()
+        .context("Failed to execute fish history merge")?;
+
+    if !status.success() {
+        bail!("fish history merge exited with status: {}", status);
+    }
+    Ok(())
+}
+
+/// Warn about (or refuse) a server clock too far from ours: `when` ordering and
+/// the local age cap both assume sane clocks on every machine.
+fn check_clock_skew(options: &SyncOptions, server_time: Option<i64>, now: i64) -> Result<()> {
+    let Some(server_time) = server_time else {
+        return Ok(());
+    };
+    let skew = server_time - now;
+    if skew.abs() <= options.max_clock_skew {
+        return Ok(());
+    }
+    let message = format!(
+        "server clock is {}s {} the local clock",
+        skew.abs(),
+        if skew > 0 { "ahead of" } else { "behind" }
+    );
+    if options.abort_on_clock_skew {
+        bail!("Refusing to sync: {}", message);
+    }
+    eprintln!("Warning: {}; entry timestamps may be wrong", message);
+    Ok(())
+}
+
+/// The namespace `entry` is uploaded in: the one it was shared or received
+/// in, if any, or else the one the configuration puts its command in
+fn namespace<'a>(
+    config: &'a Config,
+    marked: &'a HashMap<u64, String>,
+    entry: &HistoryEntry,
+) -> &'a str {
+    marked
+        .get(&entry_hash(&entry.cmd, entry.when, &entry.extra))
+        .map_or_else(|| config.namespace_of(&entry.cmd), String::as_str)
+}
+
+/// Whether a local entry is written before a received one, so that entries
+/// sharing a timestamp come out in the same order on every machine
+fn goes_before(tie_break: TieBreak, local: &HistoryEntry, received: &HistoryEntry) -> bool {
+    match local.when.cmp(&received.when) {
+        std::cmp::Ordering::Less => true,
+        std::cmp::Ordering::Greater => false,
+        std::cmp::Ordering::Equal => match tie_break {
+            // Local entries aren't on the server yet, so they come first
+            TieBreak::Insertion => true,
+            TieBreak::Host => local.host <= received.host,
+            TieBreak::Command => local.cmd <= received.cmd,
+        },
+    }
+}
+
+/// What the server sends in reply to GetHistory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Received {
+    Entry(Box<HistoryEntry>),
+    /// Hashes of entries deleted on the server, sent before any entry
+    Deleted(Vec<u64>),
+}
+
+/// Read HistoryEntry messages, or a Snapshot of them, until End, handing each
+/// entry, and the Tombstones before them, to `on_received` as they arrive;
+/// returns how many entries were received, and the summary End holds
+pub(crate) fn receive_history<R: Read>(
+    reader: &mut R,
+    mut on_received: impl FnMut(Received) -> Result<()>,
+) -> Result<(usize, Option<SessionSummary>)> {
+    let mut received = 0;
+
+    loop {
+        let msg = connection::recv_from(reader)?;
+
+        match msg.msg_type {
+            MessageType::HistoryEntry => {
+                let entry = HistoryEntry::decode(&msg.data)
+                    .context("Failed to decode history entry from server")?;
+                on_received(Received::Entry(Box::new(entry)))?;
+                received += 1;
+            }
+            MessageType::Snapshot => {
+                let mut snapshot = zstd::Decoder::new(&msg.data[..])
+                    .context("Failed to start decompressing snapshot")?;
+                loop {
+                    let msg = match Message::read_from(&mut snapshot) {
+                        Ok(msg) => msg,
+                        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                        Err(e) => return Err(e).context("Failed to decompress snapshot"),
+                    };
+                    if msg.msg_type != MessageType::HistoryEntry {
+                        bail!("Unexpected {:?} message in snapshot", msg.msg_type);
+                    }
+                    let entry = HistoryEntry::decode(&msg.data)
+                        .context("Failed to decode history entry from snapshot")?;
+                    on_received(Received::Entry(Box::new(entry)))?;
+                    received += 1;
+                }
+            }
+            MessageType::Tombstones => {
+                let hashes =
+                    decode_hashes(&msg.data).context("Failed to decode tombstones from server")?;
+                on_received(Received::Deleted(hashes))?;
+            }
+            // Uploads are all confirmed by the summary in End
+            MessageType::Stored => {}
+            MessageType::End => {
+                // Empty from servers older than summaries
+                let summary = match msg.data.is_empty() {
+                    true => None,
+                    false => Some(
+                        SessionSummary::decode(&msg.data)
+                            .context("Failed to decode session summary from server")?,
+                    ),
+                };
+                return Ok((received, summary));
+            }
+            _ => {
+                bail!("Unexpected message type from server");
+            }
+        }
+    }
+}
+
+/// Leave out the uploads in buckets of time whose entries the server already
+/// holds exactly, so that syncing an unchanged history sends almost nothing
+fn unstored_uploads(uploads: Vec<HistoryEntry>, stored: &[Bucket]) -> Vec<HistoryEntry> {
+    let stored: HashSet<_> = stored.iter().collect();
+    let unchanged: HashSet<_> = buckets(&uploads)
+        .into_iter()
+        .filter(|bucket| stored.contains(bucket))
+        .map(|bucket| bucket.start)
+        .collect();
+    uploads
+        .into_iter()
+        .filter(|entry| !unchanged.contains(&Bucket::start_of(entry.when)))
+        .collect()
+}
+
+fn sync_with_server(
+    host: &str,
+    server: &Hello,
+    mut connection: Connection,
+    local: &Local,
+    config: &Config,
+    args: &SyncArgs,
+) -> Result<SyncReport> {
+    let (local_entries, read) = read_local_history(&local.history)?;
+    // Where each local entry was, for the order keeping it
+    let local_positions: HashMap<u64, usize> = if config.sync.order == HistoryOrder::Local {
+        local_entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (entry_hash(&entry.cmd, entry.when, &entry.extra), i))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    // fish_private_mode is set when we're run from `fish --private`
+    let private_mode = std::env::var_os("fish_private_mode").is_some_and(|v| !v.is_empty());
+    if private_mode {
+        eprintln!("fish private mode is on, not uploading anything");
+    }
+
+    // Hosts namespaces are shared with only get, and send, their entries
+    let shared = config.shared_with(host);
+    if !shared.is_empty() && !server.supports("namespaces") {
+        bail!(
+            "plentys {} on {} can't keep namespaces apart; upgrade it",
+            server.version,
+            host
+        );
+    }
+
+    let mut cache = Cache::open_in(&local.data_dir)?;
+    let marked = cache.entry_namespaces()?;
+
+    // Entries outside the filter or the namespaces shared with the host, and
+    // private ones, are neither uploaded nor replaced: they are merged back,
+    // in order, into the history received from the server. When pulling,
+    // that's every local entry.
+    let uploading = !private_mode && args.direction != Direction::Pull;
+    let (uploads, mut untouched): (Vec<_>, Vec<_>) = local_entries.into_iter().partition(|entry| {
+        uploading
+            && args.filter.matches(entry)
+            && !config.sync.is_private(&entry.cmd)
+            && (shared.is_empty()
+                || shared
+                    .iter()
+                    .any(|n| n == namespace(config, &marked, entry)))
+    });
+    let tie_break = config.sync.tie_break;
+    untouched.sort_by(|a, b| {
+        a.when.cmp(&b.when).then_with(|| match tie_break {
+            TieBreak::Command => a.cmd.cmp(&b.cmd),
+            _ => std::cmp::Ordering::Equal,
+        })
+    });
+    let mut untouched = untouched.into_iter().peekable();
+
+    let last_sync = State::load_in(&local.data_dir)?
+        .last_sync
+        .get(host)
+        .copied();
+    let first_contact = last_sync.is_none();
+    // Pulling never sends anything, so there is nothing to confirm
+    let bootstrap =
+        if args.direction != Direction::Pull && args.first_sync.needs_summary(first_contact) {
+            let server_entries = connection.stats()?.entries;
+            args.first_sync
+                .resolve(host, first_contact, uploads.len(), server_entries)?
+        } else {
+            Bootstrap::Merge
+        };
+    let uploads = if bootstrap == Bootstrap::Server {
+        Vec::new()
+    } else {
+        uploads
+    };
+    let uploads = if server.supports("buckets") && !uploads.is_empty() {
+        let candidates = uploads.len();
+        let uploads = unstored_uploads(uploads, &connection.buckets()?);
+        eprintln!(
+            "Skipping {} entries the server already has",
+            candidates - uploads.len()
+        );
+        uploads
+    } else {
+        uploads
+    };
+    let upload_only = bootstrap == Bootstrap::Local || args.direction == Direction::Push;
+
+    let hostname = config.sync.hostname()?;
+    let signer = match config.signing.sign {
+        true => Some(Signer::load_in(&local.data_dir)?),
+        false => None,
+    };
+    let mut request = config.local.request(now()?);
+    args.filter.restrict(&mut request);
+    request.namespaces = shared;
+    request.tie_break = tie_break;
+    // Seeding a machine downloads the whole history: compressed, it's much smaller
+    request.snapshot = first_contact;
+    request.tombstones_since = last_sync.map(|when| when - TOMBSTONE_OVERLAP);
+    // Local entries deleted on the server since the last sync
+    let mut deleted = HashSet::new();
+    let mut history_writer = HistoryWriter::create(&local.history, read)?;
+    // Other orders rearrange the whole history once merged
+    let order = config.sync.order;
+    let mut merged = Vec::new();
+    // Everything written to fish_history is mirrored to the cache
+    let mut write = |entry: &HistoryEntry, received: bool| -> Result<()> {
+        if order == HistoryOrder::When {
+            history_writer.write(entry)?;
+        } else {
+            merged.push(entry.clone());
+        }
+        if received {
+            cache.add_received(entry)
+        } else {
+            cache.add(entry)
+        }
+    };
+
+    // Upload on a separate thread while this one downloads, so neither side of
+    // the ssh pipe can fill up and stall the other. Received entries go straight
+    // to disk instead of being collected in memory.
+    eprintln!("Sending local history to server…");
+    connection.set_flush(FlushPolicy::Bytes(UPLOAD_FLUSH));
+    let (upload, download) = std::thread::scope(|scope| {
+        let Connection {
+            writer,
+            reader,
+            chunked,
+            ..
+        } = &mut connection;
+        let uploader = scope.spawn(|| -> Result<()> {
+            let mut buf = Vec::new();
+            for entry in &uploads {
+                let mut entry = entry.clone().with_host(hostname.clone());
+                entry.namespace = namespace(config, &marked, &entry).to_string();
+                if let Some(signer) = &signer {
+                    signer.sign(&mut entry);
+                }
+                connection::send_entry_to(writer, &entry, *chunked, &mut buf)?;
+            }
+            if upload_only {
+                // Answered once the uploads are committed
+                return connection::send_to(writer, MessageType::GetStats, Vec::new());
+            }
+            eprintln!("Requesting history from server…");
+            connection::send_to(writer, MessageType::GetHistory, request.encode())
+        });
+        let download = if upload_only {
+            connection::await_commit(reader, |_| {}).map(|()| (0, None))
+        } else {
+            receive_history(reader, |received| match received {
+                Received::Deleted(hashes) => {
+                    deleted.extend(hashes);
+                    Ok(())
+                }
+                Received::Entry(entry) => {
+                    while let Some(local) =
+                        untouched.next_if(|local| goes_before(tie_break, local, &entry))
+                    {
+                        if !deleted.contains(&entry_hash(&local.cmd, local.when, &local.extra)) {
+                            write(&local, false)?;
+                        }
+                    }
+                    write(&entry, true)
+                }
+            })
+        };
+        let upload = uploader
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Upload thread panicked")));
+        (upload, download)
+    });
+    upload?;
+    let (received, summary) = download?;
+
+    eprintln!("Received {} history entries from server", received);
+
+    connection.close()?;
+
+    if upload_only {
+        // The local file is left exactly as it was
+        eprintln!("Sync complete!");
+        return Ok(SyncReport {
+            uploaded: uploads.len(),
+            received,
+            written: 0,
+            summary,
+        });
+    }
+
+    for local in untouched {
+        if !deleted.contains(&entry_hash(&local.cmd, local.when, &local.extra)) {
+            write(&local, false)?;
+        }
+    }
+    if order != HistoryOrder::When {
+        for entry in order.arrange(merged, &local_positions) {
+            history_writer.write(&entry)?;
+        }
+    }
+    if !deleted.is_empty() {
+        let deleted: Vec<_> = deleted.into_iter().collect();
+        cache.delete_entries(&deleted)?;
+        eprintln!("Deleted {} entries deleted on the server", deleted.len());
+    }
+    let (written, added) = history_writer.commit()?;
+    eprintln!("Wrote {} entries to local history file", written);
+    // Uploaded by the next sync
+    for entry in &added {
+        cache.add(entry)?;
+    }
+    cache.flush()?;
+
+    if local.refresh_fish {
+        refresh_fish()?;
+    }
+
+    eprintln!("Sync complete!");
+
+    Ok(SyncReport {
+        uploaded: uploads.len(),
+        received,
+        written,
+        summary,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LocalPolicy;
+    use plenty_common::{encode_hashes, HistoryRequest};
+    use std::io::Write;
+
+    #[test]
+    fn history_writer_skips_duplicates() {
+        let dir = std::env::temp_dir().join(format!("plenty-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("fish_history");
+        std::fs::write(&path, "").unwrap();
+
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        let (_, read) = read_local_history(&path).unwrap();
+        let mut writer = HistoryWriter::create(&path, read).unwrap();
+        for e in [entry("a", 1), entry("b", 1), entry("a", 1), entry("a", 2)] {
+            writer.write(&e).unwrap();
+        }
+        assert_eq!(writer.commit().unwrap(), (3, vec![]));
+        let entries = fish::parse_history(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let cmds: Vec<_> = entries.iter().map(|e| (e.cmd.as_str(), e.when)).collect();
+        assert_eq!(cmds, vec![("a", 1), ("b", 1), ("a", 2)]);
+    }
+
+    #[test]
+    fn entries_fish_appends_meanwhile_are_merged() {
+        let dir = std::env::temp_dir().join(format!("plenty-append-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("fish_history");
+        std::fs::write(&path, "- cmd: a\n  when: 1\n").unwrap();
+
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        let (local, read) = read_local_history(&path).unwrap();
+        assert_eq!(local, [entry("a", 1)]);
+        // As fish does while the sync runs
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"- cmd: late\n  when: 3\n")
+            .unwrap();
+        let mut writer = HistoryWriter::create(&path, read).unwrap();
+        for e in [entry("a", 1), entry("b", 2), entry("c", 4)] {
+            writer.write(&e).unwrap();
+        }
+        assert_eq!(writer.commit().unwrap(), (4, vec![entry("late", 3)]));
+        let entries = fish::parse_history(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let cmds: Vec<_> = entries.iter().map(|e| (e.cmd.as_str(), e.when)).collect();
+        assert_eq!(cmds, [("a", 1), ("b", 2), ("late", 3), ("c", 4)]);
+    }
+
+    #[test]
+    fn snapshots_and_tombstones_are_received() {
+        let entries: Vec<_> = (0..3)
+            .map(|when| HistoryEntry::new(format!("echo {}", when), when, String::new()))
+            .collect();
+        let mut frames = Vec::new();
+        for entry in &entries {
+            Message::new(MessageType::HistoryEntry, entry.encode())
+                .write_to(&mut frames)
+                .unwrap();
+        }
+        let mut input = Vec::new();
+        Message::new(MessageType::Tombstones, encode_hashes(&[7]))
+            .write_to(&mut input)
+            .unwrap();
+        Message::new(MessageType::HistoryEntry, entries[0].encode())
+            .write_to(&mut input)
+            .unwrap();
+        Message::new(
+            MessageType::Snapshot,
+            zstd::encode_all(&frames[..], 3).unwrap(),
+        )
+        .write_to(&mut input)
+        .unwrap();
+        let summary = SessionSummary {
+            high_water_mark: 9,
+            stored: 2,
+            duplicates: Some(1),
+            rejected: Some(0),
+        };
+        Message::new(MessageType::End, summary.encode())
+            .write_to(&mut input)
+            .unwrap();
+
+        let mut received = Vec::new();
+        let (count, end) = receive_history(&mut &input[..], |message| {
+            received.push(message);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!((count, end), (4, Some(summary)));
+        assert_eq!(received[0], Received::Deleted(vec![7]));
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|entry| Received::Entry(Box::new(entry)))
+            .collect();
+        assert_eq!(received[2..], entries);
+    }
+
+    #[test]
+    fn uploads_skip_buckets_the_server_holds() {
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        let day = plenty_common::BUCKET_SECONDS;
+        let stored = [entry("ls", 1), entry("make", 2), entry("ls", day)];
+        let uploads = vec![
+            entry("make", 2),
+            entry("ls", 1),
+            entry("ls", day),
+            entry("pwd", day + 1),
+        ];
+        assert_eq!(
+            unstored_uploads(uploads, &buckets(&stored)),
+            [entry("ls", day), entry("pwd", day + 1)]
+        );
+    }
+
+    #[test]
+    fn ties_follow_the_configured_policy() {
+        let local = HistoryEntry::new("zz".to_string(), 5, String::new());
+        let received = HistoryEntry::new("aa".to_string(), 5, String::new());
+        assert!(goes_before(TieBreak::Insertion, &local, &received));
+        assert!(!goes_before(TieBreak::Command, &local, &received));
+        assert!(goes_before(
+            TieBreak::Host,
+            &local,
+            &received.clone().with_host("b".into())
+        ));
+        let later = HistoryEntry::new("aa".to_string(), 6, String::new());
+        assert!(!goes_before(TieBreak::Insertion, &later, &received));
+    }
+
+    #[test]
+    fn local_policy_becomes_history_request() {
+        let policy = LocalPolicy {
+            max_entries: Some(3),
+            max_age_days: Some(2),
+            ..Default::default()
+        };
+        let request = policy.request(9 * 86400);
+        assert_eq!(request.since, Some(7 * 86400));
+        assert_eq!(request.limit, Some(3));
+        assert_eq!(LocalPolicy::default().request(0), HistoryRequest::default());
+    }
+
+    #[test]
+    fn space_prefixed_commands_are_private() {
+        let options = SyncOptions::default();
+        assert!(options.is_private(" export TOKEN=hunter2"));
+        assert!(!options.is_private("ls"));
+        let none = SyncOptions {
+            private_prefixes: vec![String::new()],
+            ..Default::default()
+        };
+        assert!(!none.is_private(" ls"));
+    }
+
+    #[test]
+    fn clock_skew_is_checked_against_threshold() {
+        let mut options = SyncOptions::default();
+        assert!(check_clock_skew(&options, Some(1000), 1000 + 300).is_ok());
+        assert!(check_clock_skew(&options, Some(0), 1_700_000_000).is_ok());
+        assert!(check_clock_skew(&options, None, 0).is_ok());
+
+        options.abort_on_clock_skew = true;
+        assert!(check_clock_skew(&options, Some(1000), 1000 + 300).is_ok());
+        assert!(check_clock_skew(&options, Some(0), 1_700_000_000).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{bail, Context, Result};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// A writer limited to a number of bytes per second by a token bucket
+/// holding up to one second worth of bytes. It wraps any writer, so it
+/// works the same whatever the connection is made of.
+pub struct Throttle<W> {
+    inner: W,
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl<W: Write> Throttle<W> {
+    /// Wrap `inner` without limiting it
+    pub fn new(inner: W) -> Self {
+        Throttle {
+            inner,
+            rate: None,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Limit writes to `rate` bytes per second, or lift the limit with `None`
+    pub fn set_rate(&mut self, rate: Option<u64>) {
+        self.rate = rate.filter(|rate| *rate > 0);
+        self.tokens = self.rate.unwrap_or(0) as f64;
+        self.last = Instant::now();
+    }
+
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last = now;
+    }
+}
+
+impl<W: Write> Write for Throttle<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(rate) = self.rate else {
+            return self.inner.write(buf);
+        };
+        self.refill(rate);
+        if self.tokens < 1.0 {
+            std::thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / rate as f64));
+            self.refill(rate);
+        }
+        let allowed = buf.len().min(self.tokens as usize).max(1);
+        let written = self.inner.write(&buf[..allowed])?;
+        self.tokens -= written as f64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Parse a rate in bytes per second, with an optional k, m or g suffix (powers of 1024)
+pub fn parse_rate(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid rate {:?}", value))?;
+    if number == 0 {
+        bail!("Rate must be positive");
+    }
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("Rate {:?} is too large", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("512").unwrap(), 512);
+        assert_eq!(parse_rate("100k").unwrap(), 100 * 1024);
+        assert_eq!(parse_rate("2M").unwrap(), 2 * 1024 * 1024);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn throttle_delays_writes_beyond_the_burst() {
+        let mut out = Vec::new();
+        let mut throttle = Throttle::new(&mut out);
+        throttle.set_rate(Some(1000));
+        let start = Instant::now();
+        throttle.write_all(&[0; 1500]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(out.len(), 1500);
+    }
+}
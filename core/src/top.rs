@@ -0,0 +1,296 @@
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::connection::Connection;
+use anyhow::{bail, Context, Result};
+use plenty_common::{time, HistoryEntry, HistoryRequest, MatchMode, SearchQuery};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+/// Width of the longest bar of the charts
+const BAR_WIDTH: u64 = 40;
+/// Days of history past which growth is charted per month rather than per week
+const WEEKLY_DAYS: i64 = 26 * 7;
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// What `plenty top` looks at
+#[derive(Debug, Clone, Default)]
+pub struct TopOptions {
+    /// Only entries run at or after this time
+    pub since: Option<i64>,
+    /// Also rank commands per host
+    pub by_host: bool,
+    /// Also rank commands per working directory
+    pub by_cwd: bool,
+    /// Commands, and hosts or directories, listed per ranking
+    pub limit: usize,
+    /// Ask the first host rather than the cache
+    pub remote: bool,
+}
+
+/// Print the most frequent commands, when commands run and how the history
+/// grew: from the local cache unless `remote`, or from the first of `hosts` if
+/// there is no cache yet. Times are local; entries the cache has without a
+/// host are `hostname`'s.
+pub fn run(config: &Config, hosts: &[String], hostname: &str, options: &TopOptions) -> Result<()> {
+    let remote = options.remote || !Cache::path()?.exists();
+    let mut report = Report::new(options, (!remote).then_some(hostname), time::utc_offset()?);
+    if !remote {
+        let request = HistoryRequest {
+            since: options.since,
+            ..Default::default()
+        };
+        Cache::open_existing()?.for_each_entry(&request, |entry| {
+            report.add(&entry);
+            Ok(())
+        })?;
+    } else {
+        let Some(host) = hosts.first() else {
+            bail!("No history cache and no host to ask; run plenty sync first");
+        };
+        let mut connection = Connection::open(host, &config.remote)?;
+        connection.handshake()?;
+        // Empty substrings match every command
+        let query = SearchQuery {
+            mode: MatchMode::Substring,
+            since: options.since,
+            ..Default::default()
+        };
+        connection.search(&query, |entry| {
+            report.add(&entry);
+            Ok(())
+        })?;
+        connection.close()?;
+    }
+    let mut out = std::io::stdout().lock();
+    report
+        .write(&mut out, options.limit)
+        .context("Failed to print statistics")
+}
+
+/// Counts of the entries `plenty top` looked at, by local time
+struct Report {
+    by_host: bool,
+    by_cwd: bool,
+    /// Host of the entries without one, unknown if `None`
+    hostname: Option<String>,
+    offset: i64,
+    entries: u64,
+    commands: HashMap<String, u64>,
+    /// Commands per host, directory, or both
+    groups: HashMap<String, HashMap<String, u64>>,
+    hours: [u64; 24],
+    weekdays: [u64; 7],
+    /// Entries per day, by days since the epoch
+    days: BTreeMap<i64, u64>,
+    /// Day each command first ran
+    first_days: HashMap<String, i64>,
+}
+
+impl Report {
+    fn new(options: &TopOptions, hostname: Option<&str>, offset: i64) -> Self {
+        Report {
+            by_host: options.by_host,
+            by_cwd: options.by_cwd,
+            hostname: hostname.map(str::to_string),
+            offset,
+            entries: 0,
+            commands: HashMap::new(),
+            groups: HashMap::new(),
+            hours: [0; 24],
+            weekdays: [0; 7],
+            days: BTreeMap::new(),
+            first_days: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, entry: &HistoryEntry) {
+        let local = entry.when.saturating_add(self.offset);
+        let day = local.div_euclid(86400);
+        self.entries += 1;
+        *self.commands.entry(entry.cmd.clone()).or_default() += 1;
+        if let Some(group) = self.group(entry) {
+            *self
+                .groups
+                .entry(group)
+                .or_default()
+                .entry(entry.cmd.clone())
+                .or_default() += 1;
+        }
+        self.hours[(local.rem_euclid(86400) / 3600) as usize] += 1;
+        // 1970-01-01 was a Thursday
+        self.weekdays[(day + 3).rem_euclid(7) as usize] += 1;
+        *self.days.entry(day).or_default() += 1;
+        let first = self.first_days.entry(entry.cmd.clone()).or_insert(day);
+        *first = (*first).min(day);
+    }
+
+    /// The host, directory, or both, commands are ranked per
+    fn group(&self, entry: &HistoryEntry) -> Option<String> {
+        let host = match (entry.host.as_str(), &self.hostname) {
+            ("", Some(hostname)) => hostname,
+            ("", None) => "(unknown)",
+            (host, _) => host,
+        };
+        let cwd = match entry.cwd.as_str() {
+            "" => "(unknown)",
+            cwd => cwd,
+        };
+        match (self.by_host, self.by_cwd) {
+            (true, true) => Some(format!("{}:{}", host, cwd)),
+            (true, false) => Some(host.to_string()),
+            (false, true) => Some(cwd.to_string()),
+            (false, false) => None,
+        }
+    }
+
+    /// Entries and commands first run per week or month, as long as the
+    /// history spans, labelled by when they start
+    fn growth(&self) -> Vec<(String, u64, u64)> {
+        let (Some(&first), Some(&last)) = (self.days.keys().next(), self.days.keys().last()) else {
+            return Vec::new();
+        };
+        let mut new_commands: HashMap<i64, u64> = HashMap::new();
+        for day in self.first_days.values() {
+            *new_commands.entry(*day).or_default() += 1;
+        }
+        let weekly = last - first < WEEKLY_DAYS;
+        let mut periods: Vec<(String, u64, u64)> = Vec::new();
+        for day in first..=last {
+            let label = if weekly {
+                // Weeks start on Mondays
+                time::date((day - (day + 3).rem_euclid(7)) * 86400)
+            } else {
+                time::date(day * 86400)[..7].to_string()
+            };
+            if periods.last().is_none_or(|(last, _, _)| *last != label) {
+                periods.push((label, 0, 0));
+            }
+            let period = periods.last_mut().expect("a period was pushed");
+            period.1 += self.days.get(&day).copied().unwrap_or(0);
+            period.2 += new_commands.get(&day).copied().unwrap_or(0);
+        }
+        periods
+    }
+
+    fn write(&self, out: &mut impl Write, limit: usize) -> Result<()> {
+        if self.entries == 0 {
+            writeln!(out, "No entries.")?;
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "{} entries of {} commands",
+            self.entries,
+            self.commands.len()
+        )?;
+        writeln!(out, "\nTop commands:")?;
+        write_top(out, &self.commands, limit)?;
+
+        let mut groups: Vec<_> = self
+            .groups
+            .iter()
+            .map(|(group, commands)| (group, commands, commands.values().sum::<u64>()))
+            .collect();
+        groups.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(b.0)));
+        for (group, commands, entries) in groups.into_iter().take(limit) {
+            writeln!(out, "\nTop commands in {} ({} entries):", group, entries)?;
+            write_top(out, commands, limit)?;
+        }
+
+        writeln!(out, "\nEntries per hour:")?;
+        let hours: Vec<_> = (0..24)
+            .map(|hour| (format!("{:02}h", hour), self.hours[hour]))
+            .collect();
+        chart(out, &hours)?;
+        writeln!(out, "\nEntries per day of the week:")?;
+        let weekdays: Vec<_> = WEEKDAYS
+            .iter()
+            .zip(self.weekdays)
+            .map(|(day, entries)| (day.to_string(), entries))
+            .collect();
+        chart(out, &weekdays)?;
+
+        writeln!(out, "\nGrowth (entries, new commands):")?;
+        let growth = self.growth();
+        let most = growth.iter().map(|(_, n, _)| *n).max().unwrap_or(0).max(1);
+        for (label, entries, new) in growth {
+            let bar = (entries * BAR_WIDTH).div_ceil(most);
+            write!(out, "  {} {:>8} {:>6}", label, entries, new)?;
+            if bar > 0 {
+                write!(out, " {}", "#".repeat(bar as usize))?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `limit` most frequent of `commands`, most first
+fn write_top(out: &mut impl Write, commands: &HashMap<String, u64>, limit: usize) -> Result<()> {
+    let mut top: Vec<_> = commands.iter().collect();
+    top.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    for (cmd, entries) in top.into_iter().take(limit) {
+        writeln!(out, "  {:>8} {}", entries, cmd)?;
+    }
+    Ok(())
+}
+
+/// One bar per row, the longest `BAR_WIDTH` wide
+fn chart(out: &mut impl Write, rows: &[(String, u64)]) -> Result<()> {
+    let most = rows.iter().map(|(_, n)| *n).max().unwrap_or(0).max(1);
+    for (label, entries) in rows {
+        let bar = (entries * BAR_WIDTH).div_ceil(most);
+        write!(out, "  {} {:>8}", label, entries)?;
+        if bar > 0 {
+            write!(out, " {}", "#".repeat(bar as usize))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_counted_in_local_time() {
+        let options = TopOptions {
+            by_host: true,
+            limit: 1,
+            ..Default::default()
+        };
+        // Two hours ahead of UTC
+        let mut report = Report::new(&options, Some("laptop"), 7200);
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        // Thursday 1970-01-01 23:00 UTC is Friday 01:00 locally
+        for when in [82_800, 90_000, 8 * 86400] {
+            report.add(&entry("git status", when));
+        }
+        report.add(&entry("ls", 8 * 86400).with_host("desktop".to_string()));
+
+        assert_eq!(
+            (report.hours[1], report.hours[2], report.hours[3]),
+            (1, 2, 1)
+        );
+        // 1970-01-09 is a Friday too
+        assert_eq!(report.weekdays, [0, 0, 0, 0, 4, 0, 0]);
+        assert_eq!(
+            report.growth(),
+            [
+                ("1969-12-29".to_string(), 2, 1),
+                ("1970-01-05".to_string(), 2, 1)
+            ]
+        );
+
+        let mut out = Vec::new();
+        report.write(&mut out, 1).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with("4 entries of 2 commands\n\nTop commands:\n         3 git status\n")
+        );
+        assert!(out.contains("Top commands in laptop (3 entries):\n         3 git status\n"));
+        assert!(!out.contains("Top commands in desktop"));
+        assert!(out.contains("  Fri        4 ########################################\n"));
+    }
+}
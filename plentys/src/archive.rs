@@ -0,0 +1,250 @@
+/// Old entries moved out to a separate database, attached to search them,
+/// so that the one sessions use stays small without deleting anything
+use anyhow::{Context, Result};
+use plenty_common::{store, HistoryEntry, SearchQuery};
+use rusqlite::Connection;
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Entries of the archive, keyed by their hash, and a search index over them
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS archive.history (
+      hash INTEGER PRIMARY KEY,
+      cmd TEXT NOT NULL,
+      \"when\" INTEGER NOT NULL,
+      extra TEXT NOT NULL,
+      host TEXT,
+      cwd TEXT,
+      exit_code INTEGER,
+      duration_ms INTEGER,
+      session TEXT,
+      origin_device TEXT,
+      received_at INTEGER,
+      namespace TEXT,
+      signature BLOB
+    );
+    CREATE INDEX IF NOT EXISTS archive.idx_history_when ON history(\"when\");
+    CREATE VIRTUAL TABLE IF NOT EXISTS archive.history_fts USING fts5(
+      cmd, extra, content='history', content_rowid='hash'
+    );
+    CREATE TRIGGER IF NOT EXISTS archive.history_fts_insert AFTER INSERT ON history BEGIN
+      INSERT INTO history_fts (rowid, cmd, extra) VALUES (new.hash, new.cmd, new.extra);
+    END;
+    CREATE TRIGGER IF NOT EXISTS archive.history_fts_delete AFTER DELETE ON history BEGIN
+      INSERT INTO history_fts (history_fts, rowid, cmd, extra)
+      VALUES ('delete', old.hash, old.cmd, old.extra);
+    END;";
+
+/// Entries `move_entries` archives: older than ?1, and not pinned
+const ARCHIVABLE: &str =
+    "FROM main.history WHERE \"when\" < ?1 AND cmd NOT IN (SELECT cmd FROM main.pins)";
+
+/// What `move_entries` did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Archived {
+    pub moved: usize,
+    /// Archived entries deleted, of commands forgotten since or past the
+    /// retention horizon
+    pub purged: usize,
+}
+
+/// Attach the archive at `path` as `archive`, creating it if needed; an
+/// encrypted database's archive is encrypted with the same key
+pub fn attach(conn: &Connection, path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create archive directory {}", dir.display()))?;
+    }
+    // Create it first so that only its owner can read the history
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let name = path
+        .to_str()
+        .with_context(|| format!("Invalid archive path {}", path.display()))?;
+    conn.execute("ATTACH DATABASE ?1 AS archive", [name])
+        .with_context(|| format!("Failed to attach archive {}", path.display()))?;
+    conn.execute_batch(SCHEMA)
+        .context("Failed to create archive tables")?;
+    // Archives made before entries had metadata lack its columns
+    let has_metadata = conn
+        .prepare("SELECT 1 FROM pragma_table_info('history', 'archive') WHERE name = 'cwd'")?
+        .exists([])?;
+    if !has_metadata {
+        conn.execute_batch(
+            "ALTER TABLE archive.history ADD COLUMN cwd TEXT;
+             ALTER TABLE archive.history ADD COLUMN exit_code INTEGER;
+             ALTER TABLE archive.history ADD COLUMN duration_ms INTEGER;
+             ALTER TABLE archive.history ADD COLUMN session TEXT;",
+        )
+        .context("Failed to add entry metadata columns to the archive")?;
+    }
+    let has_provenance = conn
+        .prepare(
+            "SELECT 1 FROM pragma_table_info('history', 'archive') WHERE name = 'origin_device'",
+        )?
+        .exists([])?;
+    if !has_provenance {
+        conn.execute_batch(
+            "ALTER TABLE archive.history ADD COLUMN origin_device TEXT;
+             ALTER TABLE archive.history ADD COLUMN received_at INTEGER;",
+        )
+        .context("Failed to add entry provenance columns to the archive")?;
+    }
+    let has_namespace = conn
+        .prepare("SELECT 1 FROM pragma_table_info('history', 'archive') WHERE name = 'namespace'")?
+        .exists([])?;
+    if !has_namespace {
+        conn.execute("ALTER TABLE archive.history ADD COLUMN namespace TEXT", [])
+            .context("Failed to add the entry namespace column to the archive")?;
+    }
+    let has_signature = conn
+        .prepare("SELECT 1 FROM pragma_table_info('history', 'archive') WHERE name = 'signature'")?
+        .exists([])?;
+    if !has_signature {
+        conn.execute("ALTER TABLE archive.history ADD COLUMN signature BLOB", [])
+            .context("Failed to add the entry signature column to the archive")?;
+    }
+    Ok(())
+}
+
+pub fn detach(conn: &Connection) -> Result<()> {
+    conn.execute("DETACH DATABASE archive", [])
+        .context("Failed to detach archive")?;
+    Ok(())
+}
+
+/// Move the entries older than `before`, except pinned ones, to the attached
+/// archive, remembering them so that clients can't upload them again, and
+/// delete archived entries of commands forgotten since or past the retention
+/// horizon
+pub fn move_entries(conn: &mut Connection, before: i64) -> Result<Archived> {
+    let tx = conn.transaction().context("Failed to begin archiving")?;
+    tx.execute(
+        &format!(
+            "INSERT OR IGNORE INTO archive.history (hash, cmd, \"when\", extra, host, {})
+             SELECT hash, cmd, \"when\", COALESCE(extra, ''), host, {} {}",
+            store::METADATA_COLUMNS,
+            store::METADATA_COLUMNS,
+            ARCHIVABLE
+        ),
+        [before],
+    )
+    .context("Failed to copy entries to the archive")?;
+    tx.execute(
+        &format!(
+            "INSERT OR IGNORE INTO main.archived (hash) SELECT hash {}",
+            ARCHIVABLE
+        ),
+        [before],
+    )
+    .context("Failed to record archived entries")?;
+    let moved = tx
+        .execute(&format!("DELETE {}", ARCHIVABLE), [before])
+        .context("Failed to delete archived entries")?;
+    let purged = tx
+        .execute(
+            "DELETE FROM archive.history
+             WHERE plenty_cmd_hash(cmd) IN (SELECT cmd_hash FROM main.tombstones)
+             OR hash IN (SELECT hash FROM main.entry_tombstones)
+             OR \"when\" < (SELECT value FROM main.retention WHERE key = 'horizon')",
+            [],
+        )
+        .context("Failed to purge the archive")?;
+    tx.commit().context("Failed to commit archiving")?;
+    Ok(Archived { moved, purged })
+}
+
+/// Pass the archived entries matching `query` to `on_entry`, in its order,
+/// leaving out forgotten commands
+pub fn search(
+    conn: &Connection,
+    query: &SearchQuery,
+    on_entry: impl FnMut(HistoryEntry) -> Result<()>,
+) -> Result<()> {
+    store::search_in(conn, "archive", query, on_entry)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::cmd_hash;
+
+    fn search_archive(conn: &Connection, text: &str) -> Vec<String> {
+        let query = SearchQuery {
+            text: text.to_string(),
+            ..Default::default()
+        };
+        let mut found = Vec::new();
+        search(conn, &query, |entry| {
+            found.push(entry.cmd);
+            Ok(())
+        })
+        .unwrap();
+        found
+    }
+
+    #[test]
+    fn archived_entries_stay_searchable_and_out_of_history() {
+        let dir = std::env::temp_dir().join(format!("plentys-archive-test-{}", std::process::id()));
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        let mut old = entry("make old", 1);
+        old.cwd = "/src".to_string();
+        old.origin_device = "laptop".to_string();
+        old.received_at = Some(4);
+        let entries = [
+            old.clone(),
+            entry("make pinned", 2),
+            entry("make secret", 3),
+            entry("make new", 10),
+        ];
+        store::insert_entries(&mut conn, &entries).unwrap();
+        store::set_pinned(&conn, "make pinned", true).unwrap();
+
+        attach(&conn, &dir.join("history-archive.db")).unwrap();
+        let archived = move_entries(&mut conn, 5).unwrap();
+        assert_eq!(
+            archived,
+            Archived {
+                moved: 2,
+                purged: 0
+            }
+        );
+        assert_eq!(store::count_entries(&conn).unwrap(), 2);
+        assert_eq!(search_archive(&conn, "make"), ["make old", "make secret"]);
+        let query = SearchQuery {
+            text: "old".to_string(),
+            ..Default::default()
+        };
+        search(&conn, &query, |entry| {
+            assert_eq!(entry, old);
+            Ok(())
+        })
+        .unwrap();
+
+        // Uploading them again doesn't bring them back
+        store::insert_entries(&mut conn, &entries).unwrap();
+        assert_eq!(store::count_entries(&conn).unwrap(), 2);
+
+        store::forget_command(&mut conn, cmd_hash("make secret")).unwrap();
+        assert_eq!(search_archive(&conn, "make"), ["make old"]);
+        let archived = move_entries(&mut conn, 5).unwrap();
+        assert_eq!(
+            archived,
+            Archived {
+                moved: 0,
+                purged: 1
+            }
+        );
+        detach(&conn).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+/// `plentys export --as-of`: the history as it was at a given time, from the
+/// entries received by then and, for those deleted since, a backup made by then
+use anyhow::{Context, Result};
+use plenty_common::{store, HistoryEntry};
+use rusqlite::Connection;
+use std::path::Path;
+
+/// Every entry stored at `time`, each once: those of the database received
+/// by then, or before their reception was recorded, oldest first, then
+/// those of `snapshot`, a backup made by then, deleted after `time`
+pub fn entries(
+    conn: &Connection,
+    snapshot: Option<&Path>,
+    time: i64,
+    mut on_entry: impl FnMut(HistoryEntry) -> Result<()>,
+) -> Result<()> {
+    read(
+        conn,
+        &format!(
+            "SELECT cmd, \"when\", extra, COALESCE(host, ''), cmd IN (SELECT cmd FROM main.pins),
+                    {}
+             FROM main.history WHERE received_at IS NULL OR received_at <= ?1
+             ORDER BY \"when\", rowid",
+            store::METADATA_COLUMNS
+        ),
+        time,
+        &mut on_entry,
+    )?;
+    let Some(snapshot) = snapshot else {
+        return Ok(());
+    };
+    let name = snapshot
+        .to_str()
+        .with_context(|| format!("Invalid backup path {}", snapshot.display()))?;
+    conn.execute("ATTACH DATABASE ?1 AS snapshot", [name])
+        .with_context(|| format!("Failed to open backup {}", snapshot.display()))?;
+    let result = deleted_since(conn, time, &mut on_entry)
+        .with_context(|| format!("Failed to read backup {}", snapshot.display()));
+    conn.execute("DETACH DATABASE snapshot", [])
+        .context("Failed to close backup")?;
+    result
+}
+
+/// The entries of the attached snapshot that the database no longer has,
+/// other than those deleted by `time`
+fn deleted_since(
+    conn: &Connection,
+    time: i64,
+    on_entry: &mut impl FnMut(HistoryEntry) -> Result<()>,
+) -> Result<()> {
+    // Backups made by older versions lack the columns added since
+    let mut stmt = conn
+        .prepare("SELECT name FROM pragma_table_info('history', 'snapshot')")
+        .context("Failed to read the backup's schema")?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .context("Failed to read the backup's schema")?;
+    let column = |name: &str| match columns.iter().any(|column| column == name) {
+        true => format!("snapshot.history.{}", name),
+        false => "NULL".to_string(),
+    };
+    let metadata: Vec<_> = store::METADATA_COLUMNS.split(", ").map(column).collect();
+    read(
+        conn,
+        &format!(
+            "SELECT cmd, \"when\", COALESCE(extra, ''), COALESCE({}, ''),
+                    cmd IN (SELECT cmd FROM snapshot.pins), {}
+             FROM snapshot.history
+             WHERE cmd IS NOT NULL AND \"when\" IS NOT NULL
+             AND plenty_entry_hash(cmd, \"when\", COALESCE(extra, '')) NOT IN (
+               SELECT hash FROM main.history WHERE hash IS NOT NULL
+               UNION ALL SELECT hash FROM main.entry_tombstones WHERE deleted_at <= ?1
+             )
+             AND plenty_cmd_hash(cmd) NOT IN (
+               SELECT cmd_hash FROM main.tombstones WHERE deleted_at <= ?1
+             )
+             ORDER BY \"when\", rowid",
+            column("host"),
+            metadata.join(", ")
+        ),
+        time,
+        on_entry,
+    )
+}
+
+/// Pass each entry `sql` selects for `time` to `on_entry`
+fn read(
+    conn: &Connection,
+    sql: &str,
+    time: i64,
+    on_entry: &mut impl FnMut(HistoryEntry) -> Result<()>,
+) -> Result<()> {
+    let mut stmt = conn
+        .prepare(sql)
+        .context("Failed to prepare history query")?;
+    let mut rows = stmt.query([time]).context("Failed to query history")?;
+    while let Some(row) = rows.next().context("Failed to read history entry")? {
+        let entry = HistoryEntry::new(row.get(0)?, row.get(1)?, row.get(2)?)
+            .with_host(row.get(3)?)
+            .with_pinned(row.get(4)?);
+        on_entry(store::with_metadata(entry, row, 5)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::{cmd_hash, entry_hash};
+
+    fn received(cmd: &str, when: i64, received_at: i64) -> HistoryEntry {
+        let mut entry = HistoryEntry::new(cmd.to_string(), when, String::new());
+        entry.received_at = Some(received_at);
+        entry
+    }
+
+    #[test]
+    fn past_histories_are_rebuilt_from_backups() {
+        let path = std::env::temp_dir().join(format!("plentys-as-of-test-{}", std::process::id()));
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        let mut before = vec![
+            received("ls", 1, 50),
+            received("make", 2, 60),
+            received("rm -rf build", 3, 70),
+            received("secret", 4, 80),
+        ];
+        before[0].pinned = true;
+        store::insert_entries(&mut conn, &before).unwrap();
+        store::set_pinned(&conn, "ls", true).unwrap();
+        conn.execute("VACUUM INTO ?1", [path.to_str().unwrap()])
+            .unwrap();
+        let after = [received("cargo test", 5, 150), received("pwd", 6, 250)];
+        store::insert_entries(&mut conn, &after).unwrap();
+        // A bad client wiped some entries after the time asked for, and one
+        // was deleted and another forgotten on purpose before it
+        let hash = |entry: &HistoryEntry| entry_hash(&entry.cmd, entry.when, &entry.extra);
+        store::delete_entries(&mut conn, &[hash(&before[1])], 300).unwrap();
+        store::delete_entries(&mut conn, &[hash(&before[2])], 120).unwrap();
+        store::forget_command(&mut conn, cmd_hash("secret")).unwrap();
+        conn.execute("UPDATE tombstones SET deleted_at = 130", [])
+            .unwrap();
+
+        let mut found = Vec::new();
+        entries(&conn, Some(&path), 200, |entry| {
+            found.push(entry);
+            Ok(())
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            found,
+            [before[0].clone(), after[0].clone(), before[1].clone()]
+        );
+
+        let mut found = Vec::new();
+        entries(&conn, None, 200, |entry| {
+            found.push(entry.cmd);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(found, ["ls", "cargo test"]);
+    }
+}
@@ -0,0 +1,215 @@
+/// Backups taken with SQLite's online backup API, consistent even while
+/// sessions write to the database
+use crate::config::{BackupOptions, DatabaseOptions};
+use anyhow::{bail, Context, Result};
+use plenty_common::store;
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags};
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Pages copied at a time, pausing in between so sessions aren't held up
+const PAGES_PER_STEP: i32 = 256;
+const PAUSE_BETWEEN_STEPS: Duration = Duration::from_millis(10);
+
+/// Copy the database to `dest`, replacing it only once the copy is complete;
+/// the copy of an encrypted database is encrypted with the same key
+pub fn backup(conn: &Connection, dest: &Path, database: &DatabaseOptions) -> Result<()> {
+    let mut tmp_name = dest
+        .file_name()
+        .with_context(|| format!("Invalid backup path {}", dest.display()))?
+        .to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = dest.with_file_name(tmp_name);
+    let result = copy_to(conn, &tmp, database).and_then(|()| {
+        std::fs::rename(&tmp, dest)
+            .with_context(|| format!("Failed to move backup to {}", dest.display()))
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn copy_to(conn: &Connection, path: &Path, database: &DatabaseOptions) -> Result<()> {
+    // Create it first so that only its owner can read the history
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut copy = Connection::open(path).context("Failed to open backup")?;
+    database.unlock(&copy)?;
+    Backup::new(conn, &mut copy)
+        .and_then(|backup| backup.run_to_completion(PAGES_PER_STEP, PAUSE_BETWEEN_STEPS, None))
+        .context("Failed to back up database")?;
+    // The copy inherits WAL mode; a single self-contained file is easier to move around
+    copy.pragma_update(None, "journal_mode", "delete")
+        .context("Failed to finish backup")?;
+    Ok(())
+}
+
+/// Replace everything in the database with the backup at `source`, once
+/// it's checked to be an intact plenty database no newer than this one
+pub fn restore(conn: &mut Connection, source: &Path, database: &DatabaseOptions) -> Result<()> {
+    // Not read-only: checking the search index needs to write, though it doesn't change anything
+    let backup = Connection::open_with_flags(source, OpenFlags::SQLITE_OPEN_READ_WRITE)
+        .with_context(|| format!("Failed to open {}", source.display()))?;
+    database.unlock(&backup)?;
+    let integrity: String = backup
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .with_context(|| format!("Failed to check {}", source.display()))?;
+    if integrity != "ok" {
+        bail!("{} is damaged: {}", source.display(), integrity);
+    }
+    let version = store::schema_version(&backup)
+        .with_context(|| format!("{} is not a plenty database", source.display()))?;
+    if version > store::schema_version(conn)? {
+        bail!(
+            "{} has schema version {}, newer than this database's, upgrade plentys",
+            source.display(),
+            version
+        );
+    }
+    Backup::new(&backup, conn)
+        .and_then(|restore| restore.run_to_completion(PAGES_PER_STEP, PAUSE_BETWEEN_STEPS, None))
+        .context("Failed to restore database")?;
+    // Backups from older versions need the migrations since
+    store::init_schema(conn)
+}
+
+/// Timestamped backups of the database at `db_path` in `dir`, oldest first
+fn rotated(db_path: &Path, dir: &Path) -> Result<Vec<(i64, PathBuf)>> {
+    let prefix = format!("{}-", stem(db_path));
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", dir.display())),
+    };
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+        let name = entry.file_name();
+        let time = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|name| name.strip_suffix(".db"))
+            .and_then(|time| time.parse::<i64>().ok());
+        if let Some(time) = time {
+            backups.push((time, entry.path()));
+        }
+    }
+    backups.sort();
+    Ok(backups)
+}
+
+fn stem(db_path: &Path) -> String {
+    db_path.file_stem().map_or_else(
+        || "history".to_string(),
+        |stem| stem.to_string_lossy().into_owned(),
+    )
+}
+
+/// When the newest backup in `options.dir` was made, if any
+pub fn latest(db_path: &Path, options: &BackupOptions) -> Result<Option<i64>> {
+    let Some(dir) = &options.dir else {
+        return Ok(None);
+    };
+    Ok(rotated(db_path, dir)?.last().map(|(time, _)| *time))
+}
+
+/// The newest backup in `options.dir` made at or before `time`, and when
+pub fn made_by(
+    db_path: &Path,
+    options: &BackupOptions,
+    time: i64,
+) -> Result<Option<(i64, PathBuf)>> {
+    let Some(dir) = &options.dir else {
+        return Ok(None);
+    };
+    Ok(rotated(db_path, dir)?
+        .into_iter()
+        .rev()
+        .find(|(made, _)| *made <= time))
+}
+
+/// Back up to a new file named after the time in `options.dir`, then delete
+/// the oldest backups beyond `options.keep`
+pub fn rotate(
+    conn: &Connection,
+    db_path: &Path,
+    options: &BackupOptions,
+    database: &DatabaseOptions,
+) -> Result<PathBuf> {
+    let Some(dir) = &options.dir else {
+        bail!("Nowhere to back up to: configure [backup] dir in server.toml or pass a path");
+    };
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create backup directory {}", dir.display()))?;
+    let dest = dir.join(format!("{}-{}.db", stem(db_path), store::unix_now()));
+    backup(conn, &dest, database)?;
+    let backups = rotated(db_path, dir)?;
+    for (_, old) in &backups[..backups.len().saturating_sub(options.keep)] {
+        std::fs::remove_file(old)
+            .with_context(|| format!("Failed to delete old backup {}", old.display()))?;
+    }
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::HistoryEntry;
+
+    #[test]
+    fn backups_restore_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("plentys-backup-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("history.db");
+        let mut conn = Connection::open(&db_path).unwrap();
+        conn.pragma_update(None, "journal_mode", "wal").unwrap();
+        store::init_schema(&mut conn).unwrap();
+        let entry = HistoryEntry::new("ls".to_string(), 1, String::new());
+        store::insert_entries(&mut conn, &[entry]).unwrap();
+
+        let options = BackupOptions {
+            dir: Some(dir.join("backups")),
+            keep: 2,
+            ..Default::default()
+        };
+        let backups = options.dir.as_ref().unwrap();
+        std::fs::create_dir_all(backups).unwrap();
+        for name in ["history-10.db", "history-20.db", "other-5.db"] {
+            std::fs::write(backups.join(name), "").unwrap();
+        }
+        let backup_path = rotate(&conn, &db_path, &options, &DatabaseOptions::default()).unwrap();
+        let kept = rotated(&db_path, backups).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].0, 20);
+        assert_eq!(kept[1].1, backup_path);
+        assert_eq!(latest(&db_path, &options).unwrap(), Some(kept[1].0));
+        assert_eq!(
+            made_by(&db_path, &options, 25).unwrap(),
+            Some((20, backups.join("history-20.db")))
+        );
+        assert_eq!(made_by(&db_path, &options, 15).unwrap(), None);
+        assert!(backups.join("other-5.db").exists());
+
+        let entry = HistoryEntry::new("make".to_string(), 2, String::new());
+        store::insert_entries(&mut conn, &[entry]).unwrap();
+        assert_eq!(store::count_entries(&conn).unwrap(), 2);
+        restore(&mut conn, &backup_path, &DatabaseOptions::default()).unwrap();
+        assert_eq!(store::count_entries(&conn).unwrap(), 1);
+        let damaged = restore(
+            &mut conn,
+            &backups.join("other-5.db"),
+            &DatabaseOptions::default(),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(damaged.is_err());
+    }
+}
@@ -0,0 +1,292 @@
+/// Enrolling devices instead of handing out API tokens: a device asks
+/// `plentys web` for one, showing its short code to an admin, who approves
+/// that code with `plentys devices approve`; a new token is then appended to
+/// the `[api]` token_file, and handed to the device when it next asks
+use crate::config::ApiOptions;
+use crate::storage::HistoryStore;
+use anyhow::{bail, Context, Result};
+use plenty_common::store::Enrollment;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Seconds an enrollment waits to be approved and collected
+pub const ENROLLMENT_LIFETIME: i64 = 86400;
+/// Enrollments waiting at once, as anyone reaching the server can ask
+pub const MAX_PENDING: usize = 100;
+/// Letters of codes, without those easily mistaken for others
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 8;
+/// Random bytes of secrets and tokens, written in hex
+const SECRET_BYTES: usize = 32;
+/// Longest device name
+const MAX_DEVICE: usize = 64;
+
+/// What a device waits with: the code it shows, and the secret it collects
+/// its token with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requested {
+    pub code: String,
+    pub secret: String,
+}
+
+/// Where an enrollment is at, as its device sees it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Pending,
+    /// With the device's token, now only known to it and the token_file
+    Approved(String),
+}
+
+/// Check the name a device asks to upload as, which the token_file holds
+/// after its token
+pub fn check_device(device: &str) -> Result<()> {
+    if device.is_empty()
+        || device.len() > MAX_DEVICE
+        || device.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        bail!(
+            "Device names are 1 to {} characters without spaces, not {:?}",
+            MAX_DEVICE,
+            device
+        );
+    }
+    Ok(())
+}
+
+/// Record the request of `device` from `peer`, unless too many are pending
+pub fn request(
+    store: &mut dyn HistoryStore,
+    device: &str,
+    peer: &str,
+    now: i64,
+) -> Result<Option<Requested>> {
+    check_device(device)?;
+    store.expire_enrollments(now - ENROLLMENT_LIFETIME)?;
+    if store.enrollments()?.len() >= MAX_PENDING {
+        return Ok(None);
+    }
+    let enrollment = Enrollment {
+        code: random_code()?,
+        secret: random_hex(SECRET_BYTES)?,
+        device: device.to_string(),
+        peer: peer.to_string(),
+        requested_at: now,
+        token: None,
+    };
+    store.request_enrollment(&enrollment)?;
+    Ok(Some(Requested {
+        code: enrollment.code,
+        secret: enrollment.secret,
+    }))
+}
+
+/// Where the enrollment `code` is at, if `secret` is its own; approved ones
+/// are forgotten once their token is collected
+pub fn collect(
+    store: &mut dyn HistoryStore,
+    code: &str,
+    secret: &str,
+    now: i64,
+) -> Result<Option<Status>> {
+    let code = normalize_code(code);
+    let Some(enrollment) = pending(store, now)?
+        .into_iter()
+        .find(|enrollment| enrollment.code == code && same_secret(&enrollment.secret, secret))
+    else {
+        return Ok(None);
+    };
+    match enrollment.token {
+        None => Ok(Some(Status::Pending)),
+        Some(token) => {
+            store.remove_enrollment(&code)?;
+            Ok(Some(Status::Approved(token)))
+        }
+    }
+}
+
+/// The enrollments not expired nor collected yet, oldest first
+pub fn pending(store: &mut dyn HistoryStore, now: i64) -> Result<Vec<Enrollment>> {
+    store.expire_enrollments(now - ENROLLMENT_LIFETIME)?;
+    store.enrollments()
+}
+
+/// Approve the enrollment `code`: append a new token for its device to the
+/// token_file, for its device to collect, and register the device
+pub fn approve(
+    store: &mut dyn HistoryStore,
+    api: &ApiOptions,
+    code: &str,
+    now: i64,
+) -> Result<Enrollment> {
+    let Some(path) = &api.token_file else {
+        bail!("Configure [api] token_file in server.toml to approve devices");
+    };
+    let code = normalize_code(code);
+    let Some(mut enrollment) = pending(store, now)?
+        .into_iter()
+        .find(|enrollment| enrollment.code == code)
+    else {
+        bail!("No pending enrollment {}", format_code(&code));
+    };
+    if enrollment.token.is_some() {
+        bail!("Enrollment {} is already approved", format_code(&code));
+    }
+    let token = random_hex(SECRET_BYTES)?;
+    // Written first, so that no device gets a token the server doesn't take
+    append_token(path, &token, &enrollment.device)?;
+    if !store.approve_enrollment(&code, &token)? {
+        bail!(
+            "Enrollment {} expired while approving it",
+            format_code(&code)
+        );
+    }
+    store.device_seen(&enrollment.device, &enrollment.peer, now)?;
+    enrollment.token = Some(token);
+    Ok(enrollment)
+}
+
+/// Refuse the enrollment `code`, approved or not
+pub fn deny(store: &mut dyn HistoryStore, code: &str) -> Result<()> {
+    let code = normalize_code(code);
+    if !store.remove_enrollment(&code)? {
+        bail!("No enrollment {}", format_code(&code));
+    }
+    Ok(())
+}
+
+/// A code as shown to people: its halves separated by a dash
+pub fn format_code(code: &str) -> String {
+    match code.char_indices().nth(CODE_LENGTH / 2) {
+        Some((middle, _)) => format!("{}-{}", &code[..middle], &code[middle..]),
+        None => code.to_string(),
+    }
+}
+
+/// A code as typed by people, in any case and with or without its dash
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Whether `a` and `b` are the same, compared in a time that only depends
+/// on their lengths
+pub fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Append a line for `token` and `device` to the token file, creating it
+/// only readable by its owner
+fn append_token(path: &Path, token: &str, device: &str) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to open API tokens {}", path.display()))?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .with_context(|| format!("Failed to read API tokens from {}", path.display()))?;
+    let separator = if content.is_empty() || content.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    writeln!(file, "{}{} {}", separator, token, device)
+        .with_context(|| format!("Failed to write API tokens to {}", path.display()))
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("Failed to read /dev/urandom")?;
+    Ok(bytes)
+}
+
+fn random_hex(len: usize) -> Result<String> {
+    Ok(random_bytes(len)?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn random_code() -> Result<String> {
+    // 256 is a multiple of the alphabet's 32 letters, so each is as likely
+    Ok(random_bytes(CODE_LENGTH)?
+        .iter()
+        .map(|byte| CODE_ALPHABET[*byte as usize % CODE_ALPHABET.len()] as char)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::store;
+    use rusqlite::Connection;
+
+    #[test]
+    fn approved_devices_collect_their_token_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        let path = std::env::temp_dir().join(format!("plentys-enroll-test-{}", std::process::id()));
+        std::fs::write(&path, "s3cret scripts").unwrap();
+        let api = ApiOptions {
+            token_file: Some(path.clone()),
+        };
+        assert!(request(&mut conn, "my phone", "10.0.0.2", 100).is_err());
+
+        let requested = request(&mut conn, "phone", "10.0.0.2", 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(requested.code.len(), CODE_LENGTH);
+        assert_eq!(requested.secret.len(), 2 * SECRET_BYTES);
+        let code = format_code(&requested.code).to_lowercase();
+        assert_eq!(code.len(), CODE_LENGTH + 1);
+        let poll = |conn: &mut Connection, secret: &str| collect(conn, &code, secret, 200);
+        assert_eq!(poll(&mut conn, "guess").unwrap(), None);
+        assert_eq!(
+            poll(&mut conn, &requested.secret).unwrap(),
+            Some(Status::Pending)
+        );
+
+        assert!(approve(&mut conn, &ApiOptions::default(), &code, 200).is_err());
+        let approved = approve(&mut conn, &api, &code, 200).unwrap();
+        assert!(approve(&mut conn, &api, &code, 200).is_err());
+        let token = approved.token.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("s3cret scripts\n{} phone\n", token)
+        );
+        assert_eq!(store::devices(&conn).unwrap()[0].peer, "10.0.0.2");
+        assert_eq!(
+            poll(&mut conn, &requested.secret).unwrap(),
+            Some(Status::Approved(token))
+        );
+        assert_eq!(poll(&mut conn, &requested.secret).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+
+        let requested = request(&mut conn, "phone", "10.0.0.2", 100)
+            .unwrap()
+            .unwrap();
+        deny(&mut conn, &requested.code).unwrap();
+        assert!(deny(&mut conn, &requested.code).is_err());
+        for _ in 0..MAX_PENDING {
+            request(&mut conn, "phone", "10.0.0.2", 100).unwrap();
+        }
+        assert_eq!(request(&mut conn, "phone", "10.0.0.2", 100).unwrap(), None);
+        // Until the pending ones expire
+        let later = 100 + ENROLLMENT_LIFETIME + 1;
+        assert!(request(&mut conn, "phone", "10.0.0.2", later)
+            .unwrap()
+            .is_some());
+        assert_eq!(pending(&mut conn, later).unwrap().len(), 1);
+    }
+}
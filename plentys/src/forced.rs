@@ -0,0 +1,131 @@
+use anyhow::{bail, Result};
+
+/// Check what the client asked ssh to run when plentys is an authorized_keys
+/// forced command, returning the user whose database to serve: anything other
+/// than a sync (what `plenty` runs) is refused rather than silently served.
+/// The sync may pick a database with `--user <name>`: the one `pinned` by the
+/// forced command, or without one, any of those `allowed` to the key
+pub fn check_original_command(
+    original: Option<&str>,
+    pinned: Option<&str>,
+    allowed: &[String],
+) -> Result<Option<String>> {
+    let Some(original) = original else {
+        return Ok(pinned.map(str::to_string));
+    };
+    let refuse = || {
+        bail!(
+            "This key may only sync with plentys, not run {:?}",
+            original
+        )
+    };
+    let words: Vec<&str> = original.split_whitespace().collect();
+    let picked = match words.as_slice() {
+        [program, rest @ ..] if *program == "plentys" || program.ends_with("/plentys") => {
+            match rest.strip_prefix(&["serve"]).unwrap_or(rest) {
+                [] => None,
+                ["--user", user] => Some(*user),
+                _ => return refuse(),
+            }
+        }
+        _ => return refuse(),
+    };
+    match (picked, pinned) {
+        (None, _) => Ok(pinned.map(str::to_string)),
+        (Some(picked), Some(pinned)) if picked == pinned => Ok(Some(picked.to_string())),
+        (Some(picked), None) if allowed.iter().any(|user| user == picked) => {
+            Ok(Some(picked.to_string()))
+        }
+        (Some(picked), _) => bail!("This key may not sync with the history of {:?}", picked),
+    }
+}
+
+/// The databases a key not pinned to one may pick: those its forced command
+/// allows with `--allow`, comma-separated, that are also among the
+/// `configured` ones of `[forced_command] users`, unless there are none
+pub fn allowed_users(allow: Option<&str>, configured: &[String]) -> Vec<String> {
+    allow
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|user| !user.is_empty())
+        .filter(|user| configured.is_empty() || configured.iter().any(|c| c == user))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_syncs_are_allowed() {
+        let check = |original| check_original_command(original, None, &[]);
+        assert_eq!(check(None).unwrap(), None);
+        assert!(check(Some("plentys")).is_ok());
+        assert!(check(Some("/usr/local/bin/plentys serve")).is_ok());
+        assert!(check(Some("plentys export")).is_err());
+        assert!(check(Some("plentys --db-path /etc/passwd")).is_err());
+        assert!(check(Some("plentys serve serve")).is_err());
+        assert!(check(Some("sh -c plentys")).is_err());
+        assert!(check(Some("")).is_err());
+    }
+
+    #[test]
+    fn syncs_pick_allowed_databases() {
+        let allowed = ["work".to_string(), "personal".to_string()];
+        let check = |original, pinned| check_original_command(Some(original), pinned, &allowed);
+        assert_eq!(check("plentys", None).unwrap(), None);
+        assert_eq!(
+            check("plentys --user work", None).unwrap().as_deref(),
+            Some("work")
+        );
+        assert_eq!(
+            check("plentys serve --user personal", None)
+                .unwrap()
+                .as_deref(),
+            Some("personal")
+        );
+        assert!(check("plentys --user alice", None).is_err());
+        assert!(check("plentys --user", None).is_err());
+        assert!(check("plentys --user work --user personal", None).is_err());
+        assert!(check("plentys --user work serve", None).is_err());
+
+        // Forced commands naming a database keep to it
+        assert_eq!(
+            check("plentys", Some("alice")).unwrap().as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            check("plentys --user alice", Some("alice"))
+                .unwrap()
+                .as_deref(),
+            Some("alice")
+        );
+        assert!(check("plentys --user work", Some("alice")).is_err());
+        assert_eq!(
+            check_original_command(None, Some("alice"), &allowed)
+                .unwrap()
+                .as_deref(),
+            Some("alice")
+        );
+    }
+
+    #[test]
+    fn keys_pick_among_their_own_databases() {
+        let configured = ["work".to_string(), "personal".to_string()];
+        let me = allowed_users(Some("work,personal"), &configured);
+        let colleague = allowed_users(Some("work"), &configured);
+        let check = |original, allowed: &[String]| {
+            check_original_command(Some(original), None, allowed).map(|user| user.unwrap())
+        };
+        assert_eq!(check("plentys --user personal", &me).unwrap(), "personal");
+        assert_eq!(check("plentys --user work", &colleague).unwrap(), "work");
+        assert!(check("plentys --user personal", &colleague).is_err());
+
+        // Keys allowing nothing pick nothing, and the server has the last word
+        assert!(allowed_users(None, &configured).is_empty());
+        assert_eq!(allowed_users(Some("work, ops"), &configured), ["work"]);
+        assert_eq!(allowed_users(Some("work, ops"), &[]), ["work", "ops"]);
+    }
+}
@@ -0,0 +1,516 @@
+/// `plentys grpc`: the sync service of proto/plenty.proto over gRPC, for
+/// clients in any language that want streaming and TLS without speaking the
+/// TLV protocol; backed by the same stores as sessions
+use crate::api::{self, Tokens};
+use crate::config::ServerConfig;
+use crate::listen::Pool;
+use crate::normalize;
+use crate::storage::{HistoryStore, StoreStats};
+use crate::tls::certificate_device;
+use anyhow::{Context, Result};
+use plenty_common::{cmd_hash, HistoryEntry, HistoryRequest, MatchMode, SearchOrder, SearchQuery};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Code, Request, Response, Status, Streaming};
+
+#[allow(clippy::all)]
+mod proto {
+    tonic::include_proto!("plenty.v1");
+}
+
+use proto::fetch_reply::Reply;
+use proto::history_server::{History, HistoryServer};
+
+/// Replies queued for a slow client before reading the store pauses
+const STREAM_BUFFER: usize = 256;
+
+/// What every call needs
+struct Shared {
+    pool: Pool,
+    config: ServerConfig,
+    /// Who may call; without any, anyone may read and nobody write
+    tokens: Tokens,
+}
+
+#[derive(Clone)]
+struct Service(Arc<Shared>);
+
+/// Serve gRPC on `address` until killed, over TLS if `[listen]` has a
+/// certificate and key
+pub fn run(address: &str, config: &ServerConfig, pool: Pool) -> Result<()> {
+    let tokens = Tokens::load(&config.api)?;
+    let tls = match &config.listen.tls {
+        Some((cert, key)) => Some(Identity::from_pem(
+            std::fs::read(cert)
+                .with_context(|| format!("Failed to read TLS certificate {}", cert.display()))?,
+            std::fs::read(key)
+                .with_context(|| format!("Failed to read TLS key {}", key.display()))?,
+        )),
+        None => None,
+    };
+    let client_ca = match &config.listen.client_ca {
+        Some(path) => Some(Certificate::from_pem(std::fs::read(path).with_context(
+            || {
+                format!(
+                    "Failed to read client certificate authority {}",
+                    path.display()
+                )
+            },
+        )?)),
+        None => None,
+    };
+    // Clients without certificates need tokens, if there are any
+    let certificate_required = client_ca.is_some() && tokens.current().is_empty();
+    let listener = std::net::TcpListener::bind(address)
+        .with_context(|| format!("Failed to listen on {}", address))?;
+    if !listener
+        .local_addr()
+        .is_ok_and(|addr| addr.ip().is_loopback())
+    {
+        if tokens.current().is_empty() && client_ca.is_none() {
+            tracing::warn!(
+                "gRPC calls are not authenticated, and anyone reaching {} can read every \
+                 entry; configure [api] token_file in server.toml",
+                address
+            );
+        }
+        if tls.is_none() {
+            tracing::warn!(
+                "gRPC is not encrypted without [listen] tls_cert and tls_key, only serve it \
+                 on trusted networks"
+            );
+        }
+    }
+    tracing::info!("Serving gRPC on {}…", address);
+    let shared = Shared {
+        pool,
+        config: config.clone(),
+        tokens,
+    };
+    let runtime = tokio::runtime::Runtime::new().context("Failed to start the gRPC server")?;
+    runtime.block_on(async {
+        listener
+            .set_nonblocking(true)
+            .context("Failed to set up the gRPC server's socket")?;
+        let listener = tokio::net::TcpListener::from_std(listener)
+            .context("Failed to set up the gRPC server's socket")?;
+        let mut server = Server::builder();
+        if let Some(identity) = tls {
+            let mut tls = ServerTlsConfig::new().identity(identity);
+            if let Some(ca) = client_ca {
+                tls = tls
+                    .client_ca_root(ca)
+                    .client_auth_optional(!certificate_required);
+            }
+            server = server
+                .tls_config(tls)
+                .context("Invalid TLS certificate, key or client certificate authority")?;
+        }
+        server
+            .add_service(HistoryServer::new(Service(Arc::new(shared))))
+            .serve_with_incoming(TcpIncoming::from(listener))
+            .await
+            .context("The gRPC server failed")
+    })
+}
+
+impl Service {
+    /// Check the client certificate or bearer token of a call, returning
+    /// the device it uploads as; servers without either let anyone read, and
+    /// nobody write
+    fn authorize<T>(&self, request: &Request<T>, writes: bool) -> Result<String, Refused> {
+        // Only sent, and verified against [listen] tls_client_ca, when set
+        if let Some(certificate) = request
+            .peer_certs()
+            .and_then(|certs| certs.first().cloned())
+        {
+            return certificate_device(&certificate).ok_or(Refused(
+                Code::Unauthenticated,
+                "Client certificate without a common name",
+            ));
+        }
+        let tokens = self.0.tokens.current();
+        if tokens.is_empty() {
+            if writes {
+                return Err(Refused(
+                    Code::PermissionDenied,
+                    "Configure [api] token_file in server.toml to write over gRPC",
+                ));
+            }
+            return Ok(String::new());
+        }
+        request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|bearer| api::find_token(&tokens, bearer))
+            .map(|token| token.device.clone())
+            .ok_or(Refused(
+                Code::Unauthenticated,
+                "Missing or unknown API token",
+            ))
+    }
+
+    /// Run `work` with a store from the pool, on a thread that may block
+    async fn with_store<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&mut dyn HistoryStore, &Shared) -> Result<T> + Send + 'static,
+    ) -> Result<T, Status> {
+        let shared = self.0.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut store = shared.pool.get()?;
+            let result = work(store.as_mut(), &shared);
+            shared.pool.put(store);
+            result
+        })
+        .await
+        .map_err(|e| Status::internal(format!("Call handler panicked: {}", e)))?
+        .map_err(internal)
+    }
+
+    /// Stream what `work` sends from a store of the pool, ending with its
+    /// error, if any
+    fn stream<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&mut dyn HistoryStore, &mpsc::Sender<Result<T, Status>>) -> Result<()>
+            + Send
+            + 'static,
+    ) -> ReceiverStream<Result<T, Status>> {
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let shared = self.0.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = shared.pool.get().and_then(|mut store| {
+                let result = work(store.as_mut(), &sender);
+                shared.pool.put(store);
+                result
+            });
+            if let Err(e) = result {
+                // The client may be gone already
+                let _ = sender.blocking_send(Err(internal(e)));
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+}
+
+/// Why a call was refused before reaching the store
+struct Refused(Code, &'static str);
+
+impl From<Refused> for Status {
+    fn from(Refused(code, message): Refused) -> Self {
+        Status::new(code, message)
+    }
+}
+
+/// A failure of the store, logged, and reported to the client
+fn internal(e: anyhow::Error) -> Status {
+    tracing::error!("gRPC call failed: {:#}", e);
+    Status::internal(format!("{:#}", e))
+}
+
+/// Pass `reply` on to the client, failing once it stopped listening
+fn send<T>(sender: &mpsc::Sender<Result<T, Status>>, reply: T) -> Result<()> {
+    sender
+        .blocking_send(Ok(reply))
+        .ok()
+        .context("The client went away")
+}
+
+#[tonic::async_trait]
+impl History for Service {
+    async fn upload(
+        &self,
+        request: Request<Streaming<proto::Entry>>,
+    ) -> Result<Response<proto::UploadSummary>, Status> {
+        let device = self.authorize(&request, true)?;
+        let mut entries = request.into_inner();
+        let mut summary = proto::UploadSummary::default();
+        let mut batch = Vec::new();
+        loop {
+            let entry = entries.next().await.transpose()?;
+            let done = entry.is_none();
+            batch.extend(entry.map(HistoryEntry::from));
+            if batch.len() >= api::UPLOAD_BATCH_SIZE || (done && !batch.is_empty()) {
+                let device = device.clone();
+                let batch = std::mem::take(&mut batch);
+                let uploaded = self
+                    .with_store(move |store, shared| {
+                        api::store_uploads(store, &shared.config, &device, batch)
+                    })
+                    .await?;
+                summary.received += uploaded.received as u64;
+                summary.stored += uploaded.stored as u64;
+                summary.rejected += uploaded.rejected as u64;
+            }
+            if done {
+                return Ok(Response::new(summary));
+            }
+        }
+    }
+
+    type FetchStream = ReceiverStream<Result<proto::FetchReply, Status>>;
+
+    async fn fetch(
+        &self,
+        request: Request<proto::FetchRequest>,
+    ) -> Result<Response<Self::FetchStream>, Status> {
+        self.authorize(&request, false)?;
+        let request = HistoryRequest {
+            after_seq: request.into_inner().after_seq,
+            ..Default::default()
+        };
+        Ok(Response::new(self.stream(move |store, sender| {
+            // Read before streaming, so entries stored meanwhile are sent
+            // again next time rather than missed
+            let mark = store.high_water_mark()?;
+            store.query_since(&request, &mut |page| {
+                page.into_iter().try_for_each(|entry| {
+                    let reply = Reply::Entry(entry.into());
+                    send(sender, proto::FetchReply { reply: Some(reply) })
+                })
+            })?;
+            let reply = Reply::HighWaterMark(mark);
+            send(sender, proto::FetchReply { reply: Some(reply) })
+        })))
+    }
+
+    type SearchStream = ReceiverStream<Result<proto::Entry, Status>>;
+
+    async fn search(
+        &self,
+        request: Request<proto::SearchRequest>,
+    ) -> Result<Response<Self::SearchStream>, Status> {
+        self.authorize(&request, false)?;
+        let query = normalize::query(&self.0.config.ingest, search_query(request.into_inner()));
+        Ok(Response::new(self.stream(move |store, sender| {
+            store.search(&query, &mut |entry| send(sender, entry.into()))?;
+            Ok(())
+        })))
+    }
+
+    async fn forget(
+        &self,
+        request: Request<proto::ForgetRequest>,
+    ) -> Result<Response<proto::ForgetReply>, Status> {
+        self.authorize(&request, true)?;
+        let cmd = request.into_inner().cmd;
+        if cmd.is_empty() {
+            return Err(Status::invalid_argument("Missing cmd"));
+        }
+        let deleted = self
+            .with_store(move |store, _| store.delete(cmd_hash(&cmd)))
+            .await?;
+        Ok(Response::new(proto::ForgetReply {
+            deleted: deleted as u64,
+        }))
+    }
+
+    async fn stats(
+        &self,
+        request: Request<proto::StatsRequest>,
+    ) -> Result<Response<proto::StatsReply>, Status> {
+        self.authorize(&request, false)?;
+        let stats = self.with_store(|store, _| store.stats()).await?;
+        Ok(Response::new(stats.into()))
+    }
+}
+
+/// The search a request asks for; without text, every entry matches
+fn search_query(request: proto::SearchRequest) -> SearchQuery {
+    let mode = match request.mode() {
+        _ if request.text.is_empty() => MatchMode::Substring,
+        proto::MatchMode::Words => MatchMode::Words,
+        proto::MatchMode::Substring => MatchMode::Substring,
+        proto::MatchMode::Regex => MatchMode::Regex,
+        proto::MatchMode::Glob => MatchMode::Glob,
+    };
+    let order = match request.order() {
+        proto::SearchOrder::Recent => SearchOrder::Recent,
+        proto::SearchOrder::Newest => SearchOrder::Newest,
+        proto::SearchOrder::Oldest => SearchOrder::Oldest,
+    };
+    SearchQuery {
+        text: request.text,
+        mode,
+        limit: request.limit,
+        since: request.since,
+        until: request.until,
+        hosts: request.hosts,
+        cwd: request.cwd,
+        order,
+        after: None,
+    }
+}
+
+impl From<proto::Entry> for HistoryEntry {
+    fn from(entry: proto::Entry) -> Self {
+        let mut converted = HistoryEntry::new(entry.cmd, entry.when, entry.extra)
+            .with_host(entry.host)
+            .with_pinned(entry.pinned);
+        converted.cwd = entry.cwd.unwrap_or_default();
+        converted.exit_code = entry.exit_code;
+        converted.duration_ms = entry.duration_ms;
+        converted.session = entry.session.unwrap_or_default();
+        converted.namespace = entry.namespace.unwrap_or_default();
+        converted.signature = entry.signature;
+        // Provenance is the server's to record
+        converted
+    }
+}
+
+impl From<HistoryEntry> for proto::Entry {
+    fn from(entry: HistoryEntry) -> Self {
+        let known = |text: String| Some(text).filter(|text| !text.is_empty());
+        proto::Entry {
+            cmd: entry.cmd,
+            when: entry.when,
+            extra: entry.extra,
+            host: entry.host,
+            pinned: entry.pinned,
+            cwd: known(entry.cwd),
+            exit_code: entry.exit_code,
+            duration_ms: entry.duration_ms,
+            session: known(entry.session),
+            origin_device: known(entry.origin_device),
+            received_at: entry.received_at,
+            namespace: known(entry.namespace),
+            signature: entry.signature,
+        }
+    }
+}
+
+impl From<StoreStats> for proto::StatsReply {
+    fn from(stats: StoreStats) -> Self {
+        proto::StatsReply {
+            entries: stats.entries,
+            commands: stats.commands,
+            hosts: stats.hosts,
+            pinned: stats.pinned,
+            forgotten: stats.forgotten,
+            oldest: stats.oldest,
+            newest: stats.newest,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiToken;
+    use crate::storage::Location;
+    use plenty_common::store;
+    use rusqlite::Connection;
+
+    fn service(tokens: Vec<ApiToken>, entries: &[HistoryEntry]) -> Service {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        store::insert_entries(&mut conn, entries).unwrap();
+        let config = ServerConfig::default();
+        let location = Location::Sqlite("/nonexistent/history.db".into());
+        let pool = Pool::new(location, config.database.clone(), Box::new(conn));
+        Service(Arc::new(Shared {
+            pool,
+            config,
+            tokens: Tokens::fixed(tokens),
+        }))
+    }
+
+    fn with_token<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        let value = format!("Bearer {}", token).parse().unwrap();
+        request.metadata_mut().insert("authorization", value);
+        request
+    }
+
+    /// Run a call to completion, as tonic would on its runtime
+    fn block_on<F: std::future::Future>(call: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(call)
+    }
+
+    #[test]
+    fn calls_read_and_forget_entries() {
+        block_on(async {
+            let mut entry = HistoryEntry::new("make".to_string(), 1, String::new());
+            entry.cwd = "/src".to_string();
+            let service = service(
+                Vec::new(),
+                &[
+                    entry.clone(),
+                    HistoryEntry::new("ls".to_string(), 2, String::new()),
+                ],
+            );
+
+            let mut replies = service
+                .fetch(Request::new(proto::FetchRequest { after_seq: None }))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut fetched = Vec::new();
+            while let Some(reply) = replies.next().await {
+                fetched.push(reply.unwrap().reply.unwrap());
+            }
+            assert_eq!(fetched.len(), 3);
+            assert_eq!(fetched[0], Reply::Entry(entry.clone().into()));
+            assert!(matches!(fetched[2], Reply::HighWaterMark(mark) if mark > 0));
+
+            let request = proto::SearchRequest {
+                text: "mak".to_string(),
+                ..Default::default()
+            };
+            let found: Vec<_> = service
+                .search(Request::new(request))
+                .await
+                .unwrap()
+                .into_inner()
+                .collect()
+                .await;
+            let found: Vec<_> = found.into_iter().map(|e| e.unwrap()).collect();
+            assert_eq!(found, [entry.into()]);
+
+            let stats = service.stats(Request::new(proto::StatsRequest {})).await;
+            assert_eq!(stats.unwrap().into_inner().entries, 2);
+
+            // Writing needs a token, which servers without any can't check
+            let forget = proto::ForgetRequest {
+                cmd: "ls".to_string(),
+            };
+            let error = service.forget(Request::new(forget)).await.unwrap_err();
+            assert_eq!(error.code(), Code::PermissionDenied);
+        })
+    }
+
+    #[test]
+    fn calls_need_a_known_token() {
+        block_on(async {
+            let token = ApiToken {
+                token: "s3cret".to_string(),
+                device: "phone".to_string(),
+            };
+            let service = service(
+                vec![token],
+                &[HistoryEntry::new("ls".to_string(), 2, String::new())],
+            );
+            let error = service
+                .stats(Request::new(proto::StatsRequest {}))
+                .await
+                .unwrap_err();
+            assert_eq!(error.code(), Code::Unauthenticated);
+            let error = service
+                .stats(with_token(proto::StatsRequest {}, "s3cre7"))
+                .await
+                .unwrap_err();
+            assert_eq!(error.code(), Code::Unauthenticated);
+
+            let forget = proto::ForgetRequest {
+                cmd: "ls".to_string(),
+            };
+            let reply = service.forget(with_token(forget, "s3cret")).await.unwrap();
+            assert_eq!(reply.into_inner().deleted, 1);
+        })
+    }
+}
@@ -0,0 +1,244 @@
+/// The `[hooks]` of server.toml: external commands that vet, rewrite or
+/// forward entries as they are received, and hear about finished sessions
+use crate::config::{HookOptions, Outcome};
+use anyhow::{bail, Context, Result};
+use plenty_common::store::SessionRecord;
+use plenty_common::{json, HistoryEntry};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// Asks `entry_command` about each received entry, starting it with the
+/// first one and stopping it when dropped
+pub struct EntryFilter<'a> {
+    options: &'a HookOptions,
+    process: Option<Process>,
+    /// Once the command fails, `on_failure` decides for every entry
+    failed: bool,
+}
+
+/// A running `entry_command`
+struct Process {
+    child: Child,
+    /// Closed first when stopping, so the command sees the end of its input
+    input: Option<ChildStdin>,
+    output: BufReader<ChildStdout>,
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        drop(self.input.take());
+        let _ = self.child.wait();
+    }
+}
+
+impl<'a> EntryFilter<'a> {
+    pub fn new(options: &'a HookOptions) -> Self {
+        EntryFilter {
+            options,
+            process: None,
+            failed: false,
+        }
+    }
+
+    /// The entry to store for `entry`, if any: itself, what the command
+    /// answered instead, or nothing once denied
+    pub fn check(&mut self, entry: HistoryEntry) -> Option<HistoryEntry> {
+        let Some(command) = &self.options.entry_command else {
+            return Some(entry);
+        };
+        if !self.failed {
+            match self.ask(command, &entry) {
+                Ok(Some(replacement)) => return Some(replacement),
+                Ok(None) => return None,
+                Err(e) => {
+                    tracing::error!(
+                        "hooks.entry_command failed, {} entries from now on: {:#}",
+                        match self.options.on_failure {
+                            Outcome::Allow => "allowing",
+                            Outcome::Deny => "denying",
+                        },
+                        e
+                    );
+                    self.failed = true;
+                    self.process = None;
+                }
+            }
+        }
+        match self.options.on_failure {
+            Outcome::Allow => Some(entry),
+            Outcome::Deny => None,
+        }
+    }
+
+    /// Send `entry` to the command as a jsonl line and read its answer
+    fn ask(&mut self, command: &str, entry: &HistoryEntry) -> Result<Option<HistoryEntry>> {
+        let process = match &mut self.process {
+            Some(process) => process,
+            None => self.process.insert(start(command)?),
+        };
+        let input = process.input.as_mut().context("Input already closed")?;
+        writeln!(input, "{}", json::entry(entry))
+            .and_then(|()| input.flush())
+            .context("Failed to send an entry")?;
+        let mut answer = String::new();
+        if process
+            .output
+            .read_line(&mut answer)
+            .context("Failed to read its answer")?
+            == 0
+        {
+            bail!("Exited without answering");
+        }
+        match answer.trim_end() {
+            "allow" => Ok(Some(entry.clone())),
+            "deny" => Ok(None),
+            line if line.starts_with('{') => {
+                let mut replacement =
+                    json::parse_entry(line).context("Answered an invalid entry")?;
+                // Where entries come from is still for the server to say
+                replacement.origin_device = entry.origin_device.clone();
+                replacement.received_at = entry.received_at;
+                Ok(Some(replacement))
+            }
+            other => bail!("Answered {:?} rather than allow, deny or an entry", other),
+        }
+    }
+}
+
+fn start(command: &str) -> Result<Process> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context("Failed to start it")?;
+    let input = child.stdin.take();
+    let output = BufReader::new(child.stdout.take().context("No output to read")?);
+    Ok(Process {
+        child,
+        input,
+        output,
+    })
+}
+
+/// Run `session_command`, if any, with a summary of `session`; failures
+/// are only logged, the session being over
+pub fn session_ended(options: &HookOptions, session: &SessionRecord) {
+    if let Some(command) = &options.session_command {
+        if let Err(e) = run_session_command(command, session) {
+            tracing::error!("hooks.session_command failed: {:#}", e);
+        }
+    }
+}
+
+fn run_session_command(command: &str, session: &SessionRecord) -> Result<()> {
+    // Its output mustn't mix with the protocol of sessions on stdout
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context("Failed to start it")?;
+    let summary = format!(
+        "{{\"peer\":{},\"started\":{},\"ended\":{},\"received\":{},\"sent\":{},\
+         \"rejected\":{},\"error\":{}}}\n",
+        json::string(&session.peer),
+        session.started,
+        session.ended,
+        session.received,
+        session.sent,
+        session.rejected,
+        session
+            .error
+            .as_deref()
+            .map_or("null".to_string(), json::string)
+    );
+    let written = child
+        .stdin
+        .take()
+        .context("No input to write")?
+        .write_all(summary.as_bytes());
+    let status = child.wait().context("Failed to wait for it")?;
+    written.context("Failed to send the summary")?;
+    if !status.success() {
+        bail!("Exited with status: {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filtering(entry_command: &str, on_failure: Outcome) -> HookOptions {
+        HookOptions {
+            entry_command: Some(entry_command.to_string()),
+            on_failure,
+            session_command: None,
+        }
+    }
+
+    #[test]
+    fn entry_commands_allow_deny_and_rewrite() {
+        let options = filtering(
+            r#"while read -r entry; do
+                 case "$entry" in
+                   *secret*) echo deny ;;
+                   *'"ls"'*) echo '{"cmd":"ls -l","when":1,"origin_device":"forged"}' ;;
+                   *) echo allow ;;
+                 esac
+               done"#,
+            Outcome::Deny,
+        );
+        let mut filter = EntryFilter::new(&options);
+        let make = HistoryEntry::new("make".to_string(), 1, String::new());
+        assert_eq!(filter.check(make.clone()), Some(make));
+        let secret = HistoryEntry::new("echo secret".to_string(), 2, String::new());
+        assert_eq!(filter.check(secret), None);
+        let mut ls = HistoryEntry::new("ls".to_string(), 3, String::new());
+        ls.origin_device = "laptop".to_string();
+        let rewritten = filter.check(ls).unwrap();
+        assert_eq!(rewritten.cmd, "ls -l");
+        assert_eq!(rewritten.origin_device, "laptop");
+    }
+
+    #[test]
+    fn failed_entry_commands_apply_on_failure() {
+        let entry = HistoryEntry::new("make".to_string(), 1, String::new());
+        let options = filtering("read -r entry; echo maybe", Outcome::Deny);
+        let mut filter = EntryFilter::new(&options);
+        assert_eq!(filter.check(entry.clone()), None);
+        assert_eq!(filter.check(entry.clone()), None);
+        let options = filtering("exit 1", Outcome::Allow);
+        let mut filter = EntryFilter::new(&options);
+        assert_eq!(filter.check(entry.clone()), Some(entry));
+    }
+
+    #[test]
+    fn session_commands_read_a_summary() {
+        let path = std::env::temp_dir().join(format!("plentys-hooks-test-{}", std::process::id()));
+        let options = HookOptions {
+            session_command: Some(format!("cat > {}", path.display())),
+            ..Default::default()
+        };
+        let session = SessionRecord {
+            peer: "10.0.0.5".to_string(),
+            started: 1,
+            ended: 2,
+            received: 3,
+            ..Default::default()
+        };
+        session_ended(&options, &session);
+        let summary = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            summary,
+            "{\"peer\":\"10.0.0.5\",\"started\":1,\"ended\":2,\"received\":3,\"sent\":0,\
+             \"rejected\":0,\"error\":null}\n"
+        );
+    }
+}
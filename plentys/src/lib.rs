@@ -0,0 +1,50 @@
+/// The plentys server as a library: the sync protocol's session loop, the
+/// stores it keeps history in, and the maintenance behind its subcommands,
+/// for embedders and end-to-end tests running a server in-process
+pub mod admin;
+#[cfg(any(feature = "web", feature = "grpc"))]
+pub mod api;
+pub mod archive;
+pub mod as_of;
+pub mod backup;
+pub mod config;
+pub mod enroll;
+pub mod forced;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hooks;
+pub mod listen;
+pub mod log;
+pub mod maintenance;
+pub mod normalize;
+pub mod repair;
+pub mod serve;
+pub mod stats;
+pub mod storage;
+#[cfg(feature = "grpc")]
+pub mod tls;
+pub mod transfer;
+#[cfg(feature = "web")]
+pub mod web;
+
+use anyhow::Result;
+use config::ServerConfig;
+use std::io::{Read, Write};
+use storage::HistoryStore;
+
+/// Speak the sync protocol with a client over `reader` and `writer` until it
+/// is done, as `plentys` run over ssh does with the default configuration;
+/// `serve::session` takes another one
+pub fn run_session(
+    reader: impl Read,
+    writer: impl Write,
+    store: &mut dyn HistoryStore,
+) -> Result<()> {
+    serve::session(
+        store,
+        reader,
+        writer,
+        &ServerConfig::default(),
+        "in-process",
+    )
+}
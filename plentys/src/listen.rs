@@ -0,0 +1,384 @@
+use crate::backup;
+use crate::config::{DatabaseOptions, ServerConfig};
+use crate::maintenance;
+use crate::serve;
+use crate::storage::{HistoryStore, Location};
+#[cfg(feature = "grpc")]
+use crate::tls::{Acceptor, TlsStream};
+use anyhow::{bail, Context, Result};
+use plenty_common::store;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::Scope;
+use std::time::{Duration, Instant};
+
+/// Idle connections kept for reuse; busier moments open more and close them after
+const MAX_IDLE: usize = 8;
+
+/// Where `plentys listen` accepts clients
+#[derive(Debug, Clone)]
+pub enum Address {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+type Store = Box<dyn HistoryStore + Send>;
+
+/// Database connections shared by concurrent sessions
+pub struct Pool {
+    location: Location,
+    options: DatabaseOptions,
+    idle: Mutex<Vec<Store>>,
+}
+
+impl Pool {
+    pub fn new(location: Location, options: DatabaseOptions, store: Store) -> Self {
+        Pool {
+            location,
+            options,
+            idle: Mutex::new(vec![store]),
+        }
+    }
+
+    pub fn get(&self) -> Result<Store> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(store) => Ok(store),
+            None => self.location.open(&self.options),
+        }
+    }
+
+    pub fn put(&self, store: Store) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push(store);
+        }
+    }
+}
+
+/// First file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets passed by systemd socket activation, as sd_listen_fds(3)
+/// finds them
+fn inherited_listeners() -> Result<(Vec<UnixListener>, Vec<TcpListener>)> {
+    let mut unix_listeners = Vec::new();
+    let mut tcp_listeners = Vec::new();
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    if !for_us {
+        return Ok((unix_listeners, tcp_listeners));
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")
+        .context("LISTEN_PID is set without LISTEN_FDS")?
+        .parse()
+        .context("Invalid LISTEN_FDS")?;
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        // SAFETY: systemd hands these descriptors over to us, and nothing else owns them
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        if let Ok(addr) = listener.local_addr() {
+            tracing::info!("Listening on inherited {}…", addr);
+            tcp_listeners.push(listener);
+        } else {
+            let listener = UnixListener::from(std::os::fd::OwnedFd::from(listener));
+            tracing::info!("Listening on inherited socket {}…", fd);
+            unix_listeners.push(listener);
+        }
+    }
+    Ok((unix_listeners, tcp_listeners))
+}
+
+/// Sessions in progress and when the last one ended, for idle exit
+struct Activity {
+    sessions: usize,
+    since: Instant,
+}
+
+/// Accept clients on every address, and on sockets passed by systemd, serving
+/// each on its own thread, until killed or idle for `idle_timeout`
+pub fn run(
+    addresses: &[Address],
+    idle_timeout: Option<Duration>,
+    pool: &Pool,
+    config: &ServerConfig,
+) -> Result<()> {
+    let (mut unix_listeners, mut tcp_listeners) = inherited_listeners()?;
+    for address in addresses {
+        match address {
+            Address::Unix(path) => unix_listeners.push(bind_unix(path)?),
+            Address::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("Failed to listen on {}", addr))?;
+                tracing::info!("Listening on {}…", addr);
+                tcp_listeners.push(listener);
+            }
+        }
+    }
+    if unix_listeners.is_empty() && tcp_listeners.is_empty() {
+        bail!(
+            "Nothing to listen on: pass --socket or --tcp, configure [listen] in server.toml, \
+             or use socket activation"
+        );
+    }
+    if config.backup.interval.is_some() && pool.location.file().is_none() {
+        bail!("Scheduled backups only work with SQLite databases");
+    }
+    let acceptor = match &config.listen.tls {
+        Some((cert, key)) if !tcp_listeners.is_empty() => {
+            if config.listen.client_ca.is_none() {
+                tracing::warn!(
+                    "TCP clients are not authenticated without [listen] tls_client_ca, only \
+                     listen on trusted networks"
+                );
+            }
+            Some(Acceptor::load(
+                cert,
+                key,
+                config.listen.client_ca.as_deref(),
+            )?)
+        }
+        _ => None,
+    };
+    if !tcp_listeners.is_empty() && acceptor.is_none() {
+        tracing::warn!(
+            "TCP connections are neither authenticated nor encrypted without [listen] \
+             tls_cert and tls_key, only listen on trusted networks"
+        );
+    }
+    let acceptor = acceptor.as_ref();
+
+    let activity = Mutex::new(Activity {
+        sessions: 0,
+        since: Instant::now(),
+    });
+    let activity = &activity;
+    std::thread::scope(|scope| {
+        if let Some(timeout) = idle_timeout {
+            scope.spawn(move || loop {
+                std::thread::sleep(Duration::from_secs(1));
+                // Exiting with the lock held, no session can start meanwhile;
+                // clients still connecting wait in the backlog of the socket,
+                // which systemd keeps open
+                let activity = activity.lock().unwrap();
+                if activity.sessions == 0 && activity.since.elapsed() >= timeout {
+                    tracing::info!("Idle for {}s, exiting", timeout.as_secs());
+                    std::process::exit(0);
+                }
+            });
+        }
+        if let (Some(interval), Some(db_path)) = (config.backup.interval, pool.location.file()) {
+            scope.spawn(move || back_up_periodically(interval, activity, db_path, pool, config));
+        }
+        if let (Some(interval), Some(_)) = (config.maintenance.interval, pool.location.file()) {
+            scope.spawn(move || maintain_periodically(interval, activity, pool, config));
+        }
+        for listener in &unix_listeners {
+            scope.spawn(move || loop {
+                match listener.accept() {
+                    Ok((stream, _)) => spawn_session(
+                        scope,
+                        activity,
+                        pool,
+                        config,
+                        move || Ok((stream, None)),
+                        "local client".to_string(),
+                    ),
+                    Err(e) => tracing::error!("Failed to accept connection: {}", e),
+                }
+            });
+        }
+        for listener in &tcp_listeners {
+            scope.spawn(move || loop {
+                match listener.accept() {
+                    Ok((stream, peer)) => match acceptor {
+                        Some(acceptor) => spawn_session(
+                            scope,
+                            activity,
+                            pool,
+                            config,
+                            move || acceptor.accept(stream),
+                            peer.to_string(),
+                        ),
+                        None => spawn_session(
+                            scope,
+                            activity,
+                            pool,
+                            config,
+                            move || Ok((stream, None)),
+                            peer.to_string(),
+                        ),
+                    },
+                    Err(e) => tracing::error!("Failed to accept connection: {}", e),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Make a rotating backup every `interval`, the first one once that long has
+/// passed since the newest backup, so that restarts don't delay them
+fn back_up_periodically(
+    interval: Duration,
+    activity: &Mutex<Activity>,
+    db_path: &Path,
+    pool: &Pool,
+    config: &ServerConfig,
+) {
+    let mut due = match backup::latest(db_path, &config.backup) {
+        Ok(latest) => latest.map_or(0, |latest| latest.saturating_add(interval.as_secs() as i64)),
+        Err(e) => {
+            tracing::error!("Failed to find previous backups: {:#}", e);
+            0
+        }
+    };
+    loop {
+        let wait = due - store::unix_now();
+        if wait > 0 {
+            std::thread::sleep(Duration::from_secs(wait as u64));
+        }
+        // Counts as a session so idling out doesn't interrupt it
+        activity.lock().unwrap().sessions += 1;
+        let result = pool.get().and_then(|mut store| {
+            let result = store
+                .sqlite()
+                .context("Not a SQLite database")
+                .and_then(|conn| backup::rotate(conn, db_path, &config.backup, &config.database));
+            pool.put(store);
+            result
+        });
+        activity.lock().unwrap().sessions -= 1;
+        match result {
+            Ok(path) => tracing::info!("Backed up to {}", path.display()),
+            Err(e) => tracing::error!("Scheduled backup failed: {:#}", e),
+        }
+        due = store::unix_now() + interval.as_secs() as i64;
+    }
+}
+
+/// Do the maintenance that's due on start, then every `interval`; PostgreSQL
+/// looks after itself
+fn maintain_periodically(
+    interval: Duration,
+    activity: &Mutex<Activity>,
+    pool: &Pool,
+    config: &ServerConfig,
+) {
+    loop {
+        activity.lock().unwrap().sessions += 1;
+        let result = pool.get().and_then(|mut store| {
+            let result = store
+                .sqlite()
+                .context("Not a SQLite database")
+                .and_then(|conn| maintenance::run(conn, &config.maintenance, false));
+            pool.put(store);
+            result
+        });
+        activity.lock().unwrap().sessions -= 1;
+        match result {
+            Ok(maintained) if maintained.is_empty() => {}
+            Ok(maintained) => tracing::info!("Maintenance: {}", maintained),
+            Err(e) => tracing::error!("Scheduled maintenance failed: {:#}", e),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// A stream that can be split into a reader and a writer
+trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> std::io::Result<Self>;
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl Stream for TlsStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+/// Without rustls, nothing can be served over TLS
+#[cfg(not(feature = "grpc"))]
+enum Acceptor {}
+
+#[cfg(not(feature = "grpc"))]
+impl Acceptor {
+    fn load(_cert: &Path, _key: &Path, _client_ca: Option<&Path>) -> Result<Self> {
+        bail!("[listen] tls_cert needs plentys built with the grpc feature")
+    }
+
+    fn accept(&self, _stream: TcpStream) -> Result<(TcpStream, Option<String>)> {
+        match *self {}
+    }
+}
+
+/// Serve the client of the stream `open` returns, with the device it
+/// authenticated as if any, on its own thread, opening it there so that slow
+/// TLS handshakes hold up nobody else
+fn spawn_session<'scope, S: Stream>(
+    scope: &'scope Scope<'scope, '_>,
+    activity: &'scope Mutex<Activity>,
+    pool: &'scope Pool,
+    config: &'scope ServerConfig,
+    open: impl FnOnce() -> Result<(S, Option<String>)> + Send + 'scope,
+    peer: String,
+) {
+    activity.lock().unwrap().sessions += 1;
+    scope.spawn(move || {
+        let result = open().and_then(|(stream, device)| {
+            let reader = stream.try_clone().context("Failed to split connection")?;
+            let mut store = pool.get()?;
+            let result = serve::session_as(
+                store.as_mut(),
+                reader,
+                stream,
+                config,
+                &peer,
+                device.as_deref(),
+            );
+            pool.put(store);
+            result
+        });
+        if let Err(e) = result {
+            tracing::error!("Session with {} failed: {:#}", peer, e);
+        }
+        let mut activity = activity.lock().unwrap();
+        activity.sessions -= 1;
+        activity.since = Instant::now();
+    });
+}
+
+/// Listen on a Unix socket only its owner can connect to, replacing a stale one
+fn bind_unix(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("Another server is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict access to {}", path.display()))?;
+    tracing::info!("Listening on {}…", path.display());
+    Ok(listener)
+}
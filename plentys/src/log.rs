@@ -0,0 +1,101 @@
+/// Logging of the server, through `tracing`: sessions run in a `session`
+/// span, which their events carry along with what they received and sent
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// How much the server logs, from least to most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn parse(level: &str) -> Result<Self> {
+        Ok(match level.to_lowercase().as_str() {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            _ => bail!(
+                "log level must be \"error\", \"warn\", \"info\" or \"debug\", not {:?}",
+                level
+            ),
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// How each event is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// `LEVEL span{key=value...}: message`, for people
+    #[default]
+    Text,
+    /// One JSON object per line, for journald or log shippers
+    Json,
+}
+
+/// Logging settings, configured in the `[log]` section
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    /// Overridden by `$PLENTY_LOG` or `$RUST_LOG`; defaults depend on the
+    /// command
+    pub level: Option<Level>,
+    pub format: Format,
+    /// Append here instead of writing to stderr, which ssh sends to the client
+    pub file: Option<PathBuf>,
+}
+
+/// Set up logging for this process, at `default_level` unless configured
+/// otherwise: `$PLENTY_LOG`, then `$RUST_LOG`, take filters such as
+/// `plentys::serve=debug` as well as levels
+pub fn init(options: &LogOptions, default_level: Level) -> Result<()> {
+    let filter = match env_var("PLENTY_LOG") {
+        Some(filter) => EnvFilter::try_new(filter).context("Invalid $PLENTY_LOG")?,
+        None => match env_var("RUST_LOG") {
+            Some(filter) => EnvFilter::try_new(filter).context("Invalid $RUST_LOG")?,
+            None => EnvFilter::new(options.level.unwrap_or(default_level).name()),
+        },
+    };
+    let writer = match &options.file {
+        Some(path) => BoxMakeWriter::new(Mutex::new(
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?,
+        )),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_target(false);
+    // Only the first call sets it up, as embedders may have done already
+    let _ = match options.format {
+        Format::Text => subscriber.without_time().try_init(),
+        Format::Json => subscriber.json().with_span_list(false).try_init(),
+    };
+    Ok(())
+}
+
+/// A non-empty environment variable
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
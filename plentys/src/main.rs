@@ -1,187 +1,451 @@
-use anyhow::{Context, Result};
-use plenty_common::{HistoryEntry, Message, MessageType};
-use rusqlite::{params, Connection};
-use std::io::{stdin, stdout, BufReader, BufWriter};
-use std::path::PathBuf;
+use anyhow::{bail, Context, Result};
+use plenty_common::{json, time};
+use plentys::config::ServerConfig;
+use plentys::listen::{Address, Pool};
+use plentys::transfer::Format;
+use plentys::{admin, forced, listen, log, serve, storage};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-const INSERT_BATCH_SIZE: usize = 100;
+const USAGE: &str = "Usage:
+  plentys [serve]                  speak the sync protocol on stdin/stdout (run by plenty over ssh)
+  plentys listen [--socket <path> | --tcp <address>]... [--tls] [--idle-timeout <seconds>]
+                                   serve clients connecting to a Unix socket or TCP
+                                   address (or those of [listen] in server.toml, or
+                                   sockets passed by systemd socket activation), each
+                                   on its own thread, exiting after being idle that
+                                   long; TCP is over TLS with the [listen] certificate,
+                                   which --tls requires, and client certificates of
+                                   its tls_client_ca if set (in builds with the grpc
+                                   feature)
+  plentys web [--listen <address>] [--viewer <dir>]
+                                   serve a dashboard to search the history by host and
+                                   time and chart its stats in a browser, and a JSON
+                                   API to upload, search and forget entries with the
+                                   [api] tokens from server.toml, on 127.0.0.1:8080 by
+                                   default, and the files of the directory under
+                                   /viewer/, such as the WebAssembly viewer of wasm/www
+                                   (in builds with the web feature; not encrypted)
+  plentys grpc [--listen <address>]
+                                   serve the sync service of proto/plenty.proto over
+                                   gRPC, with the [api] tokens from server.toml, on
+                                   127.0.0.1:7118 by default, over TLS with the
+                                   [listen] certificate, and client certificates of
+                                   its tls_client_ca if set (in builds with the grpc
+                                   feature)
+  plentys stats [--limit <n>] [--json]
+                                   summarize the database: entries per host, user
+                                   and day, the most frequent commands (10 by
+                                   default), its size and the health of its indexes
+  plentys export [--format <format>] [--as-of <time>] > <file>
+                                   write every entry to stdout as native protocol
+                                   frames (the default), jsonl, sql or fish_history,
+                                   or those stored at a time, with those deleted
+                                   since taken from the newest [backup] made by then
+  plentys import [--format <format>] [<file>]
+                                   read entries in native, jsonl or fish format from
+                                   the file or stdin, e.g. to seed a new server with
+                                   an old machine's fish_history
+  plentys backup [<path>]          copy the database to the file, or to a new one in the
+                                   [backup] directory from server.toml, deleting the
+                                   oldest beyond its keep count; safe while syncing
+  plentys restore <path>           replace the database with a backup
+  plentys prune [--older-than <days>]
+                                   apply the [retention] policy from server.toml, or
+                                   delete entries older than this; pinned commands are kept
+  plentys archive [--older-than <days>]
+                                   move entries older than the [archive] after_days
+                                   from server.toml, or this, to an archive database
+                                   next to the database; pinned commands are kept
+  plentys vacuum [--if-needed]     reclaim space left by deleted entries and refresh
+                                   query statistics, or only what the [maintenance]
+                                   thresholds from server.toml call for
+  plentys dedupe                   delete duplicate entries and entries without a
+                                   command or time, left by older versions
+  plentys repair [--fix]           check the database's integrity, text encoding,
+                                   duplicates and indexes, e.g. after an unclean
+                                   shutdown, and with --fix repair what doesn't need
+                                   a backup; fails if anything is left to repair
+  plentys undelete [--since <time>] [<command>]
+                                   put back the entries forgotten, deleted or pruned
+                                   at or after a time, or by the last deletion,
+                                   only those of the command if given, from the trash
+                                   that SQLite databases keep for [database] trash_days
+  plentys search [--limit <n>] [--include-archive] <words>...
+                                   list the most recent commands containing every word
+                                   (or a word starting with it), also from the archive
+  plentys suggest [--cwd <dir>] [--limit <n>] [<prefix>...]
+                                   list the commands starting with the prefix most
+                                   worth suggesting (10 by default), best first: the
+                                   most used lately, more so in the directory if given
+  plentys sessions [--limit <n>]   list the most recent sync sessions (20 by default):
+                                   who, when, entries received, sent and rejected, and errors
+  plentys devices [--older-than <days>]
+                                   list the machines that synced here and those asking
+                                   to enroll, or only those that haven't synced for
+                                   that many days
+  plentys devices approve|deny <code>
+                                   let the device asking to enroll with the code collect
+                                   a new token of the [api] token_file, or refuse it
+  plentys --version [--json]       print the version, or as JSON also the protocol and
+                                   schema versions and the capabilities of this build
+  Times are Unix timestamps, local dates and times such as 2024-05-01, yesterday 18:00
+  or friday, or durations ago such as 90d or '2 weeks ago'; days can be durations
+  such as 2w or '6 months'.
+Options:
+  --config <path>                  read server settings from this file instead of
+                                   ~/.config/plenty/server.toml
+  --user <name>                    use the separate database of this user (also
+                                   $PLENTY_USER), e.g. in an authorized_keys
+                                   command=\"plentys --user alice\" per user key
+  --forced-command                 for authorized_keys command= entries: only sync,
+                                   with the database chosen there or, without one,
+                                   one of the --allow ones the client asked for with
+                                   --user, ignoring $PLENTY_DB/$PLENTY_USER
+  --allow <names>                  comma-separated databases the clients of a
+                                   --forced-command key may pick, among
+                                   [forced_command] users if any
+  --db-path <path>                 use this database instead of $PLENTY_DB, the
+                                   [database] path from ~/.config/plenty/server.toml,
+                                   or ~/.local/share/plenty/history.db";
 
-fn flush_pending_entries(conn: &mut Connection, pending: &mut Vec<HistoryEntry>) -> Result<()> {
-    if pending.is_empty() {
-        return Ok(());
-    }
-
-    let tx = conn
-        .transaction()
-        .context("Failed to begin transaction for batched history insert")?;
-
-    {
-        let mut stmt = tx
-            .prepare("INSERT OR IGNORE INTO history (cmd, \"when\", extra) VALUES (?1, ?2, ?3)")
-            .context("Failed to prepare batched history insert statement")?;
-
-        for entry in pending.iter() {
-            stmt.execute(params![&entry.cmd, entry.when, &entry.extra])
-                .with_context(|| {
-                    format!(
-                        "Failed to insert history entry during batch (cmd='{}')",
-                        &entry.cmd
-                    )
-                })?;
-        }
-    }
+/// Where `plentys web` listens without --listen
+const WEB_ADDRESS: &str = "127.0.0.1:8080";
 
-    tx.commit()
-        .context("Failed to commit batched history insert transaction")?;
-    pending.clear();
+/// Where `plentys grpc` listens without --listen
+const GRPC_ADDRESS: &str = "127.0.0.1:7118";
 
-    Ok(())
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
 }
 
-fn main() -> Result<()> {
-    // Set up database path - respect XDG_DATA_HOME
-    let data_dir = if let Ok(xdg_data_home) = std::env::var("XDG_DATA_HOME") {
-        PathBuf::from(xdg_data_home).join("plenty")
-    } else {
-        let home = std::env::var("HOME").context("HOME environment variable not set")?;
-        PathBuf::from(home).join(".local/share/plenty")
-    };
+/// A non-empty environment variable
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
 
-    // Create directory if it doesn't exist
-    std::fs::create_dir_all(&data_dir).context("Failed to create plenty directory")?;
+/// What `plentys --version --json` prints
+fn version_json() -> String {
+    let capabilities: Vec<_> = serve::CAPABILITIES
+        .iter()
+        .map(|capability| json::string(capability))
+        .collect();
+    format!(
+        "{{\"version\":{},\"protocol\":{},\"schema_version\":{},\"capabilities\":[{}]}}",
+        json::string(env!("CARGO_PKG_VERSION")),
+        plenty_common::PROTOCOL_VERSION,
+        plenty_common::store::latest_schema_version(),
+        capabilities.join(",")
+    )
+}
 
-    let db_path = data_dir.join("history.db");
+/// Who is syncing over ssh, for the sync log: the user, if any, and the
+/// client's address from `$SSH_CONNECTION`
+fn ssh_peer(user: Option<&str>) -> String {
+    let address = std::env::var("SSH_CONNECTION")
+        .ok()
+        .and_then(|connection| connection.split_whitespace().next().map(str::to_string))
+        .unwrap_or_else(|| "local".to_string());
+    match user {
+        Some(user) => format!("{}@{}", user, address),
+        None => address,
+    }
+}
 
-    // Open/create database
-    let mut conn = Connection::open(&db_path).context("Failed to open database")?;
+#[cfg(feature = "web")]
+fn web(
+    address: &str,
+    location: storage::Location,
+    config: &ServerConfig,
+    pool: Pool,
+    viewer: Option<&Path>,
+) -> Result<()> {
+    plentys::web::run(address, location, config, pool, viewer)
+}
 
-    // Create table if it doesn't exist
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS history (
-          \"when\" INTEGER,
-          cmd TEXT,
-          extra TEXT
-        )",
-        [],
-    )
-    .context("Failed to create history table")?;
+#[cfg(not(feature = "web"))]
+fn web(
+    _address: &str,
+    _location: storage::Location,
+    _config: &ServerConfig,
+    _pool: Pool,
+    _viewer: Option<&Path>,
+) -> Result<()> {
+    bail!("plentys web needs plentys built with the web feature")
+}
 
-    conn.execute(
-        "CREATE UNIQUE INDEX IF NOT EXISTS idx_history_unique
-         ON history(cmd, \"when\", extra)",
-        [],
-    )
-    .context("Failed to create unique index")?;
+#[cfg(feature = "grpc")]
+fn grpc(address: &str, config: &ServerConfig, pool: Pool) -> Result<()> {
+    plentys::grpc::run(address, config, pool)
+}
 
-    let stdin = stdin();
-    let stdout = stdout();
-    let mut reader = BufReader::new(stdin.lock());
-    let mut writer = BufWriter::new(stdout.lock());
-    let mut pending_entries: Vec<HistoryEntry> = Vec::new();
+#[cfg(not(feature = "grpc"))]
+fn grpc(_address: &str, _config: &ServerConfig, _pool: Pool) -> Result<()> {
+    bail!("plentys grpc needs plentys built with the grpc feature")
+}
 
-    // Process incoming messages
-    loop {
-        let msg = match Message::read_from(&mut reader) {
-            Ok(m) => m,
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                // Client closed connection
-                break;
+fn main() -> Result<()> {
+    let mut command = None;
+    let mut db_path = None;
+    let mut config_path = None;
+    let mut user = None;
+    let mut forced_command = false;
+    let mut allow = None;
+    let mut tls = false;
+    let mut older_than = None;
+    let mut limit = None;
+    let mut words = Vec::new();
+    let mut addresses = Vec::new();
+    let mut idle_timeout = None;
+    let mut listen_address = None;
+    let mut viewer = None;
+    let mut format = None;
+    let mut path = None;
+    let mut if_needed = false;
+    let mut fix = false;
+    let mut as_of = None;
+    let mut since = None;
+    let mut cwd = None;
+    let mut json = false;
+    let mut include_archive = false;
+    let mut version = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--version" => version = true,
+            "--forced-command" => forced_command = true,
+            "--if-needed" => if_needed = true,
+            "--fix" => fix = true,
+            "--json" => json = true,
+            "--include-archive" => include_archive = true,
+            "--user" => user = Some(args.next().unwrap_or_else(|| usage())),
+            "--allow" => allow = Some(args.next().unwrap_or_else(|| usage())),
+            "--config" => config_path = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "--db-path" => db_path = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "--older-than" => {
+                let days = args.next().unwrap_or_else(|| usage());
+                older_than = Some(match days.parse::<u64>() {
+                    Ok(days) => days,
+                    // Or a duration such as 2w or "6 months"
+                    Err(_) => {
+                        (time::parse_duration(&days)
+                            .with_context(|| format!("Invalid number of days {:?}", days))?
+                            / 86400) as u64
+                    }
+                });
             }
-            Err(e) => {
-                eprintln!("Error reading message: {}", e);
-                let error_msg = Message::new(
-                    MessageType::Error,
-                    format!("Error reading message: {}", e).into_bytes(),
+            "--socket" => addresses.push(Address::Unix(PathBuf::from(
+                args.next().unwrap_or_else(|| usage()),
+            ))),
+            "--tcp" => addresses.push(Address::Tcp(args.next().unwrap_or_else(|| usage()))),
+            "--idle-timeout" => {
+                let seconds = args.next().unwrap_or_else(|| usage());
+                idle_timeout =
+                    Some(Duration::from_secs(seconds.parse().with_context(|| {
+                        format!("Invalid idle timeout {:?}", seconds)
+                    })?));
+            }
+            "--tls" => tls = true,
+            "--listen" => listen_address = Some(args.next().unwrap_or_else(|| usage())),
+            "--viewer" => viewer = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "--format" => format = Some(Format::parse(&args.next().unwrap_or_else(|| usage()))?),
+            "--as-of" => as_of = Some(time::parse(&args.next().unwrap_or_else(|| usage()))?),
+            "--since" => since = Some(time::parse(&args.next().unwrap_or_else(|| usage()))?),
+            "--cwd" => cwd = Some(args.next().unwrap_or_else(|| usage())),
+            "--limit" => {
+                let n = args.next().unwrap_or_else(|| usage());
+                limit = Some(
+                    n.parse::<u64>()
+                        .with_context(|| format!("Invalid limit {:?}", n))?,
                 );
-                let _ = error_msg.write_to(&mut writer);
-                break;
             }
-        };
-
-        match msg.msg_type {
-            MessageType::HistoryEntry => {
-                // Decode and insert history entry
-                match HistoryEntry::decode(&msg.data) {
-                    Ok(entry) => {
-                        pending_entries.push(entry);
-                        if pending_entries.len() >= INSERT_BATCH_SIZE {
-                            if let Err(e) = flush_pending_entries(&mut conn, &mut pending_entries) {
-                                eprintln!("Error inserting history entry batch: {}", e);
-                                let error_msg = Message::new(
-                                    MessageType::Error,
-                                    format!("Error inserting history batch: {}", e).into_bytes(),
-                                );
-                                let _ = error_msg.write_to(&mut writer);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error decoding history entry: {}", e);
-                        let error_msg = Message::new(
-                            MessageType::Error,
-                            format!("Error decoding history entry: {}", e).into_bytes(),
-                        );
-                        let _ = error_msg.write_to(&mut writer);
-                    }
-                }
+            "serve" | "listen" | "web" | "grpc" | "stats" | "export" | "import" | "prune"
+            | "archive" | "vacuum" | "search" | "sessions" | "devices" | "dedupe" | "backup"
+            | "restore" | "repair" | "undelete" | "suggest"
+                if command.is_none() =>
+            {
+                command = Some(arg)
             }
-            MessageType::GetHistory => {
-                if let Err(e) = flush_pending_entries(&mut conn, &mut pending_entries) {
-                    eprintln!("Error flushing pending history before read: {}", e);
-                    let error_msg = Message::new(
-                        MessageType::Error,
-                        format!("Error preparing history read: {}", e).into_bytes(),
-                    );
-                    let _ = error_msg.write_to(&mut writer);
-                    continue;
-                }
-
-                // Send all history back to client
-                let mut stmt = conn
-                    .prepare("SELECT cmd, \"when\", extra FROM history ORDER BY \"when\" ASC")
-                    .context("Failed to prepare select statement")?;
+            _ if matches!(
+                command.as_deref(),
+                Some("search" | "undelete" | "suggest" | "devices")
+            ) && !arg.starts_with("--") =>
+            {
+                words.push(arg)
+            }
+            _ if matches!(command.as_deref(), Some("import" | "backup" | "restore"))
+                && path.is_none()
+                && (arg == "-" || !arg.starts_with("--")) =>
+            {
+                path = Some(arg)
+            }
+            _ => usage(),
+        }
+    }
+    if version {
+        if command.is_some() {
+            usage();
+        }
+        if json {
+            println!("{}", version_json());
+        } else {
+            println!("plentys {}", env!("CARGO_PKG_VERSION"));
+        }
+        return Ok(());
+    }
+    let command = command.unwrap_or_else(|| "serve".to_string());
+    if (older_than.is_some() && !matches!(command.as_str(), "prune" | "archive" | "devices"))
+        || (limit.is_some()
+            && !matches!(
+                command.as_str(),
+                "search" | "sessions" | "stats" | "suggest"
+            ))
+        || (words.is_empty() && command == "search")
+        || (!words.is_empty()
+            && !matches!(
+                command.as_str(),
+                "search" | "undelete" | "suggest" | "devices"
+            ))
+        || ((!addresses.is_empty() || idle_timeout.is_some()) && command != "listen")
+        || (listen_address.is_some() && command != "web" && command != "grpc")
+        || (viewer.is_some() && command != "web")
+        || (format.is_some() && command != "export" && command != "import")
+        || (path.is_none() && command == "restore")
+        || (if_needed && command != "vacuum")
+        || (fix && command != "repair")
+        || (as_of.is_some() && command != "export")
+        || (since.is_some() && command != "undelete")
+        || (cwd.is_some() && command != "suggest")
+        || (json && command != "stats")
+        || (include_archive && command != "search")
+    {
+        usage();
+    }
+    if forced_command {
+        if command != "serve" || (allow.is_some() && user.is_some()) {
+            usage();
+        }
+    } else if allow.is_some() {
+        usage();
+    } else {
+        // Only trust the environment outside forced commands, where clients
+        // may be able to set it
+        user = user.or_else(|| env_var("PLENTY_USER"));
+        db_path = db_path.or_else(|| env_var("PLENTY_DB").map(PathBuf::from));
+    }
 
-                let entries = stmt
-                    .query_map([], |row| {
-                        Ok(HistoryEntry::new(row.get(0)?, row.get(1)?, row.get(2)?))
-                    })
-                    .context("Failed to query history")?;
+    let mut config = ServerConfig::load(config_path.as_deref())?;
+    if forced_command {
+        user = forced::check_original_command(
+            std::env::var("SSH_ORIGINAL_COMMAND").ok().as_deref(),
+            user.as_deref(),
+            &forced::allowed_users(allow.as_deref(), &config.forced_users),
+        )?;
+    }
+    // Sessions over ssh log to the client's terminal, so only warnings by default
+    log::init(
+        &config.log,
+        if matches!(command.as_str(), "listen" | "web" | "grpc") {
+            log::Level::Info
+        } else {
+            log::Level::Warn
+        },
+    )?;
+    // Like $PLENTY_DB, the key isn't taken from the environment of forced commands
+    config.database.load_key(!forced_command)?;
+    let location = config.location(db_path, user.as_deref())?;
+    if let Some(user) = &user {
+        config.backup.dir = config.backup.dir.map(|dir| dir.join("users").join(user));
+        config.archive.dir = config.archive.dir.map(|dir| dir.join("users").join(user));
+    }
 
-                for entry_result in entries {
-                    match entry_result {
-                        Ok(entry) => {
-                            let msg = Message::new(MessageType::HistoryEntry, entry.encode());
-                            msg.write_to(&mut writer)
-                                .context("Failed to write history entry")?;
-                        }
-                        Err(e) => {
-                            eprintln!("Error reading history entry: {}", e);
-                        }
-                    }
-                }
+    let mut store = location.open(&config.database)?;
+    if command == "listen" {
+        if tls && config.listen.tls.is_none() {
+            bail!("--tls needs [listen] tls_cert and tls_key in server.toml");
+        }
+        // Addresses on the command line replace those of the config file
+        if addresses.is_empty() {
+            addresses.extend(config.listen.sockets.iter().cloned().map(Address::Unix));
+            addresses.extend(config.listen.tcp.iter().cloned().map(Address::Tcp));
+        }
+        let idle_timeout = idle_timeout.or(config.listen.idle_timeout);
+        let pool = Pool::new(location, config.database.clone(), store);
+        return listen::run(&addresses, idle_timeout, &pool, &config);
+    }
+    if command == "web" {
+        let address = listen_address.as_deref().unwrap_or(WEB_ADDRESS);
+        let pool = Pool::new(location.clone(), config.database.clone(), store);
+        return web(address, location, &config, pool, viewer.as_deref());
+    }
+    if command == "grpc" {
+        let address = listen_address.as_deref().unwrap_or(GRPC_ADDRESS);
+        let pool = Pool::new(location, config.database.clone(), store);
+        return grpc(address, &config, pool);
+    }
+    let store = store.as_mut();
 
-                // Send end marker
-                let end_msg = Message::new(MessageType::End, Vec::new());
-                end_msg
-                    .write_to(&mut writer)
-                    .context("Failed to write end marker")?;
+    match command.as_str() {
+        "stats" => admin::stats(store, &location, &config, limit.unwrap_or(10), json),
+        "export" => admin::export(store, &location, format.unwrap_or_default(), as_of, &config),
+        "import" => admin::import(
+            store,
+            format.unwrap_or_default(),
+            path.as_deref().filter(|path| *path != "-").map(Path::new),
+            &config,
+        ),
+        "prune" => {
+            let mut retention = config.retention.clone();
+            if let Some(days) = older_than {
+                retention.max_age_days = Some(days);
             }
-            MessageType::End => {
-                // Client signaling end of transmission
-                break;
+            admin::prune(store, &retention, &config)
+        }
+        "archive" => admin::archive(store, &location, older_than, &config),
+        "vacuum" => admin::vacuum(store, if_needed, &config),
+        "backup" => admin::backup(store, &location, path.as_deref().map(Path::new), &config),
+        "restore" => admin::restore(
+            store,
+            Path::new(path.as_deref().unwrap_or_else(|| usage())),
+            &config,
+        ),
+        "dedupe" => admin::dedupe(store),
+        "repair" => admin::repair(store, fix),
+        "undelete" => {
+            let cmd = words.join(" ");
+            admin::undelete(
+                store,
+                since,
+                Some(cmd.as_str()).filter(|cmd| !cmd.is_empty()),
+            )
+        }
+        "search" => {
+            let archive = match (include_archive, location.file()) {
+                (false, _) => None,
+                (true, Some(db_path)) => Some(config.archive.path(db_path)),
+                (true, None) => bail!("--include-archive only works with SQLite databases"),
+            };
+            admin::search(store, &words.join(" "), limit, archive.as_deref())
+        }
+        "suggest" => admin::suggest(
+            store,
+            &words.join(" "),
+            cwd.as_deref(),
+            limit.unwrap_or(10),
+            &config,
+        ),
+        "sessions" => admin::sessions(store, limit.unwrap_or(20)),
+        "devices" => match words.as_slice() {
+            [] => admin::devices(store, older_than),
+            [action, code] if action == "approve" && older_than.is_none() => {
+                admin::approve_device(store, code, &config)
             }
-            MessageType::Error => {
-                eprintln!(
-                    "Received error from client: {}",
-                    String::from_utf8_lossy(&msg.data)
-                );
-                break;
+            [action, code] if action == "deny" && older_than.is_none() => {
+                admin::deny_device(store, code)
             }
-        }
+            _ => usage(),
+        },
+        _ => serve::run(store, &config, &ssh_peer(user.as_deref())),
     }
-
-    flush_pending_entries(&mut conn, &mut pending_entries)
-        .context("Failed to flush pending history entries before shutdown")?;
-
-    Ok(())
 }
@@ -0,0 +1,147 @@
+/// Keeping SQLite databases compact after deletions, and their query
+/// statistics current after imports
+use crate::config::MaintenanceOptions;
+use anyhow::{Context, Result};
+use plenty_common::store;
+use rusqlite::Connection;
+use std::time::Duration;
+
+/// Free pages given back at a time, pausing in between so sessions aren't held up
+const PAGES_PER_STEP: u64 = 1024;
+const PAUSE_BETWEEN_STEPS: Duration = Duration::from_millis(10);
+
+/// What a round of maintenance did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Maintained {
+    /// Bytes the database file shrank by
+    pub reclaimed: u64,
+    /// Whether the whole file was rebuilt, rather than trimmed
+    pub rebuilt: bool,
+    pub analyzed: bool,
+}
+
+impl Maintained {
+    pub fn is_empty(&self) -> bool {
+        *self == Maintained::default()
+    }
+}
+
+impl std::fmt::Display for Maintained {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut done = Vec::new();
+        if self.rebuilt {
+            done.push(format!(
+                "rebuilt the database, reclaiming {} bytes",
+                self.reclaimed
+            ));
+        } else if self.reclaimed > 0 {
+            done.push(format!("reclaimed {} bytes", self.reclaimed));
+        }
+        if self.analyzed {
+            done.push("refreshed query statistics".to_string());
+        }
+        f.write_str(&done.join(", "))
+    }
+}
+
+/// Reclaim free space once there's `options.free_percent` of it, and refresh
+/// query statistics once `options.analyze_after` entries came or went, or do
+/// both regardless if `force`. Databases that can't reclaim space
+/// incrementally are rebuilt, which they then can.
+pub fn run(conn: &Connection, options: &MaintenanceOptions, force: bool) -> Result<Maintained> {
+    let mut maintained = Maintained::default();
+    let before = store::space(conn)?;
+    let fragmented =
+        before.free_pages > 0 && before.free_pages * 100 >= before.pages * options.free_percent;
+    if force || !store::incremental_vacuum_enabled(conn)? {
+        if force || fragmented {
+            store::vacuum(conn)?;
+            maintained.rebuilt = true;
+        }
+    } else if fragmented {
+        let mut free_pages = before.free_pages;
+        while free_pages > 0 {
+            store::incremental_vacuum(conn, PAGES_PER_STEP)?;
+            let left = store::space(conn)?.free_pages;
+            if left >= free_pages {
+                break;
+            }
+            free_pages = left;
+            std::thread::sleep(PAUSE_BETWEEN_STEPS);
+        }
+    }
+    if maintained.rebuilt || fragmented {
+        // In WAL mode, the file only shrinks once the log is written back
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .context("Failed to checkpoint database")?;
+        maintained.reclaimed = before.bytes().saturating_sub(store::space(conn)?.bytes());
+    }
+
+    let entries = store::count_entries(conn)?;
+    let changed =
+        store::analyzed_entries(conn)?.map_or(entries, |analyzed| entries.abs_diff(analyzed));
+    if force || changed >= options.analyze_after {
+        store::analyze(conn)?;
+        maintained.analyzed = true;
+    }
+    Ok(maintained)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseOptions;
+    use plenty_common::{cmd_hash, HistoryEntry};
+
+    #[test]
+    fn freed_space_is_reclaimed_and_statistics_refreshed() {
+        let dir =
+            std::env::temp_dir().join(format!("plentys-maintenance-test-{}", std::process::id()));
+        let path = dir.join("history.db");
+        let mut conn = DatabaseOptions::default().open(&path).unwrap();
+        let options = MaintenanceOptions {
+            analyze_after: 1000,
+            ..Default::default()
+        };
+        let entries: Vec<_> = (0..2000)
+            .map(|i| {
+                HistoryEntry::new(
+                    format!("echo {} {}", i / 1000, "x".repeat(200)),
+                    i,
+                    String::new(),
+                )
+            })
+            .collect();
+        store::insert_entries(&mut conn, &entries).unwrap();
+        let maintained = run(&conn, &options, false).unwrap();
+        assert!(maintained.analyzed && maintained.reclaimed == 0);
+        assert_eq!(store::analyzed_entries(&conn).unwrap(), Some(2000));
+        assert!(run(&conn, &options, false).unwrap().is_empty());
+
+        store::forget_command(&mut conn, cmd_hash(&entries[0].cmd)).unwrap();
+        let free_pages = store::space(&conn).unwrap().free_pages;
+        store::incremental_vacuum(&conn, 2).unwrap();
+        assert_eq!(store::space(&conn).unwrap().free_pages, free_pages - 2);
+        let maintained = run(&conn, &options, false).unwrap();
+        let space = store::space(&conn).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(maintained.reclaimed > 0 && !maintained.rebuilt && maintained.analyzed);
+        assert_eq!(space.free_pages, 0);
+    }
+
+    #[test]
+    fn older_databases_are_rebuilt_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        assert!(!store::incremental_vacuum_enabled(&conn).unwrap());
+        let options = MaintenanceOptions::default();
+        assert!(!run(&conn, &options, false).unwrap().rebuilt);
+
+        let entry = HistoryEntry::new("x".repeat(100_000), 1, String::new());
+        store::insert_entries(&mut conn, std::slice::from_ref(&entry)).unwrap();
+        store::forget_command(&mut conn, cmd_hash(&entry.cmd)).unwrap();
+        let maintained = run(&conn, &options, false).unwrap();
+        assert!(maintained.rebuilt && maintained.reclaimed > 0);
+        assert!(store::incremental_vacuum_enabled(&conn).unwrap());
+    }
+}
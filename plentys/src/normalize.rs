@@ -0,0 +1,212 @@
+/// Cleaning up received entries as `[ingest]` asks, before policies, hooks
+/// and the store see them, and the searches that look for them
+use crate::config::IngestOptions;
+use plenty_common::{HistoryEntry, SearchQuery, SuggestQuery};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use unicode_normalization::UnicodeNormalization;
+
+/// Bytes of the public key signatures start with
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// `entry` as `options` would have it stored; validly signed entries are
+/// stored as sent, since cleaning them up would break their signature
+pub fn entry(options: &IngestOptions, mut entry: HistoryEntry) -> HistoryEntry {
+    if validly_signed(&entry) {
+        return entry;
+    }
+    if options.normalize_unicode {
+        entry.cmd = entry.cmd.nfc().collect();
+    }
+    if options.squeeze_spaces {
+        entry.cmd = squeeze_spaces(&entry.cmd);
+    }
+    if options.trim_trailing_whitespace {
+        let trimmed = entry.cmd.trim_end().len();
+        // Commands of whitespace alone stay as they are
+        if trimmed > 0 {
+            entry.cmd.truncate(trimmed);
+        }
+    }
+    if options.tilde_home {
+        if let Some(cwd) = tilde_home(&entry.cwd) {
+            entry.cwd = cwd;
+        }
+    }
+    entry
+}
+
+/// Whether `entry` carries a signature of its content, by whichever key;
+/// `plenty verify` decides which keys to trust
+fn validly_signed(entry: &HistoryEntry) -> bool {
+    let Some((key, signature)) = entry.signature.split_at_checked(PUBLIC_KEY_LEN) else {
+        return false;
+    };
+    UnparsedPublicKey::new(&ED25519, key)
+        .verify(&entry.signed_content(), signature)
+        .is_ok()
+}
+
+/// `query`, looking for the commands and working directories `entry` stores
+pub fn query(options: &IngestOptions, mut query: SearchQuery) -> SearchQuery {
+    if options.normalize_unicode {
+        query.text = query.text.nfc().collect();
+    }
+    if options.tilde_home {
+        if let Some(cwd) = query.cwd.as_deref().and_then(tilde_home) {
+            query.cwd = Some(cwd);
+        }
+    }
+    query
+}
+
+/// `query`, completing the commands and ranking by the working directories
+/// `entry` stores
+pub fn suggestion(options: &IngestOptions, mut query: SuggestQuery) -> SuggestQuery {
+    if options.normalize_unicode {
+        query.prefix = query.prefix.nfc().collect();
+    }
+    if options.tilde_home {
+        if let Some(cwd) = query.cwd.as_deref().and_then(tilde_home) {
+            query.cwd = Some(cwd);
+        }
+    }
+    query
+}
+
+/// `cmd` with each run of spaces between words made one, except where
+/// spaces may matter: in quotes, after a backslash, indenting a line, and in
+/// commands with here-documents
+fn squeeze_spaces(cmd: &str) -> String {
+    if cmd.contains("<<") {
+        return cmd.to_string();
+    }
+    let mut squeezed = String::with_capacity(cmd.len());
+    let mut quote = None;
+    let mut escaped = false;
+    let mut indenting = true;
+    let mut after_space = false;
+    for c in cmd.chars() {
+        let space = c == ' ' && !escaped && quote.is_none();
+        if space && after_space && !indenting {
+            continue;
+        }
+        squeezed.push(c);
+        after_space = space;
+        if escaped {
+            escaped = false;
+        } else if let Some(open) = quote {
+            if c == open {
+                quote = None;
+            } else if c == '\\' && open == '"' {
+                escaped = true;
+            }
+        } else {
+            match c {
+                '\'' | '"' => quote = Some(c),
+                '\\' => escaped = true,
+                _ => {}
+            }
+        }
+        if c == '\n' {
+            indenting = true;
+        } else if !c.is_whitespace() {
+            indenting = false;
+        }
+    }
+    squeezed
+}
+
+/// `cwd` from `~` if it's in a home directory: `/home/<user>`,
+/// `/Users/<user>` or `/root`
+fn tilde_home(cwd: &str) -> Option<String> {
+    let rest = match cwd.strip_prefix("/root") {
+        Some(rest) => rest,
+        None => {
+            let home = cwd
+                .strip_prefix("/home/")
+                .or_else(|| cwd.strip_prefix("/Users/"))?;
+            let user = home.find('/').unwrap_or(home.len());
+            if user == 0 {
+                return None;
+            }
+            &home[user..]
+        }
+    };
+    (rest.is_empty() || rest.starts_with('/')).then(|| format!("~{}", rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn entries_are_normalized_as_configured() {
+        let mut ran = HistoryEntry::new("git  commit   -m 'a  b' \t".to_string(), 1, String::new());
+        ran.cwd = "/home/alice/src".to_string();
+        assert_eq!(entry(&IngestOptions::default(), ran.clone()), ran);
+
+        let options = IngestOptions {
+            trim_trailing_whitespace: true,
+            squeeze_spaces: true,
+            tilde_home: true,
+            ..Default::default()
+        };
+        let normalized = entry(&options, ran.clone());
+        assert_eq!(normalized.cmd, "git commit -m 'a  b'");
+        assert_eq!(normalized.cwd, "~/src");
+        // Signatures that don't match are broken already
+        ran.signature = vec![7; 96];
+        assert_eq!(entry(&options, ran.clone()).cmd, "git commit -m 'a  b'");
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let signature = pair.sign(&ran.signed_content());
+        ran.signature = [pair.public_key().as_ref(), signature.as_ref()].concat();
+        assert_eq!(entry(&options, ran.clone()), ran);
+
+        let search = SearchQuery {
+            cwd: Some("/Users/alice".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query(&options, search).cwd.as_deref(), Some("~"));
+        let suggest = SuggestQuery {
+            cwd: Some("/root/src".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(suggestion(&options, suggest).cwd.as_deref(), Some("~/src"));
+    }
+
+    #[test]
+    fn spaces_that_matter_are_kept() {
+        for (cmd, squeezed) in [
+            ("ls   -l  /tmp", "ls -l /tmp"),
+            ("echo \"a   b\"  c", "echo \"a   b\" c"),
+            ("echo \"\\\"  x\"   y", "echo \"\\\"  x\" y"),
+            ("touch a\\  b", "touch a\\  b"),
+            (
+                "for f in *; do\n    echo  $f\ndone",
+                "for f in *; do\n    echo $f\ndone",
+            ),
+            ("cat <<EOF\na   b\nEOF", "cat <<EOF\na   b\nEOF"),
+        ] {
+            assert_eq!(squeeze_spaces(cmd), squeezed, "{:?}", cmd);
+        }
+    }
+
+    #[test]
+    fn home_directories_become_tildes() {
+        for (cwd, tilde) in [
+            ("/home/alice", Some("~")),
+            ("/home/alice/src/plenty", Some("~/src/plenty")),
+            ("/Users/bob/Code", Some("~/Code")),
+            ("/root/.config", Some("~/.config")),
+            ("/rootfs", None),
+            ("/home/", None),
+            ("/srv/home/alice", None),
+            ("", None),
+        ] {
+            assert_eq!(tilde_home(cwd).as_deref(), tilde, "{:?}", cwd);
+        }
+    }
+}
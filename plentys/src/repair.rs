@@ -0,0 +1,123 @@
+/// `plentys repair`: checking that a SQLite database is sound, e.g. after an
+/// unclean shutdown, and fixing in place what doesn't need a backup
+use anyhow::{Context, Result};
+use plenty_common::store;
+use rusqlite::Connection;
+
+/// What a repair found, and fixed unless it was a dry run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Repair {
+    /// What `PRAGMA integrity_check` and the search index's own check found
+    pub problems: Vec<String>,
+    /// Entries with text that isn't valid UTF-8
+    pub invalid_text: usize,
+    /// Duplicate entries, and entries without a command or time
+    pub duplicates: usize,
+    /// Per-day summaries disagreeing with the entries
+    pub stale_buckets: usize,
+    /// Problems left after repairing, which only a backup can fix
+    pub remaining: Vec<String>,
+}
+
+impl Repair {
+    /// Whether anything needed repairing
+    pub fn found_anomalies(&self) -> bool {
+        !self.problems.is_empty()
+            || self.invalid_text > 0
+            || self.duplicates > 0
+            || self.stale_buckets > 0
+    }
+}
+
+impl std::fmt::Display for Repair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.problems.len() {
+            0 => writeln!(f, "Integrity: ok")?,
+            n => writeln!(f, "Integrity: {} problems", n)?,
+        }
+        for problem in &self.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+        writeln!(f, "Entries with invalid text: {}", self.invalid_text)?;
+        writeln!(f, "Duplicate or invalid entries: {}", self.duplicates)?;
+        write!(f, "Out-of-date day summaries: {}", self.stale_buckets)
+    }
+}
+
+/// Check the database, then repair its indexes, text and duplicates in one
+/// transaction, committed if `fix` and rolled back otherwise, so that dry
+/// runs also tell what repairing would leave
+pub fn run(conn: &mut Connection, fix: bool) -> Result<Repair> {
+    let problems = store::check_indexes(conn)?;
+    let tx = conn.transaction().context("Failed to begin repair")?;
+    // First, as triggers keep the search index up to date with what follows
+    store::rebuild_indexes(&tx)?;
+    let invalid_text = store::repair_text(&tx)?;
+    let duplicates = store::dedupe(&tx)?;
+    let stale_buckets = store::rebuild_buckets(&tx)?;
+    let remaining = store::check_indexes(&tx)?;
+    if fix {
+        tx.commit().context("Failed to commit repair")?;
+    }
+    Ok(Repair {
+        problems,
+        invalid_text,
+        duplicates,
+        stale_buckets,
+        remaining,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::HistoryEntry;
+
+    #[test]
+    fn anomalies_are_reported_then_fixed() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        let entries = [
+            HistoryEntry::new("ls".to_string(), 1, String::new()),
+            HistoryEntry::new("make".to_string(), 2, String::new()),
+        ];
+        store::insert_entries(&mut conn, &entries).unwrap();
+        // What a damaged page or another writer could leave behind
+        conn.execute_batch(
+            "INSERT INTO history (cmd, \"when\", extra, hash) VALUES ('ls', 1, '', 7);
+             INSERT INTO history (cmd, \"when\", extra, hash)
+             VALUES (CAST(x'6c73ff' AS TEXT), 3, '', 8);
+             INSERT INTO history_fts (history_fts) VALUES ('delete-all');
+             UPDATE buckets SET entries = 9;",
+        )
+        .unwrap();
+
+        let dry_run = run(&mut conn, false).unwrap();
+        assert_eq!(dry_run.problems.len(), 1, "{:?}", dry_run.problems);
+        assert!(dry_run.problems[0].starts_with("search index"));
+        assert_eq!(
+            (
+                dry_run.invalid_text,
+                dry_run.duplicates,
+                dry_run.stale_buckets
+            ),
+            (1, 1, 1)
+        );
+        assert!(dry_run.remaining.is_empty());
+        // Dry runs change nothing
+        assert_eq!(run(&mut conn, false).unwrap(), dry_run);
+
+        let fixed = run(&mut conn, true).unwrap();
+        assert_eq!(fixed, dry_run);
+        let after = run(&mut conn, false).unwrap();
+        assert!(!after.found_anomalies(), "{}", after);
+        let cmds: Vec<String> = conn
+            .prepare("SELECT cmd FROM history ORDER BY \"when\"")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(cmds, ["ls", "make", "ls\u{fffd}"]);
+    }
+}
@@ -0,0 +1,1315 @@
+use crate::config::{Limits, ServerConfig};
+use crate::hooks::{self, EntryFilter};
+use crate::normalize;
+use crate::storage::HistoryStore;
+use anyhow::{bail, Context, Result};
+use plenty_common::store::{self, SessionRecord};
+use plenty_common::{
+    decode_hashes, decode_u64, encode_buckets, encode_hashes, encode_suggestions, entry_hash,
+    Hello, HistoryEntry, HistoryRequest, Message, MessageType, NotStored, PinRequest, Quota,
+    QuotaExceeded, SearchQuery, ServerInfo, ServerStats, SessionSummary, SuggestQuery,
+    PROTOCOL_VERSION,
+};
+use std::io::{stdin, stdout, BufReader, BufWriter, Read, Write};
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+
+/// Entries stored per batch at the start of a session, and at least
+const MIN_BATCH_SIZE: usize = 100;
+/// Entries stored per batch at most, however fast the database is
+const MAX_BATCH_SIZE: usize = 10_000;
+/// How long storing a batch should take: batches stored faster grow, slower
+/// ones shrink
+const BATCH_TARGET: Duration = Duration::from_millis(200);
+/// Longest received entries wait for a batch to fill before being stored
+const BATCH_AGE: Duration = Duration::from_secs(1);
+
+/// Optional features sessions support, reported in ServerInfo and by
+/// `plentys --version --json`
+pub const CAPABILITIES: &[&str] = &[
+    "incremental-history",
+    "search",
+    "regex-search",
+    "search-paging",
+    "delete",
+    "pin",
+    "entry-metadata",
+    "devices",
+    "quotas",
+    "not-stored",
+    "atomic-uploads",
+    "collapse-window",
+    "server-info",
+    "snapshot",
+    "entry-tombstones",
+    "buckets",
+    "suggest",
+    "namespaces",
+    "entry-chunks",
+    "acks",
+];
+
+/// zstd level of snapshots: fast, and still several times smaller than frames
+const SNAPSHOT_LEVEL: i32 = 3;
+
+/// Size of a message's type and length
+const MESSAGE_HEADER: u64 = 5;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// What a session has used of the configured limits
+struct Usage<'a> {
+    limits: &'a Limits,
+    bytes: u64,
+    /// Start of the current minute, and entries received since, counting
+    /// those of sessions that ended in the minute before this one started
+    minute: Instant,
+    minute_entries: u64,
+    /// Entries stored, as of the last batch inserted
+    stored: u64,
+    /// Entries stored when the session started, and the high-water mark then
+    initial: u64,
+    mark: u64,
+}
+
+impl<'a> Usage<'a> {
+    fn start(store: &mut dyn HistoryStore, limits: &'a Limits) -> Result<Self> {
+        let minute_entries = match limits.entries_per_minute {
+            Some(_) => store.received_since(store::unix_now() - MINUTE.as_secs() as i64)?,
+            None => 0,
+        };
+        let (stored, mark) = match limits.stored_entries {
+            Some(_) => (store.stats()?.entries, store.high_water_mark()?),
+            None => (0, 0),
+        };
+        Ok(Usage {
+            limits,
+            bytes: 0,
+            minute: Instant::now(),
+            minute_entries,
+            stored,
+            initial: stored,
+            mark,
+        })
+    }
+
+    /// Count the entries stored by the last batch inserted
+    fn inserted(&mut self, store: &mut dyn HistoryStore) -> Result<()> {
+        if self.limits.stored_entries.is_some() {
+            // Sequence numbers only advance for entries actually stored,
+            // by this session or others
+            self.stored = self.initial + (store.high_water_mark()? - self.mark);
+        }
+        Ok(())
+    }
+
+    /// Account for a message of `len` bytes, failing if it is over the session's byte limit
+    fn message(&mut self, len: usize) -> Result<(), QuotaExceeded> {
+        self.bytes += MESSAGE_HEADER + len as u64;
+        match self.limits.session_bytes {
+            Some(limit) if self.bytes > limit => Err(QuotaExceeded {
+                quota: Quota::SessionBytes,
+                limit,
+                retry_after: None,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Account for a received entry, `pending` others to be stored along with
+    /// it, failing if it is over a limit
+    fn entry(&mut self, pending: usize) -> Result<(), QuotaExceeded> {
+        if let Some(limit) = self.limits.stored_entries {
+            if self.stored + pending as u64 >= limit {
+                return Err(QuotaExceeded {
+                    quota: Quota::StoredEntries,
+                    limit,
+                    retry_after: None,
+                });
+            }
+        }
+        if let Some(limit) = self.limits.entries_per_minute {
+            if self.minute.elapsed() >= MINUTE {
+                self.minute = Instant::now();
+                self.minute_entries = 0;
+            }
+            self.minute_entries += 1;
+            if self.minute_entries > limit {
+                let left = MINUTE.saturating_sub(self.minute.elapsed());
+                return Err(QuotaExceeded {
+                    quota: Quota::EntriesPerMinute,
+                    limit,
+                    retry_after: Some(left.as_secs().max(1)),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// When to store received entries: in batches sized to how fast the store
+/// takes them, so that large uploads commit rarely, or once the first pending
+/// entry has waited long enough
+struct Batches {
+    size: usize,
+    /// When the first pending entry was received
+    oldest: Instant,
+}
+
+impl Batches {
+    fn new() -> Self {
+        Batches {
+            size: MIN_BATCH_SIZE,
+            oldest: Instant::now(),
+        }
+    }
+
+    /// Whether to store the `pending` entries, the last one just received
+    fn due(&mut self, pending: usize) -> bool {
+        if pending == 1 {
+            self.oldest = Instant::now();
+        }
+        pending >= self.size || self.oldest.elapsed() >= BATCH_AGE
+    }
+
+    /// Adapt the batch size to storing `entries` having taken `took`
+    fn stored(&mut self, entries: usize, took: Duration) {
+        if took > BATCH_TARGET * 2 {
+            self.size = (self.size / 2).max(MIN_BATCH_SIZE);
+        } else if took < BATCH_TARGET && entries >= self.size {
+            self.size = (self.size * 2).min(MAX_BATCH_SIZE);
+        }
+    }
+}
+
+/// Store pending entries, returning how many weren't already stored, or
+/// report them as not stored, after retries, since a failed batch stores
+/// none of them
+fn flush_pending_entries(
+    store: &mut dyn HistoryStore,
+    pending: &mut Vec<HistoryEntry>,
+    config: &ServerConfig,
+) -> Result<usize, NotStored> {
+    if pending.is_empty() {
+        return Ok(0);
+    }
+    let result = store.insert_batch(pending, config.ingest.collapse);
+    let entries = pending
+        .drain(..)
+        .map(|entry| entry_hash(&entry.cmd, entry.when, &entry.extra))
+        .collect();
+    result.map_err(|e| {
+        tracing::error!("Failed to insert history entry batch: {:#}", e);
+        NotStored {
+            error: format!("{:#}", e),
+            entries,
+        }
+    })
+}
+
+/// The entries `request` asks for as HistoryEntry messages, compressed
+/// together into the data of a Snapshot message
+fn snapshot(
+    store: &mut dyn HistoryStore,
+    request: &HistoryRequest,
+    record: &mut SessionRecord,
+) -> Result<Vec<u8>> {
+    let mut encoder = zstd::Encoder::new(Vec::new(), SNAPSHOT_LEVEL)
+        .context("Failed to start compressing snapshot")?;
+    let mut buf = Vec::new();
+    store.query_since(request, &mut |page| {
+        for entry in page {
+            record.sent += 1;
+            entry
+                .write_messages_with(&mut encoder, false, &mut buf)
+                .context("Failed to compress history entry")?;
+        }
+        Ok(())
+    })?;
+    let snapshot = encoder.finish().context("Failed to compress snapshot")?;
+    if u32::try_from(snapshot.len()).is_err() {
+        bail!("Snapshot of {} bytes is too large to send", snapshot.len());
+    }
+    Ok(snapshot)
+}
+
+/// Tell the client which entries weren't stored, ending the session
+fn send_not_stored(writer: &mut impl Write, record: &mut SessionRecord, not_stored: &NotStored) {
+    let _ = Message::new(MessageType::NotStored, not_stored.encode()).write_to(writer);
+    record.error = Some(not_stored.to_string());
+}
+
+/// Acknowledge the `received` entries of the session, all dealt with by now,
+/// to clients taking acknowledgements, unless they already were
+fn acknowledge(writer: &mut impl Write, acked: &mut Option<u64>, received: u64) -> Result<()> {
+    match acked {
+        Some(acked) if *acked < received => {
+            *acked = received;
+            Message::new(MessageType::Stored, received.to_be_bytes().to_vec())
+                .write_to(writer)
+                .context("Failed to write acknowledgement")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Tell the client about an error that doesn't end the session, and remember
+/// it for the sync log
+fn send_error(writer: &mut impl Write, record: &mut SessionRecord, error: String) {
+    let _ = Message::new(MessageType::Error, error.clone().into_bytes()).write_to(writer);
+    record.error = Some(error);
+}
+
+/// Speak the sync protocol over stdin and stdout until the client is done
+pub fn run(store: &mut dyn HistoryStore, config: &ServerConfig, peer: &str) -> Result<()> {
+    session(store, stdin().lock(), stdout().lock(), config, peer)
+}
+
+/// Speak the sync protocol with `peer` until it is done, then record the
+/// session in the sync log
+pub fn session(
+    store: &mut dyn HistoryStore,
+    reader: impl Read,
+    writer: impl Write,
+    config: &ServerConfig,
+    peer: &str,
+) -> Result<()> {
+    session_as(store, reader, writer, config, peer, None)
+}
+
+/// A session with `peer`, which uploads as `device` if it authenticated as
+/// one, whatever device its hello names
+pub fn session_as(
+    store: &mut dyn HistoryStore,
+    reader: impl Read,
+    writer: impl Write,
+    config: &ServerConfig,
+    peer: &str,
+    device: Option<&str>,
+) -> Result<()> {
+    let mut record = SessionRecord {
+        peer: peer.to_string(),
+        started: store::unix_now(),
+        ..Default::default()
+    };
+    // Everything logged meanwhile carries it, and its counts once known
+    let span = tracing::info_span!(
+        "session",
+        peer,
+        device,
+        received = Empty,
+        sent = Empty,
+        rejected = Empty,
+        duration_ms = Empty,
+    );
+    let _entered = span.enter();
+    tracing::debug!("Session started");
+    let start = Instant::now();
+    let result = exchange(store, reader, writer, config, &mut record, device);
+    record.ended = store::unix_now();
+    if let Err(e) = &result {
+        record.error = Some(format!("{:#}", e));
+    }
+    span.record("received", record.received)
+        .record("sent", record.sent)
+        .record("rejected", record.rejected)
+        .record("duration_ms", start.elapsed().as_millis() as u64);
+    match &record.error {
+        Some(error) => tracing::warn!(error, "Session ended"),
+        None => tracing::info!("Session ended"),
+    }
+    if let Err(e) = store.log_session(&record) {
+        tracing::error!("Failed to record sync session: {:#}", e);
+    }
+    hooks::session_ended(&config.hooks, &record);
+    result
+}
+
+fn exchange(
+    store: &mut dyn HistoryStore,
+    reader: impl Read,
+    writer: impl Write,
+    config: &ServerConfig,
+    record: &mut SessionRecord,
+    authenticated: Option<&str>,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut pending_entries: Vec<HistoryEntry> = Vec::new();
+    let mut batches = Batches::new();
+    // The client's name, if it authenticated or introduced itself
+    let mut device: Option<String> = authenticated.map(str::to_string);
+    // Whether the client takes large entries in chunks
+    let mut chunked = false;
+    // Entries acknowledged so far, if the client takes acknowledgements
+    let mut acked: Option<u64> = None;
+    let mut usage = Usage::start(store, &config.limits)?;
+    let mut filter = EntryFilter::new(&config.hooks);
+    // Over a limit, or once a batch fails to be stored, entries are dropped
+    // until the client asks for something, so that it reads why rather than
+    // failing to send; failures list every entry dropped
+    let mut exceeded = false;
+    let mut not_stored: Option<NotStored> = None;
+    // Entries of this session stored so far
+    let mut stored: u64 = 0;
+    // Whether the client ended the session, rather than disappearing
+    let mut finished = true;
+
+    // Process incoming messages
+    loop {
+        let msg = match Message::read_joined(&mut reader) {
+            Ok(m) => m,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                // Client closed connection
+                if let Some(not_stored) = &not_stored {
+                    send_not_stored(&mut writer, record, not_stored);
+                }
+                finished = false;
+                break;
+            }
+            Err(e) => {
+                tracing::error!("Failed to read message: {}", e);
+                send_error(&mut writer, record, format!("Error reading message: {}", e));
+                finished = false;
+                break;
+            }
+        };
+
+        let is_entry = msg.msg_type == MessageType::HistoryEntry;
+        if let Some(not_stored) = &mut not_stored {
+            if !is_entry {
+                send_not_stored(&mut writer, record, not_stored);
+                break;
+            }
+            record.received += 1;
+            if let Ok(entry) = HistoryEntry::decode(&msg.data) {
+                not_stored
+                    .entries
+                    .push(entry_hash(&entry.cmd, entry.when, &entry.extra));
+            }
+            continue;
+        }
+        if exceeded {
+            if !is_entry {
+                break;
+            }
+            record.received += 1;
+            continue;
+        }
+        let quota = usage.message(msg.data.len()).and_then(|()| match is_entry {
+            // Atomic uploads are stored whole, so what they hold counts;
+            // batches otherwise hold duplicates that won't be stored
+            true if config.atomic_uploads => usage.entry(pending_entries.len()),
+            true => usage.entry(0),
+            false => Ok(()),
+        });
+        if let Err(quota) = quota {
+            tracing::warn!("{} went over a limit: {}", record.peer, quota);
+            Message::new(MessageType::QuotaExceeded, quota.encode())
+                .write_to(&mut writer)
+                .context("Failed to write quota error")?;
+            record.error = Some(quota.to_string());
+            if !is_entry {
+                break;
+            }
+            record.received += 1;
+            exceeded = true;
+            continue;
+        }
+
+        // Entries are stored before anything else is answered
+        if !is_entry && msg.msg_type != MessageType::Error {
+            match flush_pending_entries(store, &mut pending_entries, config) {
+                Ok(inserted) => {
+                    stored += inserted as u64;
+                    usage.inserted(store)?;
+                    // Clients ending the session don't wait for it
+                    if msg.msg_type != MessageType::End {
+                        acknowledge(&mut writer, &mut acked, record.received)?;
+                    }
+                }
+                Err(failed) => {
+                    send_not_stored(&mut writer, record, &failed);
+                    break;
+                }
+            }
+        }
+
+        match msg.msg_type {
+            MessageType::HistoryEntry => {
+                // Decode and insert history entry
+                let entry = HistoryEntry::decode(&msg.data)
+                    .map(|entry| normalize::entry(&config.ingest, entry));
+                match entry {
+                    Ok(entry) if config.rejects(&entry.cmd) => {
+                        record.received += 1;
+                        record.rejected += 1;
+                    }
+                    Ok(mut entry) => {
+                        record.received += 1;
+                        // Where entries come from is for the session to say, not the client
+                        entry.origin_device = device.clone().unwrap_or_else(|| record.peer.clone());
+                        entry.received_at = Some(store::unix_now());
+                        let Some(entry) = filter.check(entry) else {
+                            record.rejected += 1;
+                            continue;
+                        };
+                        pending_entries.push(entry);
+                        // Atomic uploads are stored at once when the client asks for something
+                        if !config.atomic_uploads && batches.due(pending_entries.len()) {
+                            let (entries, started) = (pending_entries.len(), Instant::now());
+                            match flush_pending_entries(store, &mut pending_entries, config) {
+                                Ok(inserted) => {
+                                    batches.stored(entries, started.elapsed());
+                                    stored += inserted as u64;
+                                    usage.inserted(store)?;
+                                    acknowledge(&mut writer, &mut acked, record.received)?;
+                                }
+                                Err(failed) => not_stored = Some(failed),
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to decode history entry: {}", e);
+                        send_error(
+                            &mut writer,
+                            record,
+                            format!("Error decoding history entry: {}", e),
+                        );
+                    }
+                }
+            }
+            MessageType::GetHistory => {
+                let request = match HistoryRequest::decode(&msg.data) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        tracing::error!("Failed to decode history request: {}", e);
+                        send_error(
+                            &mut writer,
+                            record,
+                            format!("Error decoding history request: {}", e),
+                        );
+                        continue;
+                    }
+                };
+
+                // Read before streaming, so entries stored meanwhile are sent again
+                // next time rather than missed
+                let mark = store.high_water_mark()?;
+
+                // Deletions come first, so the client doesn't keep what the
+                // server no longer sends
+                if let Some(since) = request.tombstones_since {
+                    let hashes = store.entry_tombstones_since(since)?;
+                    Message::new(MessageType::Tombstones, encode_hashes(&hashes))
+                        .write_to(&mut writer)
+                        .context("Failed to write tombstones")?;
+                }
+
+                if request.snapshot {
+                    let snapshot = snapshot(store, &request, record)?;
+                    Message::new(MessageType::Snapshot, snapshot)
+                        .write_to(&mut writer)
+                        .context("Failed to write snapshot")?;
+                } else {
+                    // Send the requested history back to client, oldest first, a
+                    // page at a time: flushing blocks while the client is slow to
+                    // read, with no statement open and at most one page in memory
+                    let mut buf = Vec::new();
+                    store.query_since(&request, &mut |page| {
+                        for entry in page {
+                            record.sent += 1;
+                            entry
+                                .write_messages_with(&mut writer, chunked, &mut buf)
+                                .context("Failed to write history entry")?;
+                        }
+                        writer.flush().context("Failed to flush history page")
+                    })?;
+                }
+
+                // Send end marker, with the high-water mark and what became
+                // of the session's uploads, every one stored by now
+                let summary = SessionSummary {
+                    high_water_mark: mark,
+                    stored,
+                    duplicates: Some(
+                        record
+                            .received
+                            .saturating_sub(record.rejected)
+                            .saturating_sub(stored),
+                    ),
+                    rejected: Some(record.rejected),
+                };
+                let end_msg = Message::new(MessageType::End, summary.encode());
+                end_msg
+                    .write_to(&mut writer)
+                    .context("Failed to write end marker")?;
+                if let Some(name) = &device {
+                    if let Err(e) = store.device_read(name, mark) {
+                        tracing::error!("Failed to record what {} read: {:#}", name, e);
+                    }
+                }
+            }
+            MessageType::Query => {
+                let mut buf = Vec::new();
+                let result = SearchQuery::decode(&msg.data).and_then(|query| {
+                    let query = normalize::query(&config.ingest, query);
+                    // Results are flushed with the End after them
+                    store.search(&query, &mut |entry| {
+                        record.sent += 1;
+                        entry
+                            .write_messages_with(&mut writer, chunked, &mut buf)
+                            .context("Failed to write history entry")
+                    })
+                });
+                let reply = match result {
+                    Ok(next) => Message::new(
+                        MessageType::End,
+                        next.map_or_else(Vec::new, |next| next.encode()),
+                    ),
+                    Err(e) => {
+                        tracing::error!("Failed to search history: {}", e);
+                        let error = format!("Error searching history: {}", e);
+                        record.error = Some(error.clone());
+                        Message::new(MessageType::Error, error.into_bytes())
+                    }
+                };
+                reply
+                    .write_to(&mut writer)
+                    .context("Failed to write search result")?;
+            }
+            MessageType::Suggest => {
+                let result = SuggestQuery::decode(&msg.data).and_then(|query| {
+                    let query = normalize::suggestion(&config.ingest, query);
+                    store.suggest(&query, store::unix_now())
+                });
+                let reply = match result {
+                    Ok(suggestions) => {
+                        Message::new(MessageType::Suggestions, encode_suggestions(&suggestions))
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to suggest commands: {}", e);
+                        let error = format!("Error suggesting commands: {}", e);
+                        record.error = Some(error.clone());
+                        Message::new(MessageType::Error, error.into_bytes())
+                    }
+                };
+                reply
+                    .write_to(&mut writer)
+                    .context("Failed to write suggestions")?;
+            }
+            MessageType::GetStats => {
+                let stats = ServerStats {
+                    entries: store.stats()?.entries,
+                };
+                Message::new(MessageType::Stats, stats.encode())
+                    .write_to(&mut writer)
+                    .context("Failed to write stats")?;
+            }
+            MessageType::GetServerInfo => {
+                let stats = store.stats()?;
+                let info = ServerInfo {
+                    protocol: PROTOCOL_VERSION,
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    schema_version: stats.schema_version as u32,
+                    entries: stats.entries,
+                    capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+                };
+                Message::new(MessageType::ServerInfo, info.encode())
+                    .write_to(&mut writer)
+                    .context("Failed to write server info")?;
+            }
+            MessageType::Hello => {
+                match Hello::decode(&msg.data) {
+                    Ok(hello) => {
+                        chunked = hello.supports("entry-chunks");
+                        if hello.supports("acks") {
+                            acked = Some(0);
+                        }
+                        // Authenticated clients can't pass for another device
+                        if let Some(name) = authenticated.map(str::to_string).or(hello.device) {
+                            if let Err(e) =
+                                store.device_seen(&name, &record.peer, store::unix_now())
+                            {
+                                tracing::error!("Failed to register device {}: {:#}", name, e);
+                            }
+                            device = Some(name);
+                        }
+                    }
+                    Err(e) => tracing::warn!("Failed to decode client hello: {}", e),
+                }
+                let hello = Hello {
+                    capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+                    ..Hello::current()
+                };
+                Message::new(MessageType::Hello, hello.encode())
+                    .write_to(&mut writer)
+                    .context("Failed to write hello")?;
+            }
+            MessageType::DeleteEntry => {
+                let result = decode_u64(&msg.data).and_then(|hash| store.delete(hash));
+                let reply = match result {
+                    Ok(deleted) => Message::new(
+                        MessageType::Deleted,
+                        (deleted as u64).to_be_bytes().to_vec(),
+                    ),
+                    Err(e) => {
+                        tracing::error!("Failed to delete history entries: {}", e);
+                        let error = format!("Error deleting history entries: {}", e);
+                        record.error = Some(error.clone());
+                        Message::new(MessageType::Error, error.into_bytes())
+                    }
+                };
+                reply
+                    .write_to(&mut writer)
+                    .context("Failed to write deletion result")?;
+            }
+            MessageType::GetBuckets => {
+                let buckets = store.buckets()?;
+                Message::new(MessageType::Buckets, encode_buckets(&buckets))
+                    .write_to(&mut writer)
+                    .context("Failed to write buckets")?;
+            }
+            MessageType::DeleteEntries => {
+                let result =
+                    decode_hashes(&msg.data).and_then(|hashes| store.delete_entries(&hashes));
+                let reply = match result {
+                    Ok(deleted) => Message::new(
+                        MessageType::Deleted,
+                        (deleted as u64).to_be_bytes().to_vec(),
+                    ),
+                    Err(e) => {
+                        tracing::error!("Failed to delete history entries: {}", e);
+                        let error = format!("Error deleting history entries: {}", e);
+                        record.error = Some(error.clone());
+                        Message::new(MessageType::Error, error.into_bytes())
+                    }
+                };
+                reply
+                    .write_to(&mut writer)
+                    .context("Failed to write deletion result")?;
+            }
+            MessageType::PinEntry => {
+                let result = PinRequest::decode(&msg.data)
+                    .and_then(|pin| store.set_pinned(&pin.cmd, pin.pinned));
+                let reply = match result {
+                    Ok(entries) => {
+                        Message::new(MessageType::Pinned, (entries as u64).to_be_bytes().to_vec())
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to pin command: {}", e);
+                        let error = format!("Error pinning command: {}", e);
+                        record.error = Some(error.clone());
+                        Message::new(MessageType::Error, error.into_bytes())
+                    }
+                };
+                reply
+                    .write_to(&mut writer)
+                    .context("Failed to write pin result")?;
+            }
+            MessageType::Stats
+            | MessageType::ServerInfo
+            | MessageType::Snapshot
+            | MessageType::Tombstones
+            | MessageType::Buckets
+            | MessageType::Suggestions
+            | MessageType::Deleted
+            | MessageType::Pinned
+            | MessageType::QuotaExceeded
+            | MessageType::NotStored
+            | MessageType::EntryChunk
+            | MessageType::Stored => {
+                tracing::warn!("Received unexpected {:?} message from client", msg.msg_type);
+                break;
+            }
+            MessageType::End => {
+                // Client signaling end of transmission
+                break;
+            }
+            MessageType::Error => {
+                let error = format!(
+                    "Received error from client: {}",
+                    String::from_utf8_lossy(&msg.data)
+                );
+                tracing::warn!("{}", error);
+                record.error = Some(error);
+                finished = false;
+                break;
+            }
+        }
+    }
+
+    // Atomic uploads that went over a limit or failed store nothing either
+    let failed = exceeded || not_stored.is_some();
+    if config.atomic_uploads && (failed || !finished) && !pending_entries.is_empty() {
+        let error = format!(
+            "Discarded {} entries of {} upload",
+            pending_entries.len(),
+            if failed { "a failed" } else { "an unfinished" }
+        );
+        tracing::warn!("{} from {}", error, record.peer);
+        // Failures already record why
+        if !failed {
+            record.error = Some(error);
+        }
+        pending_entries.clear();
+    }
+    if let Err(failed) = flush_pending_entries(store, &mut pending_entries, config) {
+        send_not_stored(&mut writer, record, &failed);
+        return Err(failed).context("Failed to flush pending history entries before shutdown");
+    }
+
+    if config.prune_after_sync && !config.retention.is_empty() {
+        store
+            .prune(&config.retention, store::unix_now())
+            .context("Failed to prune history after sync")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage;
+    use plenty_common::{MatchMode, SearchCursor, ENTRY_CHUNK_SIZE};
+    use rusqlite::Connection;
+
+    /// An empty database in memory
+    fn database() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        conn
+    }
+
+    /// Run a session in which the client sends `msgs`, returning the replies
+    fn run(conn: &mut Connection, config: &ServerConfig, msgs: &[Message]) -> Vec<Message> {
+        let mut input = Vec::new();
+        for msg in msgs {
+            msg.write_to(&mut input).unwrap();
+        }
+        let mut output = Vec::new();
+        session(conn, &input[..], &mut output, config, "test").unwrap();
+        let mut output = &output[..];
+        std::iter::from_fn(|| Message::read_from(&mut output).ok()).collect()
+    }
+
+    fn upload(entry: &HistoryEntry) -> Message {
+        Message::new(MessageType::HistoryEntry, entry.encode())
+    }
+
+    fn ls(when: i64) -> HistoryEntry {
+        HistoryEntry::new("ls".to_string(), when, String::new())
+    }
+
+    #[test]
+    fn sessions_stop_storing_over_the_rate_limit() {
+        let mut conn = database();
+        let config = ServerConfig {
+            limits: Limits {
+                entries_per_minute: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut msgs: Vec<_> = (1..=3).map(|when| upload(&ls(when))).collect();
+        msgs.push(Message::new(MessageType::GetHistory, Vec::new()));
+
+        let replies = run(&mut conn, &config, &msgs);
+        assert_eq!(replies[0].msg_type, MessageType::QuotaExceeded);
+        let exceeded = QuotaExceeded::decode(&replies[0].data).unwrap();
+        assert_eq!(exceeded.quota, Quota::EntriesPerMinute);
+        assert_eq!(store::count_entries(&conn).unwrap(), 2);
+
+        // The next session counts what this one received
+        run(&mut conn, &config, &msgs);
+        let sessions = store::recent_sessions(&conn, 2).unwrap();
+        assert_eq!(sessions[0].received, 3);
+        assert!(sessions[1].error.is_some());
+    }
+
+    #[test]
+    fn atomic_uploads_count_towards_stored_entries() {
+        let mut conn = database();
+        let config = ServerConfig {
+            atomic_uploads: true,
+            limits: Limits {
+                stored_entries: Some(3),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let get_stats = || Message::new(MessageType::GetStats, Vec::new());
+        let msgs = [
+            upload(&ls(1)),
+            get_stats(),
+            upload(&ls(2)),
+            upload(&ls(3)),
+            upload(&ls(4)),
+            get_stats(),
+        ];
+
+        // The entry stored at the first request and the two waiting for the
+        // next one leave no room for the last, and the upload going over the
+        // limit stores nothing
+        let replies: Vec<_> = run(&mut conn, &config, &msgs)
+            .into_iter()
+            .map(|msg| msg.msg_type)
+            .collect();
+        assert_eq!(replies, [MessageType::Stats, MessageType::QuotaExceeded]);
+        assert_eq!(store::count_entries(&conn).unwrap(), 1);
+
+        // Duplicates waiting in a batch don't count outside atomic uploads
+        let mut conn = database();
+        let config = ServerConfig {
+            atomic_uploads: false,
+            ..config
+        };
+        let msgs = [upload(&ls(1)), upload(&ls(1)), upload(&ls(2)), get_stats()];
+        let replies = run(&mut conn, &config, &msgs);
+        assert_eq!(replies[0].msg_type, MessageType::Stats);
+        assert_eq!(store::count_entries(&conn).unwrap(), 2);
+    }
+
+    #[test]
+    fn composed_and_decomposed_commands_are_stored_once_if_normalized() {
+        let composed = HistoryEntry::new("cd caf\u{e9}".to_string(), 1, String::new());
+        let decomposed = HistoryEntry::new("cd cafe\u{301}".to_string(), 1, String::new());
+        let msgs = [upload(&composed), upload(&decomposed), upload(&composed)];
+        for (normalize_unicode, stored) in [(false, 2), (true, 1)] {
+            let mut conn = database();
+            let mut config = ServerConfig::default();
+            config.ingest.normalize_unicode = normalize_unicode;
+            run(&mut conn, &config, &msgs);
+            assert_eq!(store::count_entries(&conn).unwrap(), stored);
+        }
+    }
+
+    #[test]
+    fn queries_end_with_the_next_page() {
+        let mut conn = database();
+        storage::register_regexp(&conn).unwrap();
+        let entries: Vec<_> = ["cargo build", "cargo test", "ls"]
+            .iter()
+            .enumerate()
+            .map(|(when, cmd)| HistoryEntry::new(cmd.to_string(), when as i64, String::new()))
+            .collect();
+        store::insert_entries(&mut conn, &entries).unwrap();
+        let query = SearchQuery {
+            text: "^cargo (build|test)$".to_string(),
+            mode: MatchMode::Regex,
+            limit: Some(1),
+            ..Default::default()
+        };
+        let invalid = SearchQuery {
+            text: "(".to_string(),
+            ..query.clone()
+        };
+        let msgs = [
+            Message::new(MessageType::Query, query.encode()),
+            Message::new(MessageType::Query, invalid.encode()),
+        ];
+
+        let replies = run(&mut conn, &ServerConfig::default(), &msgs);
+        assert_eq!(HistoryEntry::decode(&replies[0].data).unwrap(), entries[1]);
+        assert_eq!(replies[1].msg_type, MessageType::End);
+        let next = SearchCursor::decode(&replies[1].data).unwrap();
+        assert_eq!(next.when, 1);
+        assert_eq!(replies[2].msg_type, MessageType::Error);
+    }
+
+    #[test]
+    fn server_info_reports_schema_and_entries() {
+        let mut conn = database();
+        store::insert_entries(&mut conn, &[ls(1)]).unwrap();
+        let msgs = [Message::new(MessageType::GetServerInfo, Vec::new())];
+
+        let replies = run(&mut conn, &ServerConfig::default(), &msgs);
+        assert_eq!(replies[0].msg_type, MessageType::ServerInfo);
+        let info = ServerInfo::decode(&replies[0].data).unwrap();
+        assert_eq!(info.protocol, PROTOCOL_VERSION);
+        assert_eq!(info.schema_version as usize, store::latest_schema_version());
+        assert_eq!(info.entries, 1);
+        assert!(info.capabilities.iter().any(|c| c == "server-info"));
+    }
+
+    #[test]
+    fn snapshots_hold_the_requested_entries() {
+        let mut conn = database();
+        let entries: Vec<_> = (0..1000)
+            .map(|when| HistoryEntry::new("cargo build".to_string(), when, String::new()))
+            .collect();
+        store::insert_entries(&mut conn, &entries).unwrap();
+        let request = HistoryRequest {
+            snapshot: true,
+            ..Default::default()
+        };
+        let msgs = [Message::new(MessageType::GetHistory, request.encode())];
+
+        let replies = run(&mut conn, &ServerConfig::default(), &msgs);
+        assert_eq!(replies[0].msg_type, MessageType::Snapshot);
+        let frames = zstd::decode_all(&replies[0].data[..]).unwrap();
+        assert!(replies[0].data.len() * 10 < frames.len());
+        let mut frames = &frames[..];
+        for entry in &entries {
+            let msg = Message::read_from(&mut frames).unwrap();
+            assert_eq!(HistoryEntry::decode(&msg.data).unwrap(), *entry);
+        }
+        assert!(frames.is_empty());
+        assert_eq!(replies[1].msg_type, MessageType::End);
+        assert_eq!(store::recent_sessions(&conn, 1).unwrap()[0].sent, 1000);
+    }
+
+    #[test]
+    fn deleted_entries_are_sent_as_tombstones() {
+        let mut conn = database();
+        let entries = [ls(1), ls(2)];
+        store::insert_entries(&mut conn, &entries).unwrap();
+        let hash = entry_hash("ls", 1, "");
+        let request = HistoryRequest {
+            tombstones_since: Some(0),
+            ..Default::default()
+        };
+        let msgs = [
+            Message::new(MessageType::DeleteEntries, encode_hashes(&[hash])),
+            Message::new(MessageType::GetHistory, request.encode()),
+        ];
+
+        let replies = run(&mut conn, &ServerConfig::default(), &msgs);
+        assert_eq!(replies[0].msg_type, MessageType::Deleted);
+        assert_eq!(decode_u64(&replies[0].data).unwrap(), 1);
+        assert_eq!(replies[1].msg_type, MessageType::Tombstones);
+        assert_eq!(decode_hashes(&replies[1].data).unwrap(), [hash]);
+        assert_eq!(HistoryEntry::decode(&replies[2].data).unwrap(), entries[1]);
+        assert_eq!(replies[3].msg_type, MessageType::End);
+    }
+
+    #[test]
+    fn suggestions_favor_the_client_directory() {
+        let mut conn = database();
+        let when = store::unix_now() - 10;
+        let mut msgs: Vec<_> = [("make", "/other"), ("make test", "/src"), ("ls", "/src")]
+            .into_iter()
+            .map(|(cmd, cwd)| {
+                let mut entry = HistoryEntry::new(cmd.to_string(), when, String::new());
+                entry.cwd = cwd.to_string();
+                upload(&entry)
+            })
+            .collect();
+        let query = SuggestQuery {
+            prefix: "make".to_string(),
+            cwd: Some("/src".to_string()),
+            limit: 5,
+            ..Default::default()
+        };
+        msgs.push(Message::new(MessageType::Suggest, query.encode()));
+
+        let replies = run(&mut conn, &ServerConfig::default(), &msgs);
+        assert_eq!(replies[0].msg_type, MessageType::Suggestions);
+        let cmds: Vec<_> = plenty_common::decode_suggestions(&replies[0].data)
+            .unwrap()
+            .into_iter()
+            .map(|suggestion| suggestion.cmd)
+            .collect();
+        assert_eq!(cmds, ["make test", "make"]);
+    }
+
+    #[test]
+    fn hello_offers_the_buckets_it_serves() {
+        let mut conn = database();
+        let entries = [ls(1)];
+        store::insert_entries(&mut conn, &entries).unwrap();
+        let msgs = [
+            Message::new(MessageType::Hello, Hello::current().encode()),
+            Message::new(MessageType::GetBuckets, Vec::new()),
+        ];
+
+        let replies = run(&mut conn, &ServerConfig::default(), &msgs);
+        let hello = Hello::decode(&replies[0].data).unwrap();
+        assert!(hello.supports("buckets"));
+        assert_eq!(replies[1].msg_type, MessageType::Buckets);
+        assert_eq!(
+            plenty_common::decode_buckets(&replies[1].data).unwrap(),
+            plenty_common::buckets(&entries)
+        );
+    }
+
+    #[test]
+    fn large_entries_travel_in_chunks_to_clients_taking_them() {
+        let mut conn = database();
+        let heredoc = format!("cat <<EOF\n{}\nEOF", "x".repeat(3 * ENTRY_CHUNK_SIZE));
+        let entry = HistoryEntry::new(heredoc, 1, String::new());
+        let exchange = |conn: &mut Connection, capabilities: Vec<String>| {
+            let hello = Hello {
+                capabilities,
+                ..Hello::current()
+            };
+            let mut msgs = vec![Message::new(MessageType::Hello, hello.encode())];
+            msgs.extend(entry.messages(true));
+            msgs.push(Message::new(MessageType::GetHistory, Vec::new()));
+            let replies = run(conn, &ServerConfig::default(), &msgs);
+            replies[1..]
+                .iter()
+                .map(|msg| {
+                    assert!(
+                        msg.data.len() <= ENTRY_CHUNK_SIZE
+                            || msg.msg_type != MessageType::EntryChunk
+                    );
+                    msg.msg_type
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let chunked = exchange(&mut conn, vec!["entry-chunks".to_string()]);
+        assert_eq!(
+            chunked,
+            [
+                MessageType::EntryChunk,
+                MessageType::EntryChunk,
+                MessageType::EntryChunk,
+                MessageType::HistoryEntry,
+                MessageType::End
+            ]
+        );
+        assert_eq!(store::count_entries(&conn).unwrap(), 1);
+        let stored: String = conn
+            .query_row("SELECT cmd FROM history", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, entry.cmd);
+        // Older clients get the whole entry in one message
+        let whole = exchange(&mut conn, Vec::new());
+        assert_eq!(whole, [MessageType::HistoryEntry, MessageType::End]);
+    }
+
+    #[test]
+    fn batches_grow_while_stored_quickly() {
+        let mut batches = Batches::new();
+        assert!(!batches.due(1));
+        assert!(batches.due(MIN_BATCH_SIZE));
+        batches.stored(MIN_BATCH_SIZE, Duration::from_millis(10));
+        assert_eq!(batches.size, MIN_BATCH_SIZE * 2);
+        // Batches cut short by their age don't say much about the store
+        batches.stored(10, Duration::from_millis(10));
+        assert_eq!(batches.size, MIN_BATCH_SIZE * 2);
+        batches.stored(MIN_BATCH_SIZE * 2, BATCH_TARGET * 3);
+        batches.stored(MIN_BATCH_SIZE, BATCH_TARGET * 3);
+        assert_eq!(batches.size, MIN_BATCH_SIZE);
+        for _ in 0..20 {
+            batches.stored(MAX_BATCH_SIZE, Duration::ZERO);
+        }
+        assert_eq!(batches.size, MAX_BATCH_SIZE);
+
+        batches.oldest -= BATCH_AGE;
+        assert!(batches.due(2));
+    }
+
+    #[test]
+    fn atomic_uploads_are_stored_whole_or_not_at_all() {
+        let mut conn = database();
+        let config = ServerConfig {
+            atomic_uploads: true,
+            ..Default::default()
+        };
+        let mut msgs: Vec<_> = (0..MIN_BATCH_SIZE as i64 * 2)
+            .map(|when| upload(&ls(when)))
+            .collect();
+
+        // The client goes away halfway
+        run(&mut conn, &config, &msgs);
+        assert_eq!(store::count_entries(&conn).unwrap(), 0);
+        let sessions = store::recent_sessions(&conn, 1).unwrap();
+        assert_eq!(
+            sessions[0].error.as_deref(),
+            Some("Discarded 200 entries of an unfinished upload")
+        );
+
+        msgs.push(Message::new(
+            MessageType::GetHistory,
+            HistoryRequest::default().encode(),
+        ));
+        let replies = run(&mut conn, &config, &msgs);
+        assert_eq!(store::count_entries(&conn).unwrap(), 200);
+        let end = replies.last().unwrap();
+        assert_eq!(end.msg_type, MessageType::End);
+        assert_eq!(SessionSummary::decode(&end.data).unwrap().stored, 200);
+    }
+
+    #[test]
+    fn committed_batches_are_acknowledged() {
+        let mut conn = database();
+        let config = ServerConfig {
+            reject: vec![regex_lite::Regex::new("^secret").unwrap()],
+            ..Default::default()
+        };
+        let hello = Hello {
+            capabilities: vec!["acks".to_string()],
+            ..Hello::current()
+        };
+        let mut msgs = vec![Message::new(MessageType::Hello, hello.encode())];
+        let mut cmds: Vec<_> = (0..MIN_BATCH_SIZE).map(|i| format!("echo {}", i)).collect();
+        cmds.extend(["secret=x".to_string(), "ls".to_string()]);
+        for (when, cmd) in cmds.into_iter().enumerate() {
+            msgs.push(upload(&HistoryEntry::new(cmd, when as i64, String::new())));
+        }
+        for msg_type in [MessageType::GetStats, MessageType::End] {
+            msgs.push(Message::new(msg_type, Vec::new()));
+        }
+
+        let replies: Vec<_> = run(&mut conn, &config, &msgs)
+            .into_iter()
+            .map(|msg| match msg.msg_type {
+                MessageType::Stored => Some(decode_u64(&msg.data).unwrap()),
+                _ => None,
+            })
+            .collect();
+        let batch = MIN_BATCH_SIZE as u64;
+        assert_eq!(replies, [None, Some(batch), Some(batch + 2), None]);
+        assert_eq!(store::count_entries(&conn).unwrap(), batch + 1);
+    }
+
+    #[test]
+    fn history_ends_with_what_became_of_uploads() {
+        let mut conn = database();
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        store::insert_entries(&mut conn, &[entry("ls", 1)]).unwrap();
+        let config = ServerConfig {
+            reject: vec![regex_lite::Regex::new("^secret").unwrap()],
+            ..Default::default()
+        };
+        let request = HistoryRequest {
+            limit: Some(0),
+            ..Default::default()
+        };
+        let msgs = [
+            upload(&entry("ls", 1)),
+            upload(&entry("make", 2)),
+            upload(&entry("secret=x", 3)),
+            Message::new(MessageType::GetHistory, request.encode()),
+        ];
+
+        let replies = run(&mut conn, &config, &msgs);
+        assert_eq!(replies[0].msg_type, MessageType::End);
+        assert_eq!(
+            SessionSummary::decode(&replies[0].data).unwrap(),
+            SessionSummary {
+                high_water_mark: store::high_water_mark(&conn).unwrap(),
+                stored: 1,
+                duplicates: Some(1),
+                rejected: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn uploads_record_the_device_they_came_from() {
+        let mut conn = database();
+        let mut forged = ls(1);
+        forged.origin_device = "desktop".to_string();
+        forged.received_at = Some(0);
+        let hello = Hello::current().with_device("laptop".into());
+        let config = ServerConfig::default();
+        run(
+            &mut conn,
+            &config,
+            &[
+                Message::new(MessageType::Hello, hello.encode()),
+                upload(&forged),
+            ],
+        );
+        let pwd = HistoryEntry::new("pwd".to_string(), 2, String::new());
+        run(&mut conn, &config, &[upload(&pwd)]);
+
+        let mut stored = Vec::new();
+        store::for_each_entry(&conn, &HistoryRequest::default(), |entry| {
+            stored.push(entry);
+            Ok(())
+        })
+        .unwrap();
+        let origins: Vec<_> = stored.iter().map(|e| e.origin_device.as_str()).collect();
+        assert_eq!(origins, ["laptop", "test"]);
+        assert!(stored[0].received_at.unwrap() > 0);
+    }
+
+    #[test]
+    fn authenticated_clients_upload_as_their_device() {
+        let mut conn = database();
+        let hello = Hello::current().with_device("laptop".into());
+        let mut input = Vec::new();
+        for msg in [
+            Message::new(MessageType::Hello, hello.encode()),
+            upload(&ls(1)),
+        ] {
+            msg.write_to(&mut input).unwrap();
+        }
+        let config = ServerConfig::default();
+        session_as(
+            &mut conn,
+            &input[..],
+            Vec::new(),
+            &config,
+            "test",
+            Some("phone"),
+        )
+        .unwrap();
+
+        let mut origins = Vec::new();
+        store::for_each_entry(&conn, &HistoryRequest::default(), |entry| {
+            origins.push(entry.origin_device);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(origins, ["phone"]);
+    }
+
+    #[test]
+    fn hooks_vet_entries_and_hear_about_sessions() {
+        let mut conn = database();
+        let path =
+            std::env::temp_dir().join(format!("plentys-session-hook-{}", std::process::id()));
+        let mut config = ServerConfig::default();
+        config.hooks.entry_command = Some(
+            "while read -r entry; do case \"$entry\" in *secret*) echo deny;; *) echo allow;; \
+             esac; done"
+                .to_string(),
+        );
+        config.hooks.session_command = Some(format!("cat > {}", path.display()));
+        let msgs: Vec<_> = ["ls", "echo secret", "pwd"]
+            .into_iter()
+            .map(|cmd| upload(&HistoryEntry::new(cmd.to_string(), 1, String::new())))
+            .collect();
+        run(&mut conn, &config, &msgs);
+
+        let mut stored = Vec::new();
+        store::for_each_entry(&conn, &HistoryRequest::default(), |entry| {
+            stored.push(entry.cmd);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(stored, ["ls", "pwd"]);
+        let summary = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(summary.contains("\"received\":3,\"sent\":0,\"rejected\":1,"));
+    }
+
+    #[test]
+    fn failed_batches_are_reported_entry_by_entry() {
+        let mut conn = database();
+        conn.execute_batch(
+            "CREATE TRIGGER fail BEFORE INSERT ON history BEGIN SELECT RAISE(ABORT, 'disk full'); END",
+        )
+        .unwrap();
+        let entries: Vec<_> = (0..MIN_BATCH_SIZE as i64 + 5).map(ls).collect();
+        let mut msgs: Vec<_> = entries.iter().map(upload).collect();
+        msgs.push(Message::new(MessageType::GetHistory, Vec::new()));
+
+        let replies = run(&mut conn, &ServerConfig::default(), &msgs);
+        assert_eq!(replies[0].msg_type, MessageType::NotStored);
+        let not_stored = NotStored::decode(&replies[0].data).unwrap();
+        assert!(not_stored.error.contains("disk full"));
+        let hashes: Vec<_> = entries
+            .iter()
+            .map(|entry| entry_hash(&entry.cmd, entry.when, &entry.extra))
+            .collect();
+        assert_eq!(not_stored.entries, hashes);
+    }
+}
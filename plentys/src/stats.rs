@@ -0,0 +1,268 @@
+/// What `plentys stats` reports about a database, as text or as JSON
+use crate::config::{self, DatabaseOptions};
+use crate::storage::{Breakdown, HistoryStore, Location, StoreStats};
+use anyhow::{Context, Result};
+use plenty_common::{json, store, time};
+use rusqlite::{Connection, OpenFlags};
+use std::io::Write;
+
+/// Days the histogram of entries per day covers, ending today
+const HISTOGRAM_DAYS: i64 = 30;
+/// Width of the histogram's longest bar
+const BAR_WIDTH: u64 = 40;
+
+/// How healthy a SQLite database is
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    /// Space deleted entries left, which vacuuming reclaims
+    pub free_bytes: u64,
+    /// Entries when query statistics were last gathered, if ever
+    pub analyzed_entries: Option<u64>,
+    /// Problems with tables and indexes; none if healthy
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub database: String,
+    /// Size of the database file, if it's one
+    pub size: Option<u64>,
+    pub stats: StoreStats,
+    pub breakdown: Breakdown,
+    /// Entries of each user of a shared server
+    pub per_user: Vec<(String, u64)>,
+    pub health: Option<Health>,
+}
+
+impl Report {
+    /// Look through the database at `location`, and those of its users; the
+    /// histogram ends on the day of `now`
+    pub fn gather(
+        store: &mut dyn HistoryStore,
+        location: &Location,
+        database: &DatabaseOptions,
+        top: u64,
+        now: i64,
+    ) -> Result<Self> {
+        let first_day = (now.div_euclid(86400) - HISTOGRAM_DAYS + 1) * 86400;
+        let mut breakdown = store.breakdown(top, first_day)?;
+        // Days without entries get a bar too
+        breakdown.per_day = (0..HISTOGRAM_DAYS)
+            .map(|day| first_day + day * 86400)
+            .map(|day| {
+                let entries = breakdown
+                    .per_day
+                    .iter()
+                    .find(|(d, _)| *d == day)
+                    .map_or(0, |(_, entries)| *entries);
+                (day, entries)
+            })
+            .collect();
+        let health = match store.sqlite() {
+            Some(conn) => Some(Health {
+                free_bytes: store::space(conn)?.free_bytes(),
+                analyzed_entries: store::analyzed_entries(conn)?,
+                problems: store::check_indexes(conn)?,
+            }),
+            None => None,
+        };
+        let mut per_user = Vec::new();
+        if let Some(path) = location.file() {
+            for (user, path) in config::user_databases(path)? {
+                let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                database.unlock(&conn)?;
+                per_user.push((user, store::count_entries(&conn)?));
+            }
+        }
+        Ok(Report {
+            database: location.to_string(),
+            size: location
+                .file()
+                .and_then(|path| std::fs::metadata(path).ok())
+                .map(|metadata| metadata.len()),
+            stats: store.stats()?,
+            breakdown,
+            per_user,
+            health,
+        })
+    }
+
+    pub fn write_text(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let stats = &self.stats;
+        writeln!(out, "Database: {}", self.database)?;
+        if let Some(size) = self.size {
+            writeln!(out, "Size: {} bytes", size)?;
+        }
+        writeln!(out, "Schema version: {}", stats.schema_version)?;
+        writeln!(out, "Entries: {}", stats.entries)?;
+        writeln!(out, "Commands: {}", stats.commands)?;
+        writeln!(out, "Hosts: {}", stats.hosts)?;
+        writeln!(out, "Pinned commands: {}", stats.pinned)?;
+        writeln!(out, "Forgotten commands: {}", stats.forgotten)?;
+        if let (Some(oldest), Some(newest)) = (stats.oldest, stats.newest) {
+            writeln!(out, "Oldest entry: {}", oldest)?;
+            writeln!(out, "Newest entry: {}", newest)?;
+        }
+        if let Some(health) = &self.health {
+            writeln!(out, "Free space: {} bytes", health.free_bytes)?;
+            match health.analyzed_entries {
+                Some(entries) => {
+                    writeln!(out, "Query statistics: gathered at {} entries", entries)?
+                }
+                None => writeln!(out, "Query statistics: never gathered")?,
+            }
+            if health.problems.is_empty() {
+                writeln!(out, "Integrity: ok")?;
+            }
+            for problem in &health.problems {
+                writeln!(out, "Integrity: {}", problem)?;
+            }
+        }
+
+        writeln!(out, "\nEntries per host:")?;
+        for (host, entries) in &self.breakdown.per_host {
+            let host = if host.is_empty() { "(unknown)" } else { host };
+            writeln!(out, "  {:>8} {}", entries, host)?;
+        }
+        if !self.per_user.is_empty() {
+            writeln!(out, "\nEntries per user:")?;
+            for (user, entries) in &self.per_user {
+                writeln!(out, "  {:>8} {}", entries, user)?;
+            }
+        }
+        writeln!(out, "\nTop commands:")?;
+        for (cmd, entries) in &self.breakdown.top_commands {
+            writeln!(out, "  {:>8} {}", entries, cmd.escape_debug())?;
+        }
+        writeln!(out, "\nEntries per day:")?;
+        let most = self.breakdown.per_day.iter().map(|(_, n)| *n).max();
+        for (day, entries) in &self.breakdown.per_day {
+            let bar = (entries * BAR_WIDTH).div_ceil(most.unwrap_or(1).max(1));
+            write!(out, "  {} {:>8}", time::date(*day), entries)?;
+            if bar > 0 {
+                write!(out, " {}", "#".repeat(bar as usize))?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// The report as a JSON object, on one line
+    pub fn to_json(&self) -> String {
+        let stats = &self.stats;
+        let optional = |value: Option<u64>| value.map_or("null".to_string(), |v| v.to_string());
+        let counts = |key: &str, counts: &[(String, u64)]| {
+            let items: Vec<_> = counts
+                .iter()
+                .map(|(name, entries)| {
+                    format!(
+                        "{{\"{}\":{},\"entries\":{}}}",
+                        key,
+                        json::string(name),
+                        entries
+                    )
+                })
+                .collect();
+            format!("[{}]", items.join(","))
+        };
+        let per_day: Vec<_> = self
+            .breakdown
+            .per_day
+            .iter()
+            .map(|(day, entries)| {
+                format!(
+                    "{{\"day\":{},\"date\":\"{}\",\"entries\":{}}}",
+                    day,
+                    time::date(*day),
+                    entries
+                )
+            })
+            .collect();
+        let health = self.health.as_ref().map_or("null".to_string(), |health| {
+            let problems: Vec<_> = health.problems.iter().map(|p| json::string(p)).collect();
+            format!(
+                "{{\"free_bytes\":{},\"analyzed_entries\":{},\"problems\":[{}]}}",
+                health.free_bytes,
+                optional(health.analyzed_entries),
+                problems.join(",")
+            )
+        });
+        format!(
+            "{{\"database\":{},\"size\":{},\"schema_version\":{},\"entries\":{},\
+             \"commands\":{},\"hosts\":{},\"pinned\":{},\"forgotten\":{},\"oldest\":{},\
+             \"newest\":{},\"health\":{},\"per_host\":{},\"per_user\":{},\
+             \"top_commands\":{},\"per_day\":[{}]}}",
+            json::string(&self.database),
+            optional(self.size),
+            stats.schema_version,
+            stats.entries,
+            stats.commands,
+            stats.hosts,
+            stats.pinned,
+            stats.forgotten,
+            stats.oldest.map_or("null".to_string(), |t| t.to_string()),
+            stats.newest.map_or("null".to_string(), |t| t.to_string()),
+            health,
+            counts("host", &self.breakdown.per_host),
+            counts("user", &self.per_user),
+            counts("cmd", &self.breakdown.top_commands),
+            per_day.join(",")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::HistoryEntry;
+
+    #[test]
+    fn reports_cover_hosts_commands_and_days() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        let day = 86400;
+        let entries = [
+            HistoryEntry::new("ls".to_string(), 10 * day, String::new()),
+            HistoryEntry::new("ls".to_string(), 10 * day + 1, String::new())
+                .with_host("laptop".to_string()),
+            HistoryEntry::new("say \"hi\"".to_string(), 12 * day, String::new()),
+        ];
+        store::insert_entries(&mut conn, &entries).unwrap();
+        let location = Location::Sqlite("/nonexistent/history.db".into());
+        let report = Report::gather(
+            &mut conn,
+            &location,
+            &DatabaseOptions::default(),
+            1,
+            12 * day + 5,
+        )
+        .unwrap();
+        assert_eq!(report.size, None);
+        assert_eq!(report.breakdown.per_day.len(), HISTOGRAM_DAYS as usize);
+        assert_eq!(report.breakdown.per_day.last(), Some(&(12 * day, 1)));
+        assert_eq!(
+            report.health.as_ref().unwrap().problems,
+            Vec::<String>::new()
+        );
+
+        let mut text = Vec::new();
+        report.write_text(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(
+            text.contains("\n         2 (unknown)\n         1 laptop\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("\n  1970-01-11        2 ########################################\n"));
+        assert!(text.contains("\n  1970-01-13        1 ####################\n"));
+
+        let json = report.to_json();
+        assert!(json.starts_with("{\"database\":\"/nonexistent/history.db\",\"size\":null,"));
+        assert!(json.contains(
+            "\"per_host\":[{\"host\":\"\",\"entries\":2},{\"host\":\"laptop\",\"entries\":1}]"
+        ));
+        assert!(json.contains("\"top_commands\":[{\"cmd\":\"ls\",\"entries\":2}]"));
+        assert!(json.ends_with("{\"day\":1036800,\"date\":\"1970-01-13\",\"entries\":1}]}"));
+    }
+}
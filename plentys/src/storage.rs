@@ -0,0 +1,538 @@
+/// Where the server keeps history, behind a trait so that sessions and
+/// maintenance commands don't depend on SQLite
+use crate::config::DatabaseOptions;
+use anyhow::{Context, Result};
+use plenty_common::store::{self, Collapse, Device, Enrollment, Pruned, Retention, SessionRecord};
+use plenty_common::{
+    Bucket, HistoryEntry, HistoryRequest, SearchCursor, SearchQuery, SuggestQuery, Suggestion,
+};
+use regex_lite::Regex;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::FromSql;
+use rusqlite::{Connection, ErrorCode, Params};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Attempts at a write that found the database busy, after the busy timeout
+const BUSY_ATTEMPTS: u32 = 5;
+const FIRST_BUSY_BACKOFF: Duration = Duration::from_millis(50);
+
+#[cfg(feature = "postgres")]
+pub mod postgres;
+
+/// Where the history is kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    /// A SQLite database file
+    Sqlite(PathBuf),
+    /// A PostgreSQL database, in the schema of `user` if given
+    Postgres { url: String, user: Option<String> },
+}
+
+impl Location {
+    /// Open the store, bringing its schema up to date
+    pub fn open(&self, options: &DatabaseOptions) -> Result<Box<dyn HistoryStore + Send>> {
+        match self {
+            Location::Sqlite(path) => Ok(Box::new(options.open(path)?)),
+            Location::Postgres { url, user } => connect_postgres(url, user.as_deref()),
+        }
+    }
+
+    /// The SQLite database file, if that's where the history is kept
+    pub fn file(&self) -> Option<&Path> {
+        match self {
+            Location::Sqlite(path) => Some(path),
+            Location::Postgres { .. } => None,
+        }
+    }
+}
+
+/// Shown in place of URLs, which may hold passwords
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Sqlite(path) => write!(f, "{}", path.display()),
+            Location::Postgres { user: None, .. } => f.write_str("PostgreSQL"),
+            Location::Postgres {
+                user: Some(user), ..
+            } => {
+                write!(f, "PostgreSQL (schema plenty_{})", user)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn connect_postgres(url: &str, user: Option<&str>) -> Result<Box<dyn HistoryStore + Send>> {
+    Ok(Box::new(postgres::connect(url, user)?))
+}
+
+#[cfg(not(feature = "postgres"))]
+fn connect_postgres(_url: &str, _user: Option<&str>) -> Result<Box<dyn HistoryStore + Send>> {
+    anyhow::bail!("PostgreSQL databases need plentys built with the postgres feature")
+}
+
+/// A summary of what a store holds, for `plentys stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    pub schema_version: usize,
+    pub entries: u64,
+    /// Distinct commands
+    pub commands: u64,
+    /// Distinct hosts entries were uploaded from
+    pub hosts: u64,
+    pub pinned: u64,
+    pub forgotten: u64,
+    /// Times of the oldest and newest entries, if any
+    pub oldest: Option<i64>,
+    pub newest: Option<i64>,
+}
+
+/// How entries spread over hosts, commands and days, for `plentys stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Breakdown {
+    /// Entries per host, most first; entries without a host have an empty one
+    pub per_host: Vec<(String, u64)>,
+    /// The most frequent commands and their entries, most first
+    pub top_commands: Vec<(String, u64)>,
+    /// Entries per day, by the Unix time the day starts at in UTC, oldest first
+    pub per_day: Vec<(i64, u64)>,
+}
+
+/// The server's persistence: history entries, pins and tombstones, and the
+/// records of sync sessions and devices. Methods take `&mut self` so that
+/// backends holding a client connection can implement them.
+pub trait HistoryStore {
+    /// Store entries, skipping those already stored, forgotten or pruned, and
+    /// those `collapse` says repeat a stored one, returning how many were
+    /// stored
+    fn insert_batch(
+        &mut self,
+        entries: &[HistoryEntry],
+        collapse: Option<Collapse>,
+    ) -> Result<usize>;
+
+    /// Merge the entries recording the same run as a stored entry into it,
+    /// returning the others
+    fn merge_into_stored(&mut self, entries: Vec<HistoryEntry>) -> Result<Vec<HistoryEntry>>;
+
+    /// Pass the entries `request` selects to `on_page`, oldest first, a page
+    /// at a time, without holding anything open while it runs
+    fn query_since(
+        &mut self,
+        request: &HistoryRequest,
+        on_page: &mut dyn FnMut(Vec<HistoryEntry>) -> Result<()>,
+    ) -> Result<()>;
+
+    /// Sequence number of the last entry stored, which entries stored later exceed
+    fn high_water_mark(&mut self) -> Result<u64>;
+
+    /// Pass the entries matching `query` to `on_entry`, in its order,
+    /// returning where the next page starts if its limit was reached
+    fn search(
+        &mut self,
+        query: &SearchQuery,
+        on_entry: &mut dyn FnMut(HistoryEntry) -> Result<()>,
+    ) -> Result<Option<SearchCursor>>;
+
+    /// Delete every entry of the command with this hash and refuse it from
+    /// now on, returning how many were deleted
+    fn delete(&mut self, cmd_hash: u64) -> Result<usize>;
+
+    /// Delete the entries with these content hashes and refuse them until
+    /// their tombstones expire, returning how many were deleted
+    fn delete_entries(&mut self, hashes: &[u64]) -> Result<usize>;
+
+    /// Hashes of the entries deleted at or after `since`
+    fn entry_tombstones_since(&mut self, since: i64) -> Result<Vec<u64>>;
+
+    /// Summaries of the buckets holding entries, oldest first
+    fn buckets(&mut self) -> Result<Vec<Bucket>>;
+
+    /// Pin or unpin a command, returning how many entries it has
+    fn set_pinned(&mut self, cmd: &str, pinned: bool) -> Result<usize>;
+
+    fn stats(&mut self) -> Result<StoreStats>;
+
+    /// Entries per host, the `top` most frequent commands, and entries per
+    /// day from `since` on
+    fn breakdown(&mut self, top: u64, since: i64) -> Result<Breakdown>;
+
+    /// The commands `query` asks for, best ranked at `now` first
+    fn suggest(&mut self, query: &SuggestQuery, now: i64) -> Result<Vec<Suggestion>>;
+
+    /// Delete what `retention` doesn't keep, and refuse it from now on
+    fn prune(&mut self, retention: &Retention, now: i64) -> Result<Pruned>;
+
+    fn log_session(&mut self, session: &SessionRecord) -> Result<()>;
+
+    /// The `limit` most recent sync sessions, oldest first
+    fn recent_sessions(&mut self, limit: u64) -> Result<Vec<SessionRecord>>;
+
+    /// Entries received by sync sessions that ended at or after `since`
+    fn received_since(&mut self, since: i64) -> Result<u64>;
+
+    /// Register that the device `name` is syncing from `peer`
+    fn device_seen(&mut self, name: &str, peer: &str, now: i64) -> Result<()>;
+
+    /// Record that the device `name` read history up to `mark`
+    fn device_read(&mut self, name: &str, mark: u64) -> Result<()>;
+
+    /// Every registered device, least recently seen first
+    fn devices(&mut self) -> Result<Vec<Device>>;
+
+    /// Record a device's request for an API token
+    fn request_enrollment(&mut self, enrollment: &Enrollment) -> Result<()>;
+
+    /// Every enrollment not collected yet, oldest first
+    fn enrollments(&mut self) -> Result<Vec<Enrollment>>;
+
+    /// Give the pending enrollment `code` its token, returning whether there
+    /// was one
+    fn approve_enrollment(&mut self, code: &str, token: &str) -> Result<bool>;
+
+    /// Forget the enrollment `code`, returning whether there was one
+    fn remove_enrollment(&mut self, code: &str) -> Result<bool>;
+
+    /// Forget the enrollments requested before `before`, returning how many
+    fn expire_enrollments(&mut self, before: i64) -> Result<usize>;
+
+    /// The SQLite database behind the store, if any, for what only SQLite
+    /// does: backups, vacuuming and dedupe
+    fn sqlite(&mut self) -> Option<&mut Connection> {
+        None
+    }
+}
+
+/// Whether `error` comes from SQLite finding the database locked
+fn is_busy(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(e, _))
+                if matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+        )
+    })
+}
+
+/// Run a write, retrying with exponential backoff while the database is
+/// busy. The busy timeout doesn't cover every case: a transaction whose
+/// snapshot another one wrote past fails at once.
+fn retry_busy<T>(mut write: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut backoff = FIRST_BUSY_BACKOFF;
+    for _ in 1..BUSY_ATTEMPTS {
+        match write() {
+            Err(e) if is_busy(&e) => {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    write()
+}
+
+/// Define SQLite's REGEXP operator, which regex searches use, with the
+/// syntax of `regex_lite`
+pub fn register_regexp(conn: &Connection) -> Result<()> {
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            // Compiled once per statement
+            let regex = ctx.get_or_create_aux(
+                0,
+                |pattern| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+                    Ok(Regex::new(pattern.as_str()?)?)
+                },
+            )?;
+            let text = ctx
+                .get_raw(1)
+                .as_str_or_null()
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            Ok(text.is_some_and(|text| regex.is_match(text)))
+        },
+    )
+    .context("Failed to register regexp function")
+}
+
+/// Rows of a key and a count
+fn counts<K: FromSql>(conn: &Connection, sql: &str, params: impl Params) -> Result<Vec<(K, u64)>> {
+    let mut stmt = conn
+        .prepare(sql)
+        .with_context(|| format!("Failed to prepare {:?}", sql))?;
+    let rows = stmt
+        .query_map(params, |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+        })
+        .and_then(|rows| rows.collect())
+        .with_context(|| format!("Failed to run {:?}", sql))?;
+    Ok(rows)
+}
+
+/// The default store, a SQLite database
+impl HistoryStore for Connection {
+    fn insert_batch(
+        &mut self,
+        entries: &[HistoryEntry],
+        collapse: Option<Collapse>,
+    ) -> Result<usize> {
+        retry_busy(|| store::insert_entries_collapsing(self, entries, collapse))
+    }
+
+    fn merge_into_stored(&mut self, entries: Vec<HistoryEntry>) -> Result<Vec<HistoryEntry>> {
+        retry_busy(|| store::merge_into_stored(self, entries.clone()))
+    }
+
+    fn query_since(
+        &mut self,
+        request: &HistoryRequest,
+        on_page: &mut dyn FnMut(Vec<HistoryEntry>) -> Result<()>,
+    ) -> Result<()> {
+        store::for_each_page(self, request, on_page)
+    }
+
+    fn high_water_mark(&mut self) -> Result<u64> {
+        store::high_water_mark(self)
+    }
+
+    fn search(
+        &mut self,
+        query: &SearchQuery,
+        on_entry: &mut dyn FnMut(HistoryEntry) -> Result<()>,
+    ) -> Result<Option<SearchCursor>> {
+        store::search(self, query, on_entry)
+    }
+
+    fn delete(&mut self, cmd_hash: u64) -> Result<usize> {
+        retry_busy(|| store::forget_command(self, cmd_hash))
+    }
+
+    fn delete_entries(&mut self, hashes: &[u64]) -> Result<usize> {
+        retry_busy(|| store::delete_entries(self, hashes, store::unix_now()))
+    }
+
+    fn entry_tombstones_since(&mut self, since: i64) -> Result<Vec<u64>> {
+        store::entry_tombstones_since(self, since)
+    }
+
+    fn buckets(&mut self) -> Result<Vec<Bucket>> {
+        store::buckets(self)
+    }
+
+    fn set_pinned(&mut self, cmd: &str, pinned: bool) -> Result<usize> {
+        retry_busy(|| store::set_pinned(self, cmd, pinned))
+    }
+
+    fn stats(&mut self) -> Result<StoreStats> {
+        let count = |sql: &str| -> Result<u64> {
+            self.query_row(sql, [], |row| row.get::<_, i64>(0))
+                .map(|count| count as u64)
+                .with_context(|| format!("Failed to run {:?}", sql))
+        };
+        let (oldest, newest) = self
+            .query_row(
+                "SELECT MIN(\"when\"), MAX(\"when\") FROM history",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .context("Failed to read history time range")?;
+        Ok(StoreStats {
+            schema_version: store::schema_version(self)?,
+            entries: store::count_entries(self)?,
+            commands: count("SELECT COUNT(DISTINCT cmd) FROM history")?,
+            hosts: count("SELECT COUNT(DISTINCT host) FROM history WHERE host <> ''")?,
+            pinned: count("SELECT COUNT(*) FROM pins")?,
+            forgotten: count("SELECT COUNT(*) FROM tombstones")?,
+            oldest,
+            newest,
+        })
+    }
+
+    fn breakdown(&mut self, top: u64, since: i64) -> Result<Breakdown> {
+        Ok(Breakdown {
+            per_host: counts(
+                self,
+                "SELECT COALESCE(host, ''), COUNT(*) FROM history GROUP BY 1 ORDER BY 2 DESC, 1",
+                [],
+            )?,
+            top_commands: counts(
+                self,
+                "SELECT cmd, COUNT(*) FROM history GROUP BY cmd ORDER BY 2 DESC, cmd LIMIT ?1",
+                [top.min(i64::MAX as u64) as i64],
+            )?,
+            per_day: counts(
+                self,
+                "SELECT \"when\" / 86400 * 86400 AS day, COUNT(*) FROM history
+                 WHERE \"when\" >= ?1 GROUP BY day ORDER BY day",
+                [since],
+            )?,
+        })
+    }
+
+    fn suggest(&mut self, query: &SuggestQuery, now: i64) -> Result<Vec<Suggestion>> {
+        store::suggest(self, query, now)
+    }
+
+    fn prune(&mut self, retention: &Retention, now: i64) -> Result<Pruned> {
+        retry_busy(|| store::apply_retention(self, retention, now))
+    }
+
+    fn log_session(&mut self, session: &SessionRecord) -> Result<()> {
+        retry_busy(|| store::log_session(self, session))
+    }
+
+    fn recent_sessions(&mut self, limit: u64) -> Result<Vec<SessionRecord>> {
+        store::recent_sessions(self, limit)
+    }
+
+    fn received_since(&mut self, since: i64) -> Result<u64> {
+        store::received_since(self, since)
+    }
+
+    fn device_seen(&mut self, name: &str, peer: &str, now: i64) -> Result<()> {
+        retry_busy(|| store::device_seen(self, name, peer, now))
+    }
+
+    fn device_read(&mut self, name: &str, mark: u64) -> Result<()> {
+        retry_busy(|| store::device_read(self, name, mark))
+    }
+
+    fn devices(&mut self) -> Result<Vec<Device>> {
+        store::devices(self)
+    }
+
+    fn request_enrollment(&mut self, enrollment: &Enrollment) -> Result<()> {
+        retry_busy(|| store::request_enrollment(self, enrollment))
+    }
+
+    fn enrollments(&mut self) -> Result<Vec<Enrollment>> {
+        store::enrollments(self)
+    }
+
+    fn approve_enrollment(&mut self, code: &str, token: &str) -> Result<bool> {
+        retry_busy(|| store::approve_enrollment(self, code, token))
+    }
+
+    fn remove_enrollment(&mut self, code: &str) -> Result<bool> {
+        retry_busy(|| store::remove_enrollment(self, code))
+    }
+
+    fn expire_enrollments(&mut self, before: i64) -> Result<usize> {
+        retry_busy(|| store::expire_enrollments(self, before))
+    }
+
+    fn sqlite(&mut self) -> Option<&mut Connection> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::{cmd_hash, MatchMode};
+
+    #[test]
+    fn sqlite_store_counts_what_it_holds() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        let store: &mut dyn HistoryStore = &mut conn;
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        store
+            .insert_batch(&[entry("ls", 1), entry("ls", 2), entry("make", 3)], None)
+            .unwrap();
+        assert_eq!(store.set_pinned("make", true).unwrap(), 1);
+        assert_eq!(store.delete(cmd_hash("ls")).unwrap(), 2);
+
+        let stats = store.stats().unwrap();
+        assert_eq!(
+            (stats.entries, stats.commands, stats.pinned, stats.forgotten),
+            (1, 1, 1, 1)
+        );
+        assert_eq!((stats.oldest, stats.newest), (Some(3), Some(3)));
+        assert!(store.sqlite().is_some());
+
+        store
+            .insert_batch(
+                &[
+                    entry("make", 86400 + 1).with_host("laptop".to_string()),
+                    entry("cargo", 2 * 86400),
+                ],
+                None,
+            )
+            .unwrap();
+        let breakdown = store.breakdown(1, 86400).unwrap();
+        assert_eq!(
+            breakdown.per_host,
+            [("".to_string(), 2), ("laptop".to_string(), 1)]
+        );
+        assert_eq!(breakdown.top_commands, [("make".to_string(), 2)]);
+        assert_eq!(breakdown.per_day, [(86400, 1), (2 * 86400, 1)]);
+    }
+
+    #[test]
+    fn patterns_match_past_the_index_prefilter() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        register_regexp(&conn).unwrap();
+        let store: &mut dyn HistoryStore = &mut conn;
+        let entries: Vec<_> = [
+            "kubectl get pods -n prod",
+            "mykubectl get -n prod",
+            "kubectl get -n production",
+            "kubectl get -n staging",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(when, cmd)| HistoryEntry::new(cmd.to_string(), when as i64, String::new()))
+        .collect();
+        store.insert_batch(&entries, None).unwrap();
+        let mut search = |text: &str, mode| {
+            let mut found = Vec::new();
+            let query = SearchQuery {
+                text: text.to_string(),
+                mode,
+                ..Default::default()
+            };
+            store
+                .search(&query, &mut |entry| {
+                    found.push(entry.cmd);
+                    Ok(())
+                })
+                .unwrap();
+            found
+        };
+        assert_eq!(
+            search("kubectl .* -n prod$", MatchMode::Regex),
+            ["kubectl get pods -n prod", "mykubectl get -n prod"]
+        );
+        assert_eq!(
+            search("kubectl * -n prod*", MatchMode::Glob),
+            ["kubectl get pods -n prod", "kubectl get -n production"]
+        );
+    }
+
+    #[test]
+    fn busy_writes_are_retried() {
+        let dir = std::env::temp_dir().join(format!("plentys-busy-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.db");
+        let mut conn = Connection::open(&path).unwrap();
+        store::init_schema(&mut conn).unwrap();
+        conn.busy_timeout(Duration::ZERO).unwrap();
+
+        let locker = Connection::open(&path).unwrap();
+        locker.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        let unlock = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            locker.execute_batch("COMMIT").unwrap();
+        });
+        let entry = HistoryEntry::new("ls".to_string(), 1, String::new());
+        let inserted = conn.insert_batch(&[entry], None);
+        unlock.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        inserted.unwrap();
+        assert_eq!(store::count_entries(&conn).unwrap(), 1);
+    }
+}
@@ -0,0 +1,1389 @@
+/// PostgreSQL storage, for servers backed by an existing database rather
+/// than a file of their own
+use super::{Breakdown, HistoryStore, StoreStats};
+use anyhow::{bail, Context, Result};
+use plenty_common::store::{
+    self, unix_now, Collapse, Device, Enrollment, Pruned, Retention, SessionRecord, PAGE_SIZE,
+};
+use plenty_common::{
+    cmd_hash, entry_hash, glob_regex, Bucket, HistoryEntry, HistoryRequest, MatchMode,
+    SearchCursor, SearchOrder, SearchQuery, SuggestQuery, Suggestion, TieBreak,
+};
+use postgres::types::ToSql;
+use postgres::{Client, Row};
+
+/// Schema changes in order, like the SQLite ones: a database at version N
+/// has applied the first N
+const MIGRATIONS: &[&str] = &[
+    // Entries are identified by a hash of their content, and commands by a
+    // hash of their text: B-tree indexes can't hold arbitrarily long text
+    "CREATE TABLE history (
+       id BIGSERIAL PRIMARY KEY,
+       seq BIGINT NOT NULL,
+       cmd TEXT NOT NULL,
+       \"when\" BIGINT NOT NULL,
+       extra TEXT NOT NULL,
+       host TEXT NOT NULL,
+       cmd_hash BIGINT NOT NULL,
+       hash BIGINT NOT NULL UNIQUE
+     );
+     CREATE INDEX history_when ON history (\"when\");
+     CREATE INDEX history_seq ON history (seq);
+     CREATE INDEX history_cmd_hash ON history (cmd_hash);
+     CREATE INDEX history_search ON history
+       USING GIN (to_tsvector('simple', cmd || ' ' || extra));
+     CREATE TABLE sequence (value BIGINT NOT NULL);
+     INSERT INTO sequence VALUES (0);
+     CREATE TABLE tombstones (
+       cmd_hash BIGINT PRIMARY KEY,
+       deleted_at BIGINT NOT NULL
+     );
+     CREATE TABLE pins (
+       cmd_hash BIGINT PRIMARY KEY,
+       cmd TEXT NOT NULL,
+       pinned_at BIGINT NOT NULL
+     );
+     CREATE TABLE retention (
+       key TEXT PRIMARY KEY,
+       value BIGINT NOT NULL
+     );
+     CREATE TABLE sync_log (
+       id BIGSERIAL PRIMARY KEY,
+       peer TEXT NOT NULL,
+       started BIGINT NOT NULL,
+       ended BIGINT NOT NULL,
+       received BIGINT NOT NULL,
+       sent BIGINT NOT NULL,
+       error TEXT
+     );
+     CREATE TABLE devices (
+       name TEXT PRIMARY KEY,
+       first_seen BIGINT NOT NULL,
+       last_seen BIGINT NOT NULL,
+       last_mark BIGINT,
+       peer TEXT NOT NULL
+     );",
+    "ALTER TABLE sync_log ADD COLUMN rejected BIGINT NOT NULL DEFAULT 0",
+    "ALTER TABLE history
+       ADD COLUMN cwd TEXT,
+       ADD COLUMN exit_code INTEGER,
+       ADD COLUMN duration_ms BIGINT,
+       ADD COLUMN session TEXT",
+    "CREATE TABLE entry_tombstones (
+       hash BIGINT PRIMARY KEY,
+       deleted_at BIGINT NOT NULL
+     );
+     CREATE INDEX entry_tombstones_deleted_at ON entry_tombstones (deleted_at)",
+    // Per-day summaries of the stored entries, kept up to date by a trigger
+    "CREATE TABLE buckets (
+       start BIGINT PRIMARY KEY,
+       entries BIGINT NOT NULL,
+       hash BIGINT NOT NULL
+     );
+     INSERT INTO buckets
+       SELECT \"when\" - (\"when\" % 86400 + 86400) % 86400 AS start, COUNT(*), bit_xor(hash)
+       FROM history GROUP BY start;
+     CREATE FUNCTION update_buckets() RETURNS trigger LANGUAGE plpgsql AS $$
+     BEGIN
+       IF TG_OP = 'DELETE' THEN
+         UPDATE buckets SET entries = entries - 1, hash = hash # OLD.hash
+         WHERE start = OLD.\"when\" - (OLD.\"when\" % 86400 + 86400) % 86400;
+         RETURN OLD;
+       END IF;
+       INSERT INTO buckets
+       VALUES (NEW.\"when\" - (NEW.\"when\" % 86400 + 86400) % 86400, 1, NEW.hash)
+       ON CONFLICT (start) DO UPDATE
+       SET entries = buckets.entries + 1, hash = buckets.hash # NEW.hash;
+       RETURN NEW;
+     END $$;
+     CREATE TRIGGER history_buckets AFTER INSERT OR DELETE ON history
+       FOR EACH ROW EXECUTE FUNCTION update_buckets()",
+    "ALTER TABLE history
+       ADD COLUMN origin_device TEXT,
+       ADD COLUMN received_at BIGINT",
+    // How often and when each command last ran, overall and per directory,
+    // kept up to date by a trigger for ranking suggestions
+    "CREATE TABLE command_stats (
+       cmd_hash BIGINT PRIMARY KEY,
+       cmd TEXT NOT NULL,
+       uses BIGINT NOT NULL,
+       last_used BIGINT NOT NULL
+     );
+     CREATE TABLE command_dirs (
+       cmd_hash BIGINT NOT NULL,
+       cwd TEXT NOT NULL,
+       uses BIGINT NOT NULL,
+       last_used BIGINT NOT NULL
+     );
+     CREATE UNIQUE INDEX command_dirs_key ON command_dirs (cmd_hash, md5(cwd));
+     INSERT INTO command_stats
+       SELECT cmd_hash, MIN(cmd), COUNT(*), MAX(\"when\") FROM history GROUP BY cmd_hash;
+     INSERT INTO command_dirs
+       SELECT cmd_hash, cwd, COUNT(*), MAX(\"when\") FROM history
+       WHERE cwd <> '' GROUP BY cmd_hash, cwd;
+     CREATE FUNCTION update_command_stats() RETURNS trigger LANGUAGE plpgsql AS $$
+     BEGIN
+       IF TG_OP = 'DELETE' THEN
+         UPDATE command_stats SET uses = uses - 1, last_used = COALESCE(
+           (SELECT MAX(\"when\") FROM history WHERE cmd_hash = OLD.cmd_hash), last_used)
+         WHERE cmd_hash = OLD.cmd_hash;
+         DELETE FROM command_stats WHERE cmd_hash = OLD.cmd_hash AND uses <= 0;
+         UPDATE command_dirs SET uses = uses - 1, last_used = COALESCE(
+           (SELECT MAX(\"when\") FROM history
+            WHERE cmd_hash = OLD.cmd_hash AND cwd = OLD.cwd), last_used)
+         WHERE cmd_hash = OLD.cmd_hash AND md5(cwd) = md5(OLD.cwd);
+         DELETE FROM command_dirs
+         WHERE cmd_hash = OLD.cmd_hash AND md5(cwd) = md5(OLD.cwd) AND uses <= 0;
+         RETURN OLD;
+       END IF;
+       INSERT INTO command_stats VALUES (NEW.cmd_hash, NEW.cmd, 1, NEW.\"when\")
+       ON CONFLICT (cmd_hash) DO UPDATE
+       SET uses = command_stats.uses + 1,
+           last_used = GREATEST(command_stats.last_used, NEW.\"when\");
+       IF NEW.cwd <> '' THEN
+         INSERT INTO command_dirs VALUES (NEW.cmd_hash, NEW.cwd, 1, NEW.\"when\")
+         ON CONFLICT (cmd_hash, md5(cwd)) DO UPDATE
+         SET uses = command_dirs.uses + 1,
+             last_used = GREATEST(command_dirs.last_used, NEW.\"when\");
+       END IF;
+       RETURN NEW;
+     END $$;
+     CREATE TRIGGER history_command_stats AFTER INSERT OR DELETE ON history
+       FOR EACH ROW EXECUTE FUNCTION update_command_stats()",
+    "CREATE TABLE command_hosts (
+       cmd_hash BIGINT NOT NULL,
+       host TEXT NOT NULL,
+       uses BIGINT NOT NULL,
+       last_used BIGINT NOT NULL,
+       PRIMARY KEY (cmd_hash, host)
+     );
+     INSERT INTO command_hosts
+       SELECT cmd_hash, host, COUNT(*), MAX(\"when\") FROM history
+       WHERE host <> '' GROUP BY cmd_hash, host;
+     CREATE FUNCTION update_command_hosts() RETURNS trigger LANGUAGE plpgsql AS $$
+     BEGIN
+       IF TG_OP = 'DELETE' THEN
+         UPDATE command_hosts SET uses = uses - 1, last_used = COALESCE(
+           (SELECT MAX(\"when\") FROM history
+            WHERE cmd_hash = OLD.cmd_hash AND host = OLD.host), last_used)
+         WHERE cmd_hash = OLD.cmd_hash AND host = OLD.host;
+         DELETE FROM command_hosts
+         WHERE cmd_hash = OLD.cmd_hash AND host = OLD.host AND uses <= 0;
+         RETURN OLD;
+       END IF;
+       IF NEW.host <> '' THEN
+         INSERT INTO command_hosts VALUES (NEW.cmd_hash, NEW.host, 1, NEW.\"when\")
+         ON CONFLICT (cmd_hash, host) DO UPDATE
+         SET uses = command_hosts.uses + 1,
+             last_used = GREATEST(command_hosts.last_used, NEW.\"when\");
+       END IF;
+       RETURN NEW;
+     END $$;
+     CREATE TRIGGER history_command_hosts AFTER INSERT OR DELETE ON history
+       FOR EACH ROW EXECUTE FUNCTION update_command_hosts()",
+    "ALTER TABLE history ADD COLUMN namespace TEXT",
+    "CREATE TABLE enrollments (
+       code TEXT PRIMARY KEY,
+       secret TEXT NOT NULL,
+       device TEXT NOT NULL,
+       peer TEXT NOT NULL,
+       requested_at BIGINT NOT NULL,
+       token TEXT
+     )",
+    "ALTER TABLE history ADD COLUMN signature BYTEA",
+];
+
+/// Arbitrary key of the advisory lock serializing migrations between servers
+const MIGRATION_LOCK: i64 = 0x706c_656e_7479;
+
+/// SQL condition true for entries of pinned commands
+const PINNED: &str = "cmd_hash IN (SELECT cmd_hash FROM pins)";
+
+/// What full-text searches match against
+const SEARCHED: &str = "to_tsvector('simple', cmd || ' ' || extra)";
+
+/// Connect to the database at `url` (a `postgres://` URL, using TLS when the
+/// server offers it), in the schema of `user` if given, and bring it up to date
+pub fn connect(url: &str, user: Option<&str>) -> Result<Client> {
+    let tls = native_tls::TlsConnector::new().context("Failed to set up TLS")?;
+    let mut client = Client::connect(url, postgres_native_tls::MakeTlsConnector::new(tls))
+        .context("Failed to connect to PostgreSQL")?;
+    if let Some(user) = user {
+        // Users are validated to be safe in a quoted identifier
+        let schema = format!("\"plenty_{}\"", user);
+        client
+            .batch_execute(&format!(
+                "CREATE SCHEMA IF NOT EXISTS {0}; SET search_path TO {0}",
+                schema
+            ))
+            .with_context(|| format!("Failed to use schema {}", schema))?;
+    }
+    init_schema(&mut client)?;
+    Ok(client)
+}
+
+/// Apply pending migrations, each in its own transaction
+fn init_schema(client: &mut Client) -> Result<()> {
+    let mut tx = client
+        .transaction()
+        .context("Failed to begin schema migration")?;
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+        .context("Failed to lock schema for migration")?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+           version INTEGER PRIMARY KEY,
+           applied_at BIGINT NOT NULL
+         )",
+    )
+    .context("Failed to create schema_version table")?;
+    let current = schema_version(&mut tx)?;
+    if current > MIGRATIONS.len() {
+        bail!(
+            "Database schema version {} is newer than the latest known ({}), upgrade plentys",
+            current,
+            MIGRATIONS.len()
+        );
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = version as i32 + 1;
+        tx.batch_execute(migration)
+            .with_context(|| format!("Failed to migrate schema to version {}", version))?;
+        tx.execute(
+            "INSERT INTO schema_version (version, applied_at) VALUES ($1, $2)",
+            &[&version, &unix_now()],
+        )
+        .context("Failed to record schema version")?;
+    }
+    tx.commit().context("Failed to commit schema migration")
+}
+
+fn schema_version(client: &mut impl postgres::GenericClient) -> Result<usize> {
+    let version: Option<i32> = client
+        .query_one("SELECT MAX(version) FROM schema_version", &[])
+        .context("Failed to read schema version")?
+        .get(0);
+    Ok(version.unwrap_or(0) as usize)
+}
+
+fn entry(row: &Row) -> HistoryEntry {
+    let mut entry = HistoryEntry::new(row.get(0), row.get(1), row.get(2))
+        .with_host(row.get(3))
+        .with_pinned(row.get(4));
+    entry.cwd = row.get::<_, Option<String>>("cwd").unwrap_or_default();
+    entry.exit_code = row.get("exit_code");
+    entry.duration_ms = row.get::<_, Option<i64>>("duration_ms").map(|d| d as u64);
+    entry.session = row.get::<_, Option<String>>("session").unwrap_or_default();
+    entry.origin_device = row
+        .get::<_, Option<String>>("origin_device")
+        .unwrap_or_default();
+    entry.received_at = row.get("received_at");
+    entry.namespace = row
+        .get::<_, Option<String>>("namespace")
+        .unwrap_or_default();
+    entry.signature = row
+        .get::<_, Option<Vec<u8>>>("signature")
+        .unwrap_or_default();
+    entry
+}
+
+/// Turn search words into a tsquery matching entries containing all of
+/// them, each as a word prefix, without interpreting tsquery syntax
+fn ts_query(text: &str) -> Option<String> {
+    let terms: Vec<_> = text
+        .split_whitespace()
+        .map(|word| format!("'{}':*", word.replace('\\', "\\\\").replace('\'', "''")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" & "))
+}
+
+type Params = Vec<Box<dyn ToSql + Sync>>;
+
+fn refs(params: &Params) -> Vec<&(dyn ToSql + Sync)> {
+    params.iter().map(|param| param.as_ref()).collect()
+}
+
+/// Build the SELECT answering a Query like the SQLite one, in its order,
+/// with the `id` column of each entry's position
+fn search_query(query: &SearchQuery) -> Result<(String, Params)> {
+    let mut conditions = Vec::new();
+    let mut params: Params = Vec::new();
+
+    match query.mode {
+        MatchMode::Words => {
+            let Some(ts_query) = ts_query(&query.text) else {
+                bail!("Empty search");
+            };
+            params.push(Box::new(ts_query));
+            conditions.push(format!(
+                "{} @@ to_tsquery('simple', ${})",
+                SEARCHED,
+                params.len()
+            ));
+        }
+        _ if query.text.is_empty() => {}
+        MatchMode::Substring => {
+            params.push(Box::new(query.text.clone()));
+            conditions.push(format!("strpos(cmd, ${}) > 0", params.len()));
+        }
+        MatchMode::Regex => {
+            params.push(Box::new(query.text.clone()));
+            conditions.push(format!("cmd ~ ${}", params.len()));
+        }
+        MatchMode::Glob => {
+            params.push(Box::new(glob_regex(&query.text)));
+            conditions.push(format!("cmd ~ ${}", params.len()));
+        }
+    }
+    if let Some(since) = query.since {
+        params.push(Box::new(since));
+        conditions.push(format!("\"when\" >= ${}", params.len()));
+    }
+    if let Some(until) = query.until {
+        params.push(Box::new(until));
+        conditions.push(format!("\"when\" < ${}", params.len()));
+    }
+    if !query.hosts.is_empty() {
+        params.push(Box::new(query.hosts.clone()));
+        conditions.push(format!("host = ANY(${})", params.len()));
+    }
+    if let Some(cwd) = &query.cwd {
+        let (dir, prefix) = store::cwd_filter(cwd);
+        params.push(Box::new(dir));
+        params.push(Box::new(prefix));
+        conditions.push(format!(
+            "(cwd = ${}::TEXT OR left(cwd, length(${1}::TEXT)) = ${1}::TEXT)",
+            params.len() - 1,
+            params.len()
+        ));
+    }
+    let newest_first = query.order != SearchOrder::Oldest;
+    if let Some(after) = query.after {
+        params.push(Box::new(after.when));
+        params.push(Box::new(after.id));
+        conditions.push(format!(
+            "(\"when\", id) {} (${}, ${})",
+            if newest_first { "<" } else { ">" },
+            params.len() - 1,
+            params.len()
+        ));
+    }
+    params.push(Box::new(
+        query.limit.map(|limit| limit.min(i64::MAX as u64) as i64),
+    ));
+
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let (picked, listed) = match query.order {
+        SearchOrder::Recent => ("DESC", "ASC"),
+        SearchOrder::Newest => ("DESC", "DESC"),
+        SearchOrder::Oldest => ("ASC", "ASC"),
+    };
+    let sql = format!(
+        "SELECT cmd, \"when\", extra, host, pinned, id, {} FROM (
+           SELECT id, cmd, \"when\", extra, host, {} AS pinned, {}
+           FROM history {}
+           ORDER BY \"when\" {}, id {} LIMIT ${}
+         ) AS found ORDER BY \"when\" {}, id {}",
+        store::METADATA_COLUMNS,
+        PINNED,
+        store::METADATA_COLUMNS,
+        filter,
+        picked,
+        picked,
+        params.len(),
+        listed,
+        listed
+    );
+    Ok((sql, params))
+}
+
+/// Build the SELECT answering a GetHistory request like the SQLite one,
+/// oldest first, with the `tie` and `id` columns completing its sort key
+fn history_query(request: &HistoryRequest) -> (String, Params) {
+    let mut conditions = Vec::new();
+    let mut params: Params = Vec::new();
+
+    if let Some(since) = request.since {
+        params.push(Box::new(since));
+        if request.keep_pinned {
+            conditions.push(format!("(\"when\" >= ${} OR {})", params.len(), PINNED));
+        } else {
+            conditions.push(format!("\"when\" >= ${}", params.len()));
+        }
+    }
+    if let Some(until) = request.until {
+        params.push(Box::new(until));
+        conditions.push(format!("\"when\" < ${}", params.len()));
+    }
+    if let Some(after_seq) = request.after_seq {
+        params.push(Box::new(after_seq.min(i64::MAX as u64) as i64));
+        conditions.push(format!("seq > ${}", params.len()));
+    }
+    if let Some(pattern) = &request.pattern {
+        params.push(Box::new(pattern.clone()));
+        conditions.push(format!("strpos(cmd, ${}) > 0", params.len()));
+    }
+    for (hosts, negate) in [(&request.only_hosts, ""), (&request.exclude_hosts, "NOT ")] {
+        if hosts.is_empty() {
+            continue;
+        }
+        params.push(Box::new(hosts.clone()));
+        // Untagged entries predate host tagging and are never filtered out
+        conditions.push(format!(
+            "(host = '' OR {}host = ANY(${}))",
+            negate,
+            params.len()
+        ));
+    }
+    if !request.namespaces.is_empty() {
+        params.push(Box::new(request.namespaces.clone()));
+        conditions.push(format!("COALESCE(namespace, '') = ANY(${})", params.len()));
+    }
+
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+
+    // Entries sharing a timestamp are ordered by this, then by id, in byte
+    // order like SQLite
+    let tie = match request.tie_break {
+        TieBreak::Insertion => "''",
+        TieBreak::Host => "host",
+        TieBreak::Command => "cmd",
+    };
+
+    let sql = match request.limit {
+        Some(limit) => {
+            params.push(Box::new(limit.min(i64::MAX as u64) as i64));
+            let priority = if request.keep_pinned {
+                format!("{} DESC, ", PINNED)
+            } else {
+                String::new()
+            };
+            format!(
+                "SELECT * FROM (
+                   SELECT cmd, \"when\", extra, host, {} AS pinned, {} COLLATE \"C\" AS tie, id, {}
+                   FROM history {}
+                   ORDER BY {}\"when\" DESC, tie DESC, id DESC LIMIT ${}
+                 ) AS recent ORDER BY \"when\", tie, id",
+                PINNED,
+                tie,
+                store::METADATA_COLUMNS,
+                filter,
+                priority,
+                params.len(),
+            )
+        }
+        None => format!(
+            "SELECT cmd, \"when\", extra, host, {} AS pinned, {} COLLATE \"C\" AS tie, id, {}
+             FROM history {} ORDER BY \"when\", tie, id",
+            PINNED,
+            tie,
+            store::METADATA_COLUMNS,
+            filter
+        ),
+    };
+
+    (sql, params)
+}
+
+impl HistoryStore for Client {
+    fn insert_batch(
+        &mut self,
+        entries: &[HistoryEntry],
+        collapse: Option<Collapse>,
+    ) -> Result<usize> {
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        let mut tx = self
+            .transaction()
+            .context("Failed to begin transaction for batched history insert")?;
+        // Locking the sequence serializes writers, so that whoever reads a
+        // high-water mark has every entry numbered up to it
+        let mut seq: i64 = tx
+            .query_one("SELECT value FROM sequence FOR UPDATE", &[])
+            .context("Failed to read sequence")?
+            .get(0);
+        let first = seq;
+        let stmt = tx
+            .prepare(
+                "INSERT INTO history (seq, cmd, \"when\", extra, host, cmd_hash, hash,
+                   cwd, exit_code, duration_ms, session, origin_device, received_at, namespace,
+                   signature)
+                 SELECT $1::BIGINT, $2::TEXT, $3::BIGINT, $4::TEXT, $5::TEXT, $6::BIGINT, $7::BIGINT,
+                   $8::TEXT, $9::INTEGER, $10::BIGINT, $11::TEXT, $13::TEXT, $14::BIGINT, $16::TEXT,
+                   $17::BYTEA
+                 WHERE NOT EXISTS (SELECT 1 FROM tombstones WHERE cmd_hash = $6)
+                 AND NOT EXISTS (SELECT 1 FROM entry_tombstones WHERE hash = $7)
+                 AND ($6 IN (SELECT cmd_hash FROM pins) OR (
+                   NOT EXISTS (SELECT 1 FROM retention WHERE key = 'horizon' AND value > $3)
+                   AND NOT EXISTS (
+                     SELECT 1 FROM retention WHERE key = 'dedup' AND value <> 0
+                     AND EXISTS (SELECT 1 FROM history WHERE cmd_hash = $6 AND \"when\" > $3)
+                   )
+                 ))
+                 AND ($12::BIGINT IS NULL OR NOT EXISTS (
+                   SELECT 1 FROM history
+                   WHERE \"when\" BETWEEN $3 - $12 AND $3 + $12 AND ((cmd_hash = $6 AND cmd = $2) OR cmd = $15::TEXT)
+                 ))
+                 ON CONFLICT (hash) DO NOTHING",
+            )
+            .context("Failed to prepare batched history insert statement")?;
+        let collapse_window = collapse.map(|collapse| collapse.seconds());
+        for entry in entries {
+            let alias = collapse.and_then(|collapse| collapse.alias(&entry.cmd));
+            let cwd = Some(&entry.cwd).filter(|c| !c.is_empty());
+            let session = Some(&entry.session).filter(|s| !s.is_empty());
+            let origin_device = Some(&entry.origin_device).filter(|d| !d.is_empty());
+            let namespace = Some(&entry.namespace).filter(|n| !n.is_empty());
+            let signature = Some(&entry.signature).filter(|s| !s.is_empty());
+            let duration_ms = entry.duration_ms.map(|d| d.min(i64::MAX as u64) as i64);
+            let inserted = tx
+                .execute(
+                    &stmt,
+                    &[
+                        &(seq + 1),
+                        &entry.cmd,
+                        &entry.when,
+                        &entry.extra,
+                        &entry.host,
+                        &(cmd_hash(&entry.cmd) as i64),
+                        &(entry_hash(&entry.cmd, entry.when, &entry.extra) as i64),
+                        &cwd,
+                        &entry.exit_code,
+                        &duration_ms,
+                        &session,
+                        &collapse_window,
+                        &origin_device,
+                        &entry.received_at,
+                        &alias,
+                        &namespace,
+                        &signature,
+                    ],
+                )
+                .with_context(|| {
+                    format!(
+                        "Failed to insert history entry during batch (cmd='{}')",
+                        &entry.cmd
+                    )
+                })?;
+            seq += inserted as i64;
+        }
+        tx.execute("UPDATE sequence SET value = $1", &[&seq])
+            .context("Failed to update sequence")?;
+        tx.commit()
+            .context("Failed to commit batched history insert transaction")?;
+        Ok((seq - first) as usize)
+    }
+
+    fn merge_into_stored(&mut self, entries: Vec<HistoryEntry>) -> Result<Vec<HistoryEntry>> {
+        let mut tx = self
+            .transaction()
+            .context("Failed to begin transaction for merging entries")?;
+        let select = tx
+            .prepare(
+                "SELECT cmd, \"when\", extra, host, FALSE, cwd, exit_code, duration_ms, session,
+                   origin_device, received_at, namespace, signature, id, seq, hash
+                 FROM history
+                 WHERE cmd_hash = $1 AND cmd = $2 AND \"when\" = $3
+                 AND COALESCE(namespace, '') = $4",
+            )
+            .context("Failed to prepare same run query")?;
+        let mut others = Vec::new();
+        for entry in entries {
+            let rows = tx
+                .query(
+                    &select,
+                    &[
+                        &(cmd_hash(&entry.cmd) as i64),
+                        &entry.cmd,
+                        &entry.when,
+                        &entry.namespace,
+                    ],
+                )
+                .context("Failed to read entries of the same run")?;
+            let Some(row) = rows.iter().find(|row| self::entry(row).same_run(&entry)) else {
+                others.push(entry);
+                continue;
+            };
+            let stored = self::entry(row);
+            let merged = stored.merged_with(&entry);
+            let metadata = |e: &HistoryEntry| {
+                (
+                    e.host.clone(),
+                    e.cwd.clone(),
+                    e.exit_code,
+                    e.duration_ms,
+                    e.session.clone(),
+                )
+            };
+            if metadata(&merged) == metadata(&stored) {
+                continue;
+            }
+            // Replaced rather than updated, for the triggers keeping
+            // per-command counts to see the new host and directory, and
+            // without its signature, which no longer holds
+            tx.execute(
+                "DELETE FROM history WHERE id = $1",
+                &[&row.get::<_, i64>("id")],
+            )
+            .context("Failed to delete merged entry")?;
+            tx.execute(
+                "INSERT INTO history (seq, cmd, \"when\", extra, host, cmd_hash, hash,
+                   cwd, exit_code, duration_ms, session, origin_device, received_at, namespace)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)",
+                &[
+                    &row.get::<_, i64>("seq"),
+                    &stored.cmd,
+                    &stored.when,
+                    &stored.extra,
+                    &merged.host,
+                    &(cmd_hash(&stored.cmd) as i64),
+                    &row.get::<_, i64>("hash"),
+                    &Some(&merged.cwd).filter(|c| !c.is_empty()),
+                    &merged.exit_code,
+                    &merged.duration_ms.map(|d| d.min(i64::MAX as u64) as i64),
+                    &Some(&merged.session).filter(|s| !s.is_empty()),
+                    &row.get::<_, Option<String>>("origin_device"),
+                    &stored.received_at,
+                    &row.get::<_, Option<String>>("namespace"),
+                ],
+            )
+            .context("Failed to store merged entry")?;
+        }
+        tx.commit().context("Failed to commit merged entries")?;
+        Ok(others)
+    }
+
+    fn query_since(
+        &mut self,
+        request: &HistoryRequest,
+        on_page: &mut dyn FnMut(Vec<HistoryEntry>) -> Result<()>,
+    ) -> Result<()> {
+        let (sql, params) = history_query(request);
+        let first = params.len() + 1;
+        let first_page = format!("SELECT * FROM ({}) AS page LIMIT ${}", sql, first);
+        let next_page = format!(
+            "SELECT * FROM ({}) AS page WHERE (\"when\", tie, id) > (${}, ${}, ${})
+             ORDER BY \"when\", tie, id LIMIT ${}",
+            sql,
+            first,
+            first + 1,
+            first + 2,
+            first + 3
+        );
+
+        let mut after: Option<(i64, String, i64)> = None;
+        loop {
+            let mut page_params = refs(&params);
+            let page_size = PAGE_SIZE as i64;
+            let rows = match &after {
+                None => {
+                    page_params.push(&page_size);
+                    self.query(&first_page, &page_params)
+                }
+                Some((when, tie, id)) => {
+                    page_params.extend([when as &(dyn ToSql + Sync), tie, id, &page_size]);
+                    self.query(&next_page, &page_params)
+                }
+            }
+            .context("Failed to query history")?;
+
+            let last = rows.len() < PAGE_SIZE;
+            if let Some(row) = rows.last() {
+                after = Some((row.get(1), row.get(5), row.get(6)));
+            }
+            let page: Vec<_> = rows.iter().map(entry).collect();
+            if !page.is_empty() {
+                on_page(page)?;
+            }
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    fn high_water_mark(&mut self) -> Result<u64> {
+        let seq: i64 = self
+            .query_one("SELECT value FROM sequence", &[])
+            .context("Failed to read sequence")?
+            .get(0);
+        Ok(seq as u64)
+    }
+
+    fn search(
+        &mut self,
+        query: &SearchQuery,
+        on_entry: &mut dyn FnMut(HistoryEntry) -> Result<()>,
+    ) -> Result<Option<SearchCursor>> {
+        let (sql, params) = search_query(query)?;
+        let rows = self
+            .query(&sql, &refs(&params))
+            .context("Failed to search history")?;
+        rows.iter().map(entry).try_for_each(on_entry)?;
+        if query.limit.is_none_or(|limit| (rows.len() as u64) < limit) {
+            return Ok(None);
+        }
+        let position = |row: &Row| SearchCursor {
+            when: row.get(1),
+            id: row.get(5),
+        };
+        Ok(match query.order {
+            SearchOrder::Recent => rows.first().map(position),
+            SearchOrder::Newest | SearchOrder::Oldest => rows.last().map(position),
+        })
+    }
+
+    fn delete(&mut self, cmd_hash: u64) -> Result<usize> {
+        let hash = cmd_hash as i64;
+        let mut tx = self
+            .transaction()
+            .context("Failed to begin transaction for deletion")?;
+        tx.execute(
+            "INSERT INTO tombstones (cmd_hash, deleted_at) VALUES ($1, $2)
+             ON CONFLICT (cmd_hash) DO UPDATE SET deleted_at = excluded.deleted_at",
+            &[&hash, &unix_now()],
+        )
+        .context("Failed to record tombstone")?;
+        tx.execute("DELETE FROM pins WHERE cmd_hash = $1", &[&hash])
+            .context("Failed to unpin deleted command")?;
+        let deleted = tx
+            .execute("DELETE FROM history WHERE cmd_hash = $1", &[&hash])
+            .context("Failed to delete history entries")?;
+        tx.commit().context("Failed to commit deletion")?;
+        Ok(deleted as usize)
+    }
+
+    fn delete_entries(&mut self, hashes: &[u64]) -> Result<usize> {
+        let hashes: Vec<i64> = hashes.iter().map(|hash| *hash as i64).collect();
+        let mut tx = self
+            .transaction()
+            .context("Failed to begin transaction for deletion")?;
+        tx.execute(
+            "INSERT INTO entry_tombstones (hash, deleted_at) SELECT UNNEST($1::BIGINT[]), $2
+             ON CONFLICT (hash) DO UPDATE SET deleted_at = excluded.deleted_at",
+            &[&hashes, &unix_now()],
+        )
+        .context("Failed to record entry tombstones")?;
+        let deleted = tx
+            .execute("DELETE FROM history WHERE hash = ANY($1)", &[&hashes])
+            .context("Failed to delete history entries")?;
+        tx.commit().context("Failed to commit deletion")?;
+        Ok(deleted as usize)
+    }
+
+    fn entry_tombstones_since(&mut self, since: i64) -> Result<Vec<u64>> {
+        let rows = self
+            .query(
+                "SELECT hash FROM entry_tombstones WHERE deleted_at >= $1 ORDER BY hash",
+                &[&since],
+            )
+            .context("Failed to read entry tombstones")?;
+        Ok(rows.iter().map(|row| row.get::<_, i64>(0) as u64).collect())
+    }
+
+    fn buckets(&mut self) -> Result<Vec<Bucket>> {
+        let rows = self
+            .query(
+                "SELECT start, entries, hash FROM buckets WHERE entries > 0 ORDER BY start",
+                &[],
+            )
+            .context("Failed to read buckets")?;
+        Ok(rows
+            .iter()
+            .map(|row| Bucket {
+                start: row.get(0),
+                entries: row.get::<_, i64>(1) as u64,
+                hash: row.get::<_, i64>(2) as u64,
+            })
+            .collect())
+    }
+
+    fn set_pinned(&mut self, cmd: &str, pinned: bool) -> Result<usize> {
+        let hash = cmd_hash(cmd) as i64;
+        if pinned {
+            self.execute(
+                "INSERT INTO pins (cmd_hash, cmd, pinned_at) VALUES ($1, $2, $3)
+                 ON CONFLICT (cmd_hash) DO NOTHING",
+                &[&hash, &cmd, &unix_now()],
+            )
+            .context("Failed to pin command")?;
+        } else {
+            self.execute("DELETE FROM pins WHERE cmd_hash = $1", &[&hash])
+                .context("Failed to unpin command")?;
+        }
+        let entries: i64 = self
+            .query_one("SELECT COUNT(*) FROM history WHERE cmd_hash = $1", &[&hash])
+            .context("Failed to count entries for command")?
+            .get(0);
+        Ok(entries as usize)
+    }
+
+    fn stats(&mut self) -> Result<StoreStats> {
+        let row = self
+            .query_one(
+                "SELECT COUNT(*), COUNT(DISTINCT cmd), COUNT(DISTINCT NULLIF(host, '')),
+                   (SELECT COUNT(*) FROM pins), (SELECT COUNT(*) FROM tombstones),
+                   MIN(\"when\"), MAX(\"when\")
+                 FROM history",
+                &[],
+            )
+            .context("Failed to summarize history")?;
+        let count = |i| row.get::<_, i64>(i) as u64;
+        Ok(StoreStats {
+            schema_version: schema_version(self)?,
+            entries: count(0),
+            commands: count(1),
+            hosts: count(2),
+            pinned: count(3),
+            forgotten: count(4),
+            oldest: row.get(5),
+            newest: row.get(6),
+        })
+    }
+
+    fn breakdown(&mut self, top: u64, since: i64) -> Result<Breakdown> {
+        let top = top.min(i64::MAX as u64) as i64;
+        let per_host = self
+            .query(
+                "SELECT host, COUNT(*) FROM history GROUP BY host ORDER BY 2 DESC, host",
+                &[],
+            )
+            .context("Failed to count entries per host")?;
+        let top_commands = self
+            .query(
+                "SELECT cmd, COUNT(*) FROM history GROUP BY cmd ORDER BY 2 DESC, cmd LIMIT $1",
+                &[&top],
+            )
+            .context("Failed to find the most frequent commands")?;
+        let per_day = self
+            .query(
+                "SELECT \"when\" / 86400 * 86400 AS day, COUNT(*) FROM history
+                 WHERE \"when\" >= $1 GROUP BY day ORDER BY day",
+                &[&since],
+            )
+            .context("Failed to count entries per day")?;
+        let count = |row: &Row| row.get::<_, i64>(1) as u64;
+        Ok(Breakdown {
+            per_host: per_host
+                .iter()
+                .map(|row| (row.get(0), count(row)))
+                .collect(),
+            top_commands: top_commands
+                .iter()
+                .map(|row| (row.get(0), count(row)))
+                .collect(),
+            per_day: per_day.iter().map(|row| (row.get(0), count(row))).collect(),
+        })
+    }
+
+    fn suggest(&mut self, query: &SuggestQuery, now: i64) -> Result<Vec<Suggestion>> {
+        let sql = format!(
+            "SELECT s.cmd, s.uses, s.last_used FROM command_stats AS s
+             LEFT JOIN command_dirs AS d ON d.cmd_hash = s.cmd_hash AND d.cwd = $2::TEXT
+             LEFT JOIN command_hosts AS h ON h.cmd_hash = s.cmd_hash AND h.host = $5::TEXT
+             WHERE left(s.cmd, length($1::TEXT)) = $1::TEXT
+             ORDER BY {} + {} * COALESCE({}, 0) + {} * COALESCE({}, 0) DESC,
+               s.last_used DESC, s.cmd
+             LIMIT $4",
+            store::frecency("s.uses", "s.last_used", "$3::BIGINT"),
+            store::DIRECTORY_WEIGHT,
+            store::frecency("d.uses", "d.last_used", "$3::BIGINT"),
+            store::HOST_WEIGHT,
+            store::frecency("h.uses", "h.last_used", "$3::BIGINT")
+        );
+        let limit = query.limit.min(i64::MAX as u64) as i64;
+        let rows = self
+            .query(
+                &sql,
+                &[&query.prefix, &query.cwd, &now, &limit, &query.host],
+            )
+            .context("Failed to query suggestions")?;
+        Ok(rows
+            .iter()
+            .map(|row| Suggestion {
+                cmd: row.get(0),
+                uses: row.get::<_, i64>(1) as u64,
+                last_used: row.get(2),
+            })
+            .collect())
+    }
+
+    fn prune(&mut self, retention: &Retention, now: i64) -> Result<Pruned> {
+        let mut tx = self
+            .transaction()
+            .context("Failed to begin transaction for pruning")?;
+
+        let mut horizon = retention
+            .max_age_days
+            .map(|days| now.saturating_sub(days.min(i64::MAX as u64 / 86400) as i64 * 86400));
+        if let Some(max_entries) = retention.max_entries {
+            let offset = max_entries.saturating_sub(1).min(i64::MAX as u64) as i64;
+            let oldest_kept: Option<i64> = tx
+                .query_opt(
+                    &format!(
+                        "SELECT \"when\" FROM history WHERE NOT {}
+                         ORDER BY \"when\" DESC LIMIT 1 OFFSET $1",
+                        PINNED
+                    ),
+                    &[&offset],
+                )
+                .context("Failed to find the oldest entry to keep")?
+                .map(|row| row.get(0));
+            if max_entries == 0 {
+                horizon = Some(i64::MAX);
+            } else if let Some(oldest_kept) = oldest_kept {
+                horizon = Some(horizon.map_or(oldest_kept, |h| h.max(oldest_kept)));
+            }
+        }
+
+        let mut pruned = Pruned::default();
+        if let Some(horizon) = horizon {
+            tx.execute(
+                "INSERT INTO retention (key, value) VALUES ('horizon', $1)
+                 ON CONFLICT (key) DO UPDATE SET value = GREATEST(retention.value, excluded.value)",
+                &[&horizon],
+            )
+            .context("Failed to record retention horizon")?;
+            pruned.expired =
+                tx.execute(
+                    &format!("DELETE FROM history WHERE \"when\" < $1 AND NOT {}", PINNED),
+                    &[&horizon],
+                )
+                .context("Failed to prune expired history entries")? as usize;
+        }
+
+        tx.execute(
+            "INSERT INTO retention (key, value) VALUES ('dedup', $1)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            &[&(retention.dedup as i64)],
+        )
+        .context("Failed to record dedup setting")?;
+        if retention.dedup {
+            pruned.duplicates =
+                tx.execute(
+                    &format!(
+                        "DELETE FROM history WHERE NOT {} AND EXISTS (
+                           SELECT 1 FROM history AS newer WHERE newer.cmd_hash = history.cmd_hash
+                           AND (newer.\"when\", newer.id) > (history.\"when\", history.id)
+                         )",
+                        PINNED
+                    ),
+                    &[],
+                )
+                .context("Failed to prune duplicate history entries")? as usize;
+        }
+
+        if let Some(days) = retention.tombstone_days {
+            pruned.tombstones =
+                tx.execute(
+                    "DELETE FROM entry_tombstones WHERE deleted_at < $1",
+                    &[&now.saturating_sub(days.min(i64::MAX as u64 / 86400) as i64 * 86400)],
+                )
+                .context("Failed to expire entry tombstones")? as usize;
+        }
+
+        tx.commit().context("Failed to commit pruning")?;
+        Ok(pruned)
+    }
+
+    fn log_session(&mut self, session: &SessionRecord) -> Result<()> {
+        self.execute(
+            "INSERT INTO sync_log (peer, started, ended, received, sent, error, rejected)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &session.peer,
+                &session.started,
+                &session.ended,
+                &(session.received as i64),
+                &(session.sent as i64),
+                &session.error,
+                &(session.rejected as i64),
+            ],
+        )
+        .context("Failed to record sync session")?;
+        Ok(())
+    }
+
+    fn recent_sessions(&mut self, limit: u64) -> Result<Vec<SessionRecord>> {
+        let rows = self
+            .query(
+                "SELECT peer, started, ended, received, sent, error, rejected FROM (
+                   SELECT * FROM sync_log ORDER BY id DESC LIMIT $1
+                 ) AS recent ORDER BY id",
+                &[&(limit.min(i64::MAX as u64) as i64)],
+            )
+            .context("Failed to query sync log")?;
+        Ok(rows
+            .iter()
+            .map(|row| SessionRecord {
+                peer: row.get(0),
+                started: row.get(1),
+                ended: row.get(2),
+                received: row.get::<_, i64>(3) as u64,
+                sent: row.get::<_, i64>(4) as u64,
+                error: row.get(5),
+                rejected: row.get::<_, i64>(6) as u64,
+            })
+            .collect())
+    }
+
+    fn received_since(&mut self, since: i64) -> Result<u64> {
+        let received: i64 = self
+            .query_one(
+                "SELECT COALESCE(SUM(received), 0)::BIGINT FROM sync_log WHERE ended >= $1",
+                &[&since],
+            )
+            .context("Failed to sum recently received entries")?
+            .get(0);
+        Ok(received as u64)
+    }
+
+    fn device_seen(&mut self, name: &str, peer: &str, now: i64) -> Result<()> {
+        self.execute(
+            "INSERT INTO devices (name, first_seen, last_seen, peer) VALUES ($1, $3, $3, $2)
+             ON CONFLICT (name) DO UPDATE SET last_seen = $3, peer = $2",
+            &[&name, &peer, &now],
+        )
+        .context("Failed to register device")?;
+        Ok(())
+    }
+
+    fn device_read(&mut self, name: &str, mark: u64) -> Result<()> {
+        self.execute(
+            "UPDATE devices SET last_mark = $2 WHERE name = $1",
+            &[&name, &(mark as i64)],
+        )
+        .context("Failed to record device high-water mark")?;
+        Ok(())
+    }
+
+    fn devices(&mut self) -> Result<Vec<Device>> {
+        let rows = self
+            .query(
+                "SELECT name, first_seen, last_seen, last_mark, peer FROM devices
+                 ORDER BY last_seen, name",
+                &[],
+            )
+            .context("Failed to query devices")?;
+        Ok(rows
+            .iter()
+            .map(|row| Device {
+                name: row.get(0),
+                first_seen: row.get(1),
+                last_seen: row.get(2),
+                last_mark: row.get::<_, Option<i64>>(3).map(|mark| mark as u64),
+                peer: row.get(4),
+            })
+            .collect())
+    }
+
+    fn request_enrollment(&mut self, enrollment: &Enrollment) -> Result<()> {
+        self.execute(
+            "INSERT INTO enrollments (code, secret, device, peer, requested_at, token)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                &enrollment.code,
+                &enrollment.secret,
+                &enrollment.device,
+                &enrollment.peer,
+                &enrollment.requested_at,
+                &enrollment.token,
+            ],
+        )
+        .context("Failed to record enrollment")?;
+        Ok(())
+    }
+
+    fn enrollments(&mut self) -> Result<Vec<Enrollment>> {
+        let rows = self
+            .query(
+                "SELECT code, secret, device, peer, requested_at, token FROM enrollments
+                 ORDER BY requested_at, code",
+                &[],
+            )
+            .context("Failed to query enrollments")?;
+        Ok(rows
+            .iter()
+            .map(|row| Enrollment {
+                code: row.get(0),
+                secret: row.get(1),
+                device: row.get(2),
+                peer: row.get(3),
+                requested_at: row.get(4),
+                token: row.get(5),
+            })
+            .collect())
+    }
+
+    fn approve_enrollment(&mut self, code: &str, token: &str) -> Result<bool> {
+        let approved = self
+            .execute(
+                "UPDATE enrollments SET token = $2 WHERE code = $1 AND token IS NULL",
+                &[&code, &token],
+            )
+            .context("Failed to approve enrollment")?;
+        Ok(approved > 0)
+    }
+
+    fn remove_enrollment(&mut self, code: &str) -> Result<bool> {
+        let removed = self
+            .execute("DELETE FROM enrollments WHERE code = $1", &[&code])
+            .context("Failed to remove enrollment")?;
+        Ok(removed > 0)
+    }
+
+    fn expire_enrollments(&mut self, before: i64) -> Result<usize> {
+        let expired = self
+            .execute(
+                "DELETE FROM enrollments WHERE requested_at < $1",
+                &[&before],
+            )
+            .context("Failed to expire enrollments")?;
+        Ok(expired as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_words_become_prefix_queries() {
+        assert_eq!(ts_query("  "), None);
+        assert_eq!(
+            ts_query("git it's a\\b").unwrap(),
+            "'git':* & 'it''s':* & 'a\\\\b':*"
+        );
+    }
+
+    #[test]
+    fn history_queries_number_their_parameters() {
+        let request = HistoryRequest {
+            since: Some(1),
+            limit: Some(5),
+            exclude_hosts: vec!["a".to_string()],
+            tie_break: TieBreak::Host,
+            ..Default::default()
+        };
+        let (sql, params) = history_query(&request);
+        assert_eq!(params.len(), 3);
+        assert!(sql.contains("\"when\" >= $1"));
+        assert!(sql.contains("NOT host = ANY($2)"));
+        assert!(sql.contains("LIMIT $3"));
+        assert!(sql.contains("host COLLATE \"C\" AS tie"));
+    }
+
+    #[test]
+    fn search_queries_number_their_parameters() {
+        let query = SearchQuery {
+            text: "ls".to_string(),
+            mode: MatchMode::Substring,
+            cwd: Some("/src/".to_string()),
+            after: Some(SearchCursor { when: 5, id: 2 }),
+            order: SearchOrder::Oldest,
+            ..Default::default()
+        };
+        let (sql, params) = search_query(&query).unwrap();
+        assert_eq!(params.len(), 6);
+        assert!(sql.contains("strpos(cmd, $1) > 0"));
+        assert!(sql.contains("(cwd = $2::TEXT OR left(cwd, length($3::TEXT)) = $3::TEXT)"));
+        assert!(sql.contains("(\"when\", id) > ($4, $5)"));
+        assert!(sql.contains("LIMIT $6"));
+        assert!(search_query(&SearchQuery::default()).is_err());
+    }
+
+    /// Runs against the database in `$PLENTY_TEST_POSTGRES`, if set
+    #[test]
+    fn postgres_store_round_trips() {
+        let Ok(url) = std::env::var("PLENTY_TEST_POSTGRES") else {
+            return;
+        };
+        let user = format!("test{}", std::process::id());
+        let mut client = connect(&url, Some(&user)).unwrap();
+        let store: &mut dyn HistoryStore = &mut client;
+        let entry = |cmd: &str, when| HistoryEntry::new(cmd.to_string(), when, String::new());
+        store
+            .insert_batch(
+                &[entry("ls", 1), entry("ls", 1), entry("make all", 2)],
+                None,
+            )
+            .unwrap();
+        let mut build = entry("cargo build", 3).with_host("laptop".to_string());
+        build.cwd = "/src".to_string();
+        build.exit_code = Some(101);
+        build.origin_device = "laptop".to_string();
+        build.received_at = Some(4);
+        build.namespace = "team-infra".to_string();
+        build.signature = vec![7; 96];
+        store
+            .insert_batch(std::slice::from_ref(&build), None)
+            .unwrap();
+        let collapse = Collapse {
+            window: 60,
+            ignore_sudo: true,
+        };
+        let repeats = [entry("ls", 30), entry("sudo make all", 40)];
+        assert_eq!(store.insert_batch(&repeats, Some(collapse)).unwrap(), 0);
+        assert_eq!(store.high_water_mark().unwrap(), 3);
+
+        let mut read = Vec::new();
+        let request = HistoryRequest {
+            after_seq: Some(1),
+            ..Default::default()
+        };
+        store
+            .query_since(&request, &mut |page| {
+                read.extend(page);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1], build);
+        let request = HistoryRequest {
+            namespaces: vec!["team-infra".to_string()],
+            ..Default::default()
+        };
+        let mut shared = Vec::new();
+        store
+            .query_since(&request, &mut |page| {
+                shared.extend(page);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(shared, [build.clone()]);
+
+        let mut search = |query: &SearchQuery| {
+            let mut found = Vec::new();
+            let next = store
+                .search(query, &mut |entry| {
+                    found.push(entry.cmd);
+                    Ok(())
+                })
+                .unwrap();
+            (found, next)
+        };
+        let query = SearchQuery {
+            text: "mak".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(search(&query).0, ["make all"]);
+        let query = SearchQuery {
+            text: "m?ke *".to_string(),
+            mode: MatchMode::Glob,
+            ..Default::default()
+        };
+        assert_eq!(search(&query).0, ["make all"]);
+        let query = SearchQuery {
+            text: "^(ls|cargo)".to_string(),
+            mode: MatchMode::Regex,
+            limit: Some(1),
+            order: SearchOrder::Newest,
+            ..Default::default()
+        };
+        let (found, next) = search(&query);
+        assert_eq!(found, ["cargo build"]);
+        let query = SearchQuery {
+            after: next,
+            ..query
+        };
+        assert_eq!(search(&query).0, ["ls"]);
+        let query = SearchQuery {
+            mode: MatchMode::Substring,
+            hosts: vec!["laptop".to_string()],
+            cwd: Some("/".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(search(&query).0, ["cargo build"]);
+
+        assert_eq!(store.set_pinned("ls", true).unwrap(), 1);
+        assert_eq!(store.delete(cmd_hash("make all")).unwrap(), 1);
+        store.insert_batch(&[entry("make all", 5)], None).unwrap();
+        let retention = Retention {
+            max_age_days: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(store.prune(&retention, 86400 + 2).unwrap().expired, 0);
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.entries, stats.pinned, stats.forgotten), (2, 1, 1));
+        let breakdown = store.breakdown(10, 0).unwrap();
+        assert_eq!(breakdown.top_commands.len(), 2);
+        assert_eq!(breakdown.per_day, [(0, 2)]);
+        let buckets = store.buckets().unwrap();
+        assert_eq!((buckets.len(), buckets[0].entries), (1, 2));
+        let query = SuggestQuery {
+            cwd: Some("/src".to_string()),
+            limit: 10,
+            ..Default::default()
+        };
+        let suggestions = store.suggest(&query, 3600).unwrap();
+        let cmds: Vec<_> = suggestions.iter().map(|s| s.cmd.as_str()).collect();
+        assert_eq!(cmds, ["cargo build", "ls"]);
+        assert_eq!((suggestions[0].uses, suggestions[0].last_used), (1, 3));
+        let query = SuggestQuery {
+            host: Some("laptop".to_string()),
+            limit: 1,
+            ..Default::default()
+        };
+        assert_eq!(store.suggest(&query, 3600).unwrap()[0].cmd, "cargo build");
+        assert_eq!(
+            buckets[0].hash,
+            entry_hash("ls", 1, "") ^ entry_hash("cargo build", 3, "")
+        );
+        let mut ls = entry("ls", 1).with_host("laptop".to_string());
+        ls.cwd = "/home".to_string();
+        let later = entry("ls", 2);
+        assert_eq!(
+            store.merge_into_stored(vec![ls, later.clone()]).unwrap(),
+            [later]
+        );
+        let query = SuggestQuery {
+            cwd: Some("/home".to_string()),
+            limit: 10,
+            ..Default::default()
+        };
+        assert_eq!(store.suggest(&query, 3600).unwrap()[0].cmd, "ls");
+        assert_eq!(store.buckets().unwrap(), buckets);
+
+        let hash = entry_hash("cargo build", 3, "");
+        assert_eq!(store.delete_entries(&[hash]).unwrap(), 1);
+        assert_eq!(store.entry_tombstones_since(0).unwrap(), [hash]);
+        assert_eq!(store.insert_batch(&[build], None).unwrap(), 0);
+        let retention = Retention {
+            tombstone_days: Some(1),
+            ..Default::default()
+        };
+        let pruned = store.prune(&retention, unix_now() + 2 * 86400).unwrap();
+        assert_eq!(pruned.tombstones, 1);
+
+        let enrollment = Enrollment {
+            code: "ABCDEFGH".to_string(),
+            secret: "s3cret".to_string(),
+            device: "phone".to_string(),
+            peer: "10.0.0.2".to_string(),
+            requested_at: 100,
+            token: None,
+        };
+        store.request_enrollment(&enrollment).unwrap();
+        assert!(store.approve_enrollment("ABCDEFGH", "t0ken").unwrap());
+        assert!(!store.approve_enrollment("ABCDEFGH", "other").unwrap());
+        assert_eq!(
+            store.enrollments().unwrap()[0].token.as_deref(),
+            Some("t0ken")
+        );
+        assert_eq!(store.expire_enrollments(100).unwrap(), 0);
+        assert!(store.remove_enrollment("ABCDEFGH").unwrap());
+        store.request_enrollment(&enrollment).unwrap();
+        assert_eq!(store.expire_enrollments(101).unwrap(), 1);
+
+        client
+            .batch_execute(&format!("DROP SCHEMA \"plenty_{}\" CASCADE", user))
+            .unwrap();
+    }
+}
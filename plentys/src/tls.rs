@@ -0,0 +1,270 @@
+/// TLS for the TCP clients of `plentys listen`, with the `[listen]`
+/// certificate and key that `plentys grpc` serves, and the client
+/// certificates of its authority
+use anyhow::{Context, Result};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Sets up TLS on accepted connections
+pub struct Acceptor {
+    config: Arc<ServerConfig>,
+    /// Whether clients authenticate with certificates
+    client_auth: bool,
+}
+
+impl Acceptor {
+    /// Serve the certificate chain of the `cert` PEM file, with the key of
+    /// the `key` one, to clients with a certificate `client_ca` signed if set
+    pub fn load(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self> {
+        let chain = read_certificates(cert)
+            .with_context(|| format!("Failed to read TLS certificate {}", cert.display()))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .with_context(|| format!("Failed to read TLS key {}", key.display()))?;
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .context("Failed to set up TLS")?;
+        let builder = match client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certificates(path).with_context(|| {
+                    format!(
+                        "Failed to read client certificate authority {}",
+                        path.display()
+                    )
+                })? {
+                    roots
+                        .add(cert)
+                        .context("Invalid client certificate authority")?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .context("Invalid client certificate authority")?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(chain, key)
+            .context("Invalid TLS certificate or key")?;
+        Ok(Acceptor {
+            config: Arc::new(config),
+            client_auth: client_ca.is_some(),
+        })
+    }
+
+    /// Complete the handshake with a client, returning the device its
+    /// certificate names if clients authenticate
+    pub fn accept(&self, stream: TcpStream) -> Result<(TlsStream, Option<String>)> {
+        let connection =
+            ServerConnection::new(self.config.clone()).context("Failed to set up TLS")?;
+        let mut stream = StreamOwned::new(connection, stream);
+        while stream.conn.is_handshaking() {
+            stream
+                .conn
+                .complete_io(&mut stream.sock)
+                .context("TLS handshake failed")?;
+        }
+        let device = match self.client_auth {
+            true => Some(
+                stream
+                    .conn
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(|cert| certificate_device(cert))
+                    .context("Client certificate without a common name")?,
+            ),
+            false => None,
+        };
+        Ok((TlsStream(Arc::new(Mutex::new(Connection(stream)))), device))
+    }
+}
+
+/// The certificates of a PEM file
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    Ok(CertificateDer::pem_file_iter(path)?.collect::<Result<_, _>>()?)
+}
+
+/// The device a client certificate names: its subject's common name
+pub(crate) fn certificate_device(der: &[u8]) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(der).ok()?;
+    let name = certificate.subject().iter_common_name().next()?;
+    name.as_str()
+        .ok()
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+/// A connection that tells its client it's over once dropped
+struct Connection(StreamOwned<ServerConnection, TcpStream>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.conn.send_close_notify();
+        let _ = self.0.conn.complete_io(&mut self.0.sock);
+    }
+}
+
+/// An encrypted connection; sessions read and write it from one thread, so
+/// clones share it to split it into a reader and a writer
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<Connection>>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig as PlentysConfig;
+    use crate::serve;
+    use plenty_common::{store, HistoryEntry, Message, MessageType};
+    use rusqlite::Connection as Database;
+    use rustls::{ClientConfig, ClientConnection};
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::PathBuf;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    /// A client trusting the test authority, with its certificate for the
+    /// device if any
+    fn client_config(device: Option<&str>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certificates(&testdata("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        match device {
+            Some(device) => builder
+                .with_client_auth_cert(
+                    read_certificates(&testdata(&format!("{}.pem", device))).unwrap(),
+                    PrivateKeyDer::from_pem_file(testdata(&format!("{}.key", device))).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        }
+    }
+
+    fn connect(
+        address: SocketAddr,
+        device: Option<&str>,
+    ) -> StreamOwned<ClientConnection, TcpStream> {
+        let connection = ClientConnection::new(
+            Arc::new(client_config(device)),
+            "localhost".try_into().unwrap(),
+        )
+        .unwrap();
+        StreamOwned::new(connection, TcpStream::connect(address).unwrap())
+    }
+
+    #[test]
+    fn sessions_run_over_tls() {
+        let acceptor =
+            Acceptor::load(&testdata("server.pem"), &testdata("server.key"), None).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                let (stream, device) = acceptor.accept(stream).unwrap();
+                assert_eq!(device, None);
+                let mut conn = Database::open_in_memory().unwrap();
+                store::init_schema(&mut conn).unwrap();
+                let config = PlentysConfig::default();
+                serve::session(&mut conn, stream.clone(), stream, &config, "test").unwrap();
+                store::count_entries(&conn).unwrap()
+            });
+
+            let mut client = connect(address, None);
+            let entry = HistoryEntry::new("ls".to_string(), 1, String::new());
+            Message::new(MessageType::HistoryEntry, entry.encode())
+                .write_to(&mut client)
+                .unwrap();
+            Message::new(MessageType::End, Vec::new())
+                .write_to(&mut client)
+                .unwrap();
+            // Until the server closes the connection
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).unwrap();
+            assert_eq!(server.join().unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn plain_clients_are_refused() {
+        let acceptor =
+            Acceptor::load(&testdata("server.pem"), &testdata("server.key"), None).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut frame = Vec::new();
+        Message::new(MessageType::End, Vec::new())
+            .write_to(&mut frame)
+            .unwrap();
+        client.write_all(&frame).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert!(acceptor.accept(stream).is_err());
+    }
+
+    #[test]
+    fn clients_authenticate_as_the_device_their_certificate_names() {
+        let ca = testdata("ca.pem");
+        let acceptor =
+            Acceptor::load(&testdata("server.pem"), &testdata("server.key"), Some(&ca)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        for (device, accepted) in [(Some("laptop"), true), (None, false)] {
+            std::thread::scope(|scope| {
+                let server = scope.spawn(|| {
+                    let (stream, _) = listener.accept().unwrap();
+                    acceptor.accept(stream).map(|(_, device)| device.unwrap())
+                });
+                let mut client = connect(address, device);
+                // Sent once the handshake is through, as far as the client knows
+                let _ = client.write_all(b"x").and_then(|()| client.flush());
+                let result = server.join().unwrap();
+                match accepted {
+                    true => assert_eq!(result.unwrap(), "laptop"),
+                    false => assert!(result.is_err()),
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn certificates_name_their_device() {
+        let certificates = read_certificates(&testdata("laptop.pem")).unwrap();
+        assert_eq!(
+            certificate_device(&certificates[0]).as_deref(),
+            Some("laptop")
+        );
+        assert_eq!(certificate_device(b"not a certificate"), None);
+    }
+}
@@ -0,0 +1,262 @@
+/// The formats `plentys export` writes and `plentys import` reads
+use anyhow::{bail, Context, Result};
+use plenty_common::json;
+use plenty_common::{fish, HistoryEntry, Message, MessageType};
+use std::io::{BufRead, Write};
+
+/// How entries are written to or read from a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Protocol frames ending with End, as a `plentys` session sends them
+    #[default]
+    Native,
+    /// One JSON object per line with cmd, when, extra, host and pinned, and
+    /// cwd, exit_code, duration_ms, session, origin_device, received_at and
+    /// namespace when known
+    Jsonl,
+    /// SQL statements creating and filling a `history` table; export only
+    Sql,
+    /// fish_history, without hosts or pins
+    Fish,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Self> {
+        Ok(match format {
+            "native" => Format::Native,
+            "jsonl" => Format::Jsonl,
+            "sql" => Format::Sql,
+            "fish" => Format::Fish,
+            _ => bail!(
+                "format must be \"native\", \"jsonl\", \"sql\" or \"fish\", not {:?}",
+                format
+            ),
+        })
+    }
+}
+
+/// Writes entries in a format, with whatever it needs before and after them
+pub struct Exporter<W: Write> {
+    format: Format,
+    out: W,
+    /// Where native entries are encoded, reused across them
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Exporter<W> {
+    pub fn start(format: Format, mut out: W) -> Result<Self> {
+        if format == Format::Sql {
+            out.write_all(
+                b"BEGIN;\nCREATE TABLE IF NOT EXISTS history (cmd TEXT NOT NULL, \"when\" INTEGER \
+                  NOT NULL, extra TEXT NOT NULL, host TEXT NOT NULL, pinned INTEGER NOT NULL, \
+                  cwd TEXT, exit_code INTEGER, duration_ms INTEGER, session TEXT, \
+                  origin_device TEXT, received_at INTEGER, namespace TEXT, signature BLOB);\n",
+            )
+            .context("Failed to write SQL header")?;
+        }
+        Ok(Self {
+            format,
+            out,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn write(&mut self, entry: &HistoryEntry) -> Result<()> {
+        let out = &mut self.out;
+        match self.format {
+            Format::Native => entry.write_messages_with(out, false, &mut self.buf),
+            Format::Jsonl => writeln!(out, "{}", json::entry(entry)),
+            Format::Sql => {
+                let text = |text: &str| match text {
+                    "" => "NULL".to_string(),
+                    text => sql_string(text),
+                };
+                let number = |n: Option<i64>| n.map_or("NULL".to_string(), |n| n.to_string());
+                writeln!(
+                    out,
+                    "INSERT INTO history VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {});",
+                    sql_string(&entry.cmd),
+                    entry.when,
+                    sql_string(&entry.extra),
+                    sql_string(&entry.host),
+                    entry.pinned as u8,
+                    text(&entry.cwd),
+                    number(entry.exit_code.map(i64::from)),
+                    number(entry.duration_ms.map(|d| d.min(i64::MAX as u64) as i64)),
+                    text(&entry.session),
+                    text(&entry.origin_device),
+                    number(entry.received_at),
+                    text(&entry.namespace),
+                    match entry.signature.as_slice() {
+                        [] => "NULL".to_string(),
+                        signature => format!("X'{}'", json::hex(signature)),
+                    }
+                )
+            }
+            Format::Fish => fish::write_entry(out, entry),
+        }
+        .context("Failed to write history entry")
+    }
+
+    /// Write what follows the entries, returning the writer
+    pub fn finish(mut self) -> Result<W> {
+        match self.format {
+            Format::Native => Message::new(MessageType::End, Vec::new())
+                .write_unflushed(&mut self.out)
+                .context("Failed to write end marker")?,
+            Format::Sql => self
+                .out
+                .write_all(b"COMMIT;\n")
+                .context("Failed to write SQL footer")?,
+            Format::Jsonl | Format::Fish => {}
+        }
+        self.out.flush().context("Failed to flush export")?;
+        Ok(self.out)
+    }
+}
+
+/// Quote `text` as an SQL string literal
+fn sql_string(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+/// Read every entry from `input`, in batches passed to `on_batch`
+pub fn read_entries(
+    format: Format,
+    mut input: impl BufRead,
+    batch_size: usize,
+    mut on_batch: impl FnMut(&[HistoryEntry]) -> Result<()>,
+) -> Result<()> {
+    let mut pending = Vec::new();
+    let mut push = |entry: HistoryEntry| -> Result<()> {
+        pending.push(entry);
+        if pending.len() >= batch_size {
+            on_batch(&pending)?;
+            pending.clear();
+        }
+        Ok(())
+    };
+    match format {
+        Format::Native => loop {
+            let msg = match Message::read_joined(&mut input) {
+                Ok(msg) => msg,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e).context("Failed to read history entry"),
+            };
+            match msg.msg_type {
+                MessageType::HistoryEntry => push(HistoryEntry::decode(&msg.data)?)?,
+                MessageType::End => break,
+                other => bail!("Unexpected {:?} message in import", other),
+            }
+        },
+        Format::Jsonl => {
+            for (number, line) in input.lines().enumerate() {
+                let line = line.context("Failed to read import")?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                push(
+                    json::parse_entry(&line)
+                        .with_context(|| format!("Invalid entry on line {}", number + 1))?,
+                )?;
+            }
+        }
+        Format::Sql => bail!("plentys import can't read SQL; import jsonl instead"),
+        Format::Fish => {
+            let mut content = Vec::new();
+            input
+                .read_to_end(&mut content)
+                .context("Failed to read fish_history")?;
+            for entry in fish::parse_history(&content)? {
+                push(entry)?;
+            }
+        }
+    }
+    on_batch(&pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(format: Format, entries: &[HistoryEntry]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut exporter = Exporter::start(format, &mut out).unwrap();
+        for entry in entries {
+            exporter.write(entry).unwrap();
+        }
+        exporter.finish().unwrap();
+        out
+    }
+
+    fn import(format: Format, input: &[u8]) -> Result<Vec<HistoryEntry>> {
+        let mut entries = Vec::new();
+        read_entries(format, input, 2, |batch| {
+            entries.extend_from_slice(batch);
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let mut built = HistoryEntry::new("cargo build".to_string(), 4, String::new());
+        built.cwd = "/src/plenty".to_string();
+        built.exit_code = Some(101);
+        built.duration_ms = Some(61_000);
+        built.session = "1234".to_string();
+        built.origin_device = "laptop".to_string();
+        built.received_at = Some(5);
+        built.namespace = "team-infra".to_string();
+        let entries = vec![
+            HistoryEntry::new("echo 'hi' \"there\"".to_string(), 1, String::new())
+                .with_host("laptop".to_string())
+                .with_pinned(true),
+            HistoryEntry::new("ls".to_string(), 2, "  paths:\n    - /tmp".to_string()),
+            HistoryEntry::new("make".to_string(), 3, String::new()),
+            built,
+        ];
+        for format in [Format::Native, Format::Jsonl] {
+            assert_eq!(import(format, &export(format, &entries)).unwrap(), entries);
+        }
+        let from_fish = import(Format::Fish, &export(Format::Fish, &entries)).unwrap();
+        assert_eq!(from_fish[0].cmd, entries[0].cmd);
+        assert_eq!(from_fish[1], entries[1]);
+        assert!(from_fish[0].host.is_empty() && !from_fish[0].pinned);
+    }
+
+    #[test]
+    fn sql_export_quotes_strings() {
+        let entry = HistoryEntry::new("echo 'hi'".to_string(), 7, String::new());
+        let sql = String::from_utf8(export(Format::Sql, &[entry])).unwrap();
+        assert!(sql.starts_with("BEGIN;\nCREATE TABLE"));
+        assert!(sql.ends_with(
+            "INSERT INTO history VALUES ('echo ''hi''', 7, '', '', 0, NULL, NULL, NULL, NULL, \
+             NULL, NULL, NULL, NULL);\n\
+             COMMIT;\n"
+        ));
+        assert!(import(Format::Sql, sql.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn jsonl_entries_need_cmd_and_when() {
+        assert!(import(Format::Jsonl, b"{\"when\":5,\"cmd\":\"ls\",\"other\":[]}").is_err());
+        let entries = import(
+            Format::Jsonl,
+            b"\n{\"when\":5,\"cmd\":\"ls\",\"host\":null}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            entries,
+            vec![HistoryEntry::new("ls".to_string(), 5, String::new())]
+        );
+        let error = import(Format::Jsonl, b"{\"cmd\":\"ls\"}\n").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid entry on line 1");
+        assert!(import(Format::Jsonl, b"{\"cmd\":\"ls\",\"when\":\"5\"}").is_err());
+        assert!(import(
+            Format::Jsonl,
+            b"{\"cmd\":\"ls\",\"when\":5,\"duration_ms\":-1}"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,863 @@
+/// `plentys web`: a dashboard to search the history, by host and time, and
+/// chart what it holds in a browser, and the JSON API it calls, which
+/// scripts can also use to upload, search and forget entries without
+/// speaking the sync protocol, browsers with its codec built to WebAssembly
+/// in protocol frames, and devices to enroll; backed by the same stores as
+/// sessions
+use crate::api::{self, Tokens};
+use crate::config::ServerConfig;
+use crate::enroll::{self, Status};
+use crate::listen::Pool;
+use crate::normalize;
+use crate::stats::Report;
+use crate::storage::{HistoryStore, Location};
+use crate::transfer::{self, Format};
+use anyhow::{bail, Context, Result};
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Path as UrlPath, RawQuery, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::Router;
+use plenty_common::json::{self, Value};
+use plenty_common::{cmd_hash, store};
+use plenty_common::{MatchMode, SearchOrder, SearchQuery};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// Matches a search returns unless asked for fewer
+const MAX_MATCHES: u64 = 1000;
+/// Most frequent commands the stats list unless asked for another number
+const DEFAULT_TOP: u64 = 10;
+/// The whole user interface, which calls the API below
+const PAGE: &str = include_str!("web.html");
+/// Content type of bodies holding protocol frames rather than JSON
+const FRAMES: &str = "application/octet-stream";
+
+/// What every request needs
+struct Dashboard {
+    pool: Pool,
+    location: Location,
+    config: ServerConfig,
+    /// Who may use the API; without any, anyone may read and nobody write
+    tokens: Tokens,
+    /// Directory of the files served under /viewer/, such as the WebAssembly
+    /// viewer's
+    viewer: Option<PathBuf>,
+}
+
+/// Serve the dashboard on `address` until killed, and the files of `viewer`
+/// under /viewer/
+pub fn run(
+    address: &str,
+    location: Location,
+    config: &ServerConfig,
+    pool: Pool,
+    viewer: Option<&Path>,
+) -> Result<()> {
+    let tokens = Tokens::load(&config.api)?;
+    let listener = std::net::TcpListener::bind(address)
+        .with_context(|| format!("Failed to listen on {}", address))?;
+    if !listener
+        .local_addr()
+        .is_ok_and(|addr| addr.ip().is_loopback())
+    {
+        if tokens.current().is_empty() {
+            tracing::warn!(
+                "The dashboard is not authenticated, and shows every entry to whoever \
+                 reaches {}; configure [api] token_file in server.toml",
+                address
+            );
+        }
+        tracing::warn!(
+            "The dashboard is not encrypted, only serve it on trusted networks or behind a \
+             TLS terminator"
+        );
+    }
+    tracing::info!("Serving the dashboard on http://{}/…", address);
+    serve(
+        listener,
+        Dashboard {
+            pool,
+            location,
+            config: config.clone(),
+            tokens,
+            viewer: viewer.map(Path::to_path_buf),
+        },
+    )
+}
+
+fn serve(listener: std::net::TcpListener, dashboard: Dashboard) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new().context("Failed to start the web server")?;
+    runtime.block_on(async {
+        listener
+            .set_nonblocking(true)
+            .context("Failed to set up the web server's socket")?;
+        let listener = tokio::net::TcpListener::from_std(listener)
+            .context("Failed to set up the web server's socket")?;
+        let app = Router::new()
+            .route("/", get(|| async { Html(PAGE) }))
+            .route("/api/search", get(search))
+            .route("/api/stats", get(stats))
+            .route("/api/entries", post(upload))
+            .route("/api/commands", delete(forget))
+            .route("/api/enroll", post(request_enrollment))
+            .route("/api/enroll/{code}", get(collect_enrollment))
+            .route(
+                "/viewer/",
+                get(|state| viewer_file(state, UrlPath(String::new()))),
+            )
+            .route("/viewer/{*path}", get(viewer_file))
+            .with_state(Arc::new(dashboard));
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .context("The web server failed")
+    })
+}
+
+/// Run `work` with a store from the pool, on a thread that may block
+async fn with_store<T: Send + 'static>(
+    dashboard: Arc<Dashboard>,
+    work: impl FnOnce(&mut dyn HistoryStore, &Dashboard) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(move || {
+        let mut store = dashboard.pool.get()?;
+        let result = work(store.as_mut(), &dashboard);
+        dashboard.pool.put(store);
+        result
+    })
+    .await
+    .context("Request handler panicked")?
+}
+
+/// Why a request was refused before reaching the store
+struct Refused(StatusCode, &'static str);
+
+impl IntoResponse for Refused {
+    fn into_response(self) -> Response {
+        let Refused(status, message) = self;
+        if status == StatusCode::UNAUTHORIZED {
+            (status, [(header::WWW_AUTHENTICATE, "Bearer")], message).into_response()
+        } else {
+            (status, message).into_response()
+        }
+    }
+}
+
+/// Check the bearer token of a request, returning the device it uploads as;
+/// servers without tokens let anyone read, and nobody write
+fn authorize(
+    dashboard: &Dashboard,
+    headers: &HeaderMap,
+    writes: bool,
+) -> std::result::Result<String, Refused> {
+    let tokens = dashboard.tokens.current();
+    if tokens.is_empty() {
+        if writes {
+            return Err(Refused(
+                StatusCode::FORBIDDEN,
+                "Configure [api] token_file in server.toml to write over the API",
+            ));
+        }
+        return Ok(String::new());
+    }
+    bearer(headers)
+        .and_then(|bearer| api::find_token(&tokens, bearer))
+        .map(|token| token.device.clone())
+        .ok_or(Refused(
+            StatusCode::UNAUTHORIZED,
+            "Missing or unknown API token",
+        ))
+}
+
+/// The bearer token of a request, if any
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// A body of `content_type`, or the error as text
+fn body_response(content_type: &'static str, result: Result<impl IntoResponse>) -> Response {
+    match result {
+        Ok(body) => ([(header::CONTENT_TYPE, content_type)], body).into_response(),
+        Err(e) => {
+            tracing::error!("Dashboard request failed: {:#}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)).into_response()
+        }
+    }
+}
+
+/// A JSON body, or the error as text
+fn json_response(result: Result<String>) -> Response {
+    body_response("application/json", result)
+}
+
+/// The entries matching the query string, as a JSON array of objects like
+/// the jsonl export's, or with `format=native` as the frames of a native
+/// export
+async fn search(
+    State(dashboard): State<Arc<Dashboard>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    if let Err(refused) = authorize(&dashboard, &headers, false) {
+        return refused.into_response();
+    }
+    let query = query.unwrap_or_default();
+    let format = parameters(&query)
+        .into_iter()
+        .find(|(key, _)| key == "format")
+        .map(|(_, format)| format);
+    let native = match format.as_deref() {
+        None | Some("json") => false,
+        Some("native") => true,
+        Some(format) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("format must be \"json\" or \"native\", not {:?}", format),
+            )
+                .into_response()
+        }
+    };
+    let query = match search_query(&query) {
+        Ok(query) => normalize::query(&dashboard.config.ingest, query),
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{:#}", e)).into_response(),
+    };
+    if native {
+        return body_response(
+            FRAMES,
+            with_store(dashboard, move |store, _| {
+                let mut exporter = transfer::Exporter::start(Format::Native, Vec::new())?;
+                store.search(&query, &mut |entry| exporter.write(&entry))?;
+                exporter.finish()
+            })
+            .await,
+        );
+    }
+    json_response(
+        with_store(dashboard, move |store, _| {
+            let mut entries = Vec::new();
+            store.search(&query, &mut |entry| {
+                entries.push(json::entry(&entry));
+                Ok(())
+            })?;
+            Ok(format!("[{}]", entries.join(",")))
+        })
+        .await,
+    )
+}
+
+/// What `plentys stats --json` prints, with `top` most frequent commands
+async fn stats(
+    State(dashboard): State<Arc<Dashboard>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    if let Err(refused) = authorize(&dashboard, &headers, false) {
+        return refused.into_response();
+    }
+    let mut top = DEFAULT_TOP;
+    for (key, value) in parameters(query.as_deref().unwrap_or_default()) {
+        if key == "top" {
+            match value.parse() {
+                Ok(n) => top = n,
+                Err(_) => {
+                    return (StatusCode::BAD_REQUEST, format!("Invalid top {:?}", value))
+                        .into_response()
+                }
+            }
+        }
+    }
+    json_response(
+        with_store(dashboard, move |store, dashboard| {
+            let report = Report::gather(
+                store,
+                &dashboard.location,
+                &dashboard.config.database,
+                top,
+                store::unix_now(),
+            )?;
+            Ok(report.to_json())
+        })
+        .await,
+    )
+}
+
+/// Store the entries of a jsonl body, like `plentys import --format jsonl`,
+/// or of protocol frames if sent as application/octet-stream, as received
+/// from the token's device, answering how many were received, stored and
+/// rejected
+async fn upload(
+    State(dashboard): State<Arc<Dashboard>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let device = match authorize(&dashboard, &headers, true) {
+        Ok(device) => device,
+        Err(refused) => return refused.into_response(),
+    };
+    let format = match headers.get(header::CONTENT_TYPE) {
+        Some(content_type) if content_type == FRAMES => Format::Native,
+        _ => Format::Jsonl,
+    };
+    let mut entries = Vec::new();
+    let parsed = transfer::read_entries(format, &body[..], usize::MAX, |batch| {
+        entries.extend_from_slice(batch);
+        Ok(())
+    });
+    if let Err(e) = parsed {
+        return (StatusCode::BAD_REQUEST, format!("{:#}", e)).into_response();
+    }
+    json_response(
+        with_store(dashboard, move |store, dashboard| {
+            let uploaded = api::store_uploads(store, &dashboard.config, &device, entries)?;
+            Ok(format!(
+                "{{\"received\":{},\"stored\":{},\"rejected\":{}}}",
+                uploaded.received, uploaded.stored, uploaded.rejected
+            ))
+        })
+        .await,
+    )
+}
+
+/// Delete every entry of the `cmd` of the query string and refuse it from
+/// now on, as clients' DeleteEntry does, answering how many were deleted
+async fn forget(
+    State(dashboard): State<Arc<Dashboard>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    if let Err(refused) = authorize(&dashboard, &headers, true) {
+        return refused.into_response();
+    }
+    let cmd = parameters(query.as_deref().unwrap_or_default())
+        .into_iter()
+        .find(|(key, _)| key == "cmd")
+        .map(|(_, cmd)| cmd)
+        .filter(|cmd| !cmd.is_empty());
+    let Some(cmd) = cmd else {
+        return (StatusCode::BAD_REQUEST, "Missing cmd").into_response();
+    };
+    json_response(
+        with_store(dashboard, move |store, _| {
+            Ok(format!("{{\"deleted\":{}}}", store.delete(cmd_hash(&cmd))?))
+        })
+        .await,
+    )
+}
+
+/// Ask for an API token for the device of a JSON body like
+/// `{"device":"laptop"}`, answering the code an admin approves it with and
+/// the secret the device collects its token with
+async fn request_enrollment(
+    State(dashboard): State<Arc<Dashboard>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    body: Bytes,
+) -> Response {
+    if dashboard.config.api.token_file.is_none() {
+        return (
+            StatusCode::FORBIDDEN,
+            "Configure [api] token_file in server.toml to enroll devices",
+        )
+            .into_response();
+    }
+    let device = match enrollment_device(&body) {
+        Ok(device) => device,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{:#}", e)).into_response(),
+    };
+    let requested = with_store(dashboard, move |store, _| {
+        enroll::request(store, &device, &peer.ip().to_string(), store::unix_now())
+    })
+    .await;
+    match requested {
+        Ok(Some(requested)) => json_response(Ok(format!(
+            "{{\"code\":{},\"secret\":{}}}",
+            json::string(&enroll::format_code(&requested.code)),
+            json::string(&requested.secret)
+        ))),
+        Ok(None) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many devices are waiting for approval, try again later",
+        )
+            .into_response(),
+        Err(e) => json_response(Err(e)),
+    }
+}
+
+/// The device a body asking for enrollment names
+fn enrollment_device(body: &[u8]) -> Result<String> {
+    let body = std::str::from_utf8(body).context("The body isn't UTF-8")?;
+    let device = json::parse_object(body)?
+        .into_iter()
+        .find(|(key, _)| key == "device")
+        .map(|(_, value)| value);
+    match device {
+        Some(Value::String(device)) => {
+            enroll::check_device(&device)?;
+            Ok(device)
+        }
+        _ => bail!("Missing device name"),
+    }
+}
+
+/// Where the enrollment of the code is at, for the device holding its
+/// secret as bearer token: 202 while pending, then its token once, and 404
+/// once collected, denied or expired
+async fn collect_enrollment(
+    State(dashboard): State<Arc<Dashboard>>,
+    headers: HeaderMap,
+    UrlPath(code): UrlPath<String>,
+) -> Response {
+    let Some(secret) = bearer(&headers).map(str::to_string) else {
+        return Refused(StatusCode::UNAUTHORIZED, "Missing enrollment secret").into_response();
+    };
+    let status = with_store(dashboard, move |store, _| {
+        enroll::collect(store, &code, &secret, store::unix_now())
+    })
+    .await;
+    match status {
+        Ok(None) => (StatusCode::NOT_FOUND, "No such enrollment").into_response(),
+        Ok(Some(Status::Pending)) => (
+            StatusCode::ACCEPTED,
+            [(header::CONTENT_TYPE, "application/json")],
+            "{\"status\":\"pending\"}",
+        )
+            .into_response(),
+        Ok(Some(Status::Approved(token))) => json_response(Ok(format!(
+            "{{\"status\":\"approved\",\"token\":{}}}",
+            json::string(&token)
+        ))),
+        Err(e) => json_response(Err(e)),
+    }
+}
+
+/// A file of the viewer directory, `index.html` for the directory itself
+async fn viewer_file(
+    State(dashboard): State<Arc<Dashboard>>,
+    UrlPath(path): UrlPath<String>,
+) -> Response {
+    let Some(dir) = &dashboard.viewer else {
+        return (
+            StatusCode::NOT_FOUND,
+            "Start plentys web with --viewer to serve one",
+        )
+            .into_response();
+    };
+    let path = Path::new(if path.is_empty() { "index.html" } else { &path });
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return (StatusCode::NOT_FOUND, "No such file").into_response();
+    }
+    let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        _ => FRAMES,
+    };
+    match std::fs::read(dir.join(path)) {
+        Ok(content) => ([(header::CONTENT_TYPE, content_type)], content).into_response(),
+        Err(_) => (StatusCode::NOT_FOUND, "No such file").into_response(),
+    }
+}
+
+/// The search a query string asks for: `q`, `mode` (words, substring, regex
+/// or glob), `host` (repeatable), `cwd`, `since` and `until` (Unix times),
+/// `limit` and `order` (recent, newest or oldest)
+fn search_query(query: &str) -> Result<SearchQuery> {
+    let mut search = SearchQuery {
+        limit: Some(MAX_MATCHES),
+        ..Default::default()
+    };
+    let time = |value: &str| {
+        value
+            .parse::<i64>()
+            .with_context(|| format!("Invalid time {:?}", value))
+    };
+    for (key, value) in parameters(query) {
+        if value.is_empty() {
+            continue;
+        }
+        match key.as_str() {
+            "q" => search.text = value,
+            "mode" => {
+                search.mode = match value.as_str() {
+                    "words" => MatchMode::Words,
+                    "substring" => MatchMode::Substring,
+                    "regex" => MatchMode::Regex,
+                    "glob" => MatchMode::Glob,
+                    _ => bail!("Invalid mode {:?}", value),
+                }
+            }
+            "host" => search.hosts.push(value),
+            "cwd" => search.cwd = Some(value),
+            "since" => search.since = Some(time(&value)?),
+            "until" => search.until = Some(time(&value)?),
+            "limit" => {
+                let limit = value
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid limit {:?}", value))?;
+                search.limit = Some(limit.min(MAX_MATCHES));
+            }
+            "order" => {
+                search.order = match value.as_str() {
+                    "recent" => SearchOrder::Recent,
+                    "newest" => SearchOrder::Newest,
+                    "oldest" => SearchOrder::Oldest,
+                    _ => bail!("Invalid order {:?}", value),
+                }
+            }
+            _ => {}
+        }
+    }
+    // Without words, list everything rather than nothing
+    if search.text.is_empty() {
+        search.mode = MatchMode::Substring;
+    }
+    Ok(search)
+}
+
+/// The decoded keys and values of a query string, in order
+fn parameters(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+/// Undo the percent-encoding of a query string component; invalid escapes
+/// are kept as they are
+fn decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiToken;
+    use plenty_common::{HistoryEntry, Message, MessageType};
+    use rusqlite::Connection;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn query_strings_become_searches() {
+        assert_eq!(decode("git+log%20-p%2"), "git log -p%2");
+        let query =
+            search_query("q=cargo%20b&mode=substring&host=laptop&host=desk&since=5&until=&limit=7")
+                .unwrap();
+        assert_eq!(query.text, "cargo b");
+        assert_eq!(query.mode, MatchMode::Substring);
+        assert_eq!(query.hosts, ["laptop", "desk"]);
+        assert_eq!((query.since, query.until), (Some(5), None));
+        assert_eq!(query.limit, Some(7));
+        assert_eq!(search_query("").unwrap().limit, Some(MAX_MATCHES));
+        assert!(search_query("since=yesterday").is_err());
+        assert!(search_query("order=random").is_err());
+    }
+
+    /// Serve a store holding `entries` with `config`, these tokens and viewer
+    fn start(
+        entries: &[HistoryEntry],
+        config: ServerConfig,
+        tokens: Vec<ApiToken>,
+        viewer: Option<PathBuf>,
+    ) -> std::net::SocketAddr {
+        let mut conn = Connection::open_in_memory().unwrap();
+        store::init_schema(&mut conn).unwrap();
+        store::insert_entries(&mut conn, entries).unwrap();
+        let location = Location::Sqlite("/nonexistent/history.db".into());
+        let pool = Pool::new(location.clone(), config.database.clone(), Box::new(conn));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            serve(
+                listener,
+                Dashboard {
+                    pool,
+                    location,
+                    config,
+                    tokens: Tokens::fixed(tokens),
+                    viewer,
+                },
+            )
+        });
+        address
+    }
+
+    /// The status and body of the response to a request, with `token`
+    fn request(
+        address: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> (u16, String) {
+        request_with(address, method, path, token, None, body)
+    }
+
+    /// The status and body of the response to a request, with `token` and a
+    /// body of `content_type`
+    fn request_with(
+        address: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        content_type: Option<&str>,
+        body: &str,
+    ) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let authorization = token.map_or(String::new(), |token| {
+            format!("Authorization: Bearer {}\r\n", token)
+        });
+        let content_type = content_type.map_or(String::new(), |content_type| {
+            format!("Content-Type: {}\r\n", content_type)
+        });
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}{}\
+             Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            authorization,
+            content_type,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), body.to_string())
+    }
+
+    fn get(address: std::net::SocketAddr, path: &str) -> String {
+        let (status, body) = request(address, "GET", path, None, "");
+        assert_eq!(status, 200, "{}", body);
+        body
+    }
+
+    #[test]
+    fn dashboard_searches_and_summarizes_the_store() {
+        let address = start(
+            &[
+                HistoryEntry::new("make".to_string(), 1, String::new())
+                    .with_host("laptop".to_string()),
+                HistoryEntry::new("make test".to_string(), 2, String::new()),
+            ],
+            ServerConfig::default(),
+            Vec::new(),
+            None,
+        );
+
+        assert!(get(address, "/").contains("<title>plenty</title>"));
+        assert_eq!(
+            get(address, "/api/search?q=make&host=laptop"),
+            "[{\"cmd\":\"make\",\"when\":1,\"extra\":\"\",\"host\":\"laptop\",\"pinned\":false}]"
+        );
+        assert!(get(address, "/api/stats?top=1").contains("\"entries\":2,\"commands\":2,"));
+        // Nobody may write without tokens
+        let (status, _) = request(address, "DELETE", "/api/commands?cmd=make", None, "");
+        assert_eq!(status, 403);
+    }
+
+    #[test]
+    fn api_uploads_and_forgets_with_a_token() {
+        let config = ServerConfig {
+            reject: vec![regex_lite::Regex::new("^secret").unwrap()],
+            ..Default::default()
+        };
+        let token = ApiToken {
+            token: "s3cret".to_string(),
+            device: "phone".to_string(),
+        };
+        let address = start(&[], config, vec![token], None);
+
+        let (status, _) = request(address, "GET", "/api/search", None, "");
+        assert_eq!(status, 401);
+        let (status, _) = request(address, "GET", "/api/search", Some("s3cre7"), "");
+        assert_eq!(status, 401);
+        let upload = "{\"cmd\":\"ls\",\"when\":5}\n{\"cmd\":\"secret x\",\"when\":6}\n";
+        let (status, body) = request(address, "POST", "/api/entries", Some("s3cret"), upload);
+        assert_eq!(status, 200);
+        assert_eq!(body, "{\"received\":2,\"stored\":1,\"rejected\":1}");
+        let (status, _) = request(
+            address,
+            "POST",
+            "/api/entries",
+            Some("s3cret"),
+            "{\"cmd\":1}",
+        );
+        assert_eq!(status, 400);
+
+        let (_, found) = request(address, "GET", "/api/search", Some("s3cret"), "");
+        assert!(
+            found.starts_with("[{\"cmd\":\"ls\",\"when\":5,"),
+            "{}",
+            found
+        );
+        assert!(found.contains("\"origin_device\":\"phone\""), "{}", found);
+        let (status, body) = request(
+            address,
+            "DELETE",
+            "/api/commands?cmd=ls",
+            Some("s3cret"),
+            "",
+        );
+        assert_eq!((status, body.as_str()), (200, "{\"deleted\":1}"));
+        let (_, found) = request(address, "GET", "/api/search", Some("s3cret"), "");
+        assert_eq!(found, "[]");
+    }
+
+    #[test]
+    fn devices_enroll_once_approved() {
+        let dir = std::env::temp_dir().join(format!("plentys-enroll-web-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = dir.join("history.db");
+        let mut conn = Connection::open(&db).unwrap();
+        store::init_schema(&mut conn).unwrap();
+        let mut config = ServerConfig::default();
+        config.api.token_file = Some(dir.join("tokens"));
+        std::fs::write(dir.join("tokens"), "").unwrap();
+        let location = Location::Sqlite(db.clone());
+        let pool = Pool::new(location.clone(), config.database.clone(), Box::new(conn));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let dashboard = Dashboard {
+            pool,
+            location,
+            tokens: Tokens::load(&config.api).unwrap(),
+            config: config.clone(),
+            viewer: None,
+        };
+        std::thread::spawn(move || serve(listener, dashboard));
+
+        let (status, _) = request(address, "POST", "/api/enroll", None, "{\"device\":\"a b\"}");
+        assert_eq!(status, 400);
+        let (status, body) = request(
+            address,
+            "POST",
+            "/api/enroll",
+            None,
+            "{\"device\":\"phone\"}",
+        );
+        assert_eq!(status, 200, "{}", body);
+        let reply = json::parse_object(&body).unwrap();
+        let [(_, Value::String(code)), (_, Value::String(secret))] = &reply[..] else {
+            panic!("Unexpected enrollment {}", body);
+        };
+        let path = format!("/api/enroll/{}", code);
+        assert_eq!(request(address, "GET", &path, None, "").0, 401);
+        assert_eq!(request(address, "GET", &path, Some("guess"), "").0, 404);
+        assert_eq!(
+            request(address, "GET", &path, Some(secret), ""),
+            (202, "{\"status\":\"pending\"}".to_string())
+        );
+
+        let mut admin = Connection::open(&db).unwrap();
+        let approved = enroll::approve(&mut admin, &config.api, code, store::unix_now()).unwrap();
+        let token = approved.token.unwrap();
+        let (status, body) = request(address, "GET", &path, Some(secret), "");
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(
+            body,
+            format!("{{\"status\":\"approved\",\"token\":\"{}\"}}", token)
+        );
+        assert_eq!(request(address, "GET", &path, Some(secret), "").0, 404);
+
+        // The server takes the token without restarting
+        let upload = "{\"cmd\":\"ls\",\"when\":5}\n";
+        let (status, _) = request(address, "POST", "/api/entries", Some(&token), upload);
+        assert_eq!(status, 200);
+        let (_, found) = request(address, "GET", "/api/search", Some(&token), "");
+        assert!(found.contains("\"origin_device\":\"phone\""), "{}", found);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn browsers_exchange_frames_and_load_the_viewer() {
+        let dir = std::env::temp_dir().join(format!("plentys-viewer-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<title>viewer</title>").unwrap();
+        let token = ApiToken {
+            token: "s3cret".to_string(),
+            device: "browser".to_string(),
+        };
+        let make =
+            HistoryEntry::new("make".to_string(), 1, String::new()).with_host("laptop".to_string());
+        let address = start(
+            std::slice::from_ref(&make),
+            ServerConfig::default(),
+            vec![token],
+            Some(dir.clone()),
+        );
+
+        let mut frames = Message::new(MessageType::HistoryEntry, make.encode()).encode();
+        frames.extend_from_slice(&Message::new(MessageType::End, Vec::new()).encode());
+        let frames = String::from_utf8(frames).unwrap();
+        let search = |path| request(address, "GET", path, Some("s3cret"), "");
+        assert_eq!(search("/api/search?q=make&format=native"), (200, frames));
+        assert_eq!(search("/api/search?format=xml").0, 400);
+
+        let ls = HistoryEntry::new("ls".to_string(), 2, String::new());
+        let mut upload = Message::new(MessageType::HistoryEntry, ls.encode()).encode();
+        upload.extend_from_slice(&Message::new(MessageType::End, Vec::new()).encode());
+        let (status, body) = request_with(
+            address,
+            "POST",
+            "/api/entries",
+            Some("s3cret"),
+            Some(FRAMES),
+            std::str::from_utf8(&upload).unwrap(),
+        );
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(body, "{\"received\":1,\"stored\":1,\"rejected\":0}");
+
+        assert_eq!(
+            request(address, "GET", "/viewer/", None, ""),
+            (200, "<title>viewer</title>".to_string())
+        );
+        assert_eq!(
+            request(address, "GET", "/viewer/../Cargo.toml", None, "").0,
+            404
+        );
+        assert_eq!(
+            request(address, "GET", "/viewer/missing.js", None, "").0,
+            404
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+/// The protocol's history entries for browsers: built to WebAssembly with
+/// `wasm-pack build --target web wasm --out-dir www/pkg`, it lets the viewer
+/// in `www/` decode the frames `plentys web` answers searches with, and
+/// encode the entries it uploads, with the same codec as plenty and plentys
+use anyhow::{bail, Result};
+use plenty_common::{json, EntryChunks, HistoryEntry, Message, MessageType, PROTOCOL_VERSION};
+use wasm_bindgen::prelude::*;
+
+/// Version of the wire protocol this module speaks
+#[wasm_bindgen(js_name = protocolVersion)]
+pub fn protocol_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+/// The entries of protocol frames ending with End, as a JSON array of
+/// objects like the jsonl export's
+#[wasm_bindgen(js_name = decodeEntries)]
+pub fn decode_entries(frames: &[u8]) -> Result<String, JsError> {
+    entries_json(frames).map_err(|e| JsError::new(&format!("{:#}", e)))
+}
+
+/// The frames sending the entries of jsonl text, ending with End, splitting
+/// entries over 64 KiB into chunks if `chunked`
+#[wasm_bindgen(js_name = encodeEntries)]
+pub fn encode_entries(jsonl: &str, chunked: bool) -> Result<Vec<u8>, JsError> {
+    frames(jsonl, chunked).map_err(|e| JsError::new(&format!("{:#}", e)))
+}
+
+fn entries_json(mut frames: &[u8]) -> Result<String> {
+    let mut entries = Vec::new();
+    let mut chunks = EntryChunks::default();
+    loop {
+        let Some((msg, len)) = Message::decode(frames)? else {
+            bail!("The frames end before End");
+        };
+        frames = &frames[len..];
+        match chunks.push(msg)? {
+            None => {}
+            Some(msg) => match msg.msg_type {
+                MessageType::HistoryEntry => {
+                    entries.push(json::entry(&HistoryEntry::decode(&msg.data)?))
+                }
+                MessageType::End => return Ok(format!("[{}]", entries.join(","))),
+                MessageType::Error => bail!("{}", String::from_utf8_lossy(&msg.data)),
+                other => bail!("Unexpected {:?} message among entries", other),
+            },
+        }
+    }
+}
+
+fn frames(jsonl: &str, chunked: bool) -> Result<Vec<u8>> {
+    let mut frames = Vec::new();
+    for line in jsonl.lines().filter(|line| !line.trim().is_empty()) {
+        for msg in json::parse_entry(line)?.messages(chunked) {
+            frames.extend_from_slice(&msg.encode());
+        }
+    }
+    frames.extend_from_slice(&Message::new(MessageType::End, Vec::new()).encode());
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plenty_common::ENTRY_CHUNK_SIZE;
+
+    #[test]
+    fn entries_round_trip_through_frames() {
+        let long = "x".repeat(2 * ENTRY_CHUNK_SIZE);
+        let jsonl = format!(
+            "{{\"cmd\":\"ls\",\"when\":5,\"host\":\"laptop\"}}\n\n{{\"cmd\":\"{}\",\"when\":6}}\n",
+            long
+        );
+        let encoded = frames(&jsonl, true).unwrap();
+        assert_eq!(
+            entries_json(&encoded).unwrap(),
+            format!(
+                "[{{\"cmd\":\"ls\",\"when\":5,\"extra\":\"\",\"host\":\"laptop\",\"pinned\":false}},\
+                 {{\"cmd\":\"{}\",\"when\":6,\"extra\":\"\",\"host\":\"\",\"pinned\":false}}]",
+                long
+            )
+        );
+
+        assert!(entries_json(&encoded[..encoded.len() - 1]).is_err());
+        let mut error =
+            Message::new(MessageType::Error, b"Missing or unknown API token".to_vec()).encode();
+        error.extend_from_slice(&encoded);
+        assert_eq!(
+            entries_json(&error).unwrap_err().to_string(),
+            "Missing or unknown API token"
+        );
+        assert!(frames("{\"cmd\":1}", false).is_err());
+    }
+}